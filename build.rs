@@ -0,0 +1,57 @@
+use std::fs;
+use std::path::Path;
+use std::process::Command;
+
+/// Records the git commit and the exact wgpu version the binary was built from, for `--build-info`
+fn main() {
+    let manifest_dir = std::env::var("CARGO_MANIFEST_DIR").unwrap();
+    let manifest_dir = Path::new(&manifest_dir);
+
+    println!(
+        "cargo:rustc-env=IMAGE_COLORIZER_GIT_COMMIT={}",
+        git_commit(manifest_dir).unwrap_or_else(|| "unknown".to_string())
+    );
+    println!(
+        "cargo:rustc-env=IMAGE_COLORIZER_WGPU_VERSION={}",
+        locked_version(manifest_dir, "wgpu").unwrap_or_else(|| "unknown".to_string())
+    );
+
+    // Paths that don't exist would make every build rerun this script
+    for path in [
+        ".git/HEAD",
+        ".git/refs/heads",
+        ".git/packed-refs",
+        "Cargo.lock",
+    ] {
+        if manifest_dir.join(path).exists() {
+            println!("cargo:rerun-if-changed={}", path);
+        }
+    }
+}
+
+/// The commit of a git checkout, or of the commit a crate published to crates.io was packaged from
+fn git_commit(manifest_dir: &Path) -> Option<String> {
+    let output = Command::new("git")
+        .args(["rev-parse", "HEAD"])
+        .current_dir(manifest_dir)
+        .output()
+        .ok()
+        .filter(|output| output.status.success());
+    if let Some(output) = output {
+        return Some(String::from_utf8(output.stdout).ok()?.trim().to_string());
+    }
+
+    // cargo package writes the commit to .cargo_vcs_info.json as "sha1": "<commit>"
+    let vcs_info = fs::read_to_string(manifest_dir.join(".cargo_vcs_info.json")).ok()?;
+    let sha1 = vcs_info.split("\"sha1\"").nth(1)?;
+    Some(sha1.split('"').nth(1)?.to_string())
+}
+
+/// The version of `package` in Cargo.lock
+fn locked_version(manifest_dir: &Path, package: &str) -> Option<String> {
+    let lock = fs::read_to_string(manifest_dir.join("Cargo.lock")).ok()?;
+    let mut lines = lock.lines();
+    lines.find(|line| *line == format!("name = \"{}\"", package))?;
+    let version = lines.next()?.strip_prefix("version = \"")?;
+    Some(version.trim_end_matches('"').to_string())
+}
@@ -0,0 +1,240 @@
+use crate::config::AppError;
+use crate::report::Reporter;
+use crate::{colorize, PartialOutput};
+
+use std::fs::{self, File};
+use std::io::BufReader;
+use std::time::Instant;
+
+use image_colorizer::{AppConfig, ColorizeOptions, Gpu};
+
+use image::codecs::gif::{GifDecoder, GifEncoder, Repeat};
+use image::codecs::png::PngDecoder;
+use image::{AnimationDecoder, DynamicImage, Frame, ImageFormat};
+use indicatif::MultiProgress;
+
+pub enum AnimationKind {
+    Gif(Repeat),
+    /// Number of times to play the animation, with 0 meaning forever
+    Apng(u32),
+}
+
+impl AnimationKind {
+    // GIFs count how many times the animation repeats after the first play, while APNGs count
+    // every play
+
+    fn repeat(&self) -> Repeat {
+        match *self {
+            AnimationKind::Gif(repeat) => repeat,
+            AnimationKind::Apng(0) => Repeat::Infinite,
+            AnimationKind::Apng(plays) => Repeat::Finite((plays - 1).min(u16::MAX as u32) as u16),
+        }
+    }
+
+    fn plays(&self) -> u32 {
+        match *self {
+            AnimationKind::Gif(Repeat::Infinite) => 0,
+            AnimationKind::Gif(Repeat::Finite(repeats)) => repeats as u32 + 1,
+            AnimationKind::Apng(plays) => plays,
+        }
+    }
+}
+
+pub struct Animation {
+    frames: Vec<Frame>,
+    kind: AnimationKind,
+}
+
+/// Decodes every frame of an animated GIF or APNG, returning `None` for anything that isn't
+/// animated so that it can be processed as a still image
+pub fn open_animation(input_path: &str) -> Result<Option<Animation>, AppError> {
+    let format = image::io::Reader::open(input_path)?
+        .with_guessed_format()?
+        .format();
+
+    let animation = match format {
+        Some(ImageFormat::Gif) => {
+            let decoder = GifDecoder::new(BufReader::new(File::open(input_path)?))?;
+
+            Animation {
+                frames: decoder.into_frames().collect_frames()?,
+                kind: AnimationKind::Gif(read_gif_repeat(input_path)?),
+            }
+        }
+        Some(ImageFormat::Png) => {
+            let decoder = PngDecoder::new(BufReader::new(File::open(input_path)?))?;
+
+            if !decoder.is_apng() {
+                return Ok(None);
+            }
+
+            Animation {
+                frames: decoder.apng().into_frames().collect_frames()?,
+                kind: AnimationKind::Apng(read_apng_plays(input_path)?),
+            }
+        }
+        _ => return Ok(None),
+    };
+
+    if animation.frames.len() > 1 {
+        Ok(Some(animation))
+    } else {
+        Ok(None)
+    }
+}
+
+// image doesn't expose the loop count of animations, so it is read with the underlying decoders
+
+fn read_gif_repeat(input_path: &str) -> Result<Repeat, AppError> {
+    let mut decoder = gif::DecodeOptions::new()
+        .read_info(BufReader::new(File::open(input_path)?))
+        .map_err(|e| format!("Failed to read GIF: {}", e))?;
+
+    // The loop count lives in an extension block that may come after the first frame
+    while decoder
+        .next_frame_info()
+        .map_err(|e| format!("Failed to read GIF: {}", e))?
+        .is_some()
+    {}
+
+    Ok(match decoder.repeat() {
+        gif::Repeat::Finite(n) => Repeat::Finite(n),
+        gif::Repeat::Infinite => Repeat::Infinite,
+    })
+}
+
+fn read_apng_plays(input_path: &str) -> Result<u32, AppError> {
+    let reader = png::Decoder::new(BufReader::new(File::open(input_path)?))
+        .read_info()
+        .map_err(|e| format!("Failed to read APNG: {}", e))?;
+
+    Ok(reader
+        .info()
+        .animation_control
+        .map(|control| control.num_plays)
+        .unwrap_or(0))
+}
+
+pub async fn colorize_animation(
+    animation: Animation,
+    output_path: &str,
+    config: &AppConfig,
+    options: &ColorizeOptions,
+    gpu: Option<&Gpu>,
+    multi_progress: &MultiProgress,
+    reporter: &Reporter,
+) -> Result<(), AppError> {
+    // Frames only cover the part of the animation that changed, so they can't be laid out
+    // next to the original, and an overlay would be placed relative to each one
+    if config.compare.is_some() {
+        return Err(AppError::Other(
+            "--compare can't be used with animations".to_string(),
+        ));
+    }
+    if config.compose_with.is_some() {
+        return Err(AppError::Other(
+            "--compose-with can't be used with animations".to_string(),
+        ));
+    }
+
+    let Animation { frames, kind } = animation;
+    let frame_count = frames.len();
+
+    reporter.set_length(frame_count as u64);
+
+    let mut colorized_frames = Vec::with_capacity(frame_count);
+
+    for (i, frame) in frames.into_iter().enumerate() {
+        let frame_reporter =
+            reporter.frame(multi_progress, format!("Frame {}/{}", i + 1, frame_count));
+
+        let (left, top, delay) = (frame.left(), frame.top(), frame.delay());
+        let img = DynamicImage::ImageRgba8(frame.into_buffer());
+        let colorized = colorize(&img, config, options, gpu, &frame_reporter).await?;
+
+        // Frames shrink when previewing with --max-dimension, so their offsets must shrink too
+        let left = left * colorized.width() / img.width();
+        let top = top * colorized.height() / img.height();
+        colorized_frames.push(Frame::from_parts(colorized.to_rgba8(), left, top, delay));
+
+        frame_reporter.finish_and_clear();
+        reporter.inc(1);
+    }
+
+    // The output may be in a different format than the input when --format is used
+    let format = ImageFormat::from_path(output_path)?;
+    if !matches!(format, ImageFormat::Gif | ImageFormat::Png) {
+        return Err(AppError::Other(format!(
+            "Animations can only be saved as GIF or PNG, not {:?}",
+            format
+        )));
+    }
+
+    let start = Instant::now();
+    // Encoding into memory surfaces write errors, which a BufWriter would drop when flushing, so a
+    // truncated animation is never renamed into place
+    let encoded = if format == ImageFormat::Gif {
+        encode_gif(colorized_frames, kind.repeat())?
+    } else {
+        encode_apng(colorized_frames, kind.plays())?
+    };
+    reporter.add_encode_time(start.elapsed());
+
+    let partial_output = PartialOutput::new(output_path);
+    fs::write(partial_output.temp_path(), encoded)?;
+    partial_output.finish()?;
+    Ok(())
+}
+
+fn encode_gif(frames: Vec<Frame>, repeat: Repeat) -> Result<Vec<u8>, AppError> {
+    let mut output = Vec::new();
+    let mut encoder = GifEncoder::new(&mut output);
+    encoder.set_repeat(repeat)?;
+    encoder.encode_frames(frames)?;
+    drop(encoder);
+    Ok(output)
+}
+
+fn encode_apng(frames: Vec<Frame>, plays: u32) -> Result<Vec<u8>, AppError> {
+    let (width, height) = frames[0].buffer().dimensions();
+
+    let mut output = Vec::new();
+    let mut encoder = png::Encoder::new(&mut output, width, height);
+    encoder.set_color(png::ColorType::Rgba);
+    encoder.set_depth(png::BitDepth::Eight);
+    encoder
+        .set_animated(frames.len() as u32, plays)
+        .map_err(|e| format!("Failed to write APNG: {}", e))?;
+
+    let mut writer = encoder
+        .write_header()
+        .map_err(|e| format!("Failed to write APNG: {}", e))?;
+
+    for frame in frames {
+        let (numerator, denominator) = frame.delay().numer_denom_ms();
+        // png stores delays as u16 fractions of a second rather than milliseconds
+        let (numerator, denominator) = reduce_delay(numerator, denominator.saturating_mul(1000));
+
+        writer
+            .set_frame_delay(numerator, denominator)
+            .map_err(|e| format!("Failed to write APNG: {}", e))?;
+        writer
+            .write_image_data(frame.buffer())
+            .map_err(|e| format!("Failed to write APNG: {}", e))?;
+    }
+
+    writer
+        .finish()
+        .map_err(|e| format!("Failed to write APNG: {}", e))?;
+
+    Ok(output)
+}
+
+fn reduce_delay(mut numerator: u32, mut denominator: u32) -> (u16, u16) {
+    while numerator > u16::MAX as u32 || denominator > u16::MAX as u32 {
+        numerator /= 2;
+        denominator = (denominator / 2).max(1);
+    }
+
+    (numerator as u16, denominator as u16)
+}
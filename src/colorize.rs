@@ -1,8 +1,43 @@
-use crate::{types::AppConfig, utils::compute_integral_image};
+use crate::{
+    cpu::{
+        adjust_contrast_and_saturation, colorize_cpu, error_diffusion_pass, invert_luminance,
+        neutral_mask, posterize,
+    },
+    progress::{Progress, ProgressCallback},
+    types::{
+        BlendCurve, ColorSpace, ColorizeOptions, DistanceMetric, DitherMode, GpuBackend,
+        GpuOptions, Timings,
+    },
+    utils::{
+        blue_noise_thresholds, noise_offset, opacity_weights, palette_coordinates, palette_weight,
+        restore_format,
+    },
+};
+
+use std::io::Cursor;
+#[cfg(feature = "native")]
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
 
 use anyhow::{Context, Result};
-use image::{DynamicImage, GenericImageView, ImageBuffer, Rgb, RgbImage};
-use indicatif::ProgressBar;
+#[cfg(feature = "native")]
+use futures::stream::{self, Stream};
+use image::imageops::FilterType;
+#[cfg(feature = "native")]
+use image::RgbImage;
+use image::{
+    DynamicImage, GenericImage, GenericImageView, GrayImage, ImageBuffer, ImageOutputFormat, Rgb,
+    Rgb32FImage,
+};
+use instant::Instant;
+use palette::Lab;
+#[cfg(feature = "native")]
+use tokio::{
+    runtime::Handle,
+    sync::{mpsc, Semaphore},
+    task,
+};
+use tracing::{debug, info, trace, warn};
 use wgpu::util::DeviceExt;
 
 #[repr(C)]
@@ -29,51 +64,688 @@ struct Params {
     blend_factor: f32,
     dither_amount: f32,
     spatial_radius: u32,
+    dither_mode: u32,
+    color_space: u32,
+    preserve_luminance: u32,
+    linear_blend: u32,
+    /// Position of the tile within the full image, so that dithering lines up between tiles
+    offset_x: u32,
+    offset_y: u32,
+    noise_offset_x: u32,
+    noise_offset_y: u32,
+    /// Whether the blend factor of each pixel is scaled by the mask buffer, which holds the mask
+    /// times the blend map
+    has_mask: u32,
+    sharpen: f32,
+    /// 0 for linear, 1 for ease-in, 2 for ease-out, and 3 for a gamma of `blend_gamma`
+    blend_curve: u32,
+    blend_gamma: f32,
+    /// Whether pixels are matched to a mix of their two nearest colors
+    soft_match: u32,
+    /// 0 for CIEDE2000, 1 for CIE76 and 2 for Euclidean distance in the matching space
+    distance_metric: u32,
 }
 
-pub async fn colorize(
-    img: &DynamicImage,
-    config: &AppConfig,
-    pb: &ProgressBar,
-) -> Result<RgbImage> {
-    let (width, height) = img.dimensions();
+#[repr(C)]
+#[derive(Copy, Clone, bytemuck::Pod, bytemuck::Zeroable)]
+struct ScanParams {
+    width: u32,
+    height: u32,
+    radius: u32,
+    /// Whether `box_average.wgsl` averages along rows rather than columns
+    is_horizontal: u32,
+    /// Whether pixels are weighted by the opacity buffer when averaging
+    has_opacity: u32,
+}
+
+/// A wgpu device and queue that can be shared between multiple calls to `colorize_image`
+pub struct Gpu {
+    device: wgpu::Device,
+    queue: wgpu::Queue,
+    // Compiled once up front, since compiling shaders for every image is slow
+    pipelines: Pipelines,
+    // The blue noise texture never changes, so it is uploaded once
+    blue_noise_buffer: wgpu::Buffer,
+}
 
-    pb.set_length((width * height + 2).into());
+/// The compute pipelines of every pass and the layouts of their bind groups
+struct Pipelines {
+    palette_pipeline: wgpu::ComputePipeline,
+    palette_bind_group_layout: wgpu::BindGroupLayout,
+    to_lab_pipeline: wgpu::ComputePipeline,
+    to_lab_bind_group_layout: wgpu::BindGroupLayout,
+    box_average_pipeline: wgpu::ComputePipeline,
+    box_average_bind_group_layout: wgpu::BindGroupLayout,
+    spatial_averaging_pipeline: wgpu::ComputePipeline,
+    spatial_averaging_bind_group_layout: wgpu::BindGroupLayout,
+}
 
-    // Initialize wgpu
-    let instance = wgpu::Instance::new(wgpu::InstanceDescriptor::default());
-    let adapter = instance
-        .request_adapter(&wgpu::RequestAdapterOptions {
-            power_preference: wgpu::PowerPreference::HighPerformance,
-            compatible_surface: None,
-            force_fallback_adapter: false,
-        })
+/// Initializes wgpu on the high performance adapter, returning `None` if no appropriate adapter
+/// could be found
+pub async fn init_gpu() -> Result<Option<Gpu>> {
+    init_gpu_with(&GpuOptions::default()).await
+}
+
+/// Initializes wgpu on the adapter chosen by `options`, returning `None` if no appropriate adapter
+/// could be found. Choosing an adapter that doesn't exist is an error
+pub async fn init_gpu_with(options: &GpuOptions) -> Result<Option<Gpu>> {
+    let instance = create_instance(options);
+    let adapter = match options.adapter {
+        Some(index) => nth_adapter(&instance, options, index)?,
+        None => match request_high_performance_adapter(&instance).await {
+            Some(adapter) => adapter,
+            // Only fall back to the CPU when wgpu was free to pick any backend
+            None if options.backend == GpuBackend::All => {
+                warn!("No GPU adapter found, colorizing on the CPU");
+                return Ok(None);
+            }
+            None => anyhow::bail!(
+                "No GPU adapter could be found for the {} backend",
+                options.backend
+            ),
+        },
+    };
+
+    let info = adapter.get_info();
+    info!(
+        "Using {:?} adapter '{}' ({:?})",
+        info.backend, info.name, info.device_type
+    );
+
+    create_gpu(&adapter, device_limits(&adapter))
         .await
-        .context("Failed to find an appropriate adapter")?;
+        .map(Some)
+}
 
+/// Creates a device on `adapter` with `limits`, and compiles the pipelines on it
+async fn create_gpu(adapter: &wgpu::Adapter, limits: wgpu::Limits) -> Result<Gpu> {
     let (device, queue) = adapter
         .request_device(
             &wgpu::DeviceDescriptor {
                 label: None,
                 features: wgpu::Features::empty(),
-                limits: wgpu::Limits::default(),
+                limits,
             },
             None,
         )
         .await
         .context("Failed to create device")?;
 
-    let buffer_size = (std::mem::size_of::<ColorizedPixel>() * width as usize * height as usize)
-        as wgpu::BufferAddress;
+    let blue_noise_buffer = device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
+        label: Some("Blue Noise Buffer"),
+        contents: bytemuck::cast_slice(&blue_noise_thresholds()),
+        usage: wgpu::BufferUsages::UNIFORM,
+    });
+
+    Ok(Gpu {
+        pipelines: Pipelines::compile(&device),
+        device,
+        queue,
+        blue_noise_buffer,
+    })
+}
+
+impl Pipelines {
+    fn compile(device: &wgpu::Device) -> Pipelines {
+        // Load and compile the shaders
+        let shader1 = device.create_shader_module(wgpu::ShaderModuleDescriptor {
+            label: Some("Colorize Shader 1"),
+            source: wgpu::ShaderSource::Wgsl(include_str!("shaders/colorize_pass1.wgsl").into()),
+        });
+        let shader2 = device.create_shader_module(wgpu::ShaderModuleDescriptor {
+            label: Some("Colorize Shader 3"),
+            source: wgpu::ShaderSource::Wgsl(include_str!("shaders/colorize_pass3.wgsl").into()),
+        });
+        let box_average_shader = device.create_shader_module(wgpu::ShaderModuleDescriptor {
+            label: Some("Box Average Shader"),
+            source: wgpu::ShaderSource::Wgsl(include_str!("shaders/box_average.wgsl").into()),
+        });
+
+        // Create compute pipelines
+        let palette_pipeline = device.create_compute_pipeline(&wgpu::ComputePipelineDescriptor {
+            label: Some("Compute Pipeline 1"),
+            layout: None,
+            module: &shader1,
+            entry_point: "main",
+        });
+        let spatial_averaging_pipeline =
+            device.create_compute_pipeline(&wgpu::ComputePipelineDescriptor {
+                label: Some("Compute Pipeline 2"),
+                layout: None,
+                module: &shader2,
+                entry_point: "main",
+            });
+        let to_lab_pipeline = device.create_compute_pipeline(&wgpu::ComputePipelineDescriptor {
+            label: Some("To Lab Pipeline"),
+            layout: None,
+            module: &box_average_shader,
+            entry_point: "to_lab",
+        });
+        let box_average_pipeline =
+            device.create_compute_pipeline(&wgpu::ComputePipelineDescriptor {
+                label: Some("Box Average Pipeline"),
+                layout: None,
+                module: &box_average_shader,
+                entry_point: "box_average",
+            });
+
+        Pipelines {
+            palette_bind_group_layout: palette_pipeline.get_bind_group_layout(0),
+            to_lab_bind_group_layout: to_lab_pipeline.get_bind_group_layout(0),
+            box_average_bind_group_layout: box_average_pipeline.get_bind_group_layout(0),
+            spatial_averaging_bind_group_layout: spatial_averaging_pipeline
+                .get_bind_group_layout(0),
+            palette_pipeline,
+            to_lab_pipeline,
+            box_average_pipeline,
+            spatial_averaging_pipeline,
+        }
+    }
+}
+
+/// The default limits if the adapter supports them, or otherwise the lower limits that every
+/// downlevel adapter supports, with a warning naming the limits that were too high. Buffer sizes
+/// are kept as high as the adapter allows, since they decide how many tiles an image is split into
+fn device_limits(adapter: &wgpu::Adapter) -> wgpu::Limits {
+    let (limits, exceeded) = fit_limits(&adapter.limits());
+    if !exceeded.is_empty() {
+        warn!(
+            "The GPU doesn't support the default limits for {}, so lower limits are used. Large images may be split into more tiles",
+            exceeded.join(", ")
+        );
+    }
+    limits
+}
+
+/// Returns the limits to request from an adapter that supports `supported`, along with a
+/// description of each default limit that it doesn't support
+fn fit_limits(supported: &wgpu::Limits) -> (wgpu::Limits, Vec<String>) {
+    let defaults = wgpu::Limits::default();
+    let mut exceeded = Vec::new();
+    defaults.check_limits_with_fail_fn(supported, false, |name, requested, allowed| {
+        exceeded.push(format!("{} ({} > {})", name, requested, allowed))
+    });
+    if exceeded.is_empty() {
+        return (defaults, exceeded);
+    }
+
+    let limits = wgpu::Limits {
+        max_storage_buffer_binding_size: defaults
+            .max_storage_buffer_binding_size
+            .min(supported.max_storage_buffer_binding_size),
+        max_buffer_size: defaults.max_buffer_size.min(supported.max_buffer_size),
+        ..wgpu::Limits::downlevel_defaults()
+    }
+    .using_resolution(supported.clone())
+    .using_alignment(supported.clone());
+
+    (limits, exceeded)
+}
+
+fn create_instance(options: &GpuOptions) -> wgpu::Instance {
+    wgpu::Instance::new(wgpu::InstanceDescriptor {
+        backends: options.backend.backends(),
+        ..Default::default()
+    })
+}
+
+async fn request_high_performance_adapter(instance: &wgpu::Instance) -> Option<wgpu::Adapter> {
+    instance
+        .request_adapter(&wgpu::RequestAdapterOptions {
+            power_preference: wgpu::PowerPreference::HighPerformance,
+            compatible_surface: None,
+            force_fallback_adapter: false,
+        })
+        .await
+}
+
+#[cfg(not(target_arch = "wasm32"))]
+fn nth_adapter(
+    instance: &wgpu::Instance,
+    options: &GpuOptions,
+    index: usize,
+) -> Result<wgpu::Adapter> {
+    let mut adapters: Vec<_> = instance
+        .enumerate_adapters(options.backend.backends())
+        .collect();
+    if index >= adapters.len() {
+        anyhow::bail!(
+            "There is no GPU adapter {} for the {} backend, only {} were found",
+            index,
+            options.backend,
+            adapters.len()
+        );
+    }
+    Ok(adapters.swap_remove(index))
+}
+
+/// Browsers only hand out the adapter they choose, so there are none to pick from by index
+#[cfg(target_arch = "wasm32")]
+fn nth_adapter(_: &wgpu::Instance, _: &GpuOptions, index: usize) -> Result<wgpu::Adapter> {
+    anyhow::bail!(
+        "GPU adapter {} can't be chosen in a browser, which only exposes the adapter it picks",
+        index
+    )
+}
+
+/// Lists every adapter that can be chosen with `GpuOptions::adapter`, along with the index of
+/// the one that `options` would select
+#[cfg(not(target_arch = "wasm32"))]
+pub async fn list_adapters(options: &GpuOptions) -> (Vec<wgpu::AdapterInfo>, Option<usize>) {
+    let instance = create_instance(options);
+    let adapters: Vec<_> = instance
+        .enumerate_adapters(options.backend.backends())
+        .map(|adapter| adapter.get_info())
+        .collect();
+
+    let selected = match options.adapter {
+        Some(index) => Some(index).filter(|&index| index < adapters.len()),
+        None => request_high_performance_adapter(&instance)
+            .await
+            .and_then(|adapter| {
+                let info = adapter.get_info();
+                adapters.iter().position(|other| *other == info)
+            }),
+    };
+
+    (adapters, selected)
+}
+
+/// Maps `img` onto `palette`, running on the CPU when `gpu` is `None`. `progress` is called with
+/// the number of completed steps and the total number of steps as colorization proceeds
+pub async fn colorize_image(
+    img: &DynamicImage,
+    palette: &[Lab],
+    options: &ColorizeOptions,
+    gpu: Option<&Gpu>,
+    progress: Option<&ProgressCallback<'_>>,
+) -> Result<DynamicImage> {
+    colorize(img, palette, options, gpu, progress, false)
+        .await
+        .map(|(colorized, _)| colorized)
+}
+
+/// Like `colorize_image`, but decodes `encoded` from any format that `image` can recognize and
+/// returns the result encoded as a PNG, which keeps alpha and 16-bit channels. This needs no
+/// filesystem, such as in a browser
+pub async fn colorize_bytes(
+    encoded: &[u8],
+    palette: &[Lab],
+    options: &ColorizeOptions,
+    gpu: Option<&Gpu>,
+    progress: Option<&ProgressCallback<'_>>,
+) -> Result<Vec<u8>> {
+    let img = image::load_from_memory(encoded).context("Failed to decode the image")?;
+    let colorized = colorize_image(&img, palette, options, gpu, progress).await?;
+
+    let mut png = Cursor::new(Vec::new());
+    colorized.write_to(&mut png, ImageOutputFormat::Png)?;
+    Ok(png.into_inner())
+}
+
+/// Opens and colorizes the images at `paths` in a task each, with up to `concurrency` of them in
+/// progress at once, and yields each one as soon as it is done rather than in the order of
+/// `paths`. An image that fails doesn't stop the rest, and its error names its path. The tasks are
+/// spawned on the current tokio runtime, so this must be called from within one, and images that
+/// haven't started yet are skipped once the stream is dropped
+#[cfg(feature = "native")]
+pub fn colorize_batch(
+    paths: impl IntoIterator<Item = PathBuf>,
+    palette: Vec<Lab>,
+    options: ColorizeOptions,
+    gpu: Option<Arc<Gpu>>,
+    concurrency: usize,
+) -> impl Stream<Item = Result<(PathBuf, RgbImage)>> {
+    let concurrency = concurrency.max(1);
+    let palette: Arc<[Lab]> = palette.into();
+    let options = Arc::new(options);
+    // Limits how many images are held in memory at once, and is held until an image is yielded
+    let semaphore = Arc::new(Semaphore::new(concurrency));
+    let (sender, receiver) = mpsc::channel(concurrency);
+
+    for path in paths {
+        let (palette, options, gpu) = (Arc::clone(&palette), Arc::clone(&options), gpu.clone());
+        let (semaphore, sender) = (Arc::clone(&semaphore), sender.clone());
+
+        tokio::spawn(async move {
+            let Ok(_permit) = semaphore.acquire_owned().await else {
+                return;
+            };
+            if sender.is_closed() {
+                return;
+            }
+
+            let result = colorize_path(&path, palette, options, gpu)
+                .await
+                .with_context(|| format!("Failed to colorize {}", path.display()))
+                .map(|colorized| (path, colorized));
+            let _ = sender.send(result).await;
+        });
+    }
+
+    stream::unfold(receiver, |mut receiver| async move {
+        let result = receiver.recv().await?;
+        Some((result, receiver))
+    })
+}
+
+/// Decodes and colorizes one image of `colorize_batch`. Decoding and colorizing on the CPU block,
+/// so they run on the blocking thread pool rather than holding up the tasks of other images
+#[cfg(feature = "native")]
+async fn colorize_path(
+    path: &Path,
+    palette: Arc<[Lab]>,
+    options: Arc<ColorizeOptions>,
+    gpu: Option<Arc<Gpu>>,
+) -> Result<RgbImage> {
+    let opened = path.to_path_buf();
+    let img = task::spawn_blocking(move || image::open(opened)).await??;
+
+    let colorized = match gpu {
+        Some(gpu) => colorize_image(&img, &palette, &options, Some(&gpu), None).await?,
+        None => {
+            let runtime = Handle::current();
+            task::spawn_blocking(move || {
+                runtime.block_on(colorize_image(&img, &palette, &options, None, None))
+            })
+            .await??
+        }
+    };
+    Ok(colorized.to_rgb8())
+}
+
+/// Like `colorize_image`, but also returns how long each stage took, such as to decide whether
+/// spatial averaging is worth its cost. On the GPU each pass is waited on before the next is
+/// submitted so that they can be timed separately, which makes this slower than `colorize_image`
+pub async fn colorize_image_with_timings(
+    img: &DynamicImage,
+    palette: &[Lab],
+    options: &ColorizeOptions,
+    gpu: Option<&Gpu>,
+    progress: Option<&ProgressCallback<'_>>,
+) -> Result<(DynamicImage, Timings)> {
+    colorize(img, palette, options, gpu, progress, true).await
+}
+
+/// Colorizes `img`, only waiting on each GPU pass to time it when `time_gpu_passes` is set
+async fn colorize(
+    img: &DynamicImage,
+    palette: &[Lab],
+    options: &ColorizeOptions,
+    gpu: Option<&Gpu>,
+    progress: Option<&ProgressCallback<'_>>,
+    time_gpu_passes: bool,
+) -> Result<(DynamicImage, Timings)> {
+    let (width, height) = img.dimensions();
+    let mut timings = Timings::default();
+
+    if let Some(mask) = &options.mask {
+        if mask.dimensions() != (width, height) {
+            anyhow::bail!(
+                "The mask is {}x{}, but the image is {}x{}",
+                mask.width(),
+                mask.height(),
+                width,
+                height
+            );
+        }
+    }
+
+    if palette.is_empty() {
+        anyhow::bail!("The palette is empty");
+    }
+
+    if !options.palette_weights.is_empty() && options.palette_weights.len() != palette.len() {
+        anyhow::bail!(
+            "There are {} palette weights, but {} palette colors",
+            options.palette_weights.len(),
+            palette.len()
+        );
+    }
+
+    // The blend map can be any size, unlike the mask
+    let resized_options;
+    let options = match &options.blend_map {
+        Some(blend_map) if blend_map.dimensions() != (width, height) => {
+            resized_options = ColorizeOptions {
+                blend_map: Some(Arc::new(image::imageops::resize(
+                    blend_map.as_ref(),
+                    width,
+                    height,
+                    FilterType::Triangle,
+                ))),
+                ..options.clone()
+            };
+            &resized_options
+        }
+        _ => options,
+    };
+
+    // Neutral pixels are masked out, which both paths and the adjustments after them already honor
+    let neutral_options;
+    let options = if options.preserve_neutrals > 0.0 {
+        neutral_options = ColorizeOptions {
+            mask: Some(Arc::new(neutral_mask(img, options))),
+            ..options.clone()
+        };
+        &neutral_options
+    } else {
+        options
+    };
+
+    // Both paths keep a float for every channel of every pixel, which can't be addressed on 32-bit
+    // platforms for very large images
+    let pixels = u64::from(width) * u64::from(height);
+    let bytes = pixels.checked_mul(4 * std::mem::size_of::<f32>() as u64);
+    if bytes
+        .and_then(|bytes| usize::try_from(bytes).ok())
+        .is_none()
+    {
+        anyhow::bail!(
+            "The image is {}x{}, which has too many pixels to colorize on this platform",
+            width,
+            height
+        );
+    }
+
+    // Inverting is a separate step before either path, so palette matching only ever sees the
+    // inverted image
+    let inverted;
+    let source = if options.invert_luminance {
+        let mut rgb = img.to_rgb32f();
+        invert_luminance(&mut rgb, options);
+        inverted = DynamicImage::ImageRgb32F(rgb);
+        &inverted
+    } else {
+        img
+    };
+
+    // Fully transparent pixels, such as the background of a sprite, are left out of spatial
+    // averaging
+    let opacity = opacity_weights(img).map(Arc::new);
+
+    let mut colorized = match gpu {
+        Some(gpu) => {
+            let tiles = tiles(
+                &gpu.device.limits(),
+                width,
+                height,
+                options.spatial_averaging_radius,
+            )?;
+            // Each tile counts a step for each of its passes
+            let pb = Progress::new(progress, 2 * tiles.len() as u64);
+
+            let colorized = colorize_gpu(
+                source,
+                palette,
+                options,
+                opacity.as_deref(),
+                gpu,
+                &tiles,
+                &pb,
+                &mut timings,
+                time_gpu_passes,
+            )
+            .await?;
+            pb.finish();
+            colorized
+        }
+        None => {
+            // Progress is counted per pixel of the integral image, which is skipped with a radius
+            // of 0
+            let steps = match options.spatial_averaging_radius {
+                0 => 2,
+                _ => u64::from(width) * u64::from(height) + 2,
+            };
+            let pb = Progress::new(progress, steps);
+
+            let colorized =
+                colorize_cpu(source, palette, options, opacity, &pb, &mut timings).await?;
+            pb.finish();
+            colorized
+        }
+    };
+
+    debug!(
+        "Colorized a {}x{} image on the {}: {:?}",
+        width,
+        height,
+        if gpu.is_some() { "GPU" } else { "CPU" },
+        timings
+    );
+
+    // Contrast is relative to the mean of the whole image, so this can't happen per tile
+    adjust_contrast_and_saturation(&mut colorized, options);
+    // Posterizing comes last so that it quantizes the dithered colors rather than being undone by
+    // the other adjustments
+    posterize(&mut colorized, options);
+
+    // The GPU passes only work on RGB, so the original alpha channel is reattached afterwards
+    Ok((restore_format(colorized, img), timings))
+}
+
+#[derive(Debug, Clone, Copy)]
+struct Rect {
+    x: u32,
+    y: u32,
+    width: u32,
+    height: u32,
+}
+
+/// A part of the image small enough to fit in the GPU's storage buffers
+struct Tile {
+    /// The pixels that are colorized, including enough of the neighbouring tiles that spatial
+    /// averaging sees the same pixels as it would in the full image
+    bounds: Rect,
+    /// The pixels of the output that this tile is responsible for
+    core: Rect,
+}
+
+/// Splits an image into tiles that fit within a device's buffer limits. Fails with an error rather
+/// than a driver panic if the spatial averaging radius is too large for any tile to fit
+fn tiles(limits: &wgpu::Limits, width: u32, height: u32, radius: u32) -> Result<Vec<Tile>> {
+    let max_buffer_size =
+        u64::from(limits.max_storage_buffer_binding_size).min(limits.max_buffer_size);
+    let max_pixels = max_buffer_size / std::mem::size_of::<ColorizedPixel>() as u64;
+
+    // Every buffer holds one color per pixel of the tile
+    debug!(
+        "Buffers can hold up to {} bytes, or {} pixels",
+        max_buffer_size, max_pixels
+    );
+    if u64::from(width) * u64::from(height) <= max_pixels {
+        let whole = Rect {
+            x: 0,
+            y: 0,
+            width,
+            height,
+        };
+        return Ok(vec![Tile {
+            bounds: whole,
+            core: whole,
+        }]);
+    }
+
+    let tile_size = (max_pixels as f64).sqrt() as u32;
+    let core_size = tile_size.saturating_sub(2 * radius);
+    if core_size < 16 {
+        anyhow::bail!(
+                "A spatial averaging radius of {} is too large to fit within this GPU's {} byte buffer limit. Use a smaller radius or --cpu",
+                radius,
+                max_buffer_size
+            );
+    }
+
+    let mut tiles = Vec::new();
+    for core_y in (0..height).step_by(core_size as usize) {
+        for core_x in (0..width).step_by(core_size as usize) {
+            let core = Rect {
+                x: core_x,
+                y: core_y,
+                width: core_size.min(width - core_x),
+                height: core_size.min(height - core_y),
+            };
+
+            let x = core.x.saturating_sub(radius);
+            let y = core.y.saturating_sub(radius);
+            let bounds = Rect {
+                x,
+                y,
+                width: (core.x + core.width).saturating_add(radius).min(width) - x,
+                height: (core.y + core.height).saturating_add(radius).min(height) - y,
+            };
+
+            tiles.push(Tile { bounds, core });
+        }
+    }
+
+    debug!(
+        "Split the {}x{} image into {} tiles of up to {}x{} pixels",
+        width,
+        height,
+        tiles.len(),
+        tile_size,
+        tile_size
+    );
+    Ok(tiles)
+}
+
+fn crop<P: image::Pixel + 'static>(
+    img: &ImageBuffer<P, Vec<P::Subpixel>>,
+    rect: Rect,
+) -> ImageBuffer<P, Vec<P::Subpixel>> {
+    img.view(rect.x, rect.y, rect.width, rect.height).to_image()
+}
 
-    let input_buffer = create_input_buffer(&device, img);
-    let output_buffer1 = create_output_buffer(&device, width, height);
-    let staging_buffer = create_staging_buffer(&device, width, height);
+#[allow(clippy::too_many_arguments)]
+async fn colorize_gpu(
+    img: &DynamicImage,
+    palette: &[Lab],
+    options: &ColorizeOptions,
+    opacity: Option<&GrayImage>,
+    gpu: &Gpu,
+    tiles: &[Tile],
+    pb: &Progress<'_>,
+    timings: &mut Timings,
+    time_passes: bool,
+) -> Result<Rgb32FImage> {
+    let device = &gpu.device;
+    let (width, height) = img.dimensions();
+    // Pixels are kept as floats throughout, so 16-bit images keep their precision
+    let img = img.to_rgb32f();
 
-    let color_palette: Vec<[f32; 3]> = config
-        .colors
+    // Each color is padded to 16 bytes with its weight, which the shader divides distances by, and
+    // followed by its Lab coordinates, which CIE76 and CIEDE2000 measure distances in
+    let color_palette: Vec<[f32; 4]> = palette_coordinates(palette, options.color_space)
         .iter()
-        .map(|lab| [lab.l as f32, lab.a as f32, lab.b as f32])
+        .zip(palette_coordinates(palette, ColorSpace::Lab))
+        .enumerate()
+        .flat_map(|(i, (&[l, a, b], [lab_l, lab_a, lab_b]))| {
+            [
+                [l, a, b, palette_weight(&options.palette_weights, i)],
+                [lab_l, lab_a, lab_b, 0.0],
+            ]
+        })
         .collect();
     let color_palette_buffer = device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
         label: Some("Color Palette Buffer"),
@@ -81,38 +753,158 @@ pub async fn colorize(
         usage: wgpu::BufferUsages::STORAGE,
     });
 
-    let params = Params {
-        width,
-        height,
-        blend_factor: config.blend_factor,
-        dither_amount: config.dither_amount,
-        spatial_radius: config.spatial_averaging_radius,
+    // Error diffusion can't be parallelized, so the first pass has to happen on the CPU. It is
+    // done for the whole image at once so that error carries over between tiles
+    let error_diffused = if options.dither_mode == DitherMode::FloydSteinberg {
+        let start = Instant::now();
+        let first_pass = error_diffusion_pass(&img, palette, options);
+        timings.palette_pass += start.elapsed();
+        pb.inc(1);
+        Some(first_pass)
+    } else {
+        None
     };
 
-    let params_buffer = device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
-        label: Some("Params Buffer"),
-        contents: bytemuck::cast_slice(&[params]),
-        usage: wgpu::BufferUsages::UNIFORM,
-    });
+    let noise_offset = noise_offset(options.seed);
+    let mut output = Rgb32FImage::new(width, height);
 
-    // Load and compile the shaders
-    let shader1 = device.create_shader_module(wgpu::ShaderModuleDescriptor {
-        label: Some("Colorize Shader 1"),
-        source: wgpu::ShaderSource::Wgsl(include_str!("shaders/colorize_pass1.wgsl").into()),
-    });
+    for tile in tiles {
+        let Tile { bounds, core } = *tile;
+        trace!(
+            "Colorizing the {}x{} tile at ({}, {}) with buffers of {} bytes",
+            bounds.width,
+            bounds.height,
+            bounds.x,
+            bounds.y,
+            buffer_size(bounds.width, bounds.height)
+        );
+        let params = Params {
+            width: bounds.width,
+            height: bounds.height,
+            // The blend map replaces the blend factor, and is multiplied into the mask buffer
+            blend_factor: match options.blend_map {
+                Some(_) => 1.0,
+                None => options.blend_factor,
+            },
+            dither_amount: options.dither_amount,
+            spatial_radius: options.spatial_averaging_radius,
+            dither_mode: dither_mode_index(options.dither_mode),
+            color_space: match options.color_space {
+                ColorSpace::Lab => 0,
+                ColorSpace::Oklab => 1,
+            },
+            preserve_luminance: options.preserve_luminance as u32,
+            linear_blend: options.linear_blend as u32,
+            offset_x: bounds.x,
+            offset_y: bounds.y,
+            noise_offset_x: noise_offset[0],
+            noise_offset_y: noise_offset[1],
+            has_mask: (options.mask.is_some() || options.blend_map.is_some()) as u32,
+            sharpen: options.sharpen,
+            blend_curve: match options.blend_curve {
+                BlendCurve::Linear => 0,
+                BlendCurve::EaseIn => 1,
+                BlendCurve::EaseOut => 2,
+                BlendCurve::Gamma(_) => 3,
+            },
+            blend_gamma: match options.blend_curve {
+                BlendCurve::Gamma(gamma) => gamma,
+                _ => 1.0,
+            },
+            soft_match: options.soft_match as u32,
+            distance_metric: match options.distance_metric {
+                DistanceMetric::Ciede2000 => 0,
+                DistanceMetric::Cie76 => 1,
+                DistanceMetric::Euclidean => 2,
+            },
+        };
 
-    // Create compute pipelines
-    let compute_pipeline1 = device.create_compute_pipeline(&wgpu::ComputePipelineDescriptor {
-        label: Some("Compute Pipeline 1"),
-        layout: None,
-        module: &shader1,
-        entry_point: "main",
-    });
+        let params_buffer = device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
+            label: Some("Params Buffer"),
+            contents: bytemuck::cast_slice(&[params]),
+            usage: wgpu::BufferUsages::UNIFORM,
+        });
+        let blend_weights: Vec<&GrayImage> = options
+            .mask
+            .iter()
+            .chain(&options.blend_map)
+            .map(|mask| mask.as_ref())
+            .collect();
+        let mask_buffer = create_mask_buffer(device, &blend_weights, bounds);
+        let opacity_buffer = create_mask_buffer(device, opacity.as_slice(), bounds);
+
+        // The first pass stays on the GPU, since spatial averaging is done there as well
+        let first_pass = match error_diffused {
+            Some(ref error_diffused) => create_input_buffer(device, &crop(error_diffused, bounds)),
+            None => palette_pass(
+                gpu,
+                &crop(&img, bounds),
+                &color_palette_buffer,
+                &params_buffer,
+                &mask_buffer,
+                pb,
+                time_passes.then_some(&mut *timings),
+            ),
+        };
+
+        // A radius of 0 averages each pixel with only itself, so the second pass would change
+        // nothing
+        let result = if options.spatial_averaging_radius == 0 {
+            first_pass
+        } else {
+            spatial_averaging_pass(
+                gpu,
+                &first_pass,
+                &params_buffer,
+                &mask_buffer,
+                &opacity_buffer,
+                &params,
+                opacity.is_some(),
+                pb,
+                time_passes.then_some(&mut *timings),
+            )
+        };
+
+        let start = Instant::now();
+        let colorized = read_output_buffer(gpu, &result, bounds.width, bounds.height).await?;
+        timings.readback += start.elapsed();
+
+        output.copy_from(
+            &*colorized.view(
+                core.x - bounds.x,
+                core.y - bounds.y,
+                core.width,
+                core.height,
+            ),
+            core.x,
+            core.y,
+        )?;
+    }
+
+    Ok(output)
+}
+
+/// Runs `colorize_pass1.wgsl` over a tile, returning the buffer holding its result
+fn palette_pass(
+    gpu: &Gpu,
+    img: &Rgb32FImage,
+    color_palette_buffer: &wgpu::Buffer,
+    params_buffer: &wgpu::Buffer,
+    mask_buffer: &wgpu::Buffer,
+    pb: &Progress<'_>,
+    timings: Option<&mut Timings>,
+) -> wgpu::Buffer {
+    let Gpu { device, queue, .. } = gpu;
+    let (width, height) = img.dimensions();
+    let start = Instant::now();
+
+    let input_buffer = create_input_buffer(device, img);
+    let output_buffer1 = create_output_buffer(device, width, height);
 
     // Create bind groups
     let bind_group1 = device.create_bind_group(&wgpu::BindGroupDescriptor {
         label: Some("Bind Group 1"),
-        layout: &compute_pipeline1.get_bind_group_layout(0),
+        layout: &gpu.pipelines.palette_bind_group_layout,
         entries: &[
             wgpu::BindGroupEntry {
                 binding: 0,
@@ -130,6 +922,14 @@ pub async fn colorize(
                 binding: 3,
                 resource: params_buffer.as_entire_binding(),
             },
+            wgpu::BindGroupEntry {
+                binding: 4,
+                resource: mask_buffer.as_entire_binding(),
+            },
+            wgpu::BindGroupEntry {
+                binding: 5,
+                resource: gpu.blue_noise_buffer.as_entire_binding(),
+            },
         ],
     });
 
@@ -140,109 +940,193 @@ pub async fn colorize(
         {
             let mut compute_pass =
                 encoder.begin_compute_pass(&wgpu::ComputePassDescriptor { label: None });
-            compute_pass.set_pipeline(&compute_pipeline1);
+            compute_pass.set_pipeline(&gpu.pipelines.palette_pipeline);
             compute_pass.set_bind_group(0, &bind_group1, &[]);
-            compute_pass.dispatch_workgroups((width + 15) / 16, (height + 15) / 16, 1);
+            compute_pass.dispatch_workgroups(width.div_ceil(16), height.div_ceil(16), 1);
         }
-        encoder.copy_buffer_to_buffer(&output_buffer1, 0, &staging_buffer, 0, buffer_size);
         queue.submit(Some(encoder.finish()));
     }
+    // Waiting for the pass here means its time isn't counted towards the next one, but it holds
+    // up submitting the next, so it only happens when the pass is timed
+    if let Some(timings) = timings {
+        device.poll(wgpu::Maintain::Wait);
+        timings.palette_pass += start.elapsed();
+    }
 
     pb.inc(1);
 
-    // Read back the result of the first pass
+    output_buffer1
+}
+
+fn dither_mode_index(mode: DitherMode) -> u32 {
+    match mode {
+        DitherMode::None | DitherMode::FloydSteinberg => 0,
+        DitherMode::Noise => 1,
+        DitherMode::Ordered => 2,
+        DitherMode::BlueNoise => 3,
+    }
+}
+
+fn read_buffer(buffer_slice: &wgpu::BufferSlice) -> Vec<Pixel> {
+    let data = buffer_slice.get_mapped_range();
+    bytemuck::cast_slice(&data).to_vec()
+}
+
+/// Maps `staging_buffer`, copies its pixels into an image, and unmaps it again. Polling blocks
+/// until the mapping callback has run, so a task that is cancelled can never be left holding a
+/// mapped buffer
+async fn read_staging_buffer(
+    device: &wgpu::Device,
+    staging_buffer: &wgpu::Buffer,
+    width: u32,
+    height: u32,
+) -> Result<Rgb32FImage> {
     let buffer_slice = staging_buffer.slice(..);
     let (sender, receiver) = futures::channel::oneshot::channel();
-    buffer_slice.map_async(wgpu::MapMode::Read, move |v| sender.send(v).unwrap());
+    buffer_slice.map_async(wgpu::MapMode::Read, move |v| {
+        // The receiver is only gone if the task was cancelled, and then the result is unused
+        let _ = sender.send(v);
+    });
     device.poll(wgpu::Maintain::Wait);
 
     if let Ok(()) = receiver.await? {
         let result = read_buffer(&buffer_slice);
         staging_buffer.unmap();
 
-        process_result(&device, &queue, result, width, height, params_buffer, &pb).await
+        Ok(pixels_to_image(&result, width, height))
     } else {
         Err(anyhow::anyhow!("Failed to run compute on GPU!"))
     }
 }
 
-fn read_buffer(buffer_slice: &wgpu::BufferSlice) -> Vec<Pixel> {
-    let data = buffer_slice.get_mapped_range();
-    bytemuck::cast_slice(&data).to_vec()
-}
-
-async fn process_result(
-    device: &wgpu::Device,
-    queue: &wgpu::Queue,
-    result: Vec<Pixel>,
+/// Copies `buffer` into a staging buffer and reads it back as an image
+async fn read_output_buffer(
+    gpu: &Gpu,
+    buffer: &wgpu::Buffer,
     width: u32,
     height: u32,
-    params_buffer: wgpu::Buffer,
-    pb: &ProgressBar,
-) -> Result<RgbImage> {
-    let buffer_size = (std::mem::size_of::<ColorizedPixel>() * width as usize * height as usize)
-        as wgpu::BufferAddress;
-    let shader2 = device.create_shader_module(wgpu::ShaderModuleDescriptor {
-        label: Some("Colorize Shader 3"),
-        source: wgpu::ShaderSource::Wgsl(include_str!("shaders/colorize_pass3.wgsl").into()),
-    });
-    let compute_pipeline2 = device.create_compute_pipeline(&wgpu::ComputePipelineDescriptor {
-        label: Some("Compute Pipeline 2"),
-        layout: None,
-        module: &shader2,
-        entry_point: "main",
-    });
-    let output_buffer2 = create_output_buffer(&device, width, height);
-    let staging_buffer = create_staging_buffer(&device, width, height);
-    // Convert to image for CPU processing
-    let mut img = ImageBuffer::new(width, height);
-    for (i, pixel) in result.iter().enumerate() {
-        let x = i as u32 % width;
-        let y = i as u32 / width;
-        img.put_pixel(
-            x,
-            y,
-            Rgb([
-                (pixel.r * 255.0) as u8,
-                (pixel.g * 255.0) as u8,
-                (pixel.b * 255.0) as u8,
-            ]),
-        );
-    }
+) -> Result<Rgb32FImage> {
+    let Gpu { device, queue, .. } = gpu;
+    let staging_buffer = create_staging_buffer(device, width, height);
 
-    let input_buffer = create_input_buffer(&device, &img.clone().into());
+    let mut encoder =
+        device.create_command_encoder(&wgpu::CommandEncoderDescriptor { label: None });
+    encoder.copy_buffer_to_buffer(buffer, 0, &staging_buffer, 0, buffer_size(width, height));
+    queue.submit(Some(encoder.finish()));
 
-    // Perform CPU-based spatial averaging
-    let spatially_averaged = compute_integral_image(&img, pb);
+    read_staging_buffer(device, &staging_buffer, width, height).await
+}
 
-    let input_data: Vec<ColorizedPixel> = spatially_averaged
-        .iter()
-        .flatten()
-        .map(|&p| ColorizedPixel {
-            r: p.0 as f32,
-            g: p.1 as f32,
-            b: p.2 as f32,
+/// Runs `colorize_pass3.wgsl` over a tile, averaging each pixel of the first pass with its
+/// neighbours. The averages come from `box_average.wgsl`, which converts the first pass to Lab and
+/// then averages it along rows and then along columns
+#[allow(clippy::too_many_arguments)]
+fn spatial_averaging_pass(
+    gpu: &Gpu,
+    first_pass: &wgpu::Buffer,
+    params_buffer: &wgpu::Buffer,
+    mask_buffer: &wgpu::Buffer,
+    opacity_buffer: &wgpu::Buffer,
+    params: &Params,
+    has_opacity: bool,
+    pb: &Progress<'_>,
+    timings: Option<&mut Timings>,
+) -> wgpu::Buffer {
+    let Gpu { device, queue, .. } = gpu;
+    let Params { width, height, .. } = *params;
+    let start = Instant::now();
+
+    let lab_buffer = create_output_buffer(device, width, height);
+    let row_averages_buffer = create_output_buffer(device, width, height);
+    let averages_buffer = create_output_buffer(device, width, height);
+    let output_buffer2 = create_output_buffer(device, width, height);
+
+    let scan_params_buffer = |is_horizontal: bool| {
+        let scan_params = ScanParams {
+            width,
+            height,
+            radius: params.spatial_radius,
+            is_horizontal: is_horizontal as u32,
+            has_opacity: has_opacity as u32,
+        };
+        device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
+            label: Some("Scan Params Buffer"),
+            contents: bytemuck::cast_slice(&[scan_params]),
+            usage: wgpu::BufferUsages::UNIFORM,
         })
-        .collect();
+    };
+    let horizontal_params_buffer = scan_params_buffer(true);
+    let vertical_params_buffer = scan_params_buffer(false);
 
-    // Create a new buffer with the spatially averaged result
-    let spatially_averaged_buffer = device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
-        label: Some("Spatially Averaged Buffer"),
-        contents: bytemuck::cast_slice(&input_data),
-        usage: wgpu::BufferUsages::STORAGE | wgpu::BufferUsages::COPY_DST,
+    let scan_bind_group = |layout: &wgpu::BindGroupLayout,
+                           input: &wgpu::Buffer,
+                           output: &wgpu::Buffer,
+                           scan_params: &wgpu::Buffer| {
+        device.create_bind_group(&wgpu::BindGroupDescriptor {
+            label: Some("Scan Bind Group"),
+            layout,
+            entries: &[
+                wgpu::BindGroupEntry {
+                    binding: 0,
+                    resource: input.as_entire_binding(),
+                },
+                wgpu::BindGroupEntry {
+                    binding: 1,
+                    resource: output.as_entire_binding(),
+                },
+                wgpu::BindGroupEntry {
+                    binding: 2,
+                    resource: scan_params.as_entire_binding(),
+                },
+            ],
+        })
+    };
+    let to_lab_bind_group = device.create_bind_group(&wgpu::BindGroupDescriptor {
+        label: Some("To Lab Bind Group"),
+        layout: &gpu.pipelines.to_lab_bind_group_layout,
+        entries: &[
+            wgpu::BindGroupEntry {
+                binding: 0,
+                resource: first_pass.as_entire_binding(),
+            },
+            wgpu::BindGroupEntry {
+                binding: 1,
+                resource: lab_buffer.as_entire_binding(),
+            },
+            wgpu::BindGroupEntry {
+                binding: 2,
+                resource: horizontal_params_buffer.as_entire_binding(),
+            },
+            wgpu::BindGroupEntry {
+                binding: 3,
+                resource: opacity_buffer.as_entire_binding(),
+            },
+        ],
     });
+    let horizontal_bind_group = scan_bind_group(
+        &gpu.pipelines.box_average_bind_group_layout,
+        &lab_buffer,
+        &row_averages_buffer,
+        &horizontal_params_buffer,
+    );
+    let vertical_bind_group = scan_bind_group(
+        &gpu.pipelines.box_average_bind_group_layout,
+        &row_averages_buffer,
+        &averages_buffer,
+        &vertical_params_buffer,
+    );
 
     let bind_group2 = device.create_bind_group(&wgpu::BindGroupDescriptor {
         label: Some("Bind Group 2"),
-        layout: &compute_pipeline2.get_bind_group_layout(0),
+        layout: &gpu.pipelines.spatial_averaging_bind_group_layout,
         entries: &[
             wgpu::BindGroupEntry {
                 binding: 0,
-                resource: input_buffer.as_entire_binding(),
+                resource: first_pass.as_entire_binding(),
             },
             wgpu::BindGroupEntry {
                 binding: 1,
-                resource: spatially_averaged_buffer.as_entire_binding(),
+                resource: averages_buffer.as_entire_binding(),
             },
             wgpu::BindGroupEntry {
                 binding: 2,
@@ -252,66 +1136,66 @@ async fn process_result(
                 binding: 3,
                 resource: params_buffer.as_entire_binding(),
             },
+            wgpu::BindGroupEntry {
+                binding: 4,
+                resource: mask_buffer.as_entire_binding(),
+            },
         ],
     });
 
-    // Second GPU pass
+    // Second GPU pass, with wgpu inserting barriers between the dispatches that depend on each other
     {
         let mut encoder =
             device.create_command_encoder(&wgpu::CommandEncoderDescriptor { label: None });
         {
             let mut compute_pass =
                 encoder.begin_compute_pass(&wgpu::ComputePassDescriptor { label: None });
-            compute_pass.set_pipeline(&compute_pipeline2);
+            compute_pass.set_pipeline(&gpu.pipelines.to_lab_pipeline);
+            compute_pass.set_bind_group(0, &to_lab_bind_group, &[]);
+            compute_pass.dispatch_workgroups(width.div_ceil(16), height.div_ceil(16), 1);
+
+            // One invocation for each row, and then for each column
+            compute_pass.set_pipeline(&gpu.pipelines.box_average_pipeline);
+            compute_pass.set_bind_group(0, &horizontal_bind_group, &[]);
+            compute_pass.dispatch_workgroups(height.div_ceil(64), 1, 1);
+            compute_pass.set_bind_group(0, &vertical_bind_group, &[]);
+            compute_pass.dispatch_workgroups(width.div_ceil(64), 1, 1);
+
+            compute_pass.set_pipeline(&gpu.pipelines.spatial_averaging_pipeline);
             compute_pass.set_bind_group(0, &bind_group2, &[]);
-            compute_pass.dispatch_workgroups((width + 15) / 16, (height + 15) / 16, 1);
+            compute_pass.dispatch_workgroups(width.div_ceil(16), height.div_ceil(16), 1);
         }
-        encoder.copy_buffer_to_buffer(&output_buffer2, 0, &staging_buffer, 0, buffer_size);
         queue.submit(Some(encoder.finish()));
     }
+    if let Some(timings) = timings {
+        device.poll(wgpu::Maintain::Wait);
+        timings.spatial_averaging_pass += start.elapsed();
+    }
 
-    // Read back the final result
-    let buffer_slice = staging_buffer.slice(..);
-    let (sender, receiver) = futures::channel::oneshot::channel();
-    buffer_slice.map_async(wgpu::MapMode::Read, move |v| sender.send(v).unwrap());
-    device.poll(wgpu::Maintain::Wait);
-
-    if let Ok(()) = receiver.await? {
-        let result = read_buffer(&buffer_slice);
-        staging_buffer.unmap();
-
-        let mut output_image = ImageBuffer::new(width, height);
-        for (i, pixel) in result.iter().enumerate() {
-            let x = i as u32 % width;
-            let y = i as u32 / width;
+    pb.inc(1);
 
-            output_image.put_pixel(
-                x,
-                y,
-                Rgb([
-                    (pixel.r * 255.0) as u8,
-                    (pixel.g * 255.0) as u8,
-                    (pixel.b * 255.0) as u8,
-                ]),
-            );
-        }
+    output_buffer2
+}
 
-        pb.finish_with_message("Processing complete!");
+fn pixels_to_image(pixels: &[Pixel], width: u32, height: u32) -> Rgb32FImage {
+    let mut img = ImageBuffer::new(width, height);
+    for (i, pixel) in pixels.iter().enumerate() {
+        let x = (i % width as usize) as u32;
+        let y = (i / width as usize) as u32;
 
-        Ok(output_image)
-    } else {
-        Err(anyhow::anyhow!("Failed to run compute on GPU!"))
+        img.put_pixel(x, y, Rgb([pixel.r, pixel.g, pixel.b]));
     }
+
+    img
 }
 
-fn create_input_buffer(device: &wgpu::Device, img: &DynamicImage) -> wgpu::Buffer {
+fn create_input_buffer(device: &wgpu::Device, img: &Rgb32FImage) -> wgpu::Buffer {
     let input_data: Vec<ColorizedPixel> = img
-        .to_rgb8()
         .pixels()
         .map(|p| ColorizedPixel {
-            r: p[0] as f32 / 255.0,
-            g: p[1] as f32 / 255.0,
-            b: p[2] as f32 / 255.0,
+            r: p[0],
+            g: p[1],
+            b: p[2],
         })
         .collect();
     device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
@@ -321,24 +1205,214 @@ fn create_input_buffer(device: &wgpu::Device, img: &DynamicImage) -> wgpu::Buffe
     })
 }
 
+/// Uploads the part of `masks` covered by `rect` as one float per pixel, multiplying them together
+/// where there are several. Without a mask the shaders never read the buffer, but it still has to
+/// be bound, so it holds a single value
+fn create_mask_buffer(device: &wgpu::Device, masks: &[&GrayImage], rect: Rect) -> wgpu::Buffer {
+    let mask_data: Vec<f32> = match masks {
+        [] => vec![1.0],
+        _ => {
+            let mut data = vec![1.0; rect.width as usize * rect.height as usize];
+            for mask in masks {
+                for (value, p) in data.iter_mut().zip(crop(mask, rect).pixels()) {
+                    *value *= p[0] as f32 / 255.0;
+                }
+            }
+            data
+        }
+    };
+    device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
+        label: Some("Mask Buffer"),
+        contents: bytemuck::cast_slice(&mask_data),
+        usage: wgpu::BufferUsages::STORAGE,
+    })
+}
+
+/// The size in bytes of a buffer holding a color for every pixel of a tile. This is computed in 64
+/// bits, since it can be larger than `usize` on 32-bit platforms
+fn buffer_size(width: u32, height: u32) -> wgpu::BufferAddress {
+    std::mem::size_of::<ColorizedPixel>() as u64 * u64::from(width) * u64::from(height)
+}
+
 fn create_output_buffer(device: &wgpu::Device, width: u32, height: u32) -> wgpu::Buffer {
-    let buffer_size = (std::mem::size_of::<ColorizedPixel>() * width as usize * height as usize)
-        as wgpu::BufferAddress;
     device.create_buffer(&wgpu::BufferDescriptor {
         label: Some("Output Buffer"),
-        size: buffer_size,
+        size: buffer_size(width, height),
         usage: wgpu::BufferUsages::STORAGE | wgpu::BufferUsages::COPY_SRC,
         mapped_at_creation: false,
     })
 }
 
 fn create_staging_buffer(device: &wgpu::Device, width: u32, height: u32) -> wgpu::Buffer {
-    let buffer_size = (std::mem::size_of::<ColorizedPixel>() * width as usize * height as usize)
-        as wgpu::BufferAddress;
     device.create_buffer(&wgpu::BufferDescriptor {
         label: Some("Staging Buffer"),
-        size: buffer_size,
+        size: buffer_size(width, height),
         usage: wgpu::BufferUsages::MAP_READ | wgpu::BufferUsages::COPY_DST,
         mapped_at_creation: false,
     })
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn default_limits_are_kept_when_supported() {
+        let (limits, exceeded) = fit_limits(&wgpu::Limits::default());
+
+        assert!(exceeded.is_empty());
+        assert_eq!(limits, wgpu::Limits::default());
+    }
+
+    #[test]
+    fn limits_are_lowered_to_what_the_adapter_supports() {
+        let supported = wgpu::Limits {
+            max_storage_buffer_binding_size: 64 << 20,
+            ..wgpu::Limits::downlevel_defaults()
+        };
+
+        let (limits, exceeded) = fit_limits(&supported);
+
+        assert!(limits.check_limits(&supported));
+        assert_eq!(limits.max_storage_buffer_binding_size, 64 << 20);
+        assert!(exceeded
+            .iter()
+            .any(|limit| limit.starts_with("max_storage_buffers_per_shader_stage")));
+    }
+
+    /// Limits whose storage buffers hold `pixels` colors
+    fn limits_for(pixels: u32) -> wgpu::Limits {
+        wgpu::Limits {
+            max_storage_buffer_binding_size: pixels * std::mem::size_of::<ColorizedPixel>() as u32,
+            ..wgpu::Limits::default()
+        }
+    }
+
+    #[test]
+    fn images_that_fit_are_a_single_tile() {
+        let tiles = tiles(&limits_for(64 * 48), 64, 48, 10).unwrap();
+
+        assert_eq!(tiles.len(), 1);
+        let Rect {
+            x,
+            y,
+            width,
+            height,
+        } = tiles[0].bounds;
+        assert_eq!((x, y, width, height), (0, 0, 64, 48));
+    }
+
+    #[test]
+    fn tile_cores_cover_every_pixel_once_and_bounds_add_the_radius() {
+        let (width, height, radius) = (150, 97, 5);
+        let limits = limits_for(40 * 40);
+        let tiles = tiles(&limits, width, height, radius).unwrap();
+        assert!(tiles.len() > 1);
+
+        let mut covered = vec![0; (width * height) as usize];
+        for tile in &tiles {
+            let (core, bounds) = (tile.core, tile.bounds);
+            for y in core.y..core.y + core.height {
+                for x in core.x..core.x + core.width {
+                    covered[(y * width + x) as usize] += 1;
+                }
+            }
+
+            // The bounds reach the radius past the core, except where the image ends
+            assert_eq!(bounds.x, core.x.saturating_sub(radius));
+            assert_eq!(bounds.y, core.y.saturating_sub(radius));
+            assert_eq!(
+                bounds.x + bounds.width,
+                (core.x + core.width + radius).min(width)
+            );
+            assert_eq!(
+                bounds.y + bounds.height,
+                (core.y + core.height + radius).min(height)
+            );
+            assert!(bounds.width * bounds.height <= 40 * 40);
+        }
+        assert!(covered.iter().all(|&count| count == 1));
+
+        // Tiles on the edges are clamped to the image, while those inside keep the whole halo
+        let corner = tiles.first().unwrap();
+        assert_eq!((corner.bounds.x, corner.bounds.y), (0, 0));
+        assert!(tiles
+            .iter()
+            .any(|tile| tile.bounds.x + radius == tile.core.x
+                && tile.bounds.y + radius == tile.core.y));
+    }
+
+    #[test]
+    fn radii_too_large_for_any_tile_are_an_error() {
+        let limits = limits_for(40 * 40);
+
+        assert!(tiles(&limits, 150, 97, 12).is_ok());
+        assert!(tiles(&limits, 150, 97, 13).is_err());
+    }
+
+    /// Compares compiling the pipelines once for a batch against compiling them again for every
+    /// image. Both run on the same device, so only the cost of compiling is measured
+    #[tokio::test]
+    #[ignore = "benchmark that needs a GPU adapter, run with `cargo test -- --ignored`"]
+    async fn shared_pipelines_reduce_batch_setup_time() {
+        let Some(mut gpu) = init_gpu().await.unwrap() else {
+            eprintln!("No GPU adapter found, skipping test");
+            return;
+        };
+        let images: Vec<DynamicImage> = (0..10)
+            .map(|i| {
+                image::RgbImage::from_fn(64, 64, |x, y| {
+                    image::Rgb([(x * 4) as u8, (y * 4) as u8, (i * 25) as u8])
+                })
+                .into()
+            })
+            .collect();
+        let palette = [Lab::new(10.0, 0.0, 5.0), Lab::new(60.0, -20.0, 30.0)];
+        let options = ColorizeOptions::default();
+
+        // The first image also pays for setting up the device, so it isn't timed
+        colorize_image(&images[0], &palette, &options, Some(&gpu), None)
+            .await
+            .unwrap();
+
+        let start = std::time::Instant::now();
+        for img in &images {
+            gpu.pipelines = Pipelines::compile(&gpu.device);
+            colorize_image(img, &palette, &options, Some(&gpu), None)
+                .await
+                .unwrap();
+        }
+        let per_image = start.elapsed();
+
+        let start = std::time::Instant::now();
+        for img in &images {
+            colorize_image(img, &palette, &options, Some(&gpu), None)
+                .await
+                .unwrap();
+        }
+        let shared = start.elapsed();
+
+        println!(
+            "{} images: {:?} compiling pipelines for each, {:?} sharing them",
+            images.len(),
+            per_image,
+            shared
+        );
+        assert!(shared < per_image);
+    }
+
+    #[tokio::test]
+    async fn pipelines_fit_in_the_downlevel_limits() {
+        let instance = create_instance(&GpuOptions::default());
+        let Some(adapter) = request_high_performance_adapter(&instance).await else {
+            eprintln!("No GPU adapter found, skipping test");
+            return;
+        };
+
+        // Compiling a pipeline that binds more than the limits allow panics
+        let (limits, _) = fit_limits(&wgpu::Limits::downlevel_defaults());
+        create_gpu(&adapter, limits.using_resolution(adapter.limits()))
+            .await
+            .unwrap();
+    }
+}
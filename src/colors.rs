@@ -1,3 +1,21 @@
+/// Colorschemes embedded in the binary, so that they work without downloading anything. Files in
+/// the config directory take precedence over these
+pub const BUILTIN_COLORSCHEMES: [(&str, &[&str]); 5] = [
+    ("dracula", &DRACULA),
+    ("gruvbox", &GRUVBOX),
+    ("kanagawa", &KANAGAWA),
+    ("nord", &NORD),
+    ("solarized", &SOLARIZED),
+];
+
+/// Looks up a built-in colorscheme, ignoring case like downloads do
+pub fn builtin_colorscheme(name: &str) -> Option<&'static [&'static str]> {
+    BUILTIN_COLORSCHEMES
+        .iter()
+        .find(|(builtin, _)| builtin.eq_ignore_ascii_case(name))
+        .map(|&(_, colors)| colors)
+}
+
 pub const KANAGAWA: [&str; 103] = [
     "#16161D", // sumiInk0
     "#181820", // sumiInk1
@@ -103,3 +121,87 @@ pub const KANAGAWA: [&str; 103] = [
     "#5a7785", // lotusTeal3
     "#d7e3d8", // lotusCyan
 ];
+
+pub const DRACULA: [&str; 13] = [
+    "#191A21", // Darker Background
+    "#21222C", // Dark Background
+    "#282A36", // Background
+    "#44475A", // Current Line
+    "#6272A4", // Comment
+    "#F8F8F2", // Foreground
+    "#8BE9FD", // Cyan
+    "#50FA7B", // Green
+    "#FFB86C", // Orange
+    "#FF79C6", // Pink
+    "#BD93F9", // Purple
+    "#FF5555", // Red
+    "#F1FA8C", // Yellow
+];
+
+pub const GRUVBOX: [&str; 27] = [
+    "#1D2021", // bg0_h
+    "#282828", // bg0
+    "#32302F", // bg0_s
+    "#3C3836", // bg1
+    "#504945", // bg2
+    "#665C54", // bg3
+    "#7C6F64", // bg4
+    "#928374", // gray
+    "#A89984", // fg4
+    "#BDAE93", // fg3
+    "#D5C4A1", // fg2
+    "#EBDBB2", // fg1
+    "#FBF1C7", // fg0
+    "#CC241D", // red
+    "#FB4934", // bright red
+    "#98971A", // green
+    "#B8BB26", // bright green
+    "#D79921", // yellow
+    "#FABD2F", // bright yellow
+    "#458588", // blue
+    "#83A598", // bright blue
+    "#B16286", // purple
+    "#D3869B", // bright purple
+    "#689D6A", // aqua
+    "#8EC07C", // bright aqua
+    "#D65D0E", // orange
+    "#FE8019", // bright orange
+];
+
+pub const NORD: [&str; 16] = [
+    "#2E3440", // nord0 (Polar Night)
+    "#3B4252", // nord1
+    "#434C5E", // nord2
+    "#4C566A", // nord3
+    "#D8DEE9", // nord4 (Snow Storm)
+    "#E5E9F0", // nord5
+    "#ECEFF4", // nord6
+    "#8FBCBB", // nord7 (Frost)
+    "#88C0D0", // nord8
+    "#81A1C1", // nord9
+    "#5E81AC", // nord10
+    "#BF616A", // nord11 (Aurora)
+    "#D08770", // nord12
+    "#EBCB8B", // nord13
+    "#A3BE8C", // nord14
+    "#B48EAD", // nord15
+];
+
+pub const SOLARIZED: [&str; 16] = [
+    "#002B36", // base03
+    "#073642", // base02
+    "#586E75", // base01
+    "#657B83", // base00
+    "#839496", // base0
+    "#93A1A1", // base1
+    "#EEE8D5", // base2
+    "#FDF6E3", // base3
+    "#B58900", // yellow
+    "#CB4B16", // orange
+    "#DC322F", // red
+    "#D33682", // magenta
+    "#6C71C4", // violet
+    "#268BD2", // blue
+    "#2AA198", // cyan
+    "#859900", // green
+];
@@ -0,0 +1,148 @@
+use image::imageops::{self, FilterType};
+use image::{DynamicImage, GenericImageView, ImageBuffer, Pixel, Primitive};
+use image_colorizer::CompareLayout;
+
+/// Puts `original` and `colorized` together in a single image for `--compare`. The original is
+/// resized to match previews made with `--max-dimension`
+pub fn compose(
+    original: &DynamicImage,
+    colorized: &DynamicImage,
+    layout: CompareLayout,
+) -> DynamicImage {
+    let resized;
+    let original = if original.dimensions() == colorized.dimensions() {
+        original
+    } else {
+        resized =
+            original.resize_exact(colorized.width(), colorized.height(), FilterType::Triangle);
+        &resized
+    };
+
+    // Both images are converted to the type of the colorized one, so that 16-bit images stay 16-bit
+    match colorized {
+        DynamicImage::ImageRgb8(colorized) => {
+            DynamicImage::ImageRgb8(compose_buffers(&original.to_rgb8(), colorized, layout))
+        }
+        DynamicImage::ImageRgba8(colorized) => {
+            DynamicImage::ImageRgba8(compose_buffers(&original.to_rgba8(), colorized, layout))
+        }
+        DynamicImage::ImageRgb16(colorized) => {
+            DynamicImage::ImageRgb16(compose_buffers(&original.to_rgb16(), colorized, layout))
+        }
+        colorized => DynamicImage::ImageRgba16(compose_buffers(
+            &original.to_rgba16(),
+            &colorized.to_rgba16(),
+            layout,
+        )),
+    }
+}
+
+fn compose_buffers<P>(
+    original: &ImageBuffer<P, Vec<P::Subpixel>>,
+    colorized: &ImageBuffer<P, Vec<P::Subpixel>>,
+    layout: CompareLayout,
+) -> ImageBuffer<P, Vec<P::Subpixel>>
+where
+    P: Pixel + 'static,
+{
+    let (width, height) = colorized.dimensions();
+
+    match layout {
+        CompareLayout::Horizontal => {
+            let mut output = ImageBuffer::new(width * 2, height);
+            imageops::replace(&mut output, original, 0, 0);
+            imageops::replace(&mut output, colorized, i64::from(width), 0);
+            output
+        }
+        CompareLayout::Vertical => {
+            let mut output = ImageBuffer::new(width, height * 2);
+            imageops::replace(&mut output, original, 0, 0);
+            imageops::replace(&mut output, colorized, 0, i64::from(height));
+            output
+        }
+        CompareLayout::Split => split(original, colorized),
+    }
+}
+
+/// Shows the original on the left half and the colorized image on the right, divided by a line
+/// with a round handle in the middle like a before and after slider
+fn split<P>(
+    original: &ImageBuffer<P, Vec<P::Subpixel>>,
+    colorized: &ImageBuffer<P, Vec<P::Subpixel>>,
+) -> ImageBuffer<P, Vec<P::Subpixel>>
+where
+    P: Pixel + 'static,
+{
+    let (width, height) = colorized.dimensions();
+    let middle = width / 2;
+    let line_width = (width / 400).max(2);
+    let line = middle.saturating_sub(line_width / 2)..middle + line_width - line_width / 2;
+    let handle_radius = (width.min(height) / 30).max(6);
+
+    let white = *P::from_slice(&vec![
+        P::Subpixel::DEFAULT_MAX_VALUE;
+        usize::from(P::CHANNEL_COUNT)
+    ]);
+
+    ImageBuffer::from_fn(width, height, |x, y| {
+        let dx = i64::from(x) - i64::from(middle);
+        let dy = i64::from(y) - i64::from(height / 2);
+        let on_line = line.contains(&x);
+        let on_handle = dx * dx + dy * dy <= i64::from(handle_radius).pow(2);
+
+        if on_line || on_handle {
+            white
+        } else if x < middle {
+            *original.get_pixel(x, y)
+        } else {
+            *colorized.get_pixel(x, y)
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use image::{Rgb, RgbImage};
+
+    fn images() -> (DynamicImage, DynamicImage) {
+        let original = RgbImage::from_pixel(40, 20, Rgb([10, 20, 30]));
+        let colorized = RgbImage::from_pixel(40, 20, Rgb([200, 100, 50]));
+        (original.into(), colorized.into())
+    }
+
+    #[test]
+    fn side_by_side_layouts_place_the_original_first() {
+        let (original, colorized) = images();
+
+        let horizontal = compose(&original, &colorized, CompareLayout::Horizontal).to_rgb8();
+        assert_eq!(horizontal.dimensions(), (80, 20));
+        assert_eq!(horizontal.get_pixel(0, 0), &Rgb([10, 20, 30]));
+        assert_eq!(horizontal.get_pixel(40, 0), &Rgb([200, 100, 50]));
+
+        let vertical = compose(&original, &colorized, CompareLayout::Vertical).to_rgb8();
+        assert_eq!(vertical.dimensions(), (40, 40));
+        assert_eq!(vertical.get_pixel(0, 20), &Rgb([200, 100, 50]));
+    }
+
+    #[test]
+    fn split_divides_the_image_with_a_line() {
+        let (original, colorized) = images();
+
+        let split = compose(&original, &colorized, CompareLayout::Split).to_rgb8();
+        assert_eq!(split.dimensions(), (40, 20));
+        assert_eq!(split.get_pixel(0, 0), &Rgb([10, 20, 30]));
+        assert_eq!(split.get_pixel(39, 0), &Rgb([200, 100, 50]));
+        assert_eq!(split.get_pixel(20, 0), &Rgb([255, 255, 255]));
+    }
+
+    #[test]
+    fn previews_are_compared_with_a_resized_original() {
+        let (original, _) = images();
+        let preview = DynamicImage::ImageRgb8(RgbImage::new(20, 10));
+
+        let composed = compose(&original, &preview, CompareLayout::Horizontal);
+        assert_eq!(composed.dimensions(), (40, 10));
+    }
+}
@@ -1,21 +1,38 @@
-use crate::colors::KANAGAWA;
-use crate::constants::VERSION;
-use crate::types::AppConfig;
-use crate::utils::{hex_to_rgb, interpolate_color};
-
+use crate::colors::{builtin_colorscheme, BUILTIN_COLORSCHEMES};
+use crate::constants::{
+    DEFAULT_SCHEME_REPO, ENV_PREFIX, GIT_COMMIT, STDIO_PATH, VERSION, VIDEO_EXTENSIONS,
+    WGPU_VERSION,
+};
+use crate::logging;
+use crate::output_template::{OutputTemplate, TemplateValues};
+use crate::palette_preview;
+use crate::themes::load_terminal_theme;
+
+use std::collections::{BTreeSet, HashMap};
 use std::fs;
+use std::ops::RangeInclusive;
 use std::path::{Path, PathBuf};
+use std::process::Command;
 use std::sync::Arc;
+use std::time::Duration;
+
+use image_colorizer::utils::{hex_to_rgb, interpolate_color, rgb_to_hex};
+use image_colorizer::{
+    extract_palette, list_adapters, AppConfig, BlendCurve, ColorSpace, ColorizeOptions,
+    DistanceMetric, DitherMode, GpuBackend, GpuOptions, ProgressMode, WebPCompression,
+};
 
 use clap::{App, Arg};
 use config::builder::DefaultState;
-use config::{ConfigBuilder, ConfigError, File};
+use config::{ConfigBuilder, ConfigError, Environment, File, FileFormat};
 use futures::StreamExt;
+use glob::Pattern;
+use image::ImageFormat;
 use indicatif::{ProgressBar, ProgressStyle};
-use palette::{color_difference::ImprovedCiede2000, FromColor, Lab};
-use reqwest;
+use palette::color_difference::{EuclideanDistance, ImprovedCiede2000};
+use palette::{FromColor, Lab, Lch, Mix, Oklab, ShiftHue, Srgb};
 use serde_derive::Deserialize;
-use toml;
+use tracing::{debug, info, warn};
 
 #[derive(Debug)]
 pub enum AppError {
@@ -72,6 +89,13 @@ impl From<String> for AppError {
     }
 }
 
+// Errors from colorizing keep the context they were given, such as which pass failed
+impl From<anyhow::Error> for AppError {
+    fn from(err: anyhow::Error) -> Self {
+        AppError::Other(format!("{:#}", err))
+    }
+}
+
 impl From<reqwest::Error> for AppError {
     fn from(err: reqwest::Error) -> Self {
         AppError::DownloadError(err.to_string())
@@ -81,11 +105,39 @@ impl From<reqwest::Error> for AppError {
 #[derive(Debug, Deserialize)]
 struct SerializedAppConfig {
     blend_factor: String,
+    blend_curve: String,
+    color_space: String,
     colorscheme: String,
     interpolate_colors: bool,
     interpolation_threshold: String,
+    preserve_luminance: bool,
+    soft_match: bool,
+    linear_blend: bool,
+    invert_luminance: bool,
+    contrast: String,
+    saturation: String,
+    sharpen: String,
+    preserve_neutrals: String,
+    posterize: String,
+    hue_rotate: String,
     dither_amount: String,
+    dither_mode: String,
     spatial_averaging_radius: String,
+    offline: bool,
+    download_timeout: String,
+    download_retries: String,
+    scheme_repo: String,
+}
+
+/// Controls how colorschemes that aren't found locally are downloaded
+struct DownloadOptions {
+    offline: bool,
+    /// URL that `{name}.txt` is appended to, without a trailing slash
+    scheme_repo: String,
+    timeout: Duration,
+    /// How many more times a download is attempted after a network error or server error
+    retries: u32,
+    show_progress: bool,
 }
 
 #[derive(Debug)]
@@ -94,20 +146,152 @@ pub struct ConfigInfo {
     config_dir: PathBuf,
 }
 
-fn load_config(config_path: Option<&str>) -> Result<ConfigInfo, AppError> {
-    let mut builder = ConfigBuilder::default();
-
-    builder = builder
+/// A config builder holding the value of every setting that isn't in the config file
+fn default_config_builder() -> Result<ConfigBuilder<DefaultState>, AppError> {
+    Ok(ConfigBuilder::default()
         .set_default("blend_factor", "0.9")?
+        .set_default("blend_curve", "linear")?
+        .set_default("color_space", "lab")?
         .set_default("colorscheme", "kanagawa")?
         .set_default("interpolate_colors", true)?
         .set_default("interpolation_threshold", "2.5")?
+        .set_default("preserve_luminance", false)?
+        .set_default("soft_match", false)?
+        .set_default("linear_blend", false)?
+        .set_default("invert_luminance", false)?
+        .set_default("contrast", "1.0")?
+        .set_default("saturation", "1.0")?
+        .set_default("sharpen", "0.0")?
+        .set_default("preserve_neutrals", "0.0")?
+        .set_default("posterize", "0")?
+        .set_default("hue_rotate", "0.0")?
         .set_default("dither_amount", "0.1")?
-        .set_default("spatial_averaging_radius", "10")?;
+        .set_default("dither_mode", "noise")?
+        .set_default("spatial_averaging_radius", "10")?
+        .set_default("offline", false)?
+        .set_default("download_timeout", "30")?
+        .set_default("download_retries", "2")?
+        .set_default("scheme_repo", DEFAULT_SCHEME_REPO)?)
+}
+
+/// The contents of the config file written by `--init-config`, which sets every setting to its
+/// default value
+fn default_config_file() -> String {
+    format!(
+        r#"# Settings for image-colorizer. Every setting here is at its default value, and options passed on
+# the command line take precedence over them
+
+# [0.0-1.0] How much of the colorized image is used. 0 = Just use the original image, 1 = Use only
+# the colorized image
+blend_factor = "0.9"
+
+# [linear, ease-in, ease-out, or a gamma from 0.1 to 10] How the blend factor changes with the
+# lightness of each pixel. ease-in blends only the highlights fully, ease-out everything but the
+# deepest shadows, and a gamma scales it by the lightness raised to that power
+blend_curve = "linear"
+
+# Only blend the hue and saturation of each pixel, keeping its original lightness
+preserve_luminance = false
+
+# Match each pixel to a mix of its two nearest colors, weighted by how near each one is, rather
+# than snapping it to the nearest color. This smooths banding in gradients
+soft_match = false
+
+# Blend the original and colorized images in linear light rather than sRGB
+linear_blend = false
+
+# Flip the lightness of the image before colorizing, such as to turn a light screenshot dark
+invert_luminance = false
+
+# [0.0-4.0] Scales the lightness of each pixel away from the average lightness of the image
+contrast = "1.0"
+
+# [0.0-4.0] Scales the saturation of each pixel. 0 makes the output grayscale
+saturation = "1.0"
+
+# [0.0-4.0] Brings back the detail that spatial averaging softens. 1 undoes averaging, and higher
+# values sharpen further. 0 leaves the averaged image as it is
+sharpen = "0.0"
+
+# [0.0-100.0] Leaves pixels whose chroma is below this unchanged, so that grays stay gray. 0
+# colorizes every pixel
+preserve_neutrals = "0.0"
+
+# [0, 2-255] Quantizes the lightness and color of each pixel to this many levels after colorizing,
+# for a posterized look. 0 turns it off
+posterize = "0"
+
+# [-360.0-360.0] Rotates the hue of every colorscheme color by this many degrees before
+# colorizing, for shifted variants of a colorscheme. 0 leaves it unchanged
+hue_rotate = "0.0"
+
+# [lab, oklab] The color space used to match pixels to colors and to interpolate the colorscheme
+color_space = "lab"
+
+# The colorscheme to use, as the name of a file in this directory, a built-in colorscheme (see
+# --list-schemes), or a colorscheme to download from scheme_repo. Several names separated by
+# commas save an output in each colorscheme
+colorscheme = "kanagawa"
+
+# Fill gaps in the colorscheme with colors in between its colors
+interpolate_colors = true
+
+# [0.0-100.0] The maximum distance allowed between colors when interpolating. Lower values = More
+# interpolation, higher values = Less interpolation
+interpolation_threshold = "2.5"
+
+# [0.0-1.0] The amount of dithering, which reduces artifacting by adding some randomness
+dither_amount = "0.1"
+
+# [none, noise, floyd-steinberg, ordered, blue-noise] How dithering is performed
+dither_mode = "noise"
+
+# [0-100] How far away the pixels whose colors each pixel is averaged with can be. 0 disables
+# spatial averaging
+spatial_averaging_radius = "10"
+
+# Never download colorschemes that aren't found locally
+offline = false
+
+# How many seconds to wait for a colorscheme download before giving up
+download_timeout = "30"
 
-    let default_config_dir = dirs::home_dir()
+# [0-10] How many more times to try a colorscheme download after a network error or server error
+download_retries = "2"
+
+# The URL that colorschemes that aren't found locally are downloaded from, as {{URL}}/{{name}}.txt
+scheme_repo = "{}"
+"#,
+        DEFAULT_SCHEME_REPO
+    )
+}
+
+/// The platform's config directory, which is `$XDG_CONFIG_HOME/image-colorizer` on Linux, or
+/// `~/.config/image-colorizer` where earlier versions kept everything if only that has any files
+fn default_config_dir() -> PathBuf {
+    let legacy = dirs::home_dir()
         .unwrap_or_else(|| PathBuf::from(""))
         .join(".config/image-colorizer");
+    match dirs::config_dir() {
+        Some(dir) => choose_config_dir(dir.join("image-colorizer"), legacy),
+        None => legacy,
+    }
+}
+
+fn choose_config_dir(dir: PathBuf, legacy: PathBuf) -> PathBuf {
+    let is_empty =
+        |dir: &Path| fs::read_dir(dir).map_or(true, |mut entries| entries.next().is_none());
+    if is_empty(&dir) && !is_empty(&legacy) {
+        legacy
+    } else {
+        dir
+    }
+}
+
+fn load_config(config_path: Option<&str>) -> Result<ConfigInfo, AppError> {
+    let mut builder = default_config_builder()?;
+
+    let default_config_dir = default_config_dir();
     let default_config_path = default_config_dir.join("config.toml");
 
     let (config_path, config_dir) = if let Some(path) = config_path {
@@ -128,6 +312,10 @@ fn load_config(config_path: Option<&str>) -> Result<ConfigInfo, AppError> {
         );
     }
 
+    // Environment variables override the config file, and are overridden by flags. Empty
+    // variables are skipped rather than read as empty values
+    builder = builder.add_source(Environment::with_prefix(ENV_PREFIX).ignore_empty(true));
+
     let config = builder.build()?;
 
     Ok(ConfigInfo {
@@ -136,70 +324,378 @@ fn load_config(config_path: Option<&str>) -> Result<ConfigInfo, AppError> {
     })
 }
 
-async fn load_colorscheme(name: &str, config_dir: &Path) -> Result<Vec<String>, AppError> {
+/// The colors of a colorscheme, along with any names they were given
+#[derive(Debug)]
+struct Colorscheme {
+    colors: Vec<String>,
+    /// The name of each color in `colors`, from `name = color` entries in colorscheme files
+    names: Vec<Option<String>>,
+    /// The weight of each color in `colors`, from `color *weight` entries in colorscheme files
+    weights: Vec<f32>,
+}
+
+impl From<Vec<String>> for Colorscheme {
+    fn from(colors: Vec<String>) -> Self {
+        Colorscheme {
+            names: vec![None; colors.len()],
+            weights: vec![1.0; colors.len()],
+            colors,
+        }
+    }
+}
+
+async fn load_colorscheme(
+    name: &str,
+    config_dir: &Path,
+    download_options: &DownloadOptions,
+) -> Result<Colorscheme, AppError> {
     let colorscheme_path = config_dir.join(format!("{}.txt", name));
+    let base16_path = ["yaml", "yml"]
+        .iter()
+        .map(|extension| config_dir.join(format!("{}.{}", name, extension)))
+        .find(|path| path.exists());
+    let gimp_palette_path = config_dir.join(format!("{}.gpl", name));
+
+    if let Some(base16_path) = base16_path {
+        debug!("Loading base16 colorscheme from {}", base16_path.display());
+        // Load from local base16 theme
+        let colorscheme_str = fs::read_to_string(base16_path)?;
+        parse_base16_colorscheme(&colorscheme_str, name).map(Colorscheme::from)
+    } else if gimp_palette_path.exists() {
+        debug!("Loading GIMP palette from {}", gimp_palette_path.display());
+        parse_gimp_palette(&fs::read_to_string(gimp_palette_path)?, name)
+    } else if colorscheme_path.exists() {
+        // Downloaded colorschemes are checked for updates, while ones the user wrote have no ETag
+        // and are never replaced
+        let etag_path = etag_path(&colorscheme_path);
+        if let (false, Ok(etag)) = (download_options.offline, fs::read_to_string(&etag_path)) {
+            update_colorscheme(name, &colorscheme_path, etag.trim(), download_options).await;
+        }
 
-    if colorscheme_path.exists() {
+        debug!("Loading colorscheme from {}", colorscheme_path.display());
         // Load from local file
         let colorscheme_str = fs::read_to_string(&colorscheme_path)?;
         parse_and_validate_colorscheme(&colorscheme_str, name)
-    } else if name == "kanagawa" {
-        // Built-in colorscheme
-        Ok(KANAGAWA.iter().map(|&s| s.to_string()).collect())
+    } else if let Some(colors) = builtin_colorscheme(name) {
+        debug!("Using the built-in colorscheme '{}'", name);
+        Ok(colors
+            .iter()
+            .map(|&s| s.to_string())
+            .collect::<Vec<_>>()
+            .into())
+    } else if download_options.offline {
+        Err(AppError::Other(format!(
+            "Colorscheme '{name}' not found. Looked for {name}.yaml, {name}.yml, {name}.gpl, and {name}.txt in '{dir}', and downloading is disabled in offline mode. Use --list-schemes to see the built-in colorschemes",
+            name = name,
+            dir = config_dir.display()
+        )))
     } else {
         // Show warning
-        eprintln!(
-            "Warning: Colorscheme '{}' not found locally. Attempting to download from GitHub...",
-            name
+        warn!(
+            "Colorscheme '{}' not found locally. Attempting to download from {}...",
+            name,
+            colorscheme_url(name, download_options)
         );
 
-        // Attempt to download from GitHub
-        match download_colorscheme_from_github(name).await {
-            Ok(colorscheme_str) => {
-                let colorscheme = parse_and_validate_colorscheme(&colorscheme_str, name)?;
+        match download_colorscheme(name, download_options, None).await {
+            Ok(Download::Modified { content, etag }) => {
+                let colorscheme = parse_and_validate_colorscheme(&content, name)?;
 
                 // Save the downloaded scheme
-                if let Err(e) = save_colorscheme(&colorscheme_path, &colorscheme_str) {
-                    eprintln!("Warning: Failed to save downloaded colorscheme: {}", e);
+                if let Err(e) = save_colorscheme(&colorscheme_path, &content, etag.as_deref()) {
+                    warn!("Failed to save downloaded colorscheme: {}", e);
                 }
 
                 Ok(colorscheme)
             }
+            // Nothing was cached, so there's nothing that can be unmodified
+            Ok(Download::NotModified) => Err(AppError::DownloadError(format!(
+                "Failed to download colorscheme '{}'. HTTP status: 304 Not Modified",
+                name
+            ))),
             Err(e) => Err(e), // Propagate the error without additional wrapping
         }
     }
 }
 
-fn parse_and_validate_colorscheme(content: &str, name: &str) -> Result<Vec<String>, AppError> {
-    let colorscheme = parse_colorscheme(content);
-    if colorscheme.is_empty() {
+/// Replaces a downloaded colorscheme if it has changed since it was saved. The saved copy is kept
+/// if the scheme repository can't be reached or the new version is invalid
+async fn update_colorscheme(
+    name: &str,
+    colorscheme_path: &Path,
+    etag: &str,
+    download_options: &DownloadOptions,
+) {
+    let update = download_colorscheme(name, download_options, Some(etag)).await;
+    let (content, etag) = match update {
+        Ok(Download::Modified { content, etag }) => (content, etag),
+        Ok(Download::NotModified) => return,
+        Err(e) => {
+            warn!(
+                "Failed to check for updates to colorscheme '{}', using the saved copy: {}",
+                name, e
+            );
+            return;
+        }
+    };
+
+    if let Err(e) = parse_and_validate_colorscheme(&content, name) {
+        warn!(
+            "The updated colorscheme '{}' is invalid, using the saved copy: {}",
+            name, e
+        );
+        return;
+    }
+    if let Err(e) = save_colorscheme(colorscheme_path, &content, etag.as_deref()) {
+        warn!("Failed to save updated colorscheme: {}", e);
+    }
+}
+
+fn parse_and_validate_colorscheme(content: &str, name: &str) -> Result<Colorscheme, AppError> {
+    let colorscheme = parse_colorscheme(content)
+        .map_err(|(line, e)| format!("Colorscheme '{}' line {}: {}", name, line, e))?;
+    if colorscheme.colors.is_empty() {
         Err(AppError::Other(format!("Colorscheme '{}' is empty", name)))
     } else {
         Ok(colorscheme)
     }
 }
 
-async fn download_colorscheme_from_github(name: &str) -> Result<String, AppError> {
-    let url = format!(
-        "https://raw.githubusercontent.com/TaylorBeeston/image-colorizer/main/colorschemes/{}.txt",
+/// Parses a base16 YAML theme, with `base00` through `base0F` either at the top level or nested
+/// under `palette`
+fn parse_base16_colorscheme(content: &str, name: &str) -> Result<Vec<String>, AppError> {
+    let theme: serde_yaml::Mapping = serde_yaml::from_str(content)
+        .map_err(|e| format!("Failed to parse colorscheme '{}': {}", name, e))?;
+
+    let colors = match theme
+        .get("palette")
+        .and_then(|palette| palette.as_mapping())
+    {
+        Some(palette) => palette,
+        None => &theme,
+    };
+
+    (0..16)
+        .map(|i| {
+            let key = format!("base{:02X}", i);
+            let hex = colors
+                .get(key.as_str())
+                .and_then(|value| value.as_str())
+                .ok_or_else(|| format!("Colorscheme '{}' is missing {}", name, key))?;
+
+            Ok(format!("#{}", hex.trim_start_matches('#')))
+        })
+        .collect()
+}
+
+/// Parses a GIMP palette, which starts with a `GIMP Palette` header followed by `Name:` and
+/// `Columns:` fields, `#` comments, and one `R G B Name` line per color
+fn parse_gimp_palette(content: &str, name: &str) -> Result<Colorscheme, AppError> {
+    let mut lines = content.lines().enumerate();
+    if lines.next().map(|(_, header)| header.trim()) != Some("GIMP Palette") {
+        return Err(AppError::Other(format!(
+            "Colorscheme '{}' is not a GIMP palette, since it doesn't start with 'GIMP Palette'",
+            name
+        )));
+    }
+
+    let mut colorscheme = Colorscheme::from(Vec::new());
+
+    for (i, line) in lines {
+        let trimmed = line.trim();
+        if trimmed.is_empty()
+            || trimmed.starts_with('#')
+            || trimmed.starts_with("Name:")
+            || trimmed.starts_with("Columns:")
+        {
+            continue;
+        }
+
+        let mut fields = trimmed.split_whitespace();
+        let mut channel = || {
+            fields
+                .next()
+                .and_then(|value| value.parse::<u8>().ok())
+                .ok_or_else(|| {
+                    format!(
+                        "Colorscheme '{}' line {}: expected three numbers from 0 to 255, but found '{}'",
+                        name,
+                        i + 1,
+                        trimmed
+                    )
+                })
+        };
+        let (red, green, blue) = (channel()?, channel()?, channel()?);
+
+        // GIMP names colors that were never given a name "Untitled"
+        let color_name = fields.collect::<Vec<_>>().join(" ");
+        colorscheme
+            .colors
+            .push(format!("#{:02x}{:02x}{:02x}", red, green, blue));
+        colorscheme.names.push(
+            Some(color_name)
+                .filter(|color_name| !color_name.is_empty() && color_name != "Untitled"),
+        );
+        colorscheme.weights.push(1.0);
+    }
+
+    if colorscheme.colors.is_empty() {
+        return Err(AppError::Other(format!(
+            "Colorscheme '{}' does not contain any colors",
+            name
+        )));
+    }
+
+    Ok(colorscheme)
+}
+
+/// The result of downloading a colorscheme, which may be unchanged from the copy with the ETag that
+/// was sent
+enum Download {
+    NotModified,
+    Modified {
+        content: String,
+        etag: Option<String>,
+    },
+}
+
+fn colorscheme_url(name: &str, download_options: &DownloadOptions) -> String {
+    format!(
+        "{}/{}.txt",
+        download_options.scheme_repo,
         name.to_lowercase()
+    )
+}
+
+/// Checks that `url` is an http(s) URL that colorscheme names can be appended to, returning it
+/// without a trailing slash
+fn parse_scheme_repo(url: &str) -> Result<String, AppError> {
+    let parsed =
+        reqwest::Url::parse(url).map_err(|e| format!("Invalid scheme_repo '{}': {}", url, e))?;
+
+    if !matches!(parsed.scheme(), "http" | "https") {
+        return Err(AppError::Other(format!(
+            "Invalid scheme_repo '{}': expected an http or https URL",
+            url
+        )));
+    }
+    if parsed.query().is_some() || parsed.fragment().is_some() {
+        return Err(AppError::Other(format!(
+            "Invalid scheme_repo '{}': colorscheme names can't be appended to a URL with a query or fragment",
+            url
+        )));
+    }
+
+    Ok(url.trim_end_matches('/').to_string())
+}
+
+/// Why a download attempt failed, and whether trying again might succeed
+struct DownloadFailure {
+    message: String,
+    retryable: bool,
+}
+
+impl DownloadFailure {
+    /// Network errors such as timeouts and dropped connections are worth retrying, while errors
+    /// in building the request are not
+    fn network(err: reqwest::Error) -> Self {
+        DownloadFailure {
+            retryable: !err.is_builder(),
+            message: err.to_string(),
+        }
+    }
+}
+
+/// Downloads a colorscheme, trying again with exponential backoff after network errors and server
+/// errors, but not after errors like 404 that won't change
+async fn download_colorscheme(
+    name: &str,
+    download_options: &DownloadOptions,
+    etag: Option<&str>,
+) -> Result<Download, AppError> {
+    let url = colorscheme_url(name, download_options);
+    info!(
+        "Downloading colorscheme '{}' from {} with a timeout of {:?}",
+        name, url, download_options.timeout
     );
 
-    let client = reqwest::Client::new();
-    let res = client.get(&url).send().await?;
+    let client = reqwest::Client::builder()
+        .timeout(download_options.timeout)
+        .build()?;
+    let attempts = download_options.retries + 1;
+    let mut backoff = Duration::from_millis(500);
+
+    let mut attempt = 1;
+
+    loop {
+        match try_download_colorscheme(name, &url, &client, etag, download_options).await {
+            Ok(download) => return Ok(download),
+            Err(failure) if failure.retryable && attempt < attempts => {
+                info!(
+                    "Attempt {} of {} to download '{}' failed, retrying in {:?}: {}",
+                    attempt, attempts, name, backoff, failure.message
+                );
+                tokio::time::sleep(backoff).await;
+                backoff *= 2;
+                attempt += 1;
+            }
+            Err(failure) if attempt == 1 => return Err(AppError::DownloadError(failure.message)),
+            Err(failure) => {
+                return Err(AppError::DownloadError(format!(
+                    "{} (gave up after {} attempts)",
+                    failure.message, attempt
+                )))
+            }
+        }
+    }
+}
+
+async fn try_download_colorscheme(
+    name: &str,
+    url: &str,
+    client: &reqwest::Client,
+    etag: Option<&str>,
+    download_options: &DownloadOptions,
+) -> Result<Download, DownloadFailure> {
+    let mut request = client.get(url);
+    if let Some(etag) = etag {
+        debug!(
+            "Only downloading '{}' if it no longer matches ETag {}",
+            name, etag
+        );
+        request = request.header(reqwest::header::IF_NONE_MATCH, etag);
+    }
+    let res = request.send().await.map_err(DownloadFailure::network)?;
+    debug!("{} responded with {}", url, res.status());
+
+    if res.status() == reqwest::StatusCode::NOT_MODIFIED {
+        return Ok(Download::NotModified);
+    }
 
     // Check if the request was successful
     if !res.status().is_success() {
-        return Err(AppError::DownloadError(format!(
-            "Failed to download colorscheme '{}'. HTTP status: {}",
-            name,
-            res.status()
-        )));
+        return Err(DownloadFailure {
+            message: format!(
+                "Failed to download colorscheme '{}'. HTTP status: {}",
+                name,
+                res.status()
+            ),
+            retryable: res.status().is_server_error(),
+        });
     }
 
+    let etag = res
+        .headers()
+        .get(reqwest::header::ETAG)
+        .and_then(|etag| etag.to_str().ok())
+        .map(str::to_string);
     let total_size = res.content_length().unwrap_or(0);
 
-    let pb = ProgressBar::new(total_size);
+    let pb = if download_options.show_progress {
+        ProgressBar::new(total_size)
+    } else {
+        ProgressBar::hidden()
+    };
     pb.set_style(ProgressStyle::default_bar()
         .template("{spinner:.green} [{elapsed_precise}] [{bar:40.cyan/blue}] {bytes}/{total_bytes} ({eta})").unwrap()
         .progress_chars("#>-"));
@@ -208,67 +704,327 @@ async fn download_colorscheme_from_github(name: &str) -> Result<String, AppError
     let mut stream = res.bytes_stream();
 
     while let Some(item) = stream.next().await {
-        let chunk = item.map_err(|e| AppError::DownloadError(e.to_string()))?;
+        let chunk = item.map_err(|e| {
+            pb.finish_and_clear();
+            DownloadFailure::network(e)
+        })?;
         content.push_str(&String::from_utf8_lossy(&chunk));
         pb.inc(chunk.len() as u64);
     }
 
     pb.finish_with_message("Download complete");
+    info!(
+        "Downloaded {} bytes of colorscheme '{}'",
+        content.len(),
+        name
+    );
 
-    Ok(content)
+    Ok(Download::Modified { content, etag })
 }
 
-fn save_colorscheme(path: &Path, content: &str) -> std::io::Result<()> {
+/// Saves a downloaded colorscheme along with its ETag, which is sent on later runs so that it is
+/// only downloaded again when it changes
+fn save_colorscheme(path: &Path, content: &str, etag: Option<&str>) -> std::io::Result<()> {
     if let Some(parent) = path.parent() {
         fs::create_dir_all(parent)?;
     }
-    fs::write(path, content)
+    fs::write(path, content)?;
+
+    match etag {
+        Some(etag) => fs::write(etag_path(path), etag),
+        // The ETag of an older version no longer describes what was saved
+        None => match fs::remove_file(etag_path(path)) {
+            Err(e) if e.kind() != std::io::ErrorKind::NotFound => Err(e),
+            _ => Ok(()),
+        },
+    }
 }
 
-fn parse_colorscheme(content: &str) -> Vec<String> {
-    content
-        .lines()
-        .filter_map(|line| {
-            let trimmed = line.split("//").next().unwrap_or("").trim();
-            if trimmed.is_empty() {
-                None
-            } else {
-                Some(trimmed.to_string())
+fn etag_path(colorscheme_path: &Path) -> PathBuf {
+    let mut path = colorscheme_path.as_os_str().to_owned();
+    path.push(".etag");
+    PathBuf::from(path)
+}
+
+/// Parses one color per line, optionally labelled as `name = color` and weighted as
+/// `color *weight`, skipping blank lines and `//` comments. Invalid colors and weights are
+/// returned with their line number
+fn parse_colorscheme(content: &str) -> Result<Colorscheme, (usize, String)> {
+    let mut colorscheme = Colorscheme::from(Vec::new());
+
+    for (i, line) in content.lines().enumerate() {
+        let trimmed = line.split("//").next().unwrap_or("").trim();
+        if trimmed.is_empty() {
+            continue;
+        }
+
+        let (name, color) = match trimmed.split_once('=') {
+            Some((name, color)) => (Some(name.trim()), color.trim()),
+            None => (None, trimmed),
+        };
+        let (color, weight) = match color.split_once('*') {
+            Some((color, weight)) => (
+                color.trim(),
+                parse_weight(weight.trim()).map_err(|e| (i + 1, e))?,
+            ),
+            None => (color, 1.0),
+        };
+
+        hex_to_rgb(color).map_err(|e| (i + 1, e))?;
+
+        colorscheme.colors.push(color.to_string());
+        colorscheme.names.push(
+            name.filter(|name| !name.is_empty())
+                .map(|name| name.to_string()),
+        );
+        colorscheme.weights.push(weight);
+    }
+
+    Ok(colorscheme)
+}
+
+fn parse_weight(weight: &str) -> Result<f32, String> {
+    match weight.parse::<f32>() {
+        Ok(weight) if weight.is_finite() && weight > 0.0 => Ok(weight),
+        _ => Err(format!(
+            "Invalid weight '{}': expected a number greater than 0",
+            weight
+        )),
+    }
+}
+
+/// Splits the comma separated names given to `--colorscheme`, such as `kanagawa,nord`, dropping
+/// repeated names so that each colorscheme is only used once
+fn parse_colorscheme_names(list: &str) -> Result<Vec<&str>, AppError> {
+    let mut names = Vec::new();
+
+    for name in list.split(',').map(str::trim) {
+        if name.is_empty() {
+            return Err(AppError::Other(format!(
+                "Invalid colorscheme list '{}': colorscheme names can't be empty",
+                list
+            )));
+        }
+        if !names.contains(&name) {
+            names.push(name);
+        }
+    }
+
+    Ok(names)
+}
+
+/// Splits a comma separated list of colors, leaving the commas inside `rgb()` colors alone
+fn parse_color_list(list: &str) -> Result<Vec<String>, AppError> {
+    let mut colors = Vec::new();
+    let mut depth = 0;
+    let mut start = 0;
+
+    for (i, c) in list.char_indices() {
+        match c {
+            '(' => depth += 1,
+            ')' => depth -= 1,
+            ',' if depth == 0 => {
+                colors.push(&list[start..i]);
+                start = i + 1;
             }
+            _ => {}
+        }
+    }
+    colors.push(&list[start..]);
+
+    colors
+        .into_iter()
+        .map(|color| {
+            let color = color.trim();
+            hex_to_rgb(color)
+                .map_err(|e| format!("Invalid color '{}' in --colors: {}", color, e))?;
+            Ok(color.to_string())
         })
         .collect()
 }
 
-fn interpolate_colors(mut colors: Vec<Lab>, threshold: f32) -> Vec<Lab> {
-    colors.sort_by(|a, b| a.l.partial_cmp(&b.l).unwrap());
+/// Writes the palette in the same format as colorscheme files, with the lightness of each color
+/// as a comment. Colors that were named or weighted in the colorscheme keep their names and
+/// weights
+fn dump_palette(
+    colors: &[Lab],
+    names: &[(Lab, String)],
+    weights: &[f32],
+    path: &Path,
+) -> Result<(), AppError> {
+    let mut content = format!("// {} colors\n", colors.len());
+    for (i, &color) in colors.iter().enumerate() {
+        let hex = rgb_to_hex(Srgb::from_color(color));
+        // Interpolating in OKLab converts colors back and forth, so names are matched loosely
+        let mut entry = match names.iter().find(|(named, _)| named.distance(color) < 1e-3) {
+            Some((_, name)) => format!("{} = {}", name, hex),
+            None => hex,
+        };
+        let weight = weights.get(i).copied().unwrap_or(1.0);
+        if weight != 1.0 {
+            entry.push_str(&format!(" *{}", weight));
+        }
+
+        content.push_str(&format!("{} // L: {:.2}\n", entry, color.l));
+    }
+
+    fs::write(path, content)?;
+    Ok(())
+}
+
+/// Rotates the hue of `color` by `degrees` in LCh, keeping its lightness and chroma
+fn rotate_hue(color: Lab, degrees: f32) -> Lab {
+    Lab::from_color(Lch::from_color(color).shift_hue(degrees))
+}
+
+/// Fills in gaps in the colorscheme so that no two neighbouring colors are further than
+/// `threshold` apart, measured with `metric`. Colors are interpolated in `color_space`.
+///
+/// When that would make more than `max_colors` colors, the threshold is raised until it doesn't.
+/// Returns the colors along with the threshold that was used
+fn interpolate_colors(
+    colors: Vec<Lab>,
+    threshold: f32,
+    max_colors: Option<usize>,
+    color_space: ColorSpace,
+    metric: DistanceMetric,
+) -> (Vec<Lab>, f32) {
+    match color_space {
+        ColorSpace::Lab => interpolate_palette(
+            colors,
+            threshold,
+            max_colors,
+            |color| [color.l, color.a, color.b],
+            |color1, color2| match metric {
+                DistanceMetric::Ciede2000 => color1.improved_difference(*color2),
+                DistanceMetric::Cie76 | DistanceMetric::Euclidean => color1.distance(*color2),
+            },
+            interpolate_color,
+        ),
+        ColorSpace::Oklab => {
+            let colors = colors.into_iter().map(Oklab::from_color).collect();
+
+            let (colors, threshold) = interpolate_palette(
+                colors,
+                threshold,
+                max_colors,
+                |color| [color.l, color.a, color.b],
+                |&color1, &color2| match metric {
+                    DistanceMetric::Ciede2000 => {
+                        Lab::from_color(color1).improved_difference(Lab::from_color(color2))
+                    }
+                    DistanceMetric::Cie76 => {
+                        Lab::from_color(color1).distance(Lab::from_color(color2))
+                    }
+                    DistanceMetric::Euclidean => color1.distance(color2) * 100.0,
+                },
+                |color1, color2, t| color1.mix(*color2, t),
+            );
+            (colors.into_iter().map(Lab::from_color).collect(), threshold)
+        }
+    }
+}
+
+/// Sorts `colors` by their `components`, lightness first, and interpolates between neighbours.
+/// Colors with the same lightness are ordered by their chroma so that the result doesn't depend
+/// on the order of the colorscheme file, and colors with NaN components are dropped
+fn interpolate_palette<C: Copy>(
+    mut colors: Vec<C>,
+    threshold: f32,
+    max_colors: Option<usize>,
+    components: impl Fn(&C) -> [f32; 3],
+    difference: impl Fn(&C, &C) -> f32,
+    interpolate: impl Fn(&C, &C, f32) -> C,
+) -> (Vec<C>, f32) {
+    colors.retain(|color| components(color).iter().all(|c| !c.is_nan()));
+    colors.sort_by(|a, b| {
+        let (a, b) = (components(a), components(b));
+        a.iter()
+            .zip(&b)
+            .map(|(a, b)| a.total_cmp(b))
+            .find(|ordering| ordering.is_ne())
+            .unwrap_or(std::cmp::Ordering::Equal)
+    });
+
+    let Some(&last) = colors.last() else {
+        return (colors, threshold);
+    };
+
+    let distances: Vec<f32> = colors
+        .windows(2)
+        .map(|window| difference(&window[0], &window[1]))
+        .collect();
+    let threshold = match max_colors {
+        Some(max_colors) => capped_threshold(&distances, threshold, max_colors),
+        None => threshold,
+    };
 
     let mut interpolated = Vec::new();
-    for window in colors.windows(2) {
+    for (window, &distance) in colors.windows(2).zip(&distances) {
         let color1 = &window[0];
         let color2 = &window[1];
         interpolated.push(*color1);
 
-        let distance = color1.improved_difference(*color2);
-
         if distance > threshold {
             let steps = (distance / threshold).ceil() as usize;
             for i in 1..steps {
                 let t = i as f32 / steps as f32;
-                interpolated.push(interpolate_color(color1, color2, t));
+                interpolated.push(interpolate(color1, color2, t));
             }
         }
     }
-    interpolated.push(*colors.last().unwrap());
+    interpolated.push(last);
+
+    (interpolated, threshold)
+}
+
+/// The number of colors that interpolating with `threshold` makes, given the distances between
+/// neighbouring colors
+fn interpolated_len(distances: &[f32], threshold: f32) -> usize {
+    let added: f32 = distances
+        .iter()
+        .filter(|&&distance| distance > threshold)
+        .map(|&distance| (distance / threshold).ceil() - 1.0)
+        .sum();
+    distances.len() + 1 + added as usize
+}
+
+/// Raises `threshold` until interpolating makes at most `max_colors` colors. Palettes that already
+/// have more colors than that aren't interpolated at all
+fn capped_threshold(distances: &[f32], threshold: f32, max_colors: usize) -> f32 {
+    if interpolated_len(distances, threshold) <= max_colors {
+        return threshold;
+    }
+    if distances.len() + 1 >= max_colors {
+        return f32::INFINITY;
+    }
 
-    interpolated
+    // Fewer colors are made as the threshold grows, and none are added once it reaches the largest
+    // distance, so the lowest threshold under the cap can be found by bisection
+    let (mut low, mut high) = (
+        threshold,
+        distances.iter().copied().fold(threshold, f32::max),
+    );
+    for _ in 0..64 {
+        let middle = low + (high - low) / 2.0;
+        if middle <= low || middle >= high {
+            break;
+        }
+        if interpolated_len(distances, middle) <= max_colors {
+            high = middle;
+        } else {
+            low = middle;
+        }
+    }
+    high
 }
 
-pub async fn init() -> Result<Arc<AppConfig>, AppError> {
+pub async fn init() -> Result<Vec<Arc<AppConfig>>, AppError> {
     let matches = App::new("Image Colorizer")
         .version(VERSION)
         .author("Taylor Beeston")
         .about("Applies color schemes to images")
-        .after_help("Colorscheme is a string that should be the name of a colorscheme txt file (minus the extension) in the same directory as the config file. For example if 'kanagawa' is used as the name of the colorscheme string, there should be a 'kanagawa.txt' file in the same directory as the config file. If the file is not found, a colorscheme with that name will attempt to be downloaded into your config directory from github.\n\nColorscheme files are simple files with one hex code per line and may optionally have comments using double slashes, e.g.\n\n// Grayscale\n#fff\n#000")
+        .after_help("Colorscheme is a string that should be the name of a colorscheme txt file (minus the extension) in the same directory as the config file. For example if 'kanagawa' is used as the name of the colorscheme string, there should be a 'kanagawa.txt' file in the same directory as the config file. If the file is not found, a built-in colorscheme with that name is used (see --list-schemes), or else a colorscheme with that name will attempt to be downloaded into your config directory from github, or from --scheme-repo.\n\nColorscheme files are either base16 YAML themes ('kanagawa.yaml') or simple files with one color per line, either as a hex code or in rgb()/rgba() notation, and may optionally have comments using double slashes and names for each color. A color followed by *weight, e.g. '#1f1f28 *2.0', is preferred over nearer colors when matching, since distances to it are divided by its weight. For example:\n\n// Grayscale\nwhite = #fff\n#000 *1.5")
         .arg(
             Arg::with_name("Blend Factor")
                 .short('b')
@@ -277,18 +1033,162 @@ pub async fn init() -> Result<Arc<AppConfig>, AppError> {
                 .help("[0.0-1.0] (Default: 0.9) Sets the blend factor, which allows part of the original image to come through. 0 = Just use the original image, 1 = Use only the colorized image")
                 .takes_value(true),
         )
+        .arg(
+            Arg::with_name("Blend Map")
+                .long("blend-map")
+                .value_name("IMAGE")
+                .conflicts_with("Blend Factor")
+                .help("Sets the blend factor of each pixel from a grayscale image in place of --blend-factor, so that black areas keep their original color, white areas are fully blended, and grays are in between. The image is resized to fit each image, and --mask and --blend-curve still scale it")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("Auto Blend")
+                .long("auto-blend")
+                .takes_value(false)
+                .conflicts_with("Blend Map")
+                .help("Chooses the blend factor of each image from how detailed it is and how much of its lightness range the colorscheme covers, printing the chosen value. Detailed photos get a lower factor and flat images such as screenshots a higher one. An explicit --blend-factor takes precedence")
+        )
+        .arg(
+            Arg::with_name("Blend Curve")
+                .long("blend-curve")
+                .value_name("CURVE")
+                .help("(Default: linear) Scales the blend factor of each pixel by its lightness. ease-in keeps shadows and midtones closer to the original while snapping highlights to the colorscheme, ease-out snaps everything but the deepest shadows, and a gamma from 0.1 to 10 scales the blend factor by the lightness raised to that power")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("Preserve Luminance")
+                .long("preserve-luminance")
+                .takes_value(false)
+                .overrides_with("No Preserve Luminance")
+                .help("Only blends the hue and saturation of each pixel toward the colorscheme, keeping the original lightness so that photos aren't darkened or brightened")
+        )
+        .arg(
+            Arg::with_name("No Preserve Luminance")
+                .long("no-preserve-luminance")
+                .takes_value(false)
+                .overrides_with("Preserve Luminance")
+                .help("Blends the lightness of each pixel too, even if preserve_luminance is true in the config file")
+        )
+        .arg(
+            Arg::with_name("Soft Match")
+                .long("soft-match")
+                .takes_value(false)
+                .help("Matches each pixel to a mix of its two nearest colors in the colorscheme, weighted by the inverse of their distances, rather than snapping it to the nearest one. This gives smoother gradients than interpolation alone, especially at low blend factors")
+        )
+        .arg(
+            Arg::with_name("Linear Blend")
+                .long("linear-blend")
+                .takes_value(false)
+                .overrides_with("No Linear Blend")
+                .help("Blends the original and colorized images in linear light rather than sRGB, which keeps midtones from darkening")
+        )
+        .arg(
+            Arg::with_name("No Linear Blend")
+                .long("no-linear-blend")
+                .takes_value(false)
+                .overrides_with("Linear Blend")
+                .help("Blends in sRGB even if linear_blend is true in the config file")
+        )
+        .arg(
+            Arg::with_name("Invert Luminance")
+                .long("invert-luminance")
+                .takes_value(false)
+                .help("Flips the lightness of the image before colorizing, so that light backgrounds map to dark colors in the colorscheme and the other way around, such as to turn a light themed screenshot dark")
+        )
+        .arg(
+            Arg::with_name("Contrast")
+                .long("contrast")
+                .value_name("MULTIPLIER")
+                .help("[0.0-4.0] (Default: 1.0) Scales the lightness of each pixel away from the average lightness of the image after colorizing. Above 1 increases contrast, below 1 flattens it")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("Saturation")
+                .long("saturation")
+                .value_name("MULTIPLIER")
+                .help("[0.0-4.0] (Default: 1.0) Scales the saturation of each pixel after colorizing. 0 makes the output grayscale")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("Sharpen")
+                .long("sharpen")
+                .value_name("AMOUNT")
+                .help("[0.0-4.0] (Default: 0.0) Brings back the detail that spatial averaging softens, with an unsharp mask between the averaged and unaveraged colors. 1 undoes averaging, and higher values sharpen further without going past the colors of neighbouring pixels")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("Preserve Neutrals")
+                .long("preserve-neutrals")
+                .value_name("CHROMA")
+                .help("[0.0-100.0] (Default: 0.0) Leaves pixels whose Lab chroma is below the threshold unchanged, so that grays and near-grays keep their original color. 0 colorizes every pixel")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("Posterize")
+                .long("posterize")
+                .value_name("N")
+                .help("[0, 2-255] (Default: 0) Quantizes the lightness, a and b of each pixel to N levels after colorizing and dithering, for a posterized look. 0 turns it off")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("Hue Rotate")
+                .long("hue-rotate")
+                .value_name("DEGREES")
+                .allow_hyphen_values(true)
+                .help("[-360.0-360.0] (Default: 0.0) Rotates the hue of every colorscheme color by this many degrees in LCh before interpolating, keeping its lightness and chroma, for shifted variants of a colorscheme")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("Distance Metric")
+                .long("distance-metric")
+                .value_name("METRIC")
+                .possible_values(["ciede2000", "cie76", "euclidean"])
+                .help("Sets how the distance between colors is measured, both when interpolating the colorscheme and when matching pixels to its colors. Without it, colors are interpolated with ciede2000 in lab and euclidean in oklab, and pixels are matched with euclidean")
+                .takes_value(true),
+        )
         .arg(
             Arg::with_name("Interpolation Threshold")
                 .long("interpolation-threshold")
                 .value_name("THRESHOLD")
-                .help("[0.0-100.0] (Default: 2.5) Sets the maximum distance allowed in colorspace when interpolating the colorscheme. Lower values = More Interpolation, Higher vales = Less Interpolation. Must not set --no-interpolation for this to have an effect.")
+                .help("[0.0-100.0] (Default: 2.5) Sets the maximum distance allowed in colorspace when interpolating the colorscheme. Lower values = More Interpolation, Higher vales = Less Interpolation. Must not set --no-interpolate for this to have an effect.")
                 .takes_value(true),
         )
+        .arg(
+            Arg::with_name("Interpolate Colors")
+                .short('i')
+                .long("interpolate-colors")
+                .takes_value(false)
+                .overrides_with("No Interpolation")
+                .help("Interpolates the colorscheme even if interpolate_colors is false in the config file. Interpolation is on unless the config file or --no-interpolate turns it off")
+        )
         .arg(
             Arg::with_name("No Interpolation")
-                .long("no-interpolation")
+                .long("no-interpolate")
+                .visible_alias("no-interpolation")
+                .takes_value(false)
+                .overrides_with("Interpolate Colors")
+                .help("Disables color interpolation, so that pixels are only matched to the colors of the colorscheme itself. Setting this causes interpolation threshold to do nothing")
+        )
+        .arg(
+            Arg::with_name("Max Palette Colors")
+                .long("max-palette-colors")
+                .value_name("N")
+                .help("Raises the interpolation threshold when interpolating would make more than N colors, which keeps low thresholds from making palettes too large to match quickly")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("Allow Single Color")
+                .long("allow-single-color")
                 .takes_value(false)
-                .help("Disables color interpolation. Setting this causes interpolation threshold to do nothing")
+                .help("Colorizes with palettes that have only one color, which tints every pixel with it rather than failing")
+        )
+        .arg(
+            Arg::with_name("Color Space")
+                .long("color-space")
+                .value_name("SPACE")
+                .possible_values(["lab", "oklab"])
+                .help("(Default: lab) Sets the color space used to match pixels to colorscheme colors and to interpolate the colorscheme. oklab tends to interpolate saturated colors more evenly")
+                .takes_value(true),
         )
         .arg(
             Arg::with_name("Dither Amount")
@@ -298,6 +1198,21 @@ pub async fn init() -> Result<Arc<AppConfig>, AppError> {
                 .help("[0.0-1.0] (Default: 0.1) Sets the amount of dithering, which helps reduce artifacting by adding some randomness to the colorization process")
                 .takes_value(true),
         )
+        .arg(
+            Arg::with_name("Dither Mode")
+                .long("dither-mode")
+                .value_name("MODE")
+                .possible_values(["none", "noise", "floyd-steinberg", "ordered", "blue-noise"])
+                .help("(Default: noise) Sets how dithering is performed. floyd-steinberg reduces grain on smooth gradients, but is sequential and always runs on the CPU. ordered uses a Bayer matrix that grows with the dither amount. blue-noise tiles a blue noise texture, offset by --seed, for finer grain than noise at the same dither amount")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("Seed")
+                .long("seed")
+                .value_name("SEED")
+                .help("Sets the seed for noise and blue noise dithering. The same seed always produces the same output, while different seeds produce different variations")
+                .takes_value(true),
+        )
         .arg(
             Arg::with_name("Spatial Averaging Radius")
                 .long("spatial-averaging-radius")
@@ -306,48 +1221,612 @@ pub async fn init() -> Result<Arc<AppConfig>, AppError> {
                 .takes_value(true),
         )
         .arg(
-            Arg::with_name("Colorscheme")
-                .short('s')
-                .long("colorscheme")
-                .value_name("SCHEME")
-                .help("(Default: kanagawa) Sets the colorscheme to use")
-                .takes_value(true),
+            Arg::with_name("CPU")
+                .long("cpu")
+                .takes_value(false)
+                .help("Colorizes images on the CPU instead of the GPU. This is done automatically if no GPU adapter can be found")
         )
         .arg(
-            Arg::with_name("Config")
-                .short('c')
-                .long("config")
-                .value_name("/path/to/config.toml")
-                .help("(Default: ~/.config/image-colorizer/config.toml) Sets a custom config file")
+            Arg::with_name("GPU")
+                .long("gpu")
+                .value_name("INDEX")
+                .help("Colorizes images on the GPU adapter with this index, as listed by --show-gpu. By default the high performance adapter is used")
                 .takes_value(true),
         )
+        .arg(
+            Arg::with_name("Backend")
+                .long("backend")
+                .value_name("BACKEND")
+                .possible_values(["vulkan", "metal", "dx12", "gl", "all"])
+                .help("(Default: all) Forces wgpu to use a single graphics API, which is an error if it has no adapters on this machine")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("Show GPU")
+                .long("show-gpu")
+                .takes_value(false)
+                .help("Lists every GPU adapter, marking the one that would be used, and exits without colorizing any images")
+        )
+        .arg(
+            Arg::with_name("Build Info")
+                .long("build-info")
+                .takes_value(false)
+                .help("Prints the version, git commit and wgpu version this was built from, along with the GPU adapters it can use, as JSON, and exits")
+        )
+        .arg(
+            Arg::with_name("Colorscheme")
+                .short('s')
+                .long("colorscheme")
+                .value_name("SCHEME")
+                .help("(Default: kanagawa) Sets the colorscheme to use. Several colorschemes separated by commas, e.g. 'kanagawa,nord', save an output of every image in each one")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("List Schemes")
+                .long("list-schemes")
+                .takes_value(false)
+                .help("Lists the built-in colorschemes and the ones found in the config directory, and exits")
+        )
+        .arg(
+            Arg::with_name("Colors")
+                .long("colors")
+                .value_name("COLORS")
+                .help("Uses a comma separated list of colors as the colorscheme instead of loading one, e.g. '#000,#fff,#ff0000'. Outputs are named with 'custom' rather than the colorscheme")
+                .conflicts_with_all(&["Colorscheme File", "Palette From"])
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("Colorscheme File")
+                .long("colorscheme-file")
+                .value_name("/path/to/theme")
+                .help("Loads the colorscheme from a Windows Terminal .json, iTerm2 .itermcolors, or Alacritty .toml theme, using its 16 ANSI colors plus the foreground and background, or from every color of a GIMP .gpl palette. Outputs are named after the file rather than the colorscheme")
+                .conflicts_with("Palette From")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("Palette From")
+                .long("palette-from")
+                .value_name("IMAGE")
+                .help("Extracts the colorscheme from an image instead of loading one by name. Videos (mp4, mov, mkv, webm, avi and mpeg) are read from their first frame, which needs ffmpeg. Outputs are named after the image rather than the colorscheme")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("Palette Size")
+                .long("palette-size")
+                .value_name("N")
+                .help("(Default: 16) Sets the number of colors to extract when using --palette-from")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("Dump Palette")
+                .long("dump-palette")
+                .value_name("/path/to/palette.txt")
+                .help("Writes the final colorscheme, after interpolation, to a file in the colorscheme format")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("Palette Preview")
+                .long("palette-preview")
+                .value_name("/path/to/preview.png")
+                .help("Renders the final colorscheme, after interpolation, as swatches labeled with their hex code and lightness, and exits without colorizing any images")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("Offline")
+                .long("offline")
+                .takes_value(false)
+                .help("Never downloads colorschemes that aren't found locally. Can also be enabled by setting IMAGE_COLORIZER_OFFLINE=1")
+        )
+        .arg(
+            Arg::with_name("Download Timeout")
+                .long("download-timeout")
+                .value_name("SECONDS")
+                .help("(Default: 30) Sets how long to wait for a colorscheme download before giving up")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("Retries")
+                .long("retries")
+                .value_name("COUNT")
+                .help("(Default: 2) Sets how many more times to try a colorscheme download after a network error or server error")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("Scheme Repo")
+                .long("scheme-repo")
+                .value_name("URL")
+                .help("Sets the URL that colorschemes that aren't found locally are downloaded from, as {URL}/{name}.txt. Defaults to this project's colorschemes on GitHub")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("Config")
+                .short('c')
+                .long("config")
+                .value_name("/path/to/config.toml")
+                .help("(Default: $XDG_CONFIG_HOME/image-colorizer/config.toml) Sets a custom config file")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("Compare")
+                .long("compare")
+                .takes_value(false)
+                .help("Saves the original and colorized images together in one output, such as to post a before and after. Animations can't be compared")
+        )
+        .arg(
+            Arg::with_name("Compare Layout")
+                .long("compare-layout")
+                .value_name("LAYOUT")
+                .possible_values(["horizontal", "vertical", "split"])
+                .requires("Compare")
+                .help("(Default: horizontal) Sets how --compare lays out the images. horizontal puts them side by side, vertical stacks them, and split shows the original on the left half and the colorized image on the right, divided by a line")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("Compose With")
+                .long("compose-with")
+                .value_name("IMAGE")
+                .help("Alpha composites an image, such as a logo with a transparent background, onto every colorized image before it is saved. Overlays larger than an image are scaled down to fit it. Animations can't be composed with")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("Compose Position")
+                .long("compose-position")
+                .value_name("POSITION")
+                .requires("Compose With")
+                .help("(Default: bottom-right) Sets where --compose-with places its overlay: top-left, top-right, bottom-left, bottom-right or center, optionally followed by how many pixels in from that corner to move it, such as bottom-right+16+16")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("Init Config")
+                .long("init-config")
+                .takes_value(false)
+                .help("Writes a config file with every setting at its default value and a comment explaining it to $XDG_CONFIG_HOME/image-colorizer/config.toml, or to --config, and exits without colorizing any images")
+        )
+        .arg(
+            Arg::with_name("Force")
+                .long("force")
+                .takes_value(false)
+                .requires("Init Config")
+                .help("Lets --init-config replace an existing config file")
+        )
         .arg(
             Arg::with_name("Output")
                 .short('o')
                 .long("output")
-                .value_name("OUTPUT_DIR")
-                .help("Sets the output directory")
+                .value_name("OUTPUT")
+                .help("Sets the output directory, or a file name template such as '{dir}/{stem}-{scheme}-{date}.{ext}' with the placeholders {stem}, {ext}, {scheme}, {dir}, {date} and {index}")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("Format")
+                .long("format")
+                .value_name("FORMAT")
+                .possible_values(["png", "jpeg", "webp", "bmp", "tiff"])
+                .help("Sets the format of every output image, changing its extension to match. By default outputs are saved in the same format as their input. AVIF isn't supported yet")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("Quality")
+                .long("quality")
+                .value_name("QUALITY")
+                .help("[1-100] (Default: 75) Sets the quality of JPEG and lossy WebP outputs. Setting this makes WebP outputs lossy unless --webp-compression is set")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("WebP Compression")
+                .long("webp-compression")
+                .value_name("MODE")
+                .possible_values(["lossless", "lossy"])
+                .help("(Default: lossy if --quality is set, otherwise lossless) Sets whether WebP outputs keep every pixel exactly or are much smaller")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("Indexed")
+                .long("indexed")
+                .takes_value(false)
+                .help("Saves PNG outputs as 8-bit palette-indexed PNGs, snapping colors that are within a barely visible difference of a colorscheme color to it. Outputs that would need more than 256 colors are saved in truecolor")
+        )
+        .arg(
+            Arg::with_name("Suffix")
+                .long("suffix")
+                .value_name("SUFFIX")
+                .help("(Default: _{colorscheme}) Sets the text added to the end of each output file name. May only be empty when --output is set")
+                .allow_hyphen_values(true)
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("Overwrite")
+                .long("overwrite")
+                .takes_value(false)
+                .help("Overwrites existing output files. Without this, images whose output already exists are skipped")
+        )
+        .arg(
+            Arg::with_name("Copy On Failure")
+                .long("copy-on-failure")
+                .takes_value(false)
+                .conflicts_with("No Save")
+                .help("Copies the original image to its output path when it fails to colorize, with a warning, so that every input has an output. The copy is converted to the format of the output path, and only copied byte for byte when the original can't be decoded. Images read from stdin are never copied")
+        )
+        .arg(
+            Arg::with_name("Dry Run")
+                .long("dry-run")
+                .takes_value(false)
+                .help("Prints where each image would be saved, and whether that replaces an existing file, and exits without colorizing any images")
+        )
+        .arg(
+            Arg::with_name("Threads")
+                .long("threads")
+                .value_name("N")
+                .help("(Default: number of CPU cores) Sets how many threads CPU colorizing and the integral image use, shared by every image being processed. Separate from --jobs, which sets how many images are processed at once")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("Jobs")
+                .short('j')
+                .long("jobs")
+                .value_name("N")
+                .help("(Default: number of CPU cores) Sets the maximum number of images to process at once")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("Recursive")
+                .short('r')
+                .long("recursive")
+                .takes_value(false)
+                .help("Colorizes every image inside of any directories passed as image paths, preserving their directory structure under the output directory")
+        )
+        .arg(
+            Arg::with_name("Exclude")
+                .long("exclude")
+                .value_name("GLOB")
+                .multiple_occurrences(true)
+                .takes_value(true)
+                .help("Skips images found with --recursive whose file name or path within the directory matches this glob, e.g. 'drafts/*'. Can be repeated")
+        )
+        .arg(
+            Arg::with_name("Include Outputs")
+                .long("include-outputs")
+                .takes_value(false)
+                .help("Colorizes images found with --recursive even when their names end in the output suffix, which are otherwise skipped as outputs of an earlier run")
+        )
+        .arg(
+            Arg::with_name("Mask")
+                .long("mask")
+                .value_name("IMAGE")
+                .help("Only recolors part of each image. White areas of the mask are fully blended, black areas keep their original color, and grays are in between")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("Mask Resize")
+                .long("mask-resize")
+                .value_name("POLICY")
+                .possible_values(["stretch", "error"])
+                .requires("Mask")
+                .help("(Default: stretch) Whether a mask that is a different size than an image is stretched to fit it or is an error")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("Max Dimension")
+                .long("max-dimension")
+                .value_name("PIXELS")
+                .help("Downscales images so that their longest side is at most this many pixels before colorizing them, for quickly previewing settings. Outputs are marked with _preview unless --suffix is set")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("Restore Size")
+                .long("restore-size")
+                .takes_value(false)
+                .requires("Max Dimension")
+                .help("Upscales previews back to the size of the original image")
+        )
+        .arg(
+            Arg::with_name("Progress")
+                .long("progress")
+                .value_name("MODE")
+                .possible_values(["bar", "none", "json"])
+                .help("(Default: bar) Sets how progress is reported. none only prints errors, while json writes one line to stderr per update for each image, with its path, stage, and percent")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("Timings")
+                .long("timings")
+                .takes_value(false)
+                .help("Prints how long GPU setup took, and how long each stage took for every image, such as to see whether spatial averaging is worth its cost")
+        )
+        .arg(
+            Arg::with_name("Verbose")
+                .short('v')
+                .long("verbose")
+                .multiple_occurrences(true)
+                .takes_value(false)
+                .help("Logs what the colorizer is doing to stderr, such as the GPU adapter it chose, the size of its buffers, the palette size after interpolation, the time each stage took, and colorscheme downloads. Repeat for more detail: -v for info, -vv for debug, -vvv for trace")
+        )
+        .arg(
+            Arg::with_name("Coverage")
+                .long("coverage")
+                .takes_value(false)
+                .help("Prints a table of how well the colorscheme covers each image: how many of its colors are used, and the mean and max CIEDE2000 difference between each pixel and its nearest color")
+        )
+        .arg(
+            Arg::with_name("Manifest")
+                .long("manifest")
+                .value_name("/path/to/manifest.json")
+                .help("Writes a JSON record of the run once every image is done: the settings, a hash of each palette, and the input, output, result and time taken of each image")
                 .takes_value(true),
         )
+        .arg(
+            Arg::with_name("No Save")
+                .long("no-save")
+                .takes_value(false)
+                .help("Only prints the --coverage table, without colorizing or saving any images, such as to compare colorschemes")
+        )
         .arg(
             Arg::with_name("Image Paths")
-                .help("Paths to the images you'd like to colorize")
-                .required(true)
+                .help("Paths to the images you'd like to colorize. Use - to read an image from stdin and write the result to stdout")
+                .required_unless_present_any([
+                    "Show GPU",
+                    "Build Info",
+                    "List Schemes",
+                    "Init Config",
+                    "Palette Preview",
+                ])
                 .multiple(true)
                 .index(1),
         )
         .get_matches();
 
+    logging::init(matches.occurrences_of("Verbose"));
+
+    let gpu_options = GpuOptions {
+        adapter: matches
+            .value_of("GPU")
+            .map(|index| {
+                index
+                    .parse::<usize>()
+                    .map_err(|e| format!("Failed to parse GPU index: {}", e))
+            })
+            .transpose()?,
+        backend: matches.value_of("Backend").unwrap_or("all").parse()?,
+    };
+
+    if matches.is_present("Show GPU") {
+        show_gpu(&gpu_options).await;
+        std::process::exit(0);
+    }
+
+    if matches.is_present("Build Info") {
+        print_build_info(&gpu_options).await;
+        std::process::exit(0);
+    }
+
+    // This happens before the config is loaded, so that a broken config file can be replaced
+    if matches.is_present("Init Config") {
+        init_config(matches.value_of("Config"), matches.is_present("Force"))?;
+        std::process::exit(0);
+    }
+
     let ConfigInfo { config, config_dir } = load_config(matches.value_of("Config"))?;
 
-    let input_paths: Vec<&str> = matches.values_of("Image Paths").unwrap().collect();
-    let output_dir = matches.value_of("output").map(PathBuf::from);
+    if matches.is_present("List Schemes") {
+        list_schemes(&config_dir)?;
+        std::process::exit(0);
+    }
+
+    // Images are optional with --palette-preview, which exits before colorizing them
+    let input_paths: Vec<&str> = matches
+        .values_of("Image Paths")
+        .map(|paths| paths.collect())
+        .unwrap_or_default();
+
+    let colorschemes = parse_colorscheme_names(
+        matches
+            .value_of("Colorscheme")
+            .unwrap_or(&config.colorscheme),
+    )?;
+
+    // Outputs are named after the reference image or theme file when the palette comes from one
+    let palette_names = match matches
+        .value_of("Palette From")
+        .or_else(|| matches.value_of("Colorscheme File"))
+    {
+        Some(palette_path) => vec![Path::new(palette_path)
+            .file_stem()
+            .and_then(|stem| stem.to_str())
+            .ok_or_else(|| format!("'{}' does not have a file name", palette_path))?],
+        None if matches.is_present("Colors") => vec!["custom"],
+        None => colorschemes,
+    };
+
+    // Every image is colorized once per colorscheme, so anything that only happens once can't be
+    // used with several
+    if palette_names.len() > 1 {
+        for (arg, flag) in [
+            ("Suffix", "--suffix"),
+            ("Dump Palette", "--dump-palette"),
+            ("Palette Preview", "--palette-preview"),
+        ] {
+            if matches.is_present(arg) {
+                return Err(AppError::Other(format!(
+                    "{} can only be used with a single colorscheme",
+                    flag
+                )));
+            }
+        }
+        if input_paths.contains(&STDIO_PATH) {
+            return Err(AppError::Other(
+                "'-' can only be used with a single colorscheme, since stdin can only be read once"
+                    .to_string(),
+            ));
+        }
+    }
+
+    let mask = matches
+        .value_of("Mask")
+        .map(|path| {
+            image::open(path)
+                .map(|mask| Arc::new(mask.to_luma8()))
+                .map_err(|e| format!("Failed to open mask '{}': {}", path, e))
+        })
+        .transpose()?;
+
+    let compose_with = matches
+        .value_of("Compose With")
+        .map(|path| {
+            image::open(path)
+                .map(|overlay| Arc::new(overlay.to_rgba8()))
+                .map_err(|e| format!("Failed to open overlay '{}': {}", path, e))
+        })
+        .transpose()?;
+    let compose_position = matches
+        .value_of("Compose Position")
+        .map(str::parse)
+        .transpose()?
+        .unwrap_or_default();
+
+    let blend_map = matches
+        .value_of("Blend Map")
+        .map(|path| {
+            image::open(path)
+                .map(|blend_map| Arc::new(blend_map.to_luma8()))
+                .map_err(|e| format!("Failed to open blend map '{}': {}", path, e))
+        })
+        .transpose()?;
+
+    let max_dimension = matches
+        .value_of("Max Dimension")
+        .map(|max| {
+            max.parse::<u32>()
+                .ok()
+                .filter(|max| *max > 0)
+                .ok_or_else(|| {
+                    format!(
+                        "Failed to parse max dimension: '{}' is not a positive integer",
+                        max
+                    )
+                })
+        })
+        .transpose()?;
+
+    let output_format = matches
+        .value_of("Format")
+        .map(|format| {
+            ImageFormat::from_extension(format)
+                .ok_or_else(|| format!("Invalid format: '{}'", format))
+        })
+        .transpose()?;
+
+    let quality = matches
+        .value_of("Quality")
+        .map(|quality| {
+            quality
+                .parse()
+                .ok()
+                .filter(|quality| (1..=100).contains(quality))
+                .ok_or_else(|| {
+                    format!(
+                        "Failed to parse quality: '{}' is not an integer from 1 to 100",
+                        quality
+                    )
+                })
+        })
+        .transpose()?;
+
+    let webp_compression = match matches.value_of("WebP Compression") {
+        Some(compression) => compression.parse()?,
+        None if quality.is_some() => WebPCompression::Lossy,
+        None => WebPCompression::Lossless,
+    };
+
+    let excludes = Excludes {
+        patterns: matches
+            .values_of("Exclude")
+            .into_iter()
+            .flatten()
+            .map(|glob| {
+                Pattern::new(glob)
+                    .map_err(|e| format!("Invalid --exclude pattern '{}': {}", glob, e))
+            })
+            .collect::<Result<_, _>>()?,
+        // Templates can name outputs anything, so only suffixes can tell outputs apart
+        output_suffixes: match (matches.value_of("Suffix"), matches.value_of("Output")) {
+            _ if matches.is_present("Include Outputs") => Vec::new(),
+            (_, Some(output)) if OutputTemplate::is_template(output) => Vec::new(),
+            (Some(suffix), _) => vec![suffix.to_string()],
+            (None, _) => palette_names
+                .iter()
+                .flat_map(|name| [format!("_{}", name), format!("_{}_preview", name)])
+                .collect(),
+        },
+    };
+
+    let mut scheme_pairs = Vec::new();
+    for &palette_name in &palette_names {
+        let output = matches
+            .value_of("Output")
+            .map(|output| -> Result<Output, AppError> {
+                if !OutputTemplate::is_template(output) {
+                    return Ok(Output::Dir(PathBuf::from(output)));
+                }
+
+                let template = OutputTemplate::parse(output, palette_name)?;
+                if palette_names.len() > 1 && !template.names_scheme() {
+                    return Err(AppError::Other(format!(
+                        "Output template '{}' must include {{scheme}} when colorizing with more than one colorscheme, so that their outputs are named differently",
+                        output
+                    )));
+                }
+                Ok(Output::Template(template))
+            })
+            .transpose()?;
+
+        let suffix = match matches.value_of("Suffix") {
+            Some(_) if matches!(output, Some(Output::Template(_))) => return Err(AppError::Other(
+                "--suffix can't be used with an output template, since the template names the outputs"
+                    .to_string(),
+            )),
+            Some("") if output.is_none() => {
+                return Err(AppError::Other(
+                    "--suffix can only be empty when --output is set".to_string(),
+                ))
+            }
+            Some(suffix) => suffix.to_string(),
+            None if max_dimension.is_some() => format!("_{}_preview", palette_name),
+            None => format!("_{}", palette_name),
+        };
+
+        let input_output_pairs = generate_input_output_pairs(
+            &input_paths,
+            output.as_ref(),
+            &suffix,
+            output_format,
+            matches.is_present("Recursive"),
+            &excludes,
+        )?;
+        scheme_pairs.push((palette_name, input_output_pairs));
+    }
 
-    let colorscheme = matches
-        .value_of("Colorscheme")
-        .unwrap_or(&config.colorscheme);
+    if matches.is_present("Dry Run") {
+        for (_, input_output_pairs) in &scheme_pairs {
+            print_dry_run(input_output_pairs, matches.is_present("Overwrite"));
+        }
+        std::process::exit(0);
+    }
 
-    let input_output_pairs = generate_input_output_pairs(&input_paths, output_dir, &colorscheme)?;
+    // Nothing is written without saving, so existing outputs don't matter
+    let no_save = matches.is_present("No Save");
+    let scheme_pairs = if no_save {
+        scheme_pairs
+    } else {
+        scheme_pairs
+            .into_iter()
+            .map(|(palette_name, input_output_pairs)| {
+                create_output_dirs(&input_output_pairs)?;
+                let input_output_pairs =
+                    skip_existing_outputs(input_output_pairs, matches.is_present("Overwrite"))?;
+                Ok((palette_name, input_output_pairs))
+            })
+            .collect::<Result<Vec<_>, AppError>>()?
+    };
 
     let blend_factor = matches
         .value_of("Blend Factor")
@@ -356,11 +1835,80 @@ pub async fn init() -> Result<Arc<AppConfig>, AppError> {
     let blend_factor: f32 = blend_factor
         .parse()
         .map_err(|e| format!("Failed to parse blend_factor: {}", e))?;
+    let blend_factor = check_range("blend_factor", blend_factor, 0.0..=1.0)?;
+
+    let blend_curve: BlendCurve = matches
+        .value_of("Blend Curve")
+        .unwrap_or(&config.blend_curve)
+        .parse()?;
+
+    // As with interpolation, the last of --preserve-luminance and --no-preserve-luminance wins
+    let preserve_luminance = if matches.is_present("No Preserve Luminance") {
+        false
+    } else {
+        matches.is_present("Preserve Luminance") || config.preserve_luminance
+    };
+    let soft_match = matches.is_present("Soft Match") || config.soft_match;
+    // As with interpolation, the last of --linear-blend and --no-linear-blend wins
+    let linear_blend = if matches.is_present("No Linear Blend") {
+        false
+    } else {
+        matches.is_present("Linear Blend") || config.linear_blend
+    };
+    let invert_luminance = matches.is_present("Invert Luminance") || config.invert_luminance;
+
+    let contrast: f32 = matches
+        .value_of("Contrast")
+        .unwrap_or(&config.contrast)
+        .parse()
+        .map_err(|e| format!("Failed to parse contrast: {}", e))?;
+    let contrast = check_range("contrast", contrast, 0.0..=4.0)?;
 
+    let saturation: f32 = matches
+        .value_of("Saturation")
+        .unwrap_or(&config.saturation)
+        .parse()
+        .map_err(|e| format!("Failed to parse saturation: {}", e))?;
+    let saturation = check_range("saturation", saturation, 0.0..=4.0)?;
+
+    let sharpen: f32 = matches
+        .value_of("Sharpen")
+        .unwrap_or(&config.sharpen)
+        .parse()
+        .map_err(|e| format!("Failed to parse sharpen: {}", e))?;
+    let sharpen = check_range("sharpen", sharpen, 0.0..=4.0)?;
+
+    let preserve_neutrals: f32 = matches
+        .value_of("Preserve Neutrals")
+        .unwrap_or(&config.preserve_neutrals)
+        .parse()
+        .map_err(|e| format!("Failed to parse preserve neutrals: {}", e))?;
+    let preserve_neutrals = check_range("preserve_neutrals", preserve_neutrals, 0.0..=100.0)?;
+
+    let posterize: u32 = matches
+        .value_of("Posterize")
+        .unwrap_or(&config.posterize)
+        .parse()
+        .map_err(|e| format!("Failed to parse posterize: {}", e))?;
+    let posterize = check_posterize(posterize)?;
+
+    let hue_rotate: f32 = matches
+        .value_of("Hue Rotate")
+        .unwrap_or(&config.hue_rotate)
+        .parse()
+        .map_err(|e| format!("Failed to parse hue_rotate: {}", e))?;
+    let hue_rotate = check_range("hue_rotate", hue_rotate, -360.0..=360.0)?;
+
+    let color_space: ColorSpace = matches
+        .value_of("Color Space")
+        .unwrap_or(&config.color_space)
+        .parse()?;
+
+    // Whichever of the two flags comes last wins, and without either the config file decides
     let should_interpolate_colors = if matches.is_present("No Interpolation") {
         false
     } else {
-        config.interpolate_colors
+        matches.is_present("Interpolate Colors") || config.interpolate_colors
     };
 
     let interpolation_threshold = matches
@@ -370,6 +1918,17 @@ pub async fn init() -> Result<Arc<AppConfig>, AppError> {
     let interpolation_threshold: f32 = interpolation_threshold
         .parse()
         .map_err(|e| format!("Failed to parse interpolation_threshold: {}", e))?;
+    let interpolation_threshold = check_range(
+        "interpolation_threshold",
+        interpolation_threshold,
+        0.0..=100.0,
+    )?;
+    // Interpolating with a threshold of 0 would never finish
+    if interpolation_threshold == 0.0 {
+        return Err(AppError::Other(
+            "interpolation_threshold must be greater than 0".to_string(),
+        ));
+    }
 
     let dither_amount = matches
         .value_of("Dither Amount")
@@ -378,6 +1937,20 @@ pub async fn init() -> Result<Arc<AppConfig>, AppError> {
     let dither_amount: f32 = dither_amount
         .parse()
         .map_err(|e| format!("Failed to parse dither_amount: {}", e))?;
+    let dither_amount = check_range("dither_amount", dither_amount, 0.0..=1.0)?;
+
+    let dither_mode: DitherMode = matches
+        .value_of("Dither Mode")
+        .unwrap_or(&config.dither_mode)
+        .parse()?;
+
+    let seed = matches
+        .value_of("Seed")
+        .map(|seed| {
+            seed.parse::<u64>()
+                .map_err(|e| format!("Failed to parse seed: {}", e))
+        })
+        .transpose()?;
 
     let spatial_averaging_radius = matches
         .value_of("Spatial Averaging Radius")
@@ -386,51 +1959,1403 @@ pub async fn init() -> Result<Arc<AppConfig>, AppError> {
     let spatial_averaging_radius: u32 = spatial_averaging_radius
         .parse()
         .map_err(|e| format!("Failed to parse spatial_averaging_radius: {}", e))?;
+    let spatial_averaging_radius = check_range(
+        "spatial_averaging_radius",
+        spatial_averaging_radius,
+        0..=100,
+    )?;
+
+    let jobs =
+        match matches.value_of("Jobs") {
+            Some(jobs) => jobs.parse().ok().filter(|&jobs| jobs > 0).ok_or_else(|| {
+                format!("Failed to parse jobs: '{}' is not a positive integer", jobs)
+            })?,
+            None => std::thread::available_parallelism().map_or(1, |n| n.get()),
+        };
 
-    let colors = load_colorscheme(colorscheme, &config_dir).await?;
-    let colors: Vec<Lab> = colors
-        .iter()
-        .map(|hex| Lab::from_color(hex_to_rgb(hex).unwrap()))
-        .collect();
+    // Every image shares the global pool, so --jobs images at once split these threads between them
+    if let Some(threads) = matches.value_of("Threads") {
+        let threads: usize = threads
+            .parse()
+            .ok()
+            .filter(|&threads| threads > 0)
+            .ok_or_else(|| {
+                format!(
+                    "Failed to parse threads: '{}' is not a positive integer",
+                    threads
+                )
+            })?;
+        rayon::ThreadPoolBuilder::new()
+            .num_threads(threads)
+            .build_global()
+            .map_err(|e| format!("Failed to start {} threads: {}", threads, e))?;
+    }
 
-    let colors = if should_interpolate_colors {
-        interpolate_colors(colors, interpolation_threshold)
-    } else {
-        colors
+    let palette_size: usize = matches
+        .value_of("Palette Size")
+        .unwrap_or("16")
+        .parse()
+        .ok()
+        .filter(|&size| size > 0)
+        .ok_or_else(|| "Failed to parse palette_size: expected a positive integer".to_string())?;
+
+    // Without --distance-metric, pixels are matched by Euclidean distance so that the CPU can
+    // search a k-d tree, while colors are still spaced with the metric that suits the color space
+    let matching_metric: Option<DistanceMetric> = matches
+        .value_of("Distance Metric")
+        .map(str::parse)
+        .transpose()?;
+    let distance_metric = matching_metric.unwrap_or(DistanceMetric::default_for(color_space));
+
+    let max_palette_colors: Option<usize> = matches
+        .value_of("Max Palette Colors")
+        .map(|max| {
+            max.parse().ok().filter(|&max| max > 0).ok_or_else(|| {
+                "Failed to parse max_palette_colors: expected a positive integer".to_string()
+            })
+        })
+        .transpose()?;
+
+    let offline = matches.is_present("Offline") || config.offline;
+
+    let download_timeout: f32 = matches
+        .value_of("Download Timeout")
+        .unwrap_or(&config.download_timeout)
+        .parse()
+        .map_err(|e| format!("Failed to parse download_timeout: {}", e))?;
+    let download_timeout = check_range("download_timeout", download_timeout, 0.1..=3600.0)?;
+    let download_retries: u32 = matches
+        .value_of("Retries")
+        .unwrap_or(&config.download_retries)
+        .parse()
+        .map_err(|e| format!("Failed to parse download_retries: {}", e))?;
+    let download_retries = check_range("download_retries", download_retries, 0..=10)?;
+
+    let scheme_repo = parse_scheme_repo(
+        matches
+            .value_of("Scheme Repo")
+            .unwrap_or(&config.scheme_repo),
+    )?;
+
+    let progress: ProgressMode = matches.value_of("Progress").unwrap_or("bar").parse()?;
+
+    let download_options = DownloadOptions {
+        offline,
+        scheme_repo,
+        timeout: Duration::from_secs_f32(download_timeout),
+        retries: download_retries,
+        show_progress: progress == ProgressMode::Bar,
     };
 
-    Ok(Arc::new(AppConfig {
-        input_output_pairs,
-        blend_factor,
-        colors,
-        dither_amount,
-        spatial_averaging_radius,
-    }))
-}
+    let mut configs = Vec::new();
+    for (palette_name, input_output_pairs) in scheme_pairs {
+        let (mut colors, mut names, mut weights) =
+            if let Some(reference_path) = matches.value_of("Palette From") {
+                let reference = open_reference(reference_path)?;
+                let colors = extract_palette(&reference, palette_size);
+
+                if colors.is_empty() {
+                    return Err(AppError::Other(format!(
+                        "Failed to extract a palette from '{}'",
+                        reference_path
+                    )));
+                }
 
-fn generate_input_output_pairs(
-    input_paths: &[&str],
-    output_dir: Option<PathBuf>,
-    colorscheme: &str,
-) -> Result<Vec<(String, String)>, AppError> {
-    let mut pairs = Vec::new();
+                (colors, Vec::new(), Vec::new())
+            } else {
+                let colorscheme = match (
+                    matches.value_of("Colors"),
+                    matches.value_of("Colorscheme File"),
+                ) {
+                    (Some(colors), _) => parse_color_list(colors)?.into(),
+                    (None, Some(theme_path)) if theme_path.to_lowercase().ends_with(".gpl") => {
+                        parse_gimp_palette(&fs::read_to_string(theme_path)?, theme_path)?
+                    }
+                    (None, Some(theme_path)) => load_terminal_theme(Path::new(theme_path))?.into(),
+                    (None, None) => {
+                        load_colorscheme(palette_name, &config_dir, &download_options).await?
+                    }
+                };
+                // Alpha is validated but otherwise ignored, since the palette is matched on color alone
+                let colors: Vec<Lab> = colorscheme
+                    .colors
+                    .iter()
+                    .map(|hex| hex_to_rgb(hex).map(|(rgb, _alpha)| Lab::from_color(rgb)))
+                    .collect::<Result<_, _>>()?;
+                // Names are only used to label colors in --dump-palette
+                let names: Vec<(Lab, String)> = colors
+                    .iter()
+                    .zip(colorscheme.names)
+                    .filter_map(|(&color, name)| Some((color, name?)))
+                    .collect();
+                let weights: Vec<(Lab, f32)> = colors
+                    .iter()
+                    .zip(colorscheme.weights)
+                    .filter(|&(_, weight)| weight != 1.0)
+                    .map(|(&color, weight)| (color, weight))
+                    .collect();
+
+                (colors, names, weights)
+            };
+
+        // Rotating before interpolating keeps the colors filled in between on the rotated hues, and
+        // names and weights are rotated along with their colors so that they still match
+        if hue_rotate != 0.0 {
+            for color in &mut colors {
+                *color = rotate_hue(*color, hue_rotate);
+            }
+            for (color, _) in &mut names {
+                *color = rotate_hue(*color, hue_rotate);
+            }
+            for (color, _) in &mut weights {
+                *color = rotate_hue(*color, hue_rotate);
+            }
+        }
 
-    for input_path in input_paths {
-        let input_path = Path::new(input_path);
-        let file_stem = input_path.file_stem().unwrap().to_str().unwrap();
-        let extension = input_path.extension().unwrap_or_default().to_str().unwrap();
+        let colors = if should_interpolate_colors {
+            let original = colors.len();
+            let (colors, used_threshold) = interpolate_colors(
+                colors,
+                interpolation_threshold,
+                max_palette_colors,
+                color_space,
+                distance_metric,
+            );
+            if used_threshold != interpolation_threshold {
+                warn!(
+                    "Interpolating with a threshold of {} makes more than {} colors, so {}",
+                    interpolation_threshold,
+                    max_palette_colors.unwrap_or_default(),
+                    if used_threshold.is_finite() {
+                        format!("a threshold of {:.3} was used instead", used_threshold)
+                    } else {
+                        "the colorscheme was not interpolated".to_string()
+                    }
+                );
+            }
+            info!(
+                "Interpolated {} colors into {} with a threshold of {}",
+                original,
+                colors.len(),
+                used_threshold
+            );
+            colors
+        } else {
+            info!("Using {} colors without interpolation", colors.len());
+            colors
+        };
 
-        let output_path = if let Some(ref dir) = output_dir {
-            dir.join(format!("{}_{}.{}", file_stem, colorscheme, extension))
+        // Interpolated colors weigh 1, and like names, weights are matched loosely since interpolating
+        // can convert colors back and forth. Without weighted colors every color weighs 1
+        let palette_weights: Vec<f32> = if weights.is_empty() {
+            Vec::new()
         } else {
-            input_path.with_file_name(format!("{}_{}.{}", file_stem, colorscheme, extension))
+            colors
+                .iter()
+                .map(|&color| {
+                    weights
+                        .iter()
+                        .find(|(weighted, _)| weighted.distance(color) < 1e-3)
+                        .map_or(1.0, |&(_, weight)| weight)
+                })
+                .collect()
         };
 
-        pairs.push((
-            input_path.to_str().unwrap().to_string(),
-            output_path.to_str().unwrap().to_string(),
-        ));
+        check_palette(&colors, matches.is_present("Allow Single Color"))?;
+
+        if let Some(dump_path) = matches.value_of("Dump Palette") {
+            dump_palette(&colors, &names, &palette_weights, Path::new(dump_path))?;
+        }
+
+        if let Some(preview_path) = matches.value_of("Palette Preview") {
+            palette_preview::render(&colors)
+                .save(preview_path)
+                .map_err(|e| {
+                    format!("Failed to write palette preview '{}': {}", preview_path, e)
+                })?;
+            std::process::exit(0);
+        }
+
+        configs.push(Arc::new(AppConfig {
+            palette_name: palette_name.to_string(),
+            input_output_pairs,
+            colors,
+            options: ColorizeOptions {
+                blend_factor,
+                blend_curve,
+                color_space,
+                dither_amount,
+                dither_mode,
+                spatial_averaging_radius,
+                preserve_luminance,
+                soft_match,
+                distance_metric: matching_metric.unwrap_or(DistanceMetric::Euclidean),
+                linear_blend,
+                invert_luminance,
+                contrast,
+                saturation,
+                sharpen,
+                preserve_neutrals,
+                posterize,
+                seed,
+                mask: mask.clone(),
+                blend_map: blend_map.clone(),
+                palette_weights,
+            },
+            use_cpu: matches.is_present("CPU"),
+            gpu_options: gpu_options.clone(),
+            max_dimension,
+            restore_size: matches.is_present("Restore Size"),
+            progress,
+            timings: matches.is_present("Timings"),
+            coverage: no_save || matches.is_present("Coverage"),
+            no_save,
+            copy_on_failure: matches.is_present("Copy On Failure"),
+            manifest: matches.value_of("Manifest").map(String::from),
+            auto_blend: matches.is_present("Auto Blend") && !matches.is_present("Blend Factor"),
+            compose_with: compose_with.clone(),
+            compose_position,
+            mask_resize: matches
+                .value_of("Mask Resize")
+                .unwrap_or("stretch")
+                .parse()?,
+            jobs,
+            output_format,
+            quality,
+            webp_compression,
+            indexed: matches.is_present("Indexed"),
+            compare: matches
+                .is_present("Compare")
+                .then(|| {
+                    matches
+                        .value_of("Compare Layout")
+                        .unwrap_or("horizontal")
+                        .parse()
+                })
+                .transpose()?,
+        }));
     }
 
-    Ok(pairs)
+    Ok(configs)
+}
+
+/// Prints every GPU adapter along with its backend and device type, marking the one that
+/// `options` would select
+async fn show_gpu(options: &GpuOptions) {
+    let (adapters, selected) = list_adapters(options).await;
+
+    if adapters.is_empty() {
+        match options.backend {
+            GpuBackend::All => {
+                println!("No GPU adapters found. Images will be colorized on the CPU")
+            }
+            backend => println!("No GPU adapters found for the {} backend", backend),
+        }
+        return;
+    }
+
+    for (index, info) in adapters.iter().enumerate() {
+        println!(
+            "{} {}: {} ({:?}, {:?})",
+            if selected == Some(index) { "*" } else { " " },
+            index,
+            info.name,
+            info.backend,
+            info.device_type
+        );
+    }
+}
+
+/// Prints what this binary was built from and the adapters it can run on as JSON, so that scripts
+/// can record what produced an image
+async fn print_build_info(options: &GpuOptions) {
+    let (adapters, selected) = list_adapters(options).await;
+
+    let backends: BTreeSet<String> = adapters
+        .iter()
+        .map(|info| format!("{:?}", info.backend).to_lowercase())
+        .collect();
+    let adapters: Vec<_> = adapters
+        .iter()
+        .enumerate()
+        .map(|(index, info)| {
+            serde_json::json!({
+                "name": info.name,
+                "backend": format!("{:?}", info.backend).to_lowercase(),
+                "device_type": format!("{:?}", info.device_type),
+                "driver": info.driver,
+                "selected": selected == Some(index),
+            })
+        })
+        .collect();
+
+    println!(
+        "{}",
+        serde_json::json!({
+            "version": VERSION,
+            "git_commit": GIT_COMMIT,
+            "wgpu_version": WGPU_VERSION,
+            "backends": backends,
+            "adapters": adapters,
+        })
+    );
+}
+
+/// Options that a sidecar file next to an image, such as `photo.png.toml`, can override for just
+/// that image. Anything left out comes from the config file and command line
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct SidecarConfig {
+    blend_factor: Option<f32>,
+    blend_curve: Option<String>,
+    preserve_luminance: Option<bool>,
+    soft_match: Option<bool>,
+    linear_blend: Option<bool>,
+    invert_luminance: Option<bool>,
+    contrast: Option<f32>,
+    saturation: Option<f32>,
+    sharpen: Option<f32>,
+    preserve_neutrals: Option<f32>,
+    posterize: Option<u32>,
+    dither_amount: Option<f32>,
+    dither_mode: Option<String>,
+    spatial_averaging_radius: Option<u32>,
+    seed: Option<u64>,
+}
+
+impl SidecarConfig {
+    fn apply(self, options: &ColorizeOptions) -> Result<ColorizeOptions, AppError> {
+        let mut options = options.clone();
+        if let Some(blend_factor) = self.blend_factor {
+            options.blend_factor = check_range("blend_factor", blend_factor, 0.0..=1.0)?;
+        }
+        if let Some(blend_curve) = self.blend_curve {
+            options.blend_curve = blend_curve.parse()?;
+        }
+        if let Some(preserve_luminance) = self.preserve_luminance {
+            options.preserve_luminance = preserve_luminance;
+        }
+        if let Some(soft_match) = self.soft_match {
+            options.soft_match = soft_match;
+        }
+        if let Some(linear_blend) = self.linear_blend {
+            options.linear_blend = linear_blend;
+        }
+        if let Some(invert_luminance) = self.invert_luminance {
+            options.invert_luminance = invert_luminance;
+        }
+        if let Some(contrast) = self.contrast {
+            options.contrast = check_range("contrast", contrast, 0.0..=4.0)?;
+        }
+        if let Some(saturation) = self.saturation {
+            options.saturation = check_range("saturation", saturation, 0.0..=4.0)?;
+        }
+        if let Some(sharpen) = self.sharpen {
+            options.sharpen = check_range("sharpen", sharpen, 0.0..=4.0)?;
+        }
+        if let Some(preserve_neutrals) = self.preserve_neutrals {
+            options.preserve_neutrals =
+                check_range("preserve_neutrals", preserve_neutrals, 0.0..=100.0)?;
+        }
+        if let Some(posterize) = self.posterize {
+            options.posterize = check_posterize(posterize)?;
+        }
+        if let Some(dither_amount) = self.dither_amount {
+            options.dither_amount = check_range("dither_amount", dither_amount, 0.0..=1.0)?;
+        }
+        if let Some(dither_mode) = self.dither_mode {
+            options.dither_mode = dither_mode.parse()?;
+        }
+        if let Some(radius) = self.spatial_averaging_radius {
+            options.spatial_averaging_radius =
+                check_range("spatial_averaging_radius", radius, 0..=100)?;
+        }
+        if let Some(seed) = self.seed {
+            options.seed = Some(seed);
+        }
+        Ok(options)
+    }
+}
+
+/// The options of an image after applying its sidecar file
+pub struct Sidecar {
+    pub options: ColorizeOptions,
+    /// The blend factor the sidecar file sets, which takes precedence over `--auto-blend`
+    pub blend_factor: Option<f32>,
+}
+
+/// Applies the sidecar file of `input_path` on top of `options`, or returns `None` if the image
+/// doesn't have one
+pub fn load_sidecar(
+    input_path: &str,
+    options: &ColorizeOptions,
+) -> Result<Option<Sidecar>, AppError> {
+    let sidecar_path = format!("{}.toml", input_path);
+    if !Path::new(&sidecar_path).is_file() {
+        return Ok(None);
+    }
+
+    // Loaded like the config file, so values can be quoted the same way
+    config::Config::builder()
+        .add_source(File::new(&sidecar_path, FileFormat::Toml))
+        .build()
+        .and_then(|sidecar| sidecar.try_deserialize::<SidecarConfig>())
+        .map_err(AppError::from)
+        .and_then(|sidecar| {
+            let blend_factor = sidecar.blend_factor;
+            Ok(Sidecar {
+                options: sidecar.apply(options)?,
+                blend_factor,
+            })
+        })
+        .map(Some)
+        .map_err(|e| {
+            let message = match e {
+                AppError::Other(message) => message,
+                e => e.to_string(),
+            };
+            AppError::Other(format!(
+                "Invalid sidecar file {}: {}",
+                sidecar_path, message
+            ))
+        })
+}
+
+/// Opens the image that `--palette-from` extracts a palette from. Videos are read by asking ffmpeg
+/// for their first frame, which keeps a video decoder out of the build
+fn open_reference(path: &str) -> Result<image::DynamicImage, AppError> {
+    let is_video = Path::new(path)
+        .extension()
+        .and_then(|extension| extension.to_str())
+        .is_some_and(|extension| VIDEO_EXTENSIONS.contains(&extension.to_lowercase().as_str()));
+    if !is_video {
+        return Ok(image::open(path)?);
+    }
+
+    let output = Command::new("ffmpeg")
+        .args(["-v", "error", "-i", path, "-frames:v", "1"])
+        .args(["-f", "image2pipe", "-vcodec", "png", "-"])
+        .output()
+        .map_err(|e| {
+            format!(
+                "Failed to run ffmpeg to read the first frame of '{}': {}. Install ffmpeg to extract palettes from videos",
+                path, e
+            )
+        })?;
+    if !output.status.success() || output.stdout.is_empty() {
+        return Err(AppError::Other(format!(
+            "Failed to read the first frame of '{}': {}",
+            path,
+            String::from_utf8_lossy(&output.stderr).trim()
+        )));
+    }
+
+    Ok(image::load_from_memory_with_format(
+        &output.stdout,
+        ImageFormat::Png,
+    )?)
+}
+
+/// Fails for palettes that can't be colorized with: empty ones, and ones with a single distinct
+/// color, which would map every pixel to it, unless `allow_single_color` is set
+fn check_palette(colors: &[Lab], allow_single_color: bool) -> Result<(), AppError> {
+    let Some(first) = colors.first() else {
+        return Err(AppError::Other(
+            "The palette has no usable colors".to_string(),
+        ));
+    };
+
+    if !allow_single_color && colors.iter().all(|color| color == first) {
+        return Err(AppError::Other(
+            "The palette has only one color, so every pixel would be mapped to it. Add more colors, or pass --allow-single-color to tint the image with it"
+                .to_string(),
+        ));
+    }
+
+    Ok(())
+}
+
+/// Returns the number of posterize levels if it is 0, which turns posterizing off, or enough levels
+/// to posterize with
+fn check_posterize(levels: u32) -> Result<u32, AppError> {
+    if levels == 0 {
+        return Ok(levels);
+    }
+    check_range("posterize", levels, 2..=255)
+}
+
+/// Returns `value` if it is within `range`, or an error naming the parameter and its allowed range
+fn check_range<T: PartialOrd + std::fmt::Display>(
+    name: &str,
+    value: T,
+    range: RangeInclusive<T>,
+) -> Result<T, AppError> {
+    if range.contains(&value) {
+        Ok(value)
+    } else {
+        Err(AppError::Other(format!(
+            "{} must be between {} and {}, but was {}",
+            name,
+            range.start(),
+            range.end(),
+            value
+        )))
+    }
+}
+
+/// Where outputs are saved, from `--output`
+enum Output {
+    Dir(PathBuf),
+    Template(OutputTemplate),
+}
+
+/// Images that `--recursive` leaves out of the directories it walks. Images passed by name are
+/// always colorized
+struct Excludes {
+    /// Globs from `--exclude`, matched against the file name and the path within the directory
+    patterns: Vec<Pattern>,
+    /// What the file stems of outputs end in, such as `_kanagawa`, so that rerunning on a
+    /// directory doesn't colorize the outputs of the last run
+    output_suffixes: Vec<String>,
+}
+
+impl Excludes {
+    fn matches(&self, image_path: &Path, dir: &Path) -> bool {
+        let relative_path = image_path.strip_prefix(dir).unwrap_or(image_path);
+        let file_name = Path::new(image_path.file_name().unwrap_or_default());
+        self.patterns
+            .iter()
+            .any(|pattern| pattern.matches_path(file_name) || pattern.matches_path(relative_path))
+    }
+
+    fn is_output(&self, image_path: &Path) -> bool {
+        let Some(stem) = image_path.file_stem().and_then(|stem| stem.to_str()) else {
+            return false;
+        };
+        self.output_suffixes
+            .iter()
+            .any(|suffix| !suffix.is_empty() && stem.ends_with(suffix.as_str()))
+    }
+}
+
+fn generate_input_output_pairs(
+    input_paths: &[&str],
+    output: Option<&Output>,
+    suffix: &str,
+    output_format: Option<ImageFormat>,
+    recursive: bool,
+    excludes: &Excludes,
+) -> Result<Vec<(String, String)>, AppError> {
+    let mut pairs = Vec::new();
+
+    if input_paths
+        .iter()
+        .filter(|&&path| path == STDIO_PATH)
+        .count()
+        > 1
+    {
+        return Err(AppError::Other(
+            "'-' can only be used once, since stdin can only be read once".to_string(),
+        ));
+    }
+
+    for input_path in input_paths {
+        // Images read from stdin are always written to stdout
+        if *input_path == STDIO_PATH {
+            pairs.push((STDIO_PATH.to_string(), STDIO_PATH.to_string()));
+            continue;
+        }
+
+        let input_path = Path::new(input_path);
+
+        if !input_path.is_dir() {
+            pairs.push(generate_output_path(
+                input_path,
+                output,
+                suffix,
+                output_format,
+                pairs.len() + 1,
+            )?);
+            continue;
+        }
+
+        if !recursive {
+            return Err(AppError::Other(format!(
+                "'{}' is a directory. Use --recursive to colorize every image inside it",
+                input_path.display()
+            )));
+        }
+
+        for image_path in find_images(input_path)? {
+            if excludes.matches(&image_path, input_path) {
+                debug!(
+                    "Skipping '{}' as it matches --exclude",
+                    image_path.display()
+                );
+                continue;
+            }
+            if excludes.is_output(&image_path) {
+                warn!(
+                    "Skipping '{}' as it looks like an output of an earlier run. Use --include-outputs to colorize it",
+                    image_path.display()
+                );
+                continue;
+            }
+
+            // Mirror the directory structure of the input under the output directory
+            let mirrored_dir;
+            let output = match output {
+                Some(Output::Dir(dir)) => {
+                    let relative_dir = image_path
+                        .parent()
+                        .and_then(|parent| parent.strip_prefix(input_path).ok())
+                        .unwrap_or(Path::new(""));
+                    mirrored_dir = Output::Dir(dir.join(relative_dir));
+                    Some(&mirrored_dir)
+                }
+                output => output,
+            };
+
+            pairs.push(generate_output_path(
+                &image_path,
+                output,
+                suffix,
+                output_format,
+                pairs.len() + 1,
+            )?);
+        }
+    }
+
+    // Templates without {stem} or {index} can name several inputs the same
+    let mut inputs_by_output = HashMap::new();
+    for (input_path, output_path) in &pairs {
+        if output_path == STDIO_PATH {
+            continue;
+        }
+        if let Some(other_input) = inputs_by_output.insert(output_path, input_path) {
+            return Err(AppError::Other(format!(
+                "Both '{}' and '{}' would be saved to '{}'",
+                other_input, input_path, output_path
+            )));
+        }
+    }
+
+    Ok(pairs)
+}
+
+fn generate_output_path(
+    input_path: &Path,
+    output: Option<&Output>,
+    suffix: &str,
+    output_format: Option<ImageFormat>,
+    index: usize,
+) -> Result<(String, String), AppError> {
+    let path_str = input_path
+        .to_str()
+        .ok_or_else(|| format!("'{}' is not a valid UTF-8 path", input_path.display()))?;
+
+    let file_stem = input_path
+        .file_stem()
+        .and_then(|stem| stem.to_str())
+        .filter(|stem| *stem != "." && *stem != "..")
+        .ok_or_else(|| format!("'{}' does not have a file name", path_str))?;
+
+    // A trailing dot, as in 'photo.', counts as no extension
+    let extension = match (
+        output_format,
+        input_path
+            .extension()
+            .and_then(|ext| ext.to_str())
+            .filter(|ext| !ext.is_empty()),
+    ) {
+        (Some(format), _) => format.extensions_str()[0].to_string(),
+        (None, Some(extension)) => extension.to_string(),
+        (None, None) => guess_extension(input_path)?,
+    };
+
+    let output_path = match output {
+        Some(Output::Template(template)) => {
+            let dir = input_path
+                .parent()
+                .and_then(|dir| dir.to_str())
+                .filter(|dir| !dir.is_empty())
+                .unwrap_or(".");
+            PathBuf::from(template.render(&TemplateValues {
+                stem: file_stem,
+                ext: &extension,
+                dir,
+                index,
+            }))
+        }
+        Some(Output::Dir(dir)) => dir.join(format!("{}{}.{}", file_stem, suffix, extension)),
+        None => input_path.with_file_name(format!("{}{}.{}", file_stem, suffix, extension)),
+    };
+
+    let output_path = output_path
+        .to_str()
+        .ok_or_else(|| format!("'{}' is not a valid UTF-8 path", output_path.display()))?
+        .to_string();
+
+    Ok((path_str.to_string(), output_path))
+}
+
+/// Prints where each input would be saved, and what would happen to outputs that already exist
+fn print_dry_run(pairs: &[(String, String)], overwrite: bool) {
+    for (input_path, output_path) in pairs {
+        let note = match (
+            output_path != STDIO_PATH && Path::new(output_path).exists(),
+            overwrite,
+        ) {
+            (false, _) => "",
+            (true, true) => " (overwrites existing file)",
+            (true, false) => " (skipped, already exists)",
+        };
+        println!("{} -> {}{}", input_path, output_path, note);
+    }
+}
+
+/// Creates the directory of every output that doesn't exist yet, such as an `--output` directory,
+/// the directories a template names, or the ones `--recursive` mirrors
+fn create_output_dirs(pairs: &[(String, String)]) -> Result<(), AppError> {
+    for (_, output_path) in pairs {
+        let Some(dir) = Path::new(output_path).parent() else {
+            continue;
+        };
+        if dir.as_os_str().is_empty() || dir.is_dir() {
+            continue;
+        }
+
+        fs::create_dir_all(dir).map_err(|e| {
+            format!(
+                "Failed to create the output directory '{}': {}",
+                dir.display(),
+                e
+            )
+        })?;
+    }
+
+    Ok(())
+}
+
+/// Drops pairs whose output already exists unless `overwrite` is set. Writing over the input
+/// itself is always an error without `overwrite`
+fn skip_existing_outputs(
+    pairs: Vec<(String, String)>,
+    overwrite: bool,
+) -> Result<Vec<(String, String)>, AppError> {
+    if overwrite {
+        return Ok(pairs);
+    }
+
+    let mut remaining = Vec::with_capacity(pairs.len());
+
+    for (input_path, output_path) in pairs {
+        if output_path == STDIO_PATH || !Path::new(&output_path).exists() {
+            remaining.push((input_path, output_path));
+        } else if fs::canonicalize(&input_path)? == fs::canonicalize(&output_path)? {
+            return Err(AppError::Other(format!(
+                "Output for '{}' would overwrite the input. Use --overwrite to replace it",
+                input_path
+            )));
+        } else {
+            warn!(
+                "Skipping '{}' as '{}' already exists. Use --overwrite to replace it",
+                input_path, output_path
+            );
+        }
+    }
+
+    Ok(remaining)
+}
+
+/// Picks an extension for files without one based on the contents of the file. Files whose format
+/// isn't recognized, or can't be written, are saved as PNG
+fn guess_extension(input_path: &Path) -> Result<String, AppError> {
+    let format = image::io::Reader::open(input_path)
+        .and_then(|reader| reader.with_guessed_format())
+        .map_err(|e| format!("Failed to read '{}': {}", input_path.display(), e))?
+        .format();
+
+    let extension = match format {
+        Some(format) if format.can_write() && format.writing_enabled() => {
+            format.extensions_str()[0]
+        }
+        _ => "png",
+    };
+    info!(
+        "'{}' has no extension, so its output is saved as {}",
+        input_path.display(),
+        extension
+    );
+
+    Ok(extension.to_string())
+}
+
+/// Writes `default_config_file` to `config_path`, or to the default config file if that isn't set.
+/// An existing config file is only replaced when `force` is set
+fn init_config(config_path: Option<&str>, force: bool) -> Result<(), AppError> {
+    let config_path = match config_path {
+        Some(path) => PathBuf::from(path),
+        None => default_config_dir().join("config.toml"),
+    };
+
+    if config_path.exists() && !force {
+        return Err(AppError::Other(format!(
+            "'{}' already exists. Pass --force to replace it",
+            config_path.display()
+        )));
+    }
+
+    if let Some(parent) = config_path.parent() {
+        fs::create_dir_all(parent)?;
+    }
+    fs::write(&config_path, default_config_file())?;
+    println!("Wrote the default config to {}", config_path.display());
+
+    Ok(())
+}
+
+/// Prints every colorscheme that can be passed to `--colorscheme` without downloading it, marking
+/// whether it is built in or a file in the config directory
+fn list_schemes(config_dir: &Path) -> Result<(), AppError> {
+    let mut local = BTreeSet::new();
+
+    // A missing config directory just means there are no local colorschemes
+    if let Ok(entries) = fs::read_dir(config_dir) {
+        for entry in entries {
+            let path = entry?.path();
+            let is_colorscheme = path
+                .extension()
+                .and_then(|extension| extension.to_str())
+                .is_some_and(|extension| matches!(extension, "txt" | "yaml" | "yml" | "gpl"));
+            if let (true, Some(stem)) = (is_colorscheme, path.file_stem().and_then(|s| s.to_str()))
+            {
+                local.insert(stem.to_string());
+            }
+        }
+    }
+
+    let builtin: BTreeSet<String> = BUILTIN_COLORSCHEMES
+        .iter()
+        .map(|(name, _)| name.to_string())
+        .collect();
+
+    for name in builtin.union(&local) {
+        let source = match (builtin.contains(name), local.contains(name)) {
+            (true, true) => "local, overrides built-in",
+            (true, false) => "built-in",
+            _ => "local",
+        };
+        println!("{} ({})", name, source);
+    }
+
+    Ok(())
+}
+
+/// Recursively finds every file in `dir` with an image extension, skipping anything else.
+/// Symlinks to directories aren't followed, since one pointing at a parent would never end
+fn find_images(dir: &Path) -> Result<Vec<PathBuf>, AppError> {
+    let mut entries = fs::read_dir(dir)?
+        .map(|entry| entry.and_then(|entry| Ok((entry.path(), entry.file_type()?))))
+        .collect::<Result<Vec<_>, _>>()?;
+    entries.sort_by(|(a, _), (b, _)| a.cmp(b));
+
+    let mut images = Vec::new();
+
+    for (path, file_type) in entries {
+        if file_type.is_dir() {
+            images.extend(find_images(&path)?);
+        } else if file_type.is_symlink() && path.is_dir() {
+            warn!(
+                "Skipping '{}' as it is a symlink to a directory",
+                path.display()
+            );
+        } else if path
+            .extension()
+            .and_then(ImageFormat::from_extension)
+            .is_some()
+        {
+            images.push(path);
+        } else {
+            warn!("Skipping '{}' as it is not an image", path.display());
+        }
+    }
+
+    Ok(images)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn check_range_accepts_boundaries() {
+        assert_eq!(check_range("blend_factor", 0.0, 0.0..=1.0).unwrap(), 0.0);
+        assert_eq!(check_range("blend_factor", 1.0, 0.0..=1.0).unwrap(), 1.0);
+        assert_eq!(
+            check_range("spatial_averaging_radius", 0, 0..=100).unwrap(),
+            0
+        );
+        assert_eq!(
+            check_range("spatial_averaging_radius", 100, 0..=100).unwrap(),
+            100
+        );
+    }
+
+    #[test]
+    fn check_range_rejects_values_outside_the_range() {
+        let err = check_range("blend_factor", 1.5, 0.0..=1.0).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "Error: blend_factor must be between 0 and 1, but was 1.5"
+        );
+
+        assert!(check_range("dither_amount", -0.01, 0.0..=1.0).is_err());
+        assert!(check_range("interpolation_threshold", 100.5, 0.0..=100.0).is_err());
+        assert!(check_range("spatial_averaging_radius", 101, 0..=100).is_err());
+    }
+
+    #[test]
+    fn check_range_rejects_nan() {
+        assert!(check_range("blend_factor", f32::NAN, 0.0..=1.0).is_err());
+    }
+
+    #[test]
+    fn the_legacy_config_dir_is_used_only_when_the_new_one_is_empty() {
+        let root =
+            std::env::temp_dir().join(format!("image-colorizer-dirs-{}", std::process::id()));
+        let (dir, legacy) = (root.join("xdg"), root.join("home"));
+        fs::create_dir_all(&legacy).unwrap();
+        fs::write(legacy.join("config.toml"), "").unwrap();
+
+        // A missing or empty new directory falls back to the legacy one
+        assert_eq!(choose_config_dir(dir.clone(), legacy.clone()), legacy);
+        fs::create_dir_all(&dir).unwrap();
+        assert_eq!(choose_config_dir(dir.clone(), legacy.clone()), legacy);
+
+        fs::write(dir.join("nord.txt"), "").unwrap();
+        assert_eq!(choose_config_dir(dir.clone(), legacy.clone()), dir);
+
+        // Without a legacy directory the new one is used even before it exists
+        fs::remove_dir_all(&root).unwrap();
+        assert_eq!(choose_config_dir(dir.clone(), legacy), dir);
+    }
+
+    #[test]
+    fn colorscheme_lists_are_split_on_commas() {
+        assert_eq!(
+            parse_colorscheme_names("kanagawa, nord,kanagawa").unwrap(),
+            ["kanagawa", "nord"]
+        );
+        assert!(parse_colorscheme_names("kanagawa,,nord").is_err());
+    }
+
+    #[test]
+    fn parse_color_list_splits_on_commas_outside_of_functions() {
+        assert_eq!(
+            parse_color_list("#000, rgb(255, 0, 0),#fff").unwrap(),
+            ["#000", "rgb(255, 0, 0)", "#fff"]
+        );
+    }
+
+    #[test]
+    fn parse_color_list_reports_invalid_color() {
+        let err = parse_color_list("#000,#12345,#fff").unwrap_err();
+        assert!(err.to_string().contains("'#12345'"));
+    }
+
+    #[test]
+    fn parse_colorscheme_reads_named_colors() {
+        let colorscheme =
+            parse_colorscheme("// Comment\nbackground = #1f1f28\n\n#dcd7ba // Foreground\n")
+                .unwrap();
+
+        assert_eq!(colorscheme.colors, ["#1f1f28", "#dcd7ba"]);
+        assert_eq!(colorscheme.names, [Some("background".to_string()), None]);
+    }
+
+    #[test]
+    fn parse_colorscheme_reports_line_of_invalid_color() {
+        let (line, _) = parse_colorscheme("#fff\n// Comment\nred = #ff0g\n").unwrap_err();
+        assert_eq!(line, 3);
+    }
+
+    #[test]
+    fn parse_colorscheme_reads_weights() {
+        let colorscheme =
+            parse_colorscheme("background = #1f1f28 *2.5\n#dcd7ba\nrgb(1, 2, 3) * 0.5 // Dim\n")
+                .unwrap();
+
+        assert_eq!(colorscheme.colors, ["#1f1f28", "#dcd7ba", "rgb(1, 2, 3)"]);
+        assert_eq!(colorscheme.names[0].as_deref(), Some("background"));
+        assert_eq!(colorscheme.weights, [2.5, 1.0, 0.5]);
+
+        for weight in ["0", "-1", "inf", "heavy", ""] {
+            let (line, e) = parse_colorscheme(&format!("#fff\n#000 *{}\n", weight)).unwrap_err();
+            assert_eq!(line, 2);
+            assert!(e.contains("Invalid weight"), "{}", e);
+        }
+    }
+
+    #[test]
+    fn parse_gimp_palette_reads_colors_and_names() {
+        let palette = "GIMP Palette\nName: Test\nColumns: 4\n#\n255   0   0\tBright Red\n  0 128 255 Untitled\n 16  16  16\n";
+        let colorscheme = parse_gimp_palette(palette, "test").unwrap();
+
+        assert_eq!(colorscheme.colors, ["#ff0000", "#0080ff", "#101010"]);
+        assert_eq!(
+            colorscheme.names,
+            [Some("Bright Red".to_string()), None, None]
+        );
+
+        assert!(parse_gimp_palette("255 0 0 Red\n", "test").is_err());
+        assert!(parse_gimp_palette("GIMP Palette\n255 0 Red\n", "test").is_err());
+        assert!(parse_gimp_palette("GIMP Palette\n256 0 0\n", "test").is_err());
+    }
+
+    fn base16_scheme(skip: usize) -> String {
+        let colors: String = (0..16)
+            .filter(|&i| i != skip)
+            .map(|i| format!("base{:02X}: \"{:02x}{:02x}{:02x}\"\n", i, i, i, i))
+            .collect();
+        format!("scheme: \"Test\"\nauthor: \"Test\"\n{}", colors)
+    }
+
+    #[test]
+    fn parse_base16_colorscheme_reads_every_base_color() {
+        let expected: Vec<String> = (0..16)
+            .map(|i| format!("#{:02x}{:02x}{:02x}", i, i, i))
+            .collect();
+
+        let flat = base16_scheme(16);
+        assert_eq!(parse_base16_colorscheme(&flat, "test").unwrap(), expected);
+
+        // Newer schemes nest the colors under palette, with a leading #
+        let nested = format!(
+            "system: \"base16\"\nname: \"Test\"\npalette:\n{}",
+            base16_scheme(16)
+                .lines()
+                .filter(|line| line.starts_with("base"))
+                .map(|line| format!("  {}\n", line.replace(": \"", ": \"#")))
+                .collect::<String>()
+        );
+        assert_eq!(parse_base16_colorscheme(&nested, "test").unwrap(), expected);
+    }
+
+    #[test]
+    fn parse_base16_colorscheme_rejects_missing_and_invalid_colors() {
+        let missing = parse_base16_colorscheme(&base16_scheme(0x0B), "test").unwrap_err();
+        assert_eq!(
+            missing.to_string(),
+            "Error: Colorscheme 'test' is missing base0B"
+        );
+
+        let invalid = base16_scheme(0x0F) + "base0F: [1, 2, 3]\n";
+        let invalid = parse_base16_colorscheme(&invalid, "test").unwrap_err();
+        assert_eq!(
+            invalid.to_string(),
+            "Error: Colorscheme 'test' is missing base0F"
+        );
+
+        assert!(parse_base16_colorscheme("base00: [", "test").is_err());
+    }
+
+    #[test]
+    fn interpolate_colors_keeps_colors_with_the_same_lightness() {
+        let red = Lab::new(50.0, 60.0, 40.0);
+        let blue = Lab::new(50.0, 10.0, -60.0);
+
+        let (forward, _) = interpolate_colors(
+            vec![red, blue],
+            100.0,
+            None,
+            ColorSpace::Lab,
+            DistanceMetric::Ciede2000,
+        );
+        let (backward, _) = interpolate_colors(
+            vec![blue, red],
+            100.0,
+            None,
+            ColorSpace::Lab,
+            DistanceMetric::Ciede2000,
+        );
+
+        assert_eq!(forward, backward);
+        assert!(forward.contains(&red) && forward.contains(&blue));
+    }
+
+    #[test]
+    fn interpolate_colors_drops_nan() {
+        let colors = vec![Lab::new(f32::NAN, 0.0, 0.0), Lab::new(50.0, 0.0, 0.0)];
+        assert_eq!(
+            interpolate_colors(
+                colors,
+                2.5,
+                None,
+                ColorSpace::Lab,
+                DistanceMetric::Ciede2000
+            )
+            .0,
+            [Lab::new(50.0, 0.0, 0.0)]
+        );
+        assert!(interpolate_colors(
+            vec![],
+            2.5,
+            None,
+            ColorSpace::Lab,
+            DistanceMetric::Ciede2000
+        )
+        .0
+        .is_empty());
+    }
+
+    #[test]
+    fn outputs_of_files_without_an_extension_are_named_by_their_contents() {
+        let dir =
+            std::env::temp_dir().join(format!("image-colorizer-guess-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let mut png = std::io::Cursor::new(Vec::new());
+        image::RgbImage::new(1, 1)
+            .write_to(&mut png, image::ImageOutputFormat::Png)
+            .unwrap();
+        fs::write(dir.join("photo."), png.get_ref()).unwrap();
+        fs::write(dir.join("unknown"), "not an image").unwrap();
+
+        let output = |name: &str| {
+            generate_output_path(&dir.join(name), None, "_kanagawa", None, 1)
+                .unwrap()
+                .1
+        };
+        assert!(output("photo.").ends_with("photo_kanagawa.png"));
+        assert!(output("unknown").ends_with("unknown_kanagawa.png"));
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn recursive_walks_dont_follow_symlinked_directories() {
+        let dir =
+            std::env::temp_dir().join(format!("image-colorizer-symlinks-{}", std::process::id()));
+        fs::create_dir_all(dir.join("nested")).unwrap();
+        fs::write(dir.join("nested/a.png"), "").unwrap();
+        // Following this would walk nested/loop/nested/loop/... forever
+        std::os::unix::fs::symlink(&dir, dir.join("nested/loop")).unwrap();
+        std::os::unix::fs::symlink(dir.join("nested/a.png"), dir.join("b.png")).unwrap();
+
+        let images = find_images(&dir).unwrap();
+        assert_eq!(images, [dir.join("b.png"), dir.join("nested/a.png")]);
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn recursive_walks_skip_excluded_images_and_earlier_outputs() {
+        let dir =
+            std::env::temp_dir().join(format!("image-colorizer-exclude-{}", std::process::id()));
+        fs::create_dir_all(dir.join("drafts")).unwrap();
+        for name in ["a.png", "a_kanagawa.png", "b.jpg", "drafts/c.png"] {
+            fs::write(dir.join(name), "").unwrap();
+        }
+
+        let inputs = |excludes: &Excludes| -> Vec<String> {
+            let dir = dir.to_str().unwrap();
+            generate_input_output_pairs(&[dir], None, "_kanagawa", None, true, excludes)
+                .unwrap()
+                .into_iter()
+                .map(|(input, _)| input[dir.len() + 1..].to_string())
+                .collect()
+        };
+
+        let excludes = Excludes {
+            patterns: vec![
+                Pattern::new("drafts/*").unwrap(),
+                Pattern::new("*.jpg").unwrap(),
+            ],
+            output_suffixes: vec!["_kanagawa".to_string()],
+        };
+        assert_eq!(inputs(&excludes), ["a.png"]);
+
+        let excludes = Excludes {
+            patterns: Vec::new(),
+            output_suffixes: Vec::new(),
+        };
+        assert_eq!(
+            inputs(&excludes),
+            ["a.png", "a_kanagawa.png", "b.jpg", "drafts/c.png"]
+        );
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn rotate_hue_keeps_lightness_and_chroma() {
+        let color = Lab::new(60.0, 30.0, -20.0);
+
+        let rotated = rotate_hue(color, 180.0);
+        assert!((rotated.l - 60.0).abs() < 1e-3);
+        assert!((rotated.a + 30.0).abs() < 1e-3 && (rotated.b - 20.0).abs() < 1e-3);
+
+        let rotated = rotate_hue(color, 90.0);
+        assert!((rotated.a - 20.0).abs() < 1e-3 && (rotated.b - 30.0).abs() < 1e-3);
+    }
+
+    #[test]
+    fn check_palette_rejects_single_color_palettes() {
+        let gray = Lab::new(50.0, 0.0, 0.0);
+
+        assert!(check_palette(&[], true).is_err());
+        assert!(check_palette(&[gray, gray], false).is_err());
+        assert!(check_palette(&[gray], true).is_ok());
+        assert!(check_palette(&[gray, Lab::new(60.0, 0.0, 0.0)], false).is_ok());
+    }
+
+    #[test]
+    fn interpolate_colors_spaces_colors_with_the_distance_metric() {
+        // 111.8 apart in CIE76, but much closer in CIEDE2000
+        let colors = vec![Lab::new(50.0, 60.0, 40.0), Lab::new(50.0, 10.0, -60.0)];
+        let interpolated = |color_space, metric| {
+            interpolate_colors(colors.clone(), 10.0, None, color_space, metric)
+                .0
+                .len()
+        };
+
+        assert_eq!(interpolated(ColorSpace::Lab, DistanceMetric::Cie76), 13);
+        assert_eq!(interpolated(ColorSpace::Lab, DistanceMetric::Euclidean), 13);
+        assert_eq!(interpolated(ColorSpace::Oklab, DistanceMetric::Cie76), 13);
+        assert!(interpolated(ColorSpace::Lab, DistanceMetric::Ciede2000) < 13);
+    }
+
+    #[test]
+    fn interpolate_colors_raises_the_threshold_to_stay_under_the_cap() {
+        let colors = vec![
+            Lab::new(10.0, 0.0, 0.0),
+            Lab::new(50.0, 40.0, -20.0),
+            Lab::new(90.0, -30.0, 60.0),
+        ];
+
+        let (uncapped, _) = interpolate_colors(
+            colors.clone(),
+            0.25,
+            None,
+            ColorSpace::Oklab,
+            DistanceMetric::Euclidean,
+        );
+        assert!(uncapped.len() > 100, "{}", uncapped.len());
+
+        for max_colors in [3, 10, 100] {
+            let (capped, threshold) = interpolate_colors(
+                colors.clone(),
+                0.25,
+                Some(max_colors),
+                ColorSpace::Oklab,
+                DistanceMetric::Euclidean,
+            );
+            assert!(capped.len() <= max_colors, "{}", capped.len());
+            assert!(capped.len() >= max_colors - 2, "{}", capped.len());
+            assert!(threshold > 0.25);
+        }
+
+        // A cap that interpolation stays under leaves the threshold alone
+        let (capped, threshold) = interpolate_colors(
+            colors.clone(),
+            0.25,
+            Some(10_000),
+            ColorSpace::Oklab,
+            DistanceMetric::Euclidean,
+        );
+        assert_eq!((capped.len(), threshold), (uncapped.len(), 0.25));
+
+        // Palettes over the cap to begin with are kept as they are
+        let (capped, _) = interpolate_colors(
+            colors,
+            1.0,
+            Some(2),
+            ColorSpace::Lab,
+            DistanceMetric::Ciede2000,
+        );
+        assert_eq!(capped.len(), 3);
+    }
+
+    #[test]
+    fn sidecar_config_overrides_only_the_options_it_sets() {
+        let sidecar = SidecarConfig {
+            blend_factor: Some(0.5),
+            seed: Some(7),
+            ..Default::default()
+        };
+        let options = sidecar.apply(&ColorizeOptions::default()).unwrap();
+
+        assert_eq!(options.blend_factor, 0.5);
+        assert_eq!(options.seed, Some(7));
+        assert_eq!(
+            options.dither_amount,
+            ColorizeOptions::default().dither_amount
+        );
+
+        let sidecar = SidecarConfig {
+            blend_factor: Some(2.0),
+            ..Default::default()
+        };
+        assert!(sidecar.apply(&ColorizeOptions::default()).is_err());
+    }
+
+    #[test]
+    fn parse_scheme_repo_accepts_only_http_base_urls() {
+        assert_eq!(
+            parse_scheme_repo("https://example.com/palettes/").unwrap(),
+            "https://example.com/palettes"
+        );
+        assert_eq!(
+            parse_scheme_repo("http://localhost:8080").unwrap(),
+            "http://localhost:8080"
+        );
+
+        assert!(parse_scheme_repo("example.com/palettes").is_err());
+        assert!(parse_scheme_repo("file:///palettes").is_err());
+        assert!(parse_scheme_repo("https://example.com/palettes?ref=main").is_err());
+    }
+
+    #[test]
+    fn builtin_colorschemes_are_valid() {
+        for (name, colors) in BUILTIN_COLORSCHEMES {
+            assert!(parse_and_validate_colorscheme(&colors.join("\n"), name).is_ok());
+        }
+        assert!(builtin_colorscheme("Nord").is_some());
+        assert!(builtin_colorscheme("nordic").is_none());
+    }
+
+    #[test]
+    fn save_colorscheme_replaces_or_removes_the_etag() {
+        let dir = std::env::temp_dir().join(format!("image-colorizer-etag-{}", std::process::id()));
+        let path = dir.join("scheme.txt");
+
+        save_colorscheme(&path, "#000000\n", Some("\"v1\"")).unwrap();
+        assert_eq!(fs::read_to_string(etag_path(&path)).unwrap(), "\"v1\"");
+
+        save_colorscheme(&path, "#ffffff\n", None).unwrap();
+        assert_eq!(fs::read_to_string(&path).unwrap(), "#ffffff\n");
+        assert!(!etag_path(&path).exists());
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn default_config_file_sets_every_setting_to_its_default() {
+        // Without the defaults, deserializing fails if the file is missing any setting
+        let from_file: SerializedAppConfig = ConfigBuilder::<DefaultState>::default()
+            .add_source(File::from_str(&default_config_file(), FileFormat::Toml))
+            .build()
+            .unwrap()
+            .try_deserialize()
+            .unwrap();
+        let defaults: SerializedAppConfig = default_config_builder()
+            .unwrap()
+            .build()
+            .unwrap()
+            .try_deserialize()
+            .unwrap();
+
+        assert_eq!(format!("{:?}", from_file), format!("{:?}", defaults));
+    }
 }
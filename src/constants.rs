@@ -1 +1,25 @@
 pub static VERSION: &str = env!("CARGO_PKG_VERSION");
+
+/// The commit this was built from, or `unknown` outside of a git checkout. Set by build.rs
+pub static GIT_COMMIT: &str = env!("IMAGE_COLORIZER_GIT_COMMIT");
+
+/// The exact version of wgpu this was built with. Set by build.rs
+pub static WGPU_VERSION: &str = env!("IMAGE_COLORIZER_WGPU_VERSION");
+
+/// Input path that reads an image from stdin, and writes the result to stdout
+pub static STDIO_PATH: &str = "-";
+
+/// Quality of lossy WebP outputs when `--quality` isn't set, which matches `image`'s JPEG default
+pub static DEFAULT_QUALITY: u8 = 75;
+
+/// Where colorschemes that aren't found locally are downloaded from, unless `--scheme-repo` is set
+pub static DEFAULT_SCHEME_REPO: &str =
+    "https://raw.githubusercontent.com/TaylorBeeston/image-colorizer/main/colorschemes";
+
+/// Prefix of the environment variables that override config file values, such as
+/// `IMAGE_COLORIZER_BLEND_FACTOR` for `blend_factor`
+pub static ENV_PREFIX: &str = "IMAGE_COLORIZER";
+
+/// Extensions of files that `--palette-from` reads as videos, taking the palette from their first
+/// frame
+pub static VIDEO_EXTENSIONS: [&str; 8] = ["mp4", "m4v", "mov", "mkv", "webm", "avi", "mpg", "mpeg"];
@@ -0,0 +1,192 @@
+use crate::{
+    cpu::rgb_to_matching_space, kdtree::KdTree, types::ColorSpace, utils::palette_coordinates,
+};
+
+use image::DynamicImage;
+use palette::color_difference::Ciede2000;
+use palette::{FromColor, Lab, Srgb};
+use rayon::prelude::*;
+
+/// How well a palette covers an image, measured by matching every pixel to its nearest palette
+/// color the same way the palette pass does
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct PaletteCoverage {
+    /// How many distinct palette colors are nearest to at least one pixel
+    pub colors_used: usize,
+    pub palette_size: usize,
+    /// The mean CIEDE2000 difference between each pixel and its nearest palette color
+    pub mean_distance: f32,
+    /// The largest CIEDE2000 difference between a pixel and its nearest palette color
+    pub max_distance: f32,
+}
+
+/// Measures how closely `palette` matches the colors of `img`, without colorizing it. Pixels are
+/// matched in `color_space`, while distances are always CIEDE2000 so that they can be compared
+/// between color spaces
+pub fn palette_coverage(
+    img: &DynamicImage,
+    palette: &[Lab],
+    color_space: ColorSpace,
+) -> PaletteCoverage {
+    let tree = KdTree::new(&palette_coordinates(palette, color_space));
+    let img = img.to_rgb32f();
+
+    let (used, total_distance, max_distance) = img
+        .par_chunks(3)
+        .fold(
+            || (vec![false; palette.len()], 0.0f64, 0.0f32),
+            |(mut used, total, max), pixel| {
+                let rgb = [pixel[0], pixel[1], pixel[2]];
+                let Some(index) = tree.nearest_index(rgb_to_matching_space(rgb, color_space))
+                else {
+                    return (used, total, max);
+                };
+                used[index] = true;
+
+                let distance = Lab::from_color(Srgb::from(rgb)).difference(palette[index]);
+                (used, total + f64::from(distance), max.max(distance))
+            },
+        )
+        .reduce(
+            || (vec![false; palette.len()], 0.0, 0.0),
+            |(mut used, total, max), (other_used, other_total, other_max)| {
+                used.iter_mut()
+                    .zip(other_used)
+                    .for_each(|(used, other)| *used |= other);
+                (used, total + other_total, max.max(other_max))
+            },
+        );
+
+    let pixel_count = (img.width() as usize * img.height() as usize).max(1);
+
+    PaletteCoverage {
+        colors_used: used.iter().filter(|&&used| used).count(),
+        palette_size: palette.len(),
+        mean_distance: (total_distance / pixel_count as f64) as f32,
+        max_distance,
+    }
+}
+
+/// How many buckets lightness is split into when measuring how detailed an image is
+const LIGHTNESS_BINS: usize = 32;
+
+/// Chooses a blend factor for `img` from how detailed it is and how much of its lightness range
+/// `palette` can cover. Photos spread their pixels over many lightnesses, so they get a lower
+/// factor that keeps more of their detail, while flat images such as screenshots of UIs get a
+/// higher one that follows the colorscheme closely. Palettes that span from dark to light can
+/// represent more of any image, so they raise the factor slightly
+pub fn auto_blend_factor(img: &DynamicImage, palette: &[Lab]) -> f32 {
+    let img = img.to_rgb32f();
+    let histogram = img
+        .par_chunks(3)
+        .fold(
+            || [0u64; LIGHTNESS_BINS],
+            |mut histogram, pixel| {
+                let lab = Lab::from_color(Srgb::new(pixel[0], pixel[1], pixel[2]));
+                let bin = (lab.l / 100.0 * LIGHTNESS_BINS as f32) as usize;
+                histogram[bin.min(LIGHTNESS_BINS - 1)] += 1;
+                histogram
+            },
+        )
+        .reduce(
+            || [0; LIGHTNESS_BINS],
+            |mut histogram, other| {
+                histogram.iter_mut().zip(other).for_each(|(a, b)| *a += b);
+                histogram
+            },
+        );
+
+    // The entropy of the histogram, from 0 for a single lightness to 1 for an even spread
+    let total = histogram.iter().sum::<u64>().max(1) as f32;
+    let entropy: f32 = histogram
+        .iter()
+        .filter(|&&count| count > 0)
+        .map(|&count| {
+            let p = count as f32 / total;
+            -p * p.log2()
+        })
+        .sum();
+    let detail = entropy / (LIGHTNESS_BINS as f32).log2();
+
+    let (darkest, lightest) = palette
+        .iter()
+        .fold((f32::MAX, f32::MIN), |(min, max), color| {
+            (min.min(color.l), max.max(color.l))
+        });
+    let spread = ((lightest - darkest) / 100.0).clamp(0.0, 1.0);
+
+    let factor = 1.0 - 0.45 * detail + 0.15 * (spread - 0.5);
+    // Rounded so that the printed factor is the one that was used
+    (factor.clamp(0.4, 1.0) * 100.0).round() / 100.0
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use image::{Rgb, RgbImage};
+
+    fn lab(r: f32, g: f32, b: f32) -> Lab {
+        Lab::from_color(Srgb::new(r, g, b))
+    }
+
+    #[test]
+    fn exact_palette_colors_have_no_distance() {
+        let img = RgbImage::from_fn(4, 4, |x, _| {
+            if x < 2 {
+                Rgb([255, 0, 0])
+            } else {
+                Rgb([0, 0, 255])
+            }
+        });
+        let palette = [lab(1.0, 0.0, 0.0), lab(0.0, 1.0, 0.0), lab(0.0, 0.0, 1.0)];
+
+        let coverage = palette_coverage(&img.into(), &palette, ColorSpace::Lab);
+
+        assert_eq!(coverage.colors_used, 2);
+        assert_eq!(coverage.palette_size, 3);
+        assert!(coverage.max_distance < 0.01, "{:?}", coverage);
+    }
+
+    #[test]
+    fn distant_colors_increase_the_distance() {
+        let img = RgbImage::from_pixel(4, 4, Rgb([0, 200, 0]));
+        let near = palette_coverage(&img.clone().into(), &[lab(0.0, 0.7, 0.0)], ColorSpace::Lab);
+        let far = palette_coverage(&img.into(), &[lab(1.0, 0.0, 1.0)], ColorSpace::Lab);
+
+        assert!(near.mean_distance < far.mean_distance);
+        assert_eq!(far.mean_distance, far.max_distance);
+    }
+
+    #[test]
+    fn detailed_images_get_a_lower_auto_blend_factor() {
+        let palette = [lab(0.1, 0.1, 0.1), lab(0.5, 0.5, 0.6), lab(0.9, 0.9, 0.8)];
+        let flat = RgbImage::from_fn(64, 64, |x, _| {
+            if x < 40 {
+                Rgb([30, 30, 40])
+            } else {
+                Rgb([220, 220, 210])
+            }
+        });
+        let detailed = RgbImage::from_fn(64, 64, |x, y| {
+            Rgb([(x * 4) as u8, (y * 4) as u8, ((x * y) % 256) as u8])
+        });
+
+        let flat = auto_blend_factor(&flat.into(), &palette);
+        let detailed = auto_blend_factor(&detailed.into(), &palette);
+
+        assert!(flat > detailed + 0.2, "{} vs {}", flat, detailed);
+        assert!((0.4..=1.0).contains(&detailed));
+        assert!((0.4..=1.0).contains(&flat));
+    }
+
+    #[test]
+    fn palettes_spanning_more_lightness_raise_the_auto_blend_factor() {
+        let img = RgbImage::from_fn(16, 16, |x, y| Rgb([(x * 16) as u8, (y * 16) as u8, 100]));
+        let narrow = [lab(0.4, 0.3, 0.3), lab(0.5, 0.4, 0.4)];
+        let wide = [lab(0.0, 0.0, 0.0), lab(1.0, 1.0, 1.0)];
+
+        let img = DynamicImage::from(img);
+        assert!(auto_blend_factor(&img, &wide) > auto_blend_factor(&img, &narrow));
+    }
+}
@@ -0,0 +1,648 @@
+#![allow(clippy::excessive_precision)]
+
+use crate::{
+    kdtree::{KdTree, Match},
+    progress::Progress,
+    types::{BlendCurve, ColorSpace, ColorizeOptions, DistanceMetric, DitherMode, Timings},
+    utils::{
+        blend_chroma, blue_noise_threshold, compute_integral_image_off_runtime,
+        nearest_two_by_ciede2000, noise_offset, palette_coordinates, soft_match_color,
+    },
+};
+
+use image::{DynamicImage, GrayImage, ImageBuffer, Rgb, Rgb32FImage};
+use instant::Instant;
+use palette::Lab;
+use rayon::prelude::*;
+use std::sync::Arc;
+
+// These conversions intentionally mirror the WGSL shaders rather than using `palette`, so that
+// the CPU fallback produces the same output as the GPU path.
+
+fn rgb_to_lab(rgb: [f32; 3]) -> [f32; 3] {
+    xyz_to_lab(rgb_to_xyz(rgb))
+}
+
+fn linearize(c: f32) -> f32 {
+    if c > 0.04045 {
+        ((c + 0.055) / 1.055).powf(2.4)
+    } else {
+        c / 12.92
+    }
+}
+
+fn gamma(c: f32) -> f32 {
+    let c = if c > 0.0031308 {
+        1.055 * c.powf(1.0 / 2.4) - 0.055
+    } else {
+        12.92 * c
+    };
+    c.clamp(0.0, 1.0)
+}
+
+fn rgb_to_xyz(rgb: [f32; 3]) -> [f32; 3] {
+    let (r, g, b) = (linearize(rgb[0]), linearize(rgb[1]), linearize(rgb[2]));
+
+    [
+        r * 0.4124564 + g * 0.3575761 + b * 0.1804375,
+        r * 0.2126729 + g * 0.7151522 + b * 0.0721750,
+        r * 0.0193339 + g * 0.1191920 + b * 0.9503041,
+    ]
+}
+
+fn xyz_to_lab(xyz: [f32; 3]) -> [f32; 3] {
+    let epsilon = 0.008856;
+    let kappa = 903.3;
+
+    let f = |t: f32| {
+        if t > epsilon {
+            t.powf(1.0 / 3.0)
+        } else {
+            (kappa * t + 16.0) / 116.0
+        }
+    };
+    let fx = f(xyz[0] / 0.950489);
+    let fy = f(xyz[1]);
+    let fz = f(xyz[2] / 1.088840);
+
+    [116.0 * fy - 16.0, 500.0 * (fx - fy), 200.0 * (fy - fz)]
+}
+
+fn lab_to_rgb(lab: [f32; 3]) -> [f32; 3] {
+    xyz_to_rgb(lab_to_xyz(lab))
+}
+
+fn lab_to_xyz(lab: [f32; 3]) -> [f32; 3] {
+    let fy = (lab[0] + 16.0) / 116.0;
+    let fx = lab[1] / 500.0 + fy;
+    let fz = fy - lab[2] / 200.0;
+
+    let epsilon = 0.008856;
+    let kappa = 903.3;
+
+    let fx3 = fx * fx * fx;
+    let fz3 = fz * fz * fz;
+
+    let xr = if fx3 > epsilon {
+        fx3
+    } else {
+        (116.0 * fx - 16.0) / kappa
+    };
+    let yr = if lab[0] > kappa * epsilon {
+        fy * fy * fy
+    } else {
+        lab[0] / kappa
+    };
+    let zr = if fz3 > epsilon {
+        fz3
+    } else {
+        (116.0 * fz - 16.0) / kappa
+    };
+
+    [xr * 0.950489, yr, zr * 1.088840]
+}
+
+fn xyz_to_rgb(xyz: [f32; 3]) -> [f32; 3] {
+    let r = xyz[0] * 3.2404542 + xyz[1] * -1.5371385 + xyz[2] * -0.4985314;
+    let g = xyz[0] * -0.9692660 + xyz[1] * 1.8760108 + xyz[2] * 0.0415560;
+    let b = xyz[0] * 0.0556434 + xyz[1] * -0.2040259 + xyz[2] * 1.0572252;
+
+    [gamma(r), gamma(g), gamma(b)]
+}
+
+fn rgb_to_oklab(rgb: [f32; 3]) -> [f32; 3] {
+    let (r, g, b) = (linearize(rgb[0]), linearize(rgb[1]), linearize(rgb[2]));
+
+    let l = (0.4122214708 * r + 0.5363325363 * g + 0.0514459929 * b)
+        .max(0.0)
+        .powf(1.0 / 3.0);
+    let m = (0.2119034982 * r + 0.6806995451 * g + 0.1073969566 * b)
+        .max(0.0)
+        .powf(1.0 / 3.0);
+    let s = (0.0883024619 * r + 0.2817188376 * g + 0.6299787005 * b)
+        .max(0.0)
+        .powf(1.0 / 3.0);
+
+    [
+        0.2104542553 * l + 0.7936177850 * m - 0.0040720468 * s,
+        1.9779984951 * l - 2.4285922050 * m + 0.4505937099 * s,
+        0.0259040371 * l + 0.7827717662 * m - 0.8086757660 * s,
+    ]
+}
+
+fn oklab_to_rgb(lab: [f32; 3]) -> [f32; 3] {
+    let l = lab[0] + 0.3963377774 * lab[1] + 0.2158037573 * lab[2];
+    let m = lab[0] - 0.1055613458 * lab[1] - 0.0638541728 * lab[2];
+    let s = lab[0] - 0.0894841775 * lab[1] - 1.2914855480 * lab[2];
+
+    let (l, m, s) = (l * l * l, m * m * m, s * s * s);
+
+    [
+        gamma(4.0767416621 * l - 3.3077115913 * m + 0.2309699292 * s),
+        gamma(-1.2684380046 * l + 2.6097574011 * m - 0.3413193965 * s),
+        gamma(-0.0041960863 * l - 0.7034186147 * m + 1.7076147010 * s),
+    ]
+}
+
+/// Converts to the space that palette colors are matched in
+pub(crate) fn rgb_to_matching_space(rgb: [f32; 3], color_space: ColorSpace) -> [f32; 3] {
+    match color_space {
+        ColorSpace::Lab => rgb_to_lab(rgb),
+        ColorSpace::Oklab => rgb_to_oklab(rgb),
+    }
+}
+
+fn matching_space_to_rgb(color: [f32; 3], color_space: ColorSpace) -> [f32; 3] {
+    match color_space {
+        ColorSpace::Lab => lab_to_rgb(color),
+        ColorSpace::Oklab => oklab_to_rgb(color),
+    }
+}
+
+fn mix(a: [f32; 3], b: [f32; 3], t: f32) -> [f32; 3] {
+    [
+        (a[0] + (b[0] - a[0]) * t).clamp(0.0, 1.0),
+        (a[1] + (b[1] - a[1]) * t).clamp(0.0, 1.0),
+        (a[2] + (b[2] - a[2]) * t).clamp(0.0, 1.0),
+    ]
+}
+
+/// CPU equivalent of `mix_rgb` in the shaders
+fn mix_rgb(a: [f32; 3], b: [f32; 3], t: f32, options: &ColorizeOptions) -> [f32; 3] {
+    if options.linear_blend {
+        let linear = |c: [f32; 3]| c.map(linearize);
+        mix(linear(a), linear(b), t).map(gamma)
+    } else {
+        mix(a, b, t)
+    }
+}
+
+/// CPU equivalent of `blend_factor` in the shaders
+fn blend_factor(options: &ColorizeOptions, x: u32, y: u32, input_color: [f32; 3]) -> f32 {
+    let factor = match &options.blend_map {
+        Some(blend_map) => blend_map.get_pixel(x, y)[0] as f32 / 255.0,
+        None => options.blend_factor,
+    };
+    let factor = match &options.mask {
+        Some(mask) => factor * mask.get_pixel(x, y)[0] as f32 / 255.0,
+        None => factor,
+    };
+    match options.blend_curve {
+        BlendCurve::Linear => factor,
+        curve => factor * curve.weight(rgb_to_lab(input_color)[0] / 100.0),
+    }
+}
+
+/// CPU equivalent of `blend` in `colorize_pass1.wgsl`
+fn blend(
+    input_color: [f32; 3],
+    input_lab: [f32; 3],
+    lab: [f32; 3],
+    factor: f32,
+    options: &ColorizeOptions,
+) -> [f32; 3] {
+    if options.preserve_luminance {
+        let chroma = mix_chroma(input_lab, lab, factor);
+        matching_space_to_rgb([input_lab[0], chroma[0], chroma[1]], options.color_space)
+    } else {
+        let final_rgb = matching_space_to_rgb(lab, options.color_space);
+        mix_rgb(input_color, final_rgb, factor, options)
+    }
+}
+
+fn mix_chroma(a: [f32; 3], b: [f32; 3], t: f32) -> [f32; 2] {
+    [a[1] + (b[1] - a[1]) * t, a[2] + (b[2] - a[2]) * t]
+}
+
+fn dither_threshold(mode: DitherMode, amount: f32, x: u32, y: u32, noise_offset: [u32; 2]) -> f32 {
+    match mode {
+        DitherMode::Noise => {
+            let (x, y) = (x + noise_offset[0], y + noise_offset[1]);
+            let rand = ((x as f32 * 12.9898 + y as f32 * 78.233).sin() * 43758.5453).fract();
+            // WGSL's fract is always positive, unlike Rust's
+            if rand < 0.0 {
+                rand + 1.0
+            } else {
+                rand
+            }
+        }
+        DitherMode::Ordered => bayer_threshold(x, y, bayer_order(amount)),
+        DitherMode::BlueNoise => blue_noise_threshold(x, y, noise_offset),
+        DitherMode::None | DitherMode::FloydSteinberg => 0.0,
+    }
+}
+
+/// The Bayer matrix is 2^order pixels wide, growing from 2x2 to 16x16 as `amount` increases
+fn bayer_order(amount: f32) -> u32 {
+    (1.0 + (amount * 3.0 + 0.5).floor()).clamp(1.0, 4.0) as u32
+}
+
+fn bayer_threshold(x: u32, y: u32, order: u32) -> f32 {
+    let mut value = 0;
+    for i in 0..order {
+        let bx = (x >> i) & 1;
+        let by = (y >> i) & 1;
+        value |= (((bx ^ by) << 1) | by) << (2 * (order - 1 - i));
+    }
+
+    (value as f32 + 0.5) / (1 << (2 * order)) as f32
+}
+
+fn apply_dithering(color: [f32; 3], targ: [f32; 3], amount: f32, threshold: f32) -> [f32; 3] {
+    [
+        color[0] + (targ[0] - color[0]) * amount * threshold,
+        color[1] + (targ[1] - color[1]) * amount * threshold,
+        color[2] + (targ[2] - color[2]) * amount * threshold,
+    ]
+}
+
+fn to_pixel(rgb: [f32; 3]) -> Rgb<f32> {
+    Rgb(rgb.map(|c| c.clamp(0.0, 1.0)))
+}
+
+fn from_pixel(pixel: &Rgb<f32>) -> [f32; 3] {
+    pixel.0
+}
+
+/// Matches pixels to the palette, measuring distances with the distance metric of the options.
+/// Euclidean distances are searched with a k-d tree, while CIEDE2000 compares every color
+struct Matcher<'a> {
+    /// The palette in the matching space, which matched colors are returned in
+    coordinates: Vec<[f32; 3]>,
+    /// The palette in the space that distances are measured in
+    measured: Vec<[f32; 3]>,
+    /// `None` for CIEDE2000, which the tree can't prune with
+    tree: Option<KdTree>,
+    options: &'a ColorizeOptions,
+}
+
+impl<'a> Matcher<'a> {
+    fn new(palette: &[Lab], options: &'a ColorizeOptions) -> Self {
+        let measured = palette_coordinates(palette, measured_space(options));
+        let tree = match options.distance_metric {
+            DistanceMetric::Ciede2000 => None,
+            DistanceMetric::Cie76 | DistanceMetric::Euclidean => {
+                Some(KdTree::weighted(&measured, &options.palette_weights))
+            }
+        };
+        Matcher {
+            coordinates: palette_coordinates(palette, options.color_space),
+            measured,
+            tree,
+            options,
+        }
+    }
+
+    /// The palette color that `color` is matched to, or a mix of the nearest two with
+    /// `soft_match`. Both are in the matching space
+    fn nearest(&self, color: [f32; 3]) -> Option<[f32; 3]> {
+        let space = measured_space(self.options);
+        let query = if space == self.options.color_space {
+            color
+        } else {
+            rgb_to_matching_space(
+                matching_space_to_rgb(color, self.options.color_space),
+                space,
+            )
+        };
+
+        let weights = &self.options.palette_weights;
+        match (&self.tree, self.options.soft_match) {
+            (Some(tree), false) => tree
+                .nearest_index(query)
+                .map(|index| self.coordinates[index]),
+            (Some(tree), true) => tree
+                .nearest_two(query)
+                .map(|(first, second)| self.soft_match(first, second)),
+            (None, soft_match) => {
+                nearest_two_by_ciede2000(query, &self.measured, weights).map(|(first, second)| {
+                    if soft_match {
+                        self.soft_match(first, second)
+                    } else {
+                        self.coordinates[first.0]
+                    }
+                })
+            }
+        }
+    }
+
+    fn soft_match(&self, first: Match, second: Option<Match>) -> [f32; 3] {
+        let point = |(index, distance): Match| (self.coordinates[index], distance);
+        soft_match_color(point(first), second.map(point))
+    }
+}
+
+/// The space that the distance metric measures in, which is Lab for CIE76 and CIEDE2000
+fn measured_space(options: &ColorizeOptions) -> ColorSpace {
+    match options.distance_metric {
+        DistanceMetric::Euclidean => options.color_space,
+        DistanceMetric::Cie76 | DistanceMetric::Ciede2000 => ColorSpace::Lab,
+    }
+}
+
+/// CPU equivalent of `colorize_pass1.wgsl`
+fn palette_pass(img: &Rgb32FImage, palette: &[Lab], options: &ColorizeOptions) -> Rgb32FImage {
+    let palette = Matcher::new(palette, options);
+    let noise_offset = noise_offset(options.seed);
+
+    ImageBuffer::from_fn(img.width(), img.height(), |x, y| {
+        let input_color = from_pixel(img.get_pixel(x, y));
+        let lab_color = rgb_to_matching_space(input_color, options.color_space);
+        let final_lab = blend_chroma(lab_color, palette.nearest(lab_color), 1.0);
+        let threshold = dither_threshold(
+            options.dither_mode,
+            options.dither_amount,
+            x,
+            y,
+            noise_offset,
+        );
+        let dithered_lab = apply_dithering(final_lab, lab_color, options.dither_amount, threshold);
+
+        let factor = blend_factor(options, x, y, input_color);
+        to_pixel(blend(input_color, lab_color, dithered_lab, factor, options))
+    })
+}
+
+/// Replacement for `colorize_pass1.wgsl` that diffuses the error between each pixel and its
+/// palette color onto its neighbours. Each pixel depends on the ones before it, so this can only
+/// run sequentially on the CPU
+pub(crate) fn error_diffusion_pass(
+    img: &Rgb32FImage,
+    palette: &[Lab],
+    options: &ColorizeOptions,
+) -> Rgb32FImage {
+    let palette = Matcher::new(palette, options);
+    let (width, height) = img.dimensions();
+    let (w, h) = (width as usize, height as usize);
+
+    // Only the chroma is quantized, so only a and b errors are diffused
+    let mut errors = vec![[0.0f32; 2]; w * h];
+    let mut output = Rgb32FImage::new(width, height);
+
+    for y in 0..h {
+        for x in 0..w {
+            let input_color = from_pixel(img.get_pixel(x as u32, y as u32));
+            let lab_color = rgb_to_matching_space(input_color, options.color_space);
+            let error = errors[y * w + x];
+            let wanted = [
+                lab_color[0],
+                lab_color[1] + error[0],
+                lab_color[2] + error[1],
+            ];
+
+            let closest_color = blend_chroma(wanted, palette.nearest(wanted), 1.0);
+            let final_lab = [lab_color[0], closest_color[1], closest_color[2]];
+
+            let error = [
+                (wanted[1] - final_lab[1]) * options.dither_amount,
+                (wanted[2] - final_lab[2]) * options.dither_amount,
+            ];
+            let mut diffuse = |dx: isize, dy: usize, weight: f32| {
+                let (nx, ny) = (x as isize + dx, y + dy);
+                if nx >= 0 && (nx as usize) < w && ny < h {
+                    let neighbour = &mut errors[ny * w + nx as usize];
+                    neighbour[0] += error[0] * weight;
+                    neighbour[1] += error[1] * weight;
+                }
+            };
+            diffuse(1, 0, 7.0 / 16.0);
+            diffuse(-1, 1, 3.0 / 16.0);
+            diffuse(0, 1, 5.0 / 16.0);
+            diffuse(1, 1, 1.0 / 16.0);
+
+            output.put_pixel(
+                x as u32,
+                y as u32,
+                to_pixel(blend(
+                    input_color,
+                    lab_color,
+                    final_lab,
+                    blend_factor(options, x as u32, y as u32, input_color),
+                    options,
+                )),
+            );
+        }
+    }
+
+    output
+}
+
+/// CPU equivalent of `box_average.wgsl` and `colorize_pass3.wgsl`, averaging with the integral
+/// image of `img` rather than along rows and columns
+fn spatial_averaging_pass(
+    img: &Rgb32FImage,
+    sat: &[Vec<(f64, f64, f64)>],
+    options: &ColorizeOptions,
+) -> Rgb32FImage {
+    let (width, height) = img.dimensions();
+    let radius = options.spatial_averaging_radius as i64;
+
+    ImageBuffer::from_fn(width, height, |x, y| {
+        let x1 = (x as i64 - radius).max(0) as usize;
+        let y1 = (y as i64 - radius).max(0) as usize;
+        let x2 = (x as i64 + radius).min(width as i64 - 1) as usize;
+        let y2 = (y as i64 + radius).min(height as i64 - 1) as usize;
+
+        let top_left = sat[y1][x1];
+        let top_right = sat[y1][x2 + 1];
+        let bottom_left = sat[y2 + 1][x1];
+        let bottom_right = sat[y2 + 1][x2 + 1];
+
+        let weight = bottom_right.0 - top_right.0 - bottom_left.0 + top_left.0;
+        let avg_a = (bottom_right.1 - top_right.1 - bottom_left.1 + top_left.1) / weight;
+        let avg_b = (bottom_right.2 - top_right.2 - bottom_left.2 + top_left.2) / weight;
+
+        let input_color = from_pixel(img.get_pixel(x, y));
+        let input_lab = rgb_to_lab(input_color);
+
+        // Pixels with only transparent neighbours keep their own color
+        let avg_lab = if weight > 0.0 {
+            [input_lab[0], avg_a as f32, avg_b as f32]
+        } else {
+            input_lab
+        };
+
+        let factor = blend_factor(options, x, y, input_color);
+        let averaged = if options.preserve_luminance {
+            let chroma = mix_chroma(input_lab, avg_lab, factor);
+            lab_to_rgb([input_lab[0], chroma[0], chroma[1]])
+        } else {
+            let luminance_transferred_rgb = lab_to_rgb(avg_lab);
+
+            mix_rgb(input_color, luminance_transferred_rgb, factor, options)
+        };
+
+        to_pixel(sharpen(img, averaged, x, y, options.sharpen))
+    })
+}
+
+/// Moves `averaged`, the color of the pixel at (x, y) after spatial averaging, back toward its
+/// color in `img` by `amount`, and past it when `amount` is above 1. The result stays within the
+/// colors of the pixel's neighbours in `img`, so that edges don't get halos
+fn sharpen(img: &Rgb32FImage, averaged: [f32; 3], x: u32, y: u32, amount: f32) -> [f32; 3] {
+    if amount == 0.0 {
+        return averaged;
+    }
+
+    let unaveraged = from_pixel(img.get_pixel(x, y));
+    let (mut low, mut high) = (unaveraged, unaveraged);
+    for ny in y.saturating_sub(1)..=(y + 1).min(img.height() - 1) {
+        for nx in x.saturating_sub(1)..=(x + 1).min(img.width() - 1) {
+            let neighbour = from_pixel(img.get_pixel(nx, ny));
+            for c in 0..3 {
+                low[c] = low[c].min(neighbour[c]);
+                high[c] = high[c].max(neighbour[c]);
+            }
+        }
+    }
+
+    std::array::from_fn(|c| {
+        (averaged[c] + (unaveraged[c] - averaged[c]) * amount).clamp(low[c], high[c])
+    })
+}
+
+/// Builds the mask for `preserve_neutrals`, which is black wherever the source chroma is below the
+/// threshold and the user's mask, or white, everywhere else
+pub(crate) fn neutral_mask(img: &DynamicImage, options: &ColorizeOptions) -> GrayImage {
+    let rgb = img.to_rgb32f();
+    let values = rgb
+        .par_chunks(3)
+        .enumerate()
+        .map(|(i, pixel)| {
+            let [_, a, b] = rgb_to_lab([pixel[0], pixel[1], pixel[2]]);
+            if (a * a + b * b).sqrt() < options.preserve_neutrals {
+                return 0;
+            }
+            match &options.mask {
+                Some(mask) => mask.as_raw()[i],
+                None => 255,
+            }
+        })
+        .collect();
+
+    GrayImage::from_raw(rgb.width(), rgb.height(), values).unwrap()
+}
+
+/// Flips the Lab lightness of every pixel before colorizing, scaled by the mask like the blend
+/// factor so that masked out areas aren't inverted
+pub(crate) fn invert_luminance(img: &mut Rgb32FImage, options: &ColorizeOptions) {
+    let width = img.width() as usize;
+
+    img.par_chunks_mut(3).enumerate().for_each(|(i, pixel)| {
+        let [l, a, b] = rgb_to_lab([pixel[0], pixel[1], pixel[2]]);
+        let amount = match &options.mask {
+            Some(mask) => mask.get_pixel((i % width) as u32, (i / width) as u32)[0] as f32 / 255.0,
+            None => 1.0,
+        };
+        let inverted = (100.0 - l).clamp(0.0, 100.0);
+
+        pixel.copy_from_slice(&lab_to_rgb([l + (inverted - l) * amount, a, b]));
+    });
+}
+
+/// Applies `contrast` and `saturation` in Lab after colorizing, scaled by the mask like the blend
+/// factor so that masked out areas keep their original color
+pub(crate) fn adjust_contrast_and_saturation(img: &mut Rgb32FImage, options: &ColorizeOptions) {
+    let ColorizeOptions {
+        contrast,
+        saturation,
+        ..
+    } = *options;
+    if contrast == 1.0 && saturation == 1.0 {
+        return;
+    }
+
+    let width = img.width() as usize;
+    let pixel_count = (img.width() as usize * img.height() as usize).max(1);
+    let mean_lightness = img
+        .par_chunks(3)
+        .map(|pixel| rgb_to_lab([pixel[0], pixel[1], pixel[2]])[0] as f64)
+        .sum::<f64>()
+        / pixel_count as f64;
+    let mean_lightness = mean_lightness as f32;
+
+    img.par_chunks_mut(3).enumerate().for_each(|(i, pixel)| {
+        let lab = rgb_to_lab([pixel[0], pixel[1], pixel[2]]);
+        let adjusted = [
+            (mean_lightness + (lab[0] - mean_lightness) * contrast).clamp(0.0, 100.0),
+            lab[1] * saturation,
+            lab[2] * saturation,
+        ];
+
+        let amount = match &options.mask {
+            Some(mask) => mask.get_pixel((i % width) as u32, (i / width) as u32)[0] as f32 / 255.0,
+            None => 1.0,
+        };
+        let lab = [
+            lab[0] + (adjusted[0] - lab[0]) * amount,
+            lab[1] + (adjusted[1] - lab[1]) * amount,
+            lab[2] + (adjusted[2] - lab[2]) * amount,
+        ];
+
+        pixel.copy_from_slice(&lab_to_rgb(lab));
+    });
+}
+
+/// Quantizes each pixel to `posterize` levels per Lab axis after colorizing. The levels of a and b
+/// are spread evenly over [-128, 128], so an odd number of levels keeps one at 0 and grays stay gray
+pub(crate) fn posterize(img: &mut Rgb32FImage, options: &ColorizeOptions) {
+    if options.posterize < 2 {
+        return;
+    }
+
+    let width = img.width() as usize;
+    let intervals = (options.posterize - 1) as f32;
+    let quantize = |value: f32, min: f32, max: f32| {
+        let t = ((value - min) / (max - min)).clamp(0.0, 1.0);
+        min + (t * intervals).round() / intervals * (max - min)
+    };
+
+    img.par_chunks_mut(3).enumerate().for_each(|(i, pixel)| {
+        let lab = rgb_to_lab([pixel[0], pixel[1], pixel[2]]);
+        let quantized = [
+            quantize(lab[0], 0.0, 100.0),
+            quantize(lab[1], -128.0, 128.0),
+            quantize(lab[2], -128.0, 128.0),
+        ];
+
+        let amount = match &options.mask {
+            Some(mask) => mask.get_pixel((i % width) as u32, (i / width) as u32)[0] as f32 / 255.0,
+            None => 1.0,
+        };
+        let lab: [f32; 3] = std::array::from_fn(|c| lab[c] + (quantized[c] - lab[c]) * amount);
+
+        pixel.copy_from_slice(&lab_to_rgb(lab));
+    });
+}
+
+pub async fn colorize_cpu(
+    img: &DynamicImage,
+    palette: &[Lab],
+    options: &ColorizeOptions,
+    opacity: Option<Arc<GrayImage>>,
+    pb: &Progress<'_>,
+    timings: &mut Timings,
+) -> anyhow::Result<Rgb32FImage> {
+    let start = Instant::now();
+    let first_pass = match options.dither_mode {
+        DitherMode::FloydSteinberg => error_diffusion_pass(&img.to_rgb32f(), palette, options),
+        _ => palette_pass(&img.to_rgb32f(), palette, options),
+    };
+    timings.palette_pass += start.elapsed();
+
+    pb.inc(1);
+
+    // Averaging over a radius of 0 leaves every pixel as it is
+    if options.spatial_averaging_radius == 0 {
+        return Ok(first_pass);
+    }
+
+    let start = Instant::now();
+    let (first_pass, sat) = compute_integral_image_off_runtime(first_pass, opacity, pb).await?;
+    timings.integral_image += start.elapsed();
+
+    let start = Instant::now();
+    let colorized = spatial_averaging_pass(&first_pass, &sat, options);
+    timings.spatial_averaging_pass += start.elapsed();
+    Ok(colorized)
+}
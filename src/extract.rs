@@ -0,0 +1,95 @@
+use image::DynamicImage;
+use palette::{FromColor, Lab, Srgb};
+
+// Large images are subsampled, since a few thousand pixels are plenty to find the main colors
+const MAX_SAMPLES: usize = 10_000;
+const MAX_ITERATIONS: usize = 20;
+
+/// Finds up to `size` colors that best represent `img` using k-means clustering in Lab.
+/// Transparent pixels are ignored
+pub fn extract_palette(img: &DynamicImage, size: usize) -> Vec<Lab> {
+    let rgba = img.to_rgba8();
+    let step = (rgba.width() as usize * rgba.height() as usize / MAX_SAMPLES).max(1);
+
+    let mut samples: Vec<[f32; 3]> = rgba
+        .pixels()
+        .step_by(step)
+        .filter(|pixel| pixel[3] > 0)
+        .map(|pixel| {
+            let lab = Lab::from_color(Srgb::new(
+                pixel[0] as f32 / 255.0,
+                pixel[1] as f32 / 255.0,
+                pixel[2] as f32 / 255.0,
+            ));
+            [lab.l, lab.a, lab.b]
+        })
+        .collect();
+
+    if samples.is_empty() || size == 0 {
+        return Vec::new();
+    }
+
+    // Seeding the clusters evenly across the lightness range keeps the result deterministic
+    samples.sort_by(|a, b| a[0].partial_cmp(&b[0]).unwrap());
+    let size = size.min(samples.len());
+    let mut centroids: Vec<[f32; 3]> = (0..size)
+        .map(|i| samples[(2 * i + 1) * samples.len() / (2 * size)])
+        .collect();
+
+    let mut assignments = vec![usize::MAX; samples.len()];
+
+    for _ in 0..MAX_ITERATIONS {
+        let mut changed = false;
+        for (sample, assignment) in samples.iter().zip(assignments.iter_mut()) {
+            let nearest = nearest_centroid(sample, &centroids);
+            if nearest != *assignment {
+                *assignment = nearest;
+                changed = true;
+            }
+        }
+
+        if !changed {
+            break;
+        }
+
+        let mut sums = vec![([0.0f32; 3], 0usize); size];
+        for (sample, &assignment) in samples.iter().zip(&assignments) {
+            let (sum, count) = &mut sums[assignment];
+            sum[0] += sample[0];
+            sum[1] += sample[1];
+            sum[2] += sample[2];
+            *count += 1;
+        }
+
+        for (centroid, (sum, count)) in centroids.iter_mut().zip(sums) {
+            // Empty clusters keep their previous position
+            if count > 0 {
+                let count = count as f32;
+                *centroid = [sum[0] / count, sum[1] / count, sum[2] / count];
+            }
+        }
+    }
+
+    centroids.sort_by(|a, b| a.partial_cmp(b).unwrap());
+    centroids.dedup();
+
+    centroids
+        .into_iter()
+        .map(|[l, a, b]| Lab::new(l, a, b))
+        .collect()
+}
+
+fn nearest_centroid(sample: &[f32; 3], centroids: &[[f32; 3]]) -> usize {
+    let distance = |centroid: &[f32; 3]| {
+        (sample[0] - centroid[0]).powi(2)
+            + (sample[1] - centroid[1]).powi(2)
+            + (sample[2] - centroid[2]).powi(2)
+    };
+
+    centroids
+        .iter()
+        .enumerate()
+        .min_by(|(_, a), (_, b)| distance(a).partial_cmp(&distance(b)).unwrap())
+        .map(|(i, _)| i)
+        .unwrap()
+}
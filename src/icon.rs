@@ -0,0 +1,113 @@
+use crate::config::AppError;
+use crate::report::Reporter;
+use crate::{colorize, PartialOutput};
+
+use std::fs;
+use std::time::Instant;
+
+use image_colorizer::{AppConfig, ColorizeOptions, Gpu};
+
+use image::codecs::ico::{IcoEncoder, IcoFrame};
+use image::{ColorType, DynamicImage, ImageFormat};
+use indicatif::MultiProgress;
+
+/// Every resolution of an icon, in the order they were stored
+pub struct Icon {
+    images: Vec<DynamicImage>,
+}
+
+const HEADER_SIZE: usize = 6;
+const ENTRY_SIZE: usize = 16;
+
+/// Decodes every resolution of an ICO file, returning `None` for anything else and for icons with
+/// a single resolution, so that they can be processed as a still image
+pub fn open_icon(encoded: &[u8]) -> Result<Option<Icon>, AppError> {
+    if image::guess_format(encoded).ok() != Some(ImageFormat::Ico) {
+        return Ok(None);
+    }
+
+    let count = match encoded.get(4..6) {
+        Some(count) => u16::from_le_bytes([count[0], count[1]]) as usize,
+        None => 0,
+    };
+    if count < 2 {
+        return Ok(None);
+    }
+
+    let images = (0..count)
+        .map(|i| decode_entry(encoded, i))
+        .collect::<Result<_, _>>()?;
+    Ok(Some(Icon { images }))
+}
+
+// image only decodes the largest resolution of an icon, so each entry is copied into an icon of its
+// own, which leaves the PNG and BMP entries and their transparency masks to image
+fn decode_entry(encoded: &[u8], index: usize) -> Result<DynamicImage, AppError> {
+    let invalid = || format!("Invalid icon: entry {} is out of bounds", index + 1);
+
+    let start = HEADER_SIZE + index * ENTRY_SIZE;
+    let entry = encoded.get(start..start + ENTRY_SIZE).ok_or_else(invalid)?;
+    let size = u32::from_le_bytes([entry[8], entry[9], entry[10], entry[11]]) as usize;
+    let offset = u32::from_le_bytes([entry[12], entry[13], entry[14], entry[15]]) as usize;
+    let data = offset
+        .checked_add(size)
+        .and_then(|end| encoded.get(offset..end))
+        .ok_or_else(invalid)?;
+
+    let mut single = Vec::with_capacity(HEADER_SIZE + ENTRY_SIZE + size);
+    single.extend_from_slice(&[0, 0, 1, 0, 1, 0]);
+    single.extend_from_slice(&entry[..12]);
+    single.extend_from_slice(&((HEADER_SIZE + ENTRY_SIZE) as u32).to_le_bytes());
+    single.extend_from_slice(data);
+
+    Ok(image::load_from_memory_with_format(
+        &single,
+        ImageFormat::Ico,
+    )?)
+}
+
+pub async fn colorize_icon(
+    icon: Icon,
+    output_path: &str,
+    config: &AppConfig,
+    options: &ColorizeOptions,
+    gpu: Option<&Gpu>,
+    multi_progress: &MultiProgress,
+    reporter: &Reporter,
+) -> Result<(), AppError> {
+    // Icons can't be more than 256 pixels wide, which side by side comparisons often would be
+    if config.compare.is_some() {
+        return Err(AppError::Other(
+            "--compare can't be used with icons".to_string(),
+        ));
+    }
+
+    reporter.set_length(icon.images.len() as u64);
+
+    let mut colorized_images = Vec::with_capacity(icon.images.len());
+    for img in &icon.images {
+        let image_reporter = reporter.frame(
+            multi_progress,
+            format!("Icon {}x{}", img.width(), img.height()),
+        );
+        let colorized = colorize(img, config, options, gpu, &image_reporter).await?;
+        colorized_images.push(colorized.to_rgba8());
+
+        image_reporter.finish_and_clear();
+        reporter.inc(1);
+    }
+
+    let start = Instant::now();
+    let frames = colorized_images
+        .iter()
+        .map(|img| IcoFrame::as_png(img, img.width(), img.height(), ColorType::Rgba8))
+        .collect::<Result<Vec<_>, _>>()?;
+    let mut encoded = Vec::new();
+    IcoEncoder::new(&mut encoded).encode_images(&frames)?;
+    reporter.add_encode_time(start.elapsed());
+
+    let partial_output = PartialOutput::new(output_path);
+    fs::write(partial_output.temp_path(), encoded)?;
+    partial_output.finish()?;
+    Ok(())
+}
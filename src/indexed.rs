@@ -0,0 +1,168 @@
+use std::collections::HashMap;
+
+use image::DynamicImage;
+use palette::color_difference::EuclideanDistance;
+use palette::{Clamp, FromColor, Lab, Srgb};
+use tracing::info;
+
+/// How far, in Lab, a pixel can be from a palette color and still be saved as it. 2.3 is about the
+/// smallest difference that can be seen
+const TOLERANCE: f32 = 2.3;
+
+/// The most colors an 8-bit indexed PNG can hold
+const MAX_ENTRIES: usize = 256;
+
+/// Encodes `img` as an 8-bit palette-indexed PNG for `--indexed`, snapping each pixel to the
+/// nearest color of `palette` within `TOLERANCE`. Pixels that aren't near any palette color get an
+/// entry of their own. Returns `None` when that would take more than 256 entries, or when the
+/// image has more than 8 bits per channel, and the image should be saved in truecolor instead
+pub fn encode(img: &DynamicImage, palette: &[Lab]) -> Result<Option<Vec<u8>>, png::EncodingError> {
+    let color = img.color();
+    if color.bytes_per_pixel() > color.channel_count() {
+        info!("Saving a truecolor PNG, since indexed PNGs only have 8 bits per channel");
+        return Ok(None);
+    }
+
+    let Some((entries, indices)) = index_pixels(img, palette) else {
+        info!(
+            "Saving a truecolor PNG, since it has more than {} colors after snapping to the palette",
+            MAX_ENTRIES
+        );
+        return Ok(None);
+    };
+
+    let mut output = Vec::new();
+    let mut encoder = png::Encoder::new(&mut output, img.width(), img.height());
+    encoder.set_color(png::ColorType::Indexed);
+    encoder.set_depth(png::BitDepth::Eight);
+    encoder.set_palette(
+        entries
+            .iter()
+            .flat_map(|&[r, g, b, _]| [r, g, b])
+            .collect::<Vec<u8>>(),
+    );
+    // Transparency is only stored when something isn't opaque
+    if entries.iter().any(|&[.., alpha]| alpha < 255) {
+        encoder.set_trns(
+            entries
+                .iter()
+                .map(|&[.., alpha]| alpha)
+                .collect::<Vec<u8>>(),
+        );
+    }
+
+    let mut writer = encoder.write_header()?;
+    writer.write_image_data(&indices)?;
+    writer.finish()?;
+
+    Ok(Some(output))
+}
+
+/// Maps every pixel to an entry of at most `MAX_ENTRIES` RGBA colors, returning the entries and
+/// the index of each pixel
+fn index_pixels(img: &DynamicImage, palette: &[Lab]) -> Option<(Vec<[u8; 4]>, Vec<u8>)> {
+    let rgba = img.to_rgba8();
+    // Colorized images usually repeat a few colors many times, so each is only snapped once
+    let mut snapped: HashMap<[u8; 3], [u8; 3]> = HashMap::new();
+    let mut entries: Vec<[u8; 4]> = Vec::new();
+    let mut entry_indices: HashMap<[u8; 4], u8> = HashMap::new();
+    let mut indices = Vec::with_capacity(rgba.len() / 4);
+
+    for pixel in rgba.pixels() {
+        let [r, g, b, alpha] = pixel.0;
+        let [r, g, b] = *snapped
+            .entry([r, g, b])
+            .or_insert_with(|| snap([r, g, b], palette));
+        let entry = [r, g, b, alpha];
+
+        let index = match entry_indices.get(&entry) {
+            Some(&index) => index,
+            None => {
+                if entries.len() == MAX_ENTRIES {
+                    return None;
+                }
+                let index = entries.len() as u8;
+                entries.push(entry);
+                entry_indices.insert(entry, index);
+                index
+            }
+        };
+        indices.push(index);
+    }
+
+    Some((entries, indices))
+}
+
+/// The nearest color of `palette` to `rgb` if it is within `TOLERANCE`, or `rgb` itself
+fn snap(rgb: [u8; 3], palette: &[Lab]) -> [u8; 3] {
+    let lab = Lab::from_color(Srgb::new(rgb[0], rgb[1], rgb[2]).into_format::<f32>());
+
+    palette
+        .iter()
+        .map(|&color| (color, lab.distance(color)))
+        .filter(|&(_, distance)| distance <= TOLERANCE)
+        .min_by(|(_, a), (_, b)| a.total_cmp(b))
+        .map_or(rgb, |(color, _)| {
+            let srgb: Srgb<u8> = Srgb::from_color(color).clamp().into_format();
+            [srgb.red, srgb.green, srgb.blue]
+        })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use image::{Rgb, RgbImage, Rgba, RgbaImage};
+
+    fn palette() -> Vec<Lab> {
+        [(0.8, 0.2, 0.2), (0.2, 0.7, 0.3)]
+            .iter()
+            .map(|&(r, g, b)| Lab::from_color(Srgb::new(r, g, b)))
+            .collect()
+    }
+
+    fn decode(encoded: &[u8]) -> (png::OutputInfo, Vec<u8>, Vec<u8>) {
+        let mut reader = png::Decoder::new(encoded).read_info().unwrap();
+        let palette = reader.info().palette.as_ref().unwrap().to_vec();
+        let mut indices = vec![0; reader.output_buffer_size()];
+        let info = reader.next_frame(&mut indices).unwrap();
+        (info, palette, indices)
+    }
+
+    #[test]
+    fn colors_near_the_palette_are_indexed() {
+        // Red is a few steps away from the palette color, which is within the tolerance
+        let img = RgbImage::from_fn(8, 8, |x, _| {
+            if x < 4 {
+                Rgb([205, 50, 52])
+            } else {
+                Rgb([51, 178, 77])
+            }
+        });
+
+        let encoded = encode(&img.into(), &palette()).unwrap().unwrap();
+        let (info, palette, indices) = decode(&encoded);
+
+        assert_eq!(info.color_type, png::ColorType::Indexed);
+        assert_eq!(palette, [204, 51, 51, 51, 178, 76]);
+        assert_eq!(&indices[..8], [0, 0, 0, 0, 1, 1, 1, 1]);
+    }
+
+    #[test]
+    fn transparency_is_kept() {
+        let img = RgbaImage::from_fn(2, 1, |x, _| {
+            Rgba([204, 51, 51, if x == 0 { 0 } else { 255 }])
+        });
+
+        let encoded = encode(&img.into(), &palette()).unwrap().unwrap();
+        let reader = png::Decoder::new(encoded.as_slice()).read_info().unwrap();
+        assert_eq!(reader.info().trns.as_deref(), Some(&[0, 255][..]));
+    }
+
+    #[test]
+    fn too_many_colors_fall_back_to_truecolor() {
+        let img = RgbImage::from_fn(32, 32, |x, y| Rgb([(x * 8) as u8, (y * 8) as u8, 128]));
+
+        assert!(encode(&img.into(), &palette()).unwrap().is_none());
+    }
+}
@@ -0,0 +1,261 @@
+use crate::utils::{palette_weight, weighted_distance};
+
+/// A k-d tree over palette coordinates, so that finding the nearest color doesn't need to compare
+/// every pixel against every color of large interpolated palettes
+pub(crate) struct KdTree {
+    nodes: Vec<Node>,
+    /// The highest weight of any point, which bounds how near points across a split can seem
+    max_weight: f32,
+}
+
+/// The weighted distance and palette position of a point found by a search
+type Candidate = (f32, usize);
+
+/// The position of a point in the palette and its weighted distance from a query
+pub(crate) type Match = (usize, f32);
+
+struct Node {
+    point: [f32; 3],
+    weight: f32,
+    /// Position of the point in the palette, which breaks ties between equally near colors
+    index: usize,
+    axis: usize,
+    left: Option<usize>,
+    right: Option<usize>,
+}
+
+impl KdTree {
+    pub(crate) fn new(points: &[[f32; 3]]) -> KdTree {
+        KdTree::weighted(points, &[])
+    }
+
+    /// A tree where the distance to each point is divided by its weight in `weights`. Points
+    /// without a weight weigh 1
+    pub(crate) fn weighted(points: &[[f32; 3]], weights: &[f32]) -> KdTree {
+        let mut indices: Vec<usize> = (0..points.len()).collect();
+        let mut tree = KdTree {
+            nodes: Vec::with_capacity(points.len()),
+            max_weight: (0..points.len())
+                .map(|index| palette_weight(weights, index))
+                .fold(1.0, f32::max),
+        };
+        tree.build(points, weights, &mut indices, 0);
+        tree
+    }
+
+    /// Splits `indices` on the median of `axis`, returning the node of the median point
+    fn build(
+        &mut self,
+        points: &[[f32; 3]],
+        weights: &[f32],
+        indices: &mut [usize],
+        axis: usize,
+    ) -> Option<usize> {
+        if indices.is_empty() {
+            return None;
+        }
+
+        indices.sort_by(|&a, &b| points[a][axis].total_cmp(&points[b][axis]));
+        let median = indices.len() / 2;
+        let (left, rest) = indices.split_at_mut(median);
+        let (&mut index, right) = rest.split_first_mut().unwrap();
+
+        let node = self.nodes.len();
+        self.nodes.push(Node {
+            point: points[index],
+            weight: palette_weight(weights, index),
+            index,
+            axis,
+            left: None,
+            right: None,
+        });
+
+        let next_axis = (axis + 1) % 3;
+        self.nodes[node].left = self.build(points, weights, left, next_axis);
+        self.nodes[node].right = self.build(points, weights, right, next_axis);
+        Some(node)
+    }
+
+    /// Returns the position in the palette of the point nearest to `query`, with ties going to the
+    /// earliest point so that the result is always the same as `map_coordinates`
+    pub(crate) fn nearest_index(&self, query: [f32; 3]) -> Option<usize> {
+        let mut best = [None];
+        if !self.nodes.is_empty() {
+            self.search(0, query, &mut best);
+        }
+        best[0].map(|(_, index)| index)
+    }
+
+    /// The two points nearest to `query` with their weighted distances, nearest first, breaking
+    /// ties like `nearest_index`. The second is `None` for palettes of a single color
+    pub(crate) fn nearest_two(&self, query: [f32; 3]) -> Option<(Match, Option<Match>)> {
+        let mut best = [None, None];
+        if !self.nodes.is_empty() {
+            self.search(0, query, &mut best);
+        }
+        let [first, second] =
+            best.map(|candidate| candidate.map(|(distance, index)| (index, distance)));
+        first.map(|first| (first, second))
+    }
+
+    /// Keeps the nearest points to `query` in `best`, nearest first
+    fn search(&self, node: usize, query: [f32; 3], best: &mut [Option<Candidate>]) {
+        let Node {
+            point,
+            weight,
+            index,
+            axis,
+            left,
+            right,
+        } = self.nodes[node];
+
+        let distance = weighted_distance(query, point, weight);
+        let is_better = |candidate: &Option<Candidate>| match *candidate {
+            Some((best_distance, best_index)) => distance
+                .total_cmp(&best_distance)
+                .then(index.cmp(&best_index))
+                .is_lt(),
+            None => true,
+        };
+        if let Some(position) = best.iter().position(is_better) {
+            best[position..].rotate_right(1);
+            best[position] = Some((distance, index));
+        }
+
+        let offset = query[axis] - point[axis];
+        let (near, far) = if offset < 0.0 {
+            (left, right)
+        } else {
+            (right, left)
+        };
+
+        if let Some(near) = near {
+            self.search(near, query, best);
+        }
+        if let Some(far) = far {
+            // Points across the split are at least `offset` away, which no weight can bring nearer
+            // than `offset / max_weight`. The margin keeps rounding in the distance from skipping a
+            // point that ties with the best one
+            let best_distance =
+                best[best.len() - 1].map_or(f32::INFINITY, |(distance, _)| distance);
+            if offset.abs() / self.max_weight <= best_distance * (1.0 + 1e-5) {
+                self.search(far, query, best);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::utils::nearest_color;
+
+    use rand::rngs::StdRng;
+    use rand::{Rng, SeedableRng};
+
+    fn random_point(rng: &mut StdRng) -> [f32; 3] {
+        [
+            rng.gen_range(0.0..100.0),
+            rng.gen_range(-128.0..128.0),
+            rng.gen_range(-128.0..128.0),
+        ]
+    }
+
+    #[test]
+    fn nearest_matches_brute_force() {
+        let mut rng = StdRng::seed_from_u64(44);
+
+        for size in [1, 2, 7, 64, 500] {
+            let palette: Vec<[f32; 3]> = (0..size).map(|_| random_point(&mut rng)).collect();
+            let tree = KdTree::new(&palette);
+
+            for _ in 0..1000 {
+                let query = random_point(&mut rng);
+                assert_eq!(
+                    tree.nearest_index(query).map(|index| palette[index]),
+                    nearest_color(query, palette.iter().map(|&point| (point, 1.0)))
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn weighted_nearest_matches_brute_force() {
+        let mut rng = StdRng::seed_from_u64(63);
+
+        for size in [1, 2, 7, 64, 500] {
+            let palette: Vec<[f32; 3]> = (0..size).map(|_| random_point(&mut rng)).collect();
+            let weights: Vec<f32> = (0..size).map(|_| rng.gen_range(0.25..4.0)).collect();
+            let tree = KdTree::weighted(&palette, &weights);
+
+            for _ in 0..1000 {
+                let query = random_point(&mut rng);
+                assert_eq!(
+                    tree.nearest_index(query).map(|index| palette[index]),
+                    nearest_color(query, palette.iter().copied().zip(weights.iter().copied()))
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn weighted_colors_win_over_nearer_ones() {
+        let palette = [[50.0, 0.0, 0.0], [50.0, 30.0, 0.0]];
+
+        assert_eq!(
+            KdTree::new(&palette).nearest_index([50.0, 10.0, 0.0]),
+            Some(0)
+        );
+        let tree = KdTree::weighted(&palette, &[1.0, 3.0]);
+        assert_eq!(tree.nearest_index([50.0, 10.0, 0.0]), Some(1));
+    }
+
+    #[test]
+    fn nearest_breaks_ties_like_brute_force() {
+        // Every point is the same distance from the origin, and some are duplicated
+        let palette = [
+            [0.0, 0.0, 10.0],
+            [0.0, 10.0, 0.0],
+            [10.0, 0.0, 0.0],
+            [0.0, 10.0, 0.0],
+            [0.0, 0.0, -10.0],
+        ];
+        let tree = KdTree::new(&palette);
+
+        assert_eq!(tree.nearest_index([0.0; 3]), Some(0));
+        assert_eq!(tree.nearest_index([0.0, 10.0, 0.0]), Some(1));
+    }
+
+    #[test]
+    fn nearest_two_matches_brute_force() {
+        let mut rng = StdRng::seed_from_u64(93);
+
+        for size in [1, 2, 7, 64, 500] {
+            let palette: Vec<[f32; 3]> = (0..size).map(|_| random_point(&mut rng)).collect();
+            let weights: Vec<f32> = (0..size).map(|_| rng.gen_range(0.25..4.0)).collect();
+            let tree = KdTree::weighted(&palette, &weights);
+
+            for _ in 0..1000 {
+                let query = random_point(&mut rng);
+                // A stable sort keeps ties in palette order
+                let mut sorted: Vec<(usize, f32)> = palette
+                    .iter()
+                    .zip(&weights)
+                    .enumerate()
+                    .map(|(i, (&point, &weight))| (i, weighted_distance(query, point, weight)))
+                    .collect();
+                sorted.sort_by(|(_, a), (_, b)| a.total_cmp(b));
+
+                assert_eq!(
+                    tree.nearest_two(query),
+                    Some((sorted[0], sorted.get(1).copied()))
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn nearest_is_none_for_an_empty_palette() {
+        assert_eq!(KdTree::new(&[]).nearest_index([50.0, 0.0, 0.0]), None);
+    }
+}
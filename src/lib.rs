@@ -0,0 +1,46 @@
+//! Maps images onto a colorscheme, using the GPU when one is available.
+//!
+//! ```no_run
+//! use image_colorizer::{colorize_image, init_gpu, ColorizeOptions};
+//! use palette::{FromColor, Lab};
+//!
+//! # async fn run() -> anyhow::Result<()> {
+//! let img = image::open("input.png")?;
+//! let palette: Vec<Lab> = ["#1F1F28", "#DCD7BA", "#7E9CD8"]
+//!     .iter()
+//!     .map(|hex| Lab::from_color(image_colorizer::utils::hex_to_rgb(hex).unwrap().0))
+//!     .collect();
+//!
+//! let gpu = init_gpu().await?;
+//! let output = colorize_image(&img, &palette, &ColorizeOptions::default(), gpu.as_ref(), None).await?;
+//! output.save("output.png")?;
+//! # Ok(())
+//! # }
+//! ```
+
+mod colorize;
+mod coverage;
+mod cpu;
+mod extract;
+mod kdtree;
+mod progress;
+mod types;
+pub mod utils;
+#[cfg(target_arch = "wasm32")]
+pub mod wasm;
+
+#[cfg(feature = "native")]
+pub use crate::colorize::colorize_batch;
+#[cfg(not(target_arch = "wasm32"))]
+pub use crate::colorize::list_adapters;
+pub use crate::colorize::{
+    colorize_bytes, colorize_image, colorize_image_with_timings, init_gpu, init_gpu_with, Gpu,
+};
+pub use crate::coverage::{auto_blend_factor, palette_coverage, PaletteCoverage};
+pub use crate::extract::extract_palette;
+pub use crate::progress::ProgressCallback;
+pub use crate::types::{
+    AppConfig, BlendCurve, ColorSpace, ColorizeOptions, CompareLayout, ComposePosition, Corner,
+    DistanceMetric, DitherMode, GpuBackend, GpuOptions, MaskResize, ProgressMode, Timings,
+    WebPCompression,
+};
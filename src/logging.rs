@@ -0,0 +1,93 @@
+use std::fmt::{self, Write};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::OnceLock;
+
+use indicatif::MultiProgress;
+use tracing::field::{Field, Visit};
+use tracing::span::{Attributes, Id, Record};
+use tracing::{Event, Level, Metadata, Subscriber};
+
+/// Where progress bars are drawn once they exist, so that log lines can be written above them
+static MULTI_PROGRESS: OnceLock<MultiProgress> = OnceLock::new();
+
+/// Writes log events at or above a level to stderr, one line each. Spans are only given ids, since
+/// nothing in the colorizer needs their context in its logs
+struct StderrSubscriber {
+    max_level: Level,
+    next_span: AtomicU64,
+}
+
+/// Installs the subscriber for `-v` (info), `-vv` (debug), and `-vvv` or more (trace). With no
+/// `-v`, only warnings are written
+pub fn init(verbosity: u64) {
+    let max_level = match verbosity {
+        0 => Level::WARN,
+        1 => Level::INFO,
+        2 => Level::DEBUG,
+        _ => Level::TRACE,
+    };
+
+    let subscriber = StderrSubscriber {
+        max_level,
+        next_span: AtomicU64::new(1),
+    };
+    // This only fails if a subscriber was already installed, which then keeps logging
+    let _ = tracing::subscriber::set_global_default(subscriber);
+}
+
+/// Writes later log lines above the bars of `multi_progress` rather than over them
+pub fn attach(multi_progress: &MultiProgress) {
+    let _ = MULTI_PROGRESS.set(multi_progress.clone());
+}
+
+impl Subscriber for StderrSubscriber {
+    fn enabled(&self, metadata: &Metadata<'_>) -> bool {
+        *metadata.level() <= self.max_level
+    }
+
+    fn max_level_hint(&self) -> Option<tracing::level_filters::LevelFilter> {
+        Some(self.max_level.into())
+    }
+
+    fn new_span(&self, _: &Attributes<'_>) -> Id {
+        Id::from_u64(self.next_span.fetch_add(1, Ordering::Relaxed))
+    }
+
+    fn record(&self, _: &Id, _: &Record<'_>) {}
+
+    fn record_follows_from(&self, _: &Id, _: &Id) {}
+
+    fn event(&self, event: &Event<'_>) {
+        let metadata = event.metadata();
+        let mut line = format!("[{} {}]", metadata.level(), metadata.target());
+        event.record(&mut LineVisitor(&mut line));
+
+        match MULTI_PROGRESS.get() {
+            Some(multi_progress) => multi_progress.suspend(|| eprintln!("{}", line)),
+            None => eprintln!("{}", line),
+        }
+    }
+
+    fn enter(&self, _: &Id) {}
+
+    fn exit(&self, _: &Id) {}
+}
+
+/// Appends the message of an event, followed by any other fields as `name=value`
+struct LineVisitor<'a>(&'a mut String);
+
+impl Visit for LineVisitor<'_> {
+    fn record_debug(&mut self, field: &Field, value: &dyn fmt::Debug) {
+        let _ = match field.name() {
+            "message" => write!(self.0, " {:?}", value),
+            name => write!(self.0, " {}={:?}", name, value),
+        };
+    }
+
+    fn record_str(&mut self, field: &Field, value: &str) {
+        let _ = match field.name() {
+            "message" => write!(self.0, " {}", value),
+            name => write!(self.0, " {}={:?}", name, value),
+        };
+    }
+}
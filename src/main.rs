@@ -1,72 +1,612 @@
-mod colorize;
+mod animation;
 mod colors;
+mod compare;
 mod config;
 mod constants;
-mod types;
-mod utils;
+mod icon;
+mod indexed;
+mod logging;
+mod metadata;
+mod output_template;
+mod overlay;
+mod palette_preview;
+mod report;
+mod themes;
 
-use crate::colorize::colorize;
-use crate::config::{init, AppError};
-use crate::types::AppConfig;
+use crate::animation::{colorize_animation, open_animation};
+use crate::config::{init, load_sidecar, AppError};
+use crate::constants::{DEFAULT_QUALITY, STDIO_PATH};
+use crate::icon::{colorize_icon, open_icon};
+use crate::metadata::Metadata;
+use crate::report::{write_manifest, ImageResult, Reporter};
 
+use image_colorizer::{
+    auto_blend_factor, colorize_image, colorize_image_with_timings, init_gpu_with,
+    palette_coverage, AppConfig, ColorizeOptions, Gpu, MaskResize, PaletteCoverage, ProgressMode,
+    WebPCompression,
+};
+
+use std::fs;
+use std::io::{Cursor, Read, Write};
 use std::sync::Arc;
+use std::time::{Duration, Instant};
 
-use indicatif::{MultiProgress, ProgressBar, ProgressStyle};
+use futures::future::join_all;
+use image::codecs::webp::{WebPEncoder, WebPQuality};
+use image::imageops::FilterType;
+use image::{DynamicImage, GenericImageView, ImageFormat, ImageOutputFormat};
+use indicatif::MultiProgress;
+use tokio::sync::Semaphore;
 use tokio::task;
+use tracing::warn;
 
 #[tokio::main]
 async fn main() -> Result<(), AppError> {
-    let config = init().await?;
-    let multi_progress = Arc::new(MultiProgress::new());
+    let configs = init().await?;
+    // Only the palette differs between colorschemes, so the rest is read from the first
+    let config = Arc::clone(&configs[0]);
+    let multi_progress = Arc::new(report::multi_progress(config.progress));
+    logging::attach(&multi_progress);
+
+    // A single device is shared by every image, rather than each one enumerating adapters
+    let gpu = if config.use_cpu || config.no_save {
+        None
+    } else {
+        let start = Instant::now();
+        let gpu = init_gpu_with(&config.gpu_options)
+            .await
+            .map_err(|e| e.to_string())?;
+        if config.timings {
+            report::print_device_setup_time(config.progress, start.elapsed());
+        }
+        gpu.map(Arc::new)
+    };
+    // Limits how many images are held in memory and contending for the GPU at once
+    let semaphore = Arc::new(Semaphore::new(config.jobs));
+
+    // Every image is colorized once per colorscheme, and labeled with both when there are several
+    let several_schemes = configs.len() > 1;
+    let jobs: Vec<_> = configs
+        .iter()
+        .flat_map(|config| {
+            config
+                .input_output_pairs
+                .iter()
+                .map(move |(input_path, output_path)| {
+                    let label = if several_schemes {
+                        format!("{} ({})", input_path, config.palette_name)
+                    } else {
+                        input_path.clone()
+                    };
+                    (config, input_path, output_path, label)
+                })
+        })
+        .collect();
 
     let mut handles = Vec::new();
 
-    for (input_path, output_path) in &config.input_output_pairs {
-        let config = Arc::clone(&config);
+    for (config, input_path, output_path, label) in &jobs {
+        let config = Arc::clone(config);
         let multi_progress = Arc::clone(&multi_progress);
-        let input_path = input_path.clone();
-        let output_path = output_path.clone();
+        let semaphore = Arc::clone(&semaphore);
+        let gpu = gpu.clone();
+        let input_path = input_path.to_string();
+        let output_path = output_path.to_string();
+        let label = label.clone();
 
         let handle = task::spawn(async move {
-            let pb = multi_progress.add(ProgressBar::new(100));
-            pb.set_style(ProgressStyle::default_bar()
-                .template("{spinner:.green} [{elapsed_precise}] [{bar:40.cyan/blue}] {percent_precise}% ({eta}) {msg}")
-                .unwrap()
-                .progress_chars("#>-"));
-            pb.set_message(format!("Processing: {}", input_path));
+            let reporter = Reporter::new(config.progress, &multi_progress, &label);
+            reporter.stage("waiting", format!("Waiting: {}", label));
+
+            // The permit is held until the image is processed, and the wait isn't timed
+            let (result, duration) = match semaphore.acquire().await {
+                Ok(_permit) => {
+                    reporter.stage("processing", format!("Processing: {}", label));
+                    let start = Instant::now();
+                    let result = process_image(
+                        &input_path,
+                        &output_path,
+                        Arc::clone(&config),
+                        gpu.as_deref(),
+                        &multi_progress,
+                        &reporter,
+                    )
+                    .await;
+                    (result, start.elapsed())
+                }
+                Err(e) => (
+                    Err(AppError::Other(format!(
+                        "Failed to start processing: {}",
+                        e
+                    ))),
+                    Duration::ZERO,
+                ),
+            };
+
+            let mut copied = false;
+
+            let result = match result {
+                Err(e) if config.copy_on_failure => {
+                    let copy = copy_original(&input_path, &output_path, &config, e);
+                    copied = copy.is_ok();
+                    copy.map(|()| None)
+                }
+                result => result,
+            };
 
-            let result = process_image(&input_path, &output_path, config, &pb).await;
+            // Only what was actually colorized has a blend factor to record
+            let blend_factor = (result.is_ok() && !copied && !config.no_save).then(|| {
+                reporter
+                    .blend_factor()
+                    .unwrap_or(config.options.blend_factor)
+            });
 
             if result.is_ok() {
-                pb.finish_with_message(format!(
-                    "Finished: {} (Saved to: {})",
-                    input_path, output_path
-                ));
+                reporter.print_auto_blend(&label);
+            }
+            if result.is_ok() && config.timings {
+                reporter.print_timings(&label);
+            }
+
+            if copied {
+                reporter.finish(
+                    "copied",
+                    format!("Copied original: {} (Saved to: {})", label, output_path),
+                );
+            } else if result.is_ok() && config.no_save {
+                reporter.finish("finished", format!("Finished: {}", label));
+            } else if result.is_ok() {
+                reporter.finish(
+                    "finished",
+                    format!("Finished: {} (Saved to: {})", label, output_path),
+                );
             } else {
-                pb.finish_with_message(format!("Failed: {}", input_path));
+                reporter.finish("failed", format!("Failed: {}", label));
             }
 
-            result
+            (result, copied, duration, blend_factor)
         });
 
         handles.push(handle);
     }
 
-    for handle in handles {
-        handle.await.unwrap().unwrap();
+    let results = tokio::select! {
+        results = join_all(handles.iter_mut()) => Some(results),
+        Ok(()) = tokio::signal::ctrl_c() => None,
+    };
+
+    let results = match results {
+        Some(results) => results,
+        None => {
+            // Tasks stop at their next await point, and outputs are written without awaiting, so
+            // waiting on them guarantees no output is left half written
+            for handle in &handles {
+                handle.abort();
+            }
+            for handle in handles {
+                // Finished handles may have already given their result to join_all
+                if !handle.is_finished() {
+                    let _ = handle.await;
+                }
+            }
+
+            multi_progress.clear()?;
+            eprintln!("Interrupted. Any unfinished outputs were not saved");
+            std::process::exit(130);
+        }
+    };
+
+    // Every image is waited on, so one failure doesn't stop the rest from being saved
+    let mut failures = Vec::new();
+    let mut coverage = Vec::new();
+    let mut image_results = Vec::new();
+    for ((config, input_path, output_path, label), result) in jobs.iter().zip(results) {
+        let (error, copied, duration, blend_factor) = match result {
+            Ok((Ok(image_coverage), copied, duration, blend_factor)) => {
+                if let Some(image_coverage) = image_coverage {
+                    coverage.push((label.as_str(), image_coverage));
+                }
+                (None, copied, duration, blend_factor)
+            }
+            Ok((Err(e), _, duration, _)) => (Some(e.to_string()), false, duration, None),
+            Err(e) => (
+                Some(format!("Task failed: {}", e)),
+                false,
+                Duration::ZERO,
+                None,
+            ),
+        };
+        if let Some(error) = &error {
+            failures.push((label, error.clone()));
+        }
+        image_results.push(ImageResult {
+            config,
+            input: input_path,
+            output: output_path,
+            error,
+            copied,
+            blend_factor,
+            duration,
+        });
+    }
+
+    if config.coverage {
+        // The table can't go to stdout if an image was just written there
+        let to_stderr = !config.no_save
+            && jobs
+                .iter()
+                .any(|(_, _, output_path, _)| *output_path == STDIO_PATH);
+        report::print_coverage(config.progress, &coverage, to_stderr);
+    }
+
+    let total = jobs.len();
+    for (label, error) in &failures {
+        eprintln!("Failed to colorize {}: {}", label, error);
+    }
+    if config.progress != ProgressMode::None {
+        eprintln!(
+            "{} succeeded, {} failed",
+            total - failures.len(),
+            failures.len()
+        );
+    }
+
+    if let Some(manifest) = &config.manifest {
+        write_manifest(manifest, &configs, &image_results)?;
+    }
+
+    if !failures.is_empty() {
+        std::process::exit(1);
     }
 
     Ok(())
 }
 
+/// Colorizes and saves an image, returning how well the palette covers it if `--coverage` is set
 async fn process_image(
     input_path: &str,
     output_path: &str,
     config: Arc<AppConfig>,
-    pb: &ProgressBar,
+    gpu: Option<&Gpu>,
+    multi_progress: &MultiProgress,
+    reporter: &Reporter,
+) -> Result<Option<PaletteCoverage>, AppError> {
+    if input_path == STDIO_PATH {
+        return process_stdin(&config, gpu, reporter).await;
+    }
+
+    // A sidecar file next to the image can override the options for just that image
+    let sidecar = load_sidecar(input_path, &config.options)?;
+    if let Some(blend_factor) = sidecar.as_ref().and_then(|sidecar| sidecar.blend_factor) {
+        reporter.set_sidecar_blend_factor(blend_factor);
+    }
+    let options = sidecar
+        .as_ref()
+        .map_or(&config.options, |sidecar| &sidecar.options);
+
+    let encoded = fs::read(input_path)?;
+    let coverage = config
+        .coverage
+        .then(|| measure_coverage(&encoded, &config))
+        .transpose()?;
+    if config.no_save {
+        return Ok(coverage);
+    }
+
+    if let Some(animation) = open_animation(input_path)? {
+        colorize_animation(
+            animation,
+            output_path,
+            &config,
+            options,
+            gpu,
+            multi_progress,
+            reporter,
+        )
+        .await?;
+        return Ok(coverage);
+    }
+
+    // Every resolution of an icon is colorized when it is saved as an icon again. Otherwise only
+    // the largest is, like any other image
+    let format = ImageFormat::from_path(output_path)?;
+    if format == ImageFormat::Ico {
+        if let Some(icon) = open_icon(&encoded)? {
+            colorize_icon(
+                icon,
+                output_path,
+                &config,
+                options,
+                gpu,
+                multi_progress,
+                reporter,
+            )
+            .await?;
+            return Ok(coverage);
+        }
+    }
+
+    let output = colorize_encoded(&encoded, format, &config, options, gpu, reporter).await?;
+
+    let partial_output = PartialOutput::new(output_path);
+    fs::write(partial_output.temp_path(), output)?;
+    partial_output.finish()?;
+    Ok(coverage)
+}
+
+/// Copies an image that failed to colorize to its output path for `--copy-on-failure`, logging
+/// `error` as a warning. Fails with `error` if the image can't be copied, such as from stdin
+fn copy_original(
+    input_path: &str,
+    output_path: &str,
+    config: &AppConfig,
+    error: AppError,
 ) -> Result<(), AppError> {
-    let img = image::open(input_path)?;
-    let final_output = colorize(&img, &config, &pb).await.unwrap();
-    final_output.save(output_path)?;
+    if input_path == STDIO_PATH || output_path == STDIO_PATH {
+        return Err(error);
+    }
+
+    let encoded = fs::read(input_path)
+        .map_err(|e| format!("{}. Failed to copy the original: {}", error, e))?;
+
+    // The original is converted to the format of the output path so that it matches its
+    // extension. Originals already in that format, and ones that can't be decoded, are copied as
+    // they are
+    let converted = ImageFormat::from_path(output_path)
+        .ok()
+        .filter(|&format| image::guess_format(&encoded).ok() != Some(format))
+        .and_then(|format| {
+            let metadata = Metadata::read(&encoded);
+            let img = image::load_from_memory(&encoded).ok()?;
+            let img = metadata.apply_orientation(img);
+            metadata.embed(encode(&img, format, config).ok()?).ok()
+        });
+
+    let partial_output = PartialOutput::new(output_path);
+    fs::write(partial_output.temp_path(), converted.unwrap_or(encoded))
+        .map_err(|e| format!("{}. Failed to copy the original: {}", error, e))?;
+    partial_output.finish()?;
+
+    warn!(
+        "Failed to colorize {}: {}. Copied the original to {}",
+        input_path, error, output_path
+    );
     Ok(())
 }
+
+/// Measures the palette coverage of an encoded image. Only the first frame of an animation is
+/// measured
+fn measure_coverage(encoded: &[u8], config: &AppConfig) -> Result<PaletteCoverage, AppError> {
+    let img = image::io::Reader::new(Cursor::new(encoded))
+        .with_guessed_format()?
+        .decode()?;
+    Ok(palette_coverage(
+        &img,
+        &config.colors,
+        config.options.color_space,
+    ))
+}
+
+/// Writes an output to a temporary file next to it, which is renamed into place by `finish`, so
+/// that other programs never see a half written image. The temporary file is removed when dropped
+/// unless `finish` was called, so that a failed write doesn't leave it behind
+pub struct PartialOutput<'a> {
+    path: &'a str,
+    // A sibling of the output, so that renaming stays on the same filesystem and is atomic
+    temp_path: String,
+    finished: bool,
+}
+
+impl<'a> PartialOutput<'a> {
+    pub fn new(path: &'a str) -> Self {
+        PartialOutput {
+            path,
+            temp_path: format!("{}.tmp", path),
+            finished: false,
+        }
+    }
+
+    /// Where the output should be written before `finish` is called
+    pub fn temp_path(&self) -> &str {
+        &self.temp_path
+    }
+
+    pub fn finish(mut self) -> Result<(), AppError> {
+        fs::rename(&self.temp_path, self.path)
+            .map_err(|e| format!("Failed to move '{}' into place: {}", self.temp_path, e))?;
+        self.finished = true;
+        Ok(())
+    }
+}
+
+impl Drop for PartialOutput<'_> {
+    fn drop(&mut self) {
+        if !self.finished {
+            let _ = fs::remove_file(&self.temp_path);
+        }
+    }
+}
+
+/// Colorizes an image read from stdin and writes it to stdout in the same format. Progress bars
+/// are drawn to stderr, so they don't end up in the output
+async fn process_stdin(
+    config: &AppConfig,
+    gpu: Option<&Gpu>,
+    reporter: &Reporter,
+) -> Result<Option<PaletteCoverage>, AppError> {
+    let mut buffer = Vec::new();
+    std::io::stdin().lock().read_to_end(&mut buffer)?;
+
+    let coverage = config
+        .coverage
+        .then(|| measure_coverage(&buffer, config))
+        .transpose()?;
+    if config.no_save {
+        return Ok(coverage);
+    }
+
+    let format = match config.output_format {
+        Some(format) => format,
+        None => image::guess_format(&buffer)?,
+    };
+    let output = colorize_encoded(&buffer, format, config, &config.options, gpu, reporter).await?;
+
+    let mut stdout = std::io::stdout().lock();
+    stdout.write_all(&output)?;
+    stdout.flush()?;
+    Ok(coverage)
+}
+
+/// Decodes and colorizes an image, then encodes it as `format` with the ICC profile and EXIF data
+/// of the original
+async fn colorize_encoded(
+    encoded: &[u8],
+    format: ImageFormat,
+    config: &AppConfig,
+    options: &ColorizeOptions,
+    gpu: Option<&Gpu>,
+    reporter: &Reporter,
+) -> Result<Vec<u8>, AppError> {
+    let metadata = Metadata::read(encoded);
+
+    // Files without an extension are decoded based on their contents
+    let img = image::io::Reader::new(Cursor::new(encoded))
+        .with_guessed_format()?
+        .decode()?;
+    let img = metadata.apply_orientation(img);
+
+    let final_output = colorize(&img, config, options, gpu, reporter).await?;
+    let final_output = match config.compare {
+        Some(layout) => compare::compose(&img, &final_output, layout),
+        None => final_output,
+    };
+
+    let start = Instant::now();
+    let encoded = metadata.embed(encode(&final_output, format, config)?);
+    reporter.add_encode_time(start.elapsed());
+    encoded
+}
+
+/// Encodes `img` as `format`, using the quality and WebP compression from `config`
+fn encode(
+    img: &DynamicImage,
+    format: ImageFormat,
+    config: &AppConfig,
+) -> Result<Vec<u8>, AppError> {
+    let mut output = Cursor::new(Vec::new());
+
+    // 16-bit images can only be saved as PNG or TIFF, so other formats get 8 bits per channel
+    let color = img.color();
+    let converted;
+    let img = if color.bytes_per_pixel() > color.channel_count()
+        && !matches!(format, ImageFormat::Png | ImageFormat::Tiff)
+    {
+        converted = if color.has_alpha() {
+            DynamicImage::ImageRgba8(img.to_rgba8())
+        } else {
+            DynamicImage::ImageRgb8(img.to_rgb8())
+        };
+        &converted
+    } else {
+        img
+    };
+
+    if format == ImageFormat::Png && config.indexed {
+        if let Some(indexed) = indexed::encode(img, &config.colors)
+            .map_err(|e| format!("Failed to encode an indexed PNG: {}", e))?
+        {
+            return Ok(indexed);
+        }
+    }
+
+    match (format, config.quality, config.webp_compression) {
+        (ImageFormat::Jpeg, Some(quality), _) => {
+            img.write_to(&mut output, ImageOutputFormat::Jpeg(quality))?
+        }
+        (ImageFormat::WebP, quality, WebPCompression::Lossy) => {
+            let quality = quality.unwrap_or(DEFAULT_QUALITY);
+            // image only encodes lossy WebP through libwebp, which it has deprecated
+            #[allow(deprecated)]
+            let encoder = WebPEncoder::new_with_quality(&mut output, WebPQuality::lossy(quality));
+            img.write_with_encoder(encoder)?
+        }
+        _ => img.write_to(&mut output, format)?,
+    }
+
+    Ok(output.into_inner())
+}
+
+/// Colorizes `img` with the palette from `config` and `options`, reporting progress and timings to
+/// `reporter`
+async fn colorize(
+    img: &DynamicImage,
+    config: &AppConfig,
+    options: &ColorizeOptions,
+    gpu: Option<&Gpu>,
+    reporter: &Reporter,
+) -> Result<DynamicImage, AppError> {
+    let progress = |position, length| reporter.set_progress(position, length);
+
+    if let (Some(mask), MaskResize::Error) = (&options.mask, config.mask_resize) {
+        if mask.dimensions() != img.dimensions() {
+            return Err(format!(
+                "The mask is {}x{}, but the image is {}x{}. Use --mask-resize stretch to resize the mask to fit",
+                mask.width(),
+                mask.height(),
+                img.width(),
+                img.height()
+            )
+            .into());
+        }
+    }
+
+    // Previews are colorized at a lower resolution, which is much faster when tuning settings
+    let preview = match config.max_dimension {
+        Some(max) if img.width().max(img.height()) > max => {
+            Some(img.resize(max, max, FilterType::Triangle))
+        }
+        _ => None,
+    };
+    let target = preview.as_ref().unwrap_or(img);
+
+    let mut options = options.clone();
+    if config.auto_blend {
+        if let Some(blend_factor) =
+            reporter.auto_blend(|| auto_blend_factor(target, &config.colors))
+        {
+            options.blend_factor = blend_factor;
+        }
+    }
+    if let Some(mask) = &options.mask {
+        if mask.dimensions() != target.dimensions() {
+            let resized = image::imageops::resize(
+                mask.as_ref(),
+                target.width(),
+                target.height(),
+                FilterType::Triangle,
+            );
+            options.mask = Some(Arc::new(resized));
+        }
+    }
+
+    // Timing each stage slows the GPU down, so it is only done for --timings
+    let colorized = if config.timings {
+        let (colorized, timings) =
+            colorize_image_with_timings(target, &config.colors, &options, gpu, Some(&progress))
+                .await?;
+        reporter.add_timings(timings);
+        colorized
+    } else {
+        colorize_image(target, &config.colors, &options, gpu, Some(&progress)).await?
+    };
+
+    let colorized = if preview.is_some() && config.restore_size {
+        colorized.resize_exact(img.width(), img.height(), FilterType::Lanczos3)
+    } else {
+        colorized
+    };
+    Ok(match &config.compose_with {
+        Some(overlay) => overlay::compose(&colorized, overlay, config.compose_position),
+        None => colorized,
+    })
+}
@@ -0,0 +1,224 @@
+use crate::config::AppError;
+
+use std::io::Cursor;
+
+use exif::{In, Tag};
+use image::DynamicImage;
+use img_parts::{Bytes, DynImage, ImageEXIF, ImageICC};
+
+/// The ICC profile and EXIF data of an image, so they can be copied into the output of formats that
+/// support them (JPEG, PNG, and WebP)
+#[derive(Default)]
+pub struct Metadata {
+    icc_profile: Option<Bytes>,
+    exif: Option<Bytes>,
+    orientation: u32,
+}
+
+impl Metadata {
+    /// Reads the metadata of an encoded image. Metadata is copied on a best effort basis, so
+    /// anything that can't be read is left out rather than failing the image
+    pub fn read(encoded: &[u8]) -> Metadata {
+        let image = match DynImage::from_bytes(Bytes::copy_from_slice(encoded)) {
+            Ok(Some(image)) => image,
+            _ => return Metadata::default(),
+        };
+
+        let exif = image.exif();
+        let orientation = exif
+            .as_ref()
+            .and_then(|exif| exif::Reader::new().read_raw(exif.to_vec()).ok())
+            .and_then(|exif| {
+                exif.get_field(Tag::Orientation, In::PRIMARY)
+                    .and_then(|field| field.value.get_uint(0))
+            })
+            .unwrap_or(1);
+
+        Metadata {
+            icc_profile: image.icc_profile(),
+            exif,
+            orientation,
+        }
+    }
+
+    /// Rotates and flips `img` so that it is upright, since not every viewer respects the EXIF
+    /// orientation
+    pub fn apply_orientation(&self, img: DynamicImage) -> DynamicImage {
+        match self.orientation {
+            2 => img.fliph(),
+            3 => img.rotate180(),
+            4 => img.flipv(),
+            5 => img.rotate90().fliph(),
+            6 => img.rotate90(),
+            7 => img.rotate270().fliph(),
+            8 => img.rotate270(),
+            _ => img,
+        }
+    }
+
+    /// Embeds the metadata into an encoded image, leaving formats that can't hold it untouched
+    pub fn embed(&self, encoded: Vec<u8>) -> Result<Vec<u8>, AppError> {
+        let encoded = Bytes::from(encoded);
+        let mut image = match DynImage::from_bytes(encoded.clone())
+            .map_err(|e| format!("Failed to write metadata: {}", e))?
+        {
+            Some(image) => image,
+            None => return Ok(encoded.to_vec()),
+        };
+
+        image.set_icc_profile(self.icc_profile.clone());
+        // The orientation has already been applied to the pixels, so it must not be applied twice
+        image.set_exif(self.exif.as_deref().map(reset_orientation));
+
+        let mut output = Cursor::new(Vec::new());
+        image.encoder().write_to(&mut output)?;
+        Ok(output.into_inner())
+    }
+}
+
+/// Sets the orientation tag of raw EXIF data to 1 (upright), leaving everything else untouched
+fn reset_orientation(exif: &[u8]) -> Bytes {
+    let mut exif = exif.to_vec();
+    let little_endian = exif.starts_with(b"II");
+
+    let read_u16 = |bytes: &[u8], offset: usize| {
+        let bytes = [*bytes.get(offset)?, *bytes.get(offset + 1)?];
+        Some(if little_endian {
+            u16::from_le_bytes(bytes)
+        } else {
+            u16::from_be_bytes(bytes)
+        })
+    };
+    let read_u32 = |bytes: &[u8], offset: usize| {
+        let bytes: [u8; 4] = bytes.get(offset..offset + 4)?.try_into().ok()?;
+        Some(if little_endian {
+            u32::from_le_bytes(bytes)
+        } else {
+            u32::from_be_bytes(bytes)
+        })
+    };
+
+    // Each IFD entry is a 2 byte tag, 2 byte type, 4 byte count, and 4 byte value
+    let orientation_offset = read_u32(&exif, 4).and_then(|ifd| {
+        let ifd = ifd as usize;
+        let entries = read_u16(&exif, ifd)? as usize;
+
+        (0..entries)
+            .map(|i| ifd + 2 + i * 12)
+            .find(|&entry| read_u16(&exif, entry) == Some(Tag::Orientation.number()))
+            .map(|entry| entry + 8)
+    });
+
+    if let Some(offset) = orientation_offset.filter(|&offset| offset + 2 <= exif.len()) {
+        let upright = if little_endian {
+            1u16.to_le_bytes()
+        } else {
+            1u16.to_be_bytes()
+        };
+        exif[offset..offset + 2].copy_from_slice(&upright);
+    }
+
+    Bytes::from(exif)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const IMAGE_WIDTH: u16 = 0x0100;
+
+    /// Raw EXIF data with one IFD of SHORT entries, as `(tag, value)`
+    fn exif(little_endian: bool, entries: &[(u16, u16)]) -> Vec<u8> {
+        let u16_bytes = |value: u16| {
+            if little_endian {
+                value.to_le_bytes()
+            } else {
+                value.to_be_bytes()
+            }
+        };
+        let u32_bytes = |value: u32| {
+            if little_endian {
+                value.to_le_bytes()
+            } else {
+                value.to_be_bytes()
+            }
+        };
+
+        let mut exif = if little_endian {
+            b"II".to_vec()
+        } else {
+            b"MM".to_vec()
+        };
+        exif.extend_from_slice(&u16_bytes(42));
+        exif.extend_from_slice(&u32_bytes(8));
+        exif.extend_from_slice(&u16_bytes(entries.len() as u16));
+        for &(tag, value) in entries {
+            exif.extend_from_slice(&u16_bytes(tag));
+            // A count of one SHORT, stored at the start of the value field
+            exif.extend_from_slice(&u16_bytes(3));
+            exif.extend_from_slice(&u32_bytes(1));
+            exif.extend_from_slice(&u16_bytes(value));
+            exif.extend_from_slice(&[0, 0]);
+        }
+        // No next IFD
+        exif.extend_from_slice(&u32_bytes(0));
+        exif
+    }
+
+    fn field(exif: &[u8], tag: Tag) -> Option<u32> {
+        exif::Reader::new()
+            .read_raw(exif.to_vec())
+            .unwrap()
+            .get_field(tag, In::PRIMARY)
+            .and_then(|field| field.value.get_uint(0))
+    }
+
+    #[test]
+    fn orientation_is_reset_in_either_byte_order() {
+        for little_endian in [true, false] {
+            let original = exif(
+                little_endian,
+                &[(IMAGE_WIDTH, 640), (Tag::Orientation.number(), 6)],
+            );
+            assert_eq!(field(&original, Tag::Orientation), Some(6));
+
+            let reset = reset_orientation(&original);
+            assert_eq!(field(&reset, Tag::Orientation), Some(1));
+            assert_eq!(field(&reset, Tag::ImageWidth), Some(640));
+            assert_eq!(reset.len(), original.len());
+        }
+    }
+
+    #[test]
+    fn exif_without_an_orientation_is_left_unchanged() {
+        for little_endian in [true, false] {
+            let original = exif(little_endian, &[(IMAGE_WIDTH, 640)]);
+            assert_eq!(reset_orientation(&original).to_vec(), original);
+        }
+    }
+
+    #[test]
+    fn truncated_exif_is_left_unchanged() {
+        for little_endian in [true, false] {
+            let original = exif(
+                little_endian,
+                &[(IMAGE_WIDTH, 640), (Tag::Orientation.number(), 6)],
+            );
+            // Cutting anywhere before the end of the orientation value leaves nothing to reset
+            let value_end = original.len() - 4 - 2;
+            for len in 0..value_end {
+                let truncated = &original[..len];
+                assert_eq!(reset_orientation(truncated).to_vec(), truncated, "{}", len);
+            }
+        }
+
+        // An IFD offset past the end, and an entry count that runs past the end without finding
+        // an orientation
+        let mut original = exif(true, &[(Tag::Orientation.number(), 6)]);
+        original[4..8].copy_from_slice(&u32::MAX.to_le_bytes());
+        assert_eq!(reset_orientation(&original).to_vec(), original);
+        let mut original = exif(false, &[(IMAGE_WIDTH, 640)]);
+        original[8..10].copy_from_slice(&u16::MAX.to_be_bytes());
+        assert_eq!(reset_orientation(&original).to_vec(), original);
+    }
+}
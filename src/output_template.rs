@@ -0,0 +1,190 @@
+use crate::config::AppError;
+
+use std::time::{SystemTime, UNIX_EPOCH};
+
+const PLACEHOLDERS: [(&str, Placeholder); 6] = [
+    ("stem", Placeholder::Stem),
+    ("ext", Placeholder::Ext),
+    ("scheme", Placeholder::Scheme),
+    ("dir", Placeholder::Dir),
+    ("date", Placeholder::Date),
+    ("index", Placeholder::Index),
+];
+
+/// A file name template for `--output`, such as `{dir}/{stem}-{scheme}.{ext}`. Placeholders are
+/// checked when the template is parsed, so a typo fails at startup rather than naming files after it
+#[derive(Debug)]
+pub struct OutputTemplate {
+    segments: Vec<Segment>,
+    scheme: String,
+    date: String,
+}
+
+#[derive(Debug)]
+enum Segment {
+    Literal(String),
+    Placeholder(Placeholder),
+}
+
+#[derive(Debug, Clone, Copy)]
+enum Placeholder {
+    Stem,
+    Ext,
+    Scheme,
+    Dir,
+    Date,
+    Index,
+}
+
+/// The values of the placeholders that differ between inputs
+pub struct TemplateValues<'a> {
+    pub stem: &'a str,
+    pub ext: &'a str,
+    pub dir: &'a str,
+    /// Position of the input among every input, starting at 1
+    pub index: usize,
+}
+
+impl OutputTemplate {
+    /// Returns whether `output` should be treated as a template rather than a directory
+    pub fn is_template(output: &str) -> bool {
+        output.contains('{') || output.contains('}')
+    }
+
+    pub fn parse(template: &str, scheme: &str) -> Result<OutputTemplate, AppError> {
+        let mut segments = Vec::new();
+        let mut rest = template;
+
+        while let Some(start) = rest.find(['{', '}']) {
+            if rest[start..].starts_with('}') {
+                return Err(AppError::Other(format!(
+                    "Unmatched '}}' in output template '{}'",
+                    template
+                )));
+            }
+
+            let end = rest[start..]
+                .find('}')
+                .map(|end| start + end)
+                .ok_or_else(|| format!("Unclosed '{{' in output template '{}'", template))?;
+            let name = &rest[start + 1..end];
+            let (_, placeholder) = PLACEHOLDERS
+                .into_iter()
+                .find(|(placeholder, _)| *placeholder == name)
+                .ok_or_else(|| {
+                    format!(
+                        "Unknown placeholder '{{{}}}' in output template '{}'. Expected one of {}",
+                        name,
+                        template,
+                        PLACEHOLDERS.map(|(p, _)| format!("{{{}}}", p)).join(", ")
+                    )
+                })?;
+
+            if start > 0 {
+                segments.push(Segment::Literal(rest[..start].to_string()));
+            }
+            segments.push(Segment::Placeholder(placeholder));
+            rest = &rest[end + 1..];
+        }
+        if !rest.is_empty() {
+            segments.push(Segment::Literal(rest.to_string()));
+        }
+
+        Ok(OutputTemplate {
+            segments,
+            scheme: scheme.to_string(),
+            // Every output of a run shares a date, even if it runs past midnight
+            date: today(),
+        })
+    }
+
+    /// Returns whether the template includes `{scheme}`, which keeps the outputs of different
+    /// colorschemes apart
+    pub fn names_scheme(&self) -> bool {
+        self.segments
+            .iter()
+            .any(|segment| matches!(segment, Segment::Placeholder(Placeholder::Scheme)))
+    }
+
+    pub fn render(&self, values: &TemplateValues) -> String {
+        self.segments
+            .iter()
+            .map(|segment| match segment {
+                Segment::Literal(literal) => literal.clone(),
+                Segment::Placeholder(Placeholder::Stem) => values.stem.to_string(),
+                Segment::Placeholder(Placeholder::Ext) => values.ext.to_string(),
+                Segment::Placeholder(Placeholder::Scheme) => self.scheme.clone(),
+                Segment::Placeholder(Placeholder::Dir) => values.dir.to_string(),
+                Segment::Placeholder(Placeholder::Date) => self.date.clone(),
+                Segment::Placeholder(Placeholder::Index) => values.index.to_string(),
+            })
+            .collect()
+    }
+}
+
+/// Today's date in UTC as `YYYY-MM-DD`
+fn today() -> String {
+    let seconds = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|duration| duration.as_secs())
+        .unwrap_or(0);
+    let (year, month, day) = civil_from_days((seconds / 86_400) as i64);
+    format!("{:04}-{:02}-{:02}", year, month, day)
+}
+
+/// Converts days since 1970-01-01 to a year, month, and day in the proleptic Gregorian calendar.
+/// See http://howardhinnant.github.io/date_algorithms.html#civil_from_days
+fn civil_from_days(days: i64) -> (i64, u32, u32) {
+    let z = days + 719_468;
+    let era = z.div_euclid(146_097);
+    let day_of_era = z.rem_euclid(146_097);
+    let year_of_era =
+        (day_of_era - day_of_era / 1460 + day_of_era / 36524 - day_of_era / 146_096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let shifted_month = (5 * day_of_year + 2) / 153;
+    let day = (day_of_year - (153 * shifted_month + 2) / 5 + 1) as u32;
+    let month = if shifted_month < 10 {
+        shifted_month + 3
+    } else {
+        shifted_month - 9
+    } as u32;
+    let year = year_of_era + era * 400 + (month <= 2) as i64;
+
+    (year, month, day)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn render_replaces_every_placeholder() {
+        let template =
+            OutputTemplate::parse("{dir}/{stem}-{scheme}-{index}.{ext}", "nord").unwrap();
+        let values = TemplateValues {
+            stem: "photo",
+            ext: "png",
+            dir: "pictures",
+            index: 3,
+        };
+
+        assert_eq!(template.render(&values), "pictures/photo-nord-3.png");
+    }
+
+    #[test]
+    fn parse_rejects_unknown_and_unclosed_placeholders() {
+        let error = OutputTemplate::parse("{stem}-{name}.{ext}", "nord").unwrap_err();
+        assert!(error.to_string().contains("'{name}'"));
+
+        assert!(OutputTemplate::parse("{stem.png", "nord").is_err());
+        assert!(OutputTemplate::parse("stem}.png", "nord").is_err());
+    }
+
+    #[test]
+    fn civil_from_days_handles_leap_years() {
+        assert_eq!(civil_from_days(0), (1970, 1, 1));
+        assert_eq!(civil_from_days(11_016), (2000, 2, 29));
+        assert_eq!(civil_from_days(19_782), (2024, 2, 29));
+        assert_eq!(civil_from_days(-1), (1969, 12, 31));
+    }
+}
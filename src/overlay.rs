@@ -0,0 +1,138 @@
+use image::imageops::{self, FilterType};
+use image::{DynamicImage, RgbaImage};
+use image_colorizer::{ComposePosition, Corner};
+
+/// Alpha composites `overlay` onto `img` for `--compose-with`. Overlays that don't fit are scaled
+/// down to fit, and offsets are clamped so that the overlay stays inside the image
+pub fn compose(img: &DynamicImage, overlay: &RgbaImage, position: ComposePosition) -> DynamicImage {
+    let (width, height) = (img.width(), img.height());
+
+    let resized;
+    let overlay = if overlay.width() > width || overlay.height() > height {
+        let scale = f64::min(
+            f64::from(width) / f64::from(overlay.width()),
+            f64::from(height) / f64::from(overlay.height()),
+        );
+        let scaled = |size: u32| ((f64::from(size) * scale).round() as u32).max(1);
+        resized = imageops::resize(
+            overlay,
+            scaled(overlay.width()).min(width),
+            scaled(overlay.height()).min(height),
+            FilterType::Lanczos3,
+        );
+        &resized
+    } else {
+        overlay
+    };
+
+    let (free_x, free_y) = (width - overlay.width(), height - overlay.height());
+    let (x, y) = (position.x.min(free_x), position.y.min(free_y));
+    let (x, y) = match position.corner {
+        Corner::TopLeft => (x, y),
+        Corner::TopRight => (free_x - x, y),
+        Corner::BottomLeft => (x, free_y - y),
+        Corner::BottomRight => (free_x - x, free_y - y),
+        Corner::Center => ((free_x / 2 + x).min(free_x), (free_y / 2 + y).min(free_y)),
+    };
+    let (x, y) = (i64::from(x), i64::from(y));
+
+    // Composited at the bit depth of the image, which is then converted back to its own type so
+    // that alpha isn't added to images without it and 16-bit images stay 16-bit
+    match img {
+        DynamicImage::ImageRgb8(_) | DynamicImage::ImageRgba8(_) => {
+            let mut canvas = img.to_rgba8();
+            imageops::overlay(&mut canvas, overlay, x, y);
+            let composed = DynamicImage::ImageRgba8(canvas);
+            if img.color().has_alpha() {
+                composed
+            } else {
+                DynamicImage::ImageRgb8(composed.to_rgb8())
+            }
+        }
+        _ => {
+            let mut canvas = img.to_rgba16();
+            let overlay = DynamicImage::ImageRgba8(overlay.clone()).to_rgba16();
+            imageops::overlay(&mut canvas, &overlay, x, y);
+            let composed = DynamicImage::ImageRgba16(canvas);
+            if img.color().has_alpha() {
+                composed
+            } else {
+                DynamicImage::ImageRgb16(composed.to_rgb16())
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use image::{Rgb, RgbImage, Rgba};
+
+    fn image() -> DynamicImage {
+        RgbImage::from_pixel(40, 20, Rgb([10, 20, 30])).into()
+    }
+
+    fn logo() -> RgbaImage {
+        RgbaImage::from_pixel(4, 4, Rgba([200, 100, 50, 255]))
+    }
+
+    #[test]
+    fn overlays_are_placed_in_from_their_corner() {
+        let position = "bottom-right+2+1".parse().unwrap();
+        let composed = compose(&image(), &logo(), position);
+
+        let DynamicImage::ImageRgb8(composed) = composed else {
+            panic!("Images without alpha should stay without it");
+        };
+        assert_eq!(composed.get_pixel(37, 18), &Rgb([200, 100, 50]));
+        assert_eq!(composed.get_pixel(34, 15), &Rgb([200, 100, 50]));
+        assert_eq!(composed.get_pixel(38, 18), &Rgb([10, 20, 30]));
+        assert_eq!(composed.get_pixel(37, 19), &Rgb([10, 20, 30]));
+        assert_eq!(composed.get_pixel(0, 0), &Rgb([10, 20, 30]));
+    }
+
+    #[test]
+    fn translucent_overlays_are_blended() {
+        let logo = RgbaImage::from_pixel(4, 4, Rgba([210, 120, 130, 128]));
+        let composed = compose(&image(), &logo, ComposePosition::default()).to_rgb8();
+
+        let [r, g, b] = composed.get_pixel(39, 19).0;
+        assert!((108..=112).contains(&r), "{}", r);
+        assert!((68..=72).contains(&g), "{}", g);
+        assert!((78..=82).contains(&b), "{}", b);
+    }
+
+    #[test]
+    fn overlays_larger_than_the_image_are_scaled_to_fit() {
+        let logo = RgbaImage::from_pixel(100, 100, Rgba([200, 100, 50, 255]));
+        let composed = compose(&image(), &logo, "top-left+50+50".parse().unwrap()).to_rgb8();
+
+        // Scaled to 20x20, and the offset is clamped to keep it in the image
+        assert_eq!(composed.get_pixel(20, 0), &Rgb([200, 100, 50]));
+        assert_eq!(composed.get_pixel(39, 19), &Rgb([200, 100, 50]));
+        assert_eq!(composed.get_pixel(19, 19), &Rgb([10, 20, 30]));
+    }
+
+    #[test]
+    fn sixteen_bit_images_stay_sixteen_bit() {
+        let img = DynamicImage::ImageRgb16(image().to_rgb16());
+        let composed = compose(&img, &logo(), "center".parse().unwrap());
+
+        assert!(matches!(composed, DynamicImage::ImageRgb16(_)));
+        assert_eq!(composed.to_rgb8().get_pixel(18, 8), &Rgb([200, 100, 50]));
+    }
+
+    #[test]
+    fn positions_need_a_corner_and_both_offsets() {
+        let position: ComposePosition = "top-right+3+4".parse().unwrap();
+        assert_eq!(
+            (position.corner, position.x, position.y),
+            (Corner::TopRight, 3, 4)
+        );
+
+        assert!("middle".parse::<ComposePosition>().is_err());
+        assert!("top-left+3".parse::<ComposePosition>().is_err());
+        assert!("top-left+3+-4".parse::<ComposePosition>().is_err());
+    }
+}
@@ -0,0 +1,142 @@
+use image::{Rgb, RgbImage};
+use image_colorizer::utils::rgb_to_hex;
+use palette::{Clamp, FromColor, Lab, Srgb};
+
+/// Swatches per row before wrapping onto the next one, so that interpolated palettes stay readable
+const SWATCHES_PER_ROW: usize = 16;
+const SWATCH_SIZE: u32 = 96;
+/// Each pixel of the font is drawn as a square this many pixels wide
+const FONT_SCALE: u32 = 2;
+const GLYPH_WIDTH: u32 = 3;
+const GLYPH_HEIGHT: u32 = 5;
+
+/// Renders `colors` as a grid of swatches in palette order, each labeled with its hex code and its
+/// Lab lightness, for `--palette-preview`
+pub fn render(colors: &[Lab]) -> RgbImage {
+    let columns = colors.len().clamp(1, SWATCHES_PER_ROW) as u32;
+    let rows = colors.len().div_ceil(SWATCHES_PER_ROW).max(1) as u32;
+    let mut preview = RgbImage::new(columns * SWATCH_SIZE, rows * SWATCH_SIZE);
+
+    for (i, &color) in colors.iter().enumerate() {
+        let x = (i % SWATCHES_PER_ROW) as u32 * SWATCH_SIZE;
+        let y = (i / SWATCHES_PER_ROW) as u32 * SWATCH_SIZE;
+        let rgb: Srgb<u8> = Srgb::from_color(color).clamp().into_format();
+        let fill = Rgb([rgb.red, rgb.green, rgb.blue]);
+
+        for dy in 0..SWATCH_SIZE {
+            for dx in 0..SWATCH_SIZE {
+                preview.put_pixel(x + dx, y + dy, fill);
+            }
+        }
+
+        // Labels are dark on light colors and light on dark ones
+        let ink = if color.l > 50.0 {
+            Rgb([0, 0, 0])
+        } else {
+            Rgb([255, 255, 255])
+        };
+        let line_height = (GLYPH_HEIGHT + 2) * FONT_SCALE;
+        let hex = rgb_to_hex(Srgb::from_color(color));
+        let lightness = format!("L {:.1}", color.l);
+        draw_text(
+            &mut preview,
+            &hex,
+            x + 6,
+            y + SWATCH_SIZE - 2 * line_height,
+            ink,
+        );
+        draw_text(
+            &mut preview,
+            &lightness,
+            x + 6,
+            y + SWATCH_SIZE - line_height,
+            ink,
+        );
+    }
+
+    preview
+}
+
+/// A 3x5 bitmap of each character the labels use, one row per entry with the leftmost pixel in
+/// the highest bit. Other characters are drawn as spaces
+fn glyph(character: char) -> [u8; 5] {
+    match character.to_ascii_lowercase() {
+        '0' => [0b111, 0b101, 0b101, 0b101, 0b111],
+        '1' => [0b010, 0b110, 0b010, 0b010, 0b111],
+        '2' => [0b111, 0b001, 0b111, 0b100, 0b111],
+        '3' => [0b111, 0b001, 0b111, 0b001, 0b111],
+        '4' => [0b101, 0b101, 0b111, 0b001, 0b001],
+        '5' => [0b111, 0b100, 0b111, 0b001, 0b111],
+        '6' => [0b111, 0b100, 0b111, 0b101, 0b111],
+        '7' => [0b111, 0b001, 0b001, 0b001, 0b001],
+        '8' => [0b111, 0b101, 0b111, 0b101, 0b111],
+        '9' => [0b111, 0b101, 0b111, 0b001, 0b111],
+        'a' => [0b010, 0b101, 0b111, 0b101, 0b101],
+        'b' => [0b110, 0b101, 0b110, 0b101, 0b110],
+        'c' => [0b011, 0b100, 0b100, 0b100, 0b011],
+        'd' => [0b110, 0b101, 0b101, 0b101, 0b110],
+        'e' => [0b111, 0b100, 0b110, 0b100, 0b111],
+        'f' => [0b111, 0b100, 0b110, 0b100, 0b100],
+        'l' => [0b100, 0b100, 0b100, 0b100, 0b111],
+        '#' => [0b101, 0b111, 0b101, 0b111, 0b101],
+        '.' => [0b000, 0b000, 0b000, 0b000, 0b010],
+        '-' => [0b000, 0b000, 0b111, 0b000, 0b000],
+        _ => [0; 5],
+    }
+}
+
+fn draw_text(img: &mut RgbImage, text: &str, x: u32, y: u32, ink: Rgb<u8>) {
+    for (i, character) in text.chars().enumerate() {
+        let left = x + i as u32 * (GLYPH_WIDTH + 1) * FONT_SCALE;
+
+        for (row, bits) in glyph(character).iter().enumerate() {
+            for column in 0..GLYPH_WIDTH {
+                if bits & (1 << (GLYPH_WIDTH - 1 - column)) == 0 {
+                    continue;
+                }
+
+                for dy in 0..FONT_SCALE {
+                    for dx in 0..FONT_SCALE {
+                        let px = left + column * FONT_SCALE + dx;
+                        let py = y + row as u32 * FONT_SCALE + dy;
+                        if px < img.width() && py < img.height() {
+                            img.put_pixel(px, py, ink);
+                        }
+                    }
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn swatches_wrap_onto_new_rows() {
+        let colors = vec![Lab::new(50.0, 0.0, 0.0); SWATCHES_PER_ROW + 1];
+
+        let preview = render(&colors);
+        assert_eq!(
+            preview.dimensions(),
+            (SWATCHES_PER_ROW as u32 * SWATCH_SIZE, 2 * SWATCH_SIZE)
+        );
+    }
+
+    #[test]
+    fn swatches_are_filled_with_their_color_and_labeled() {
+        let red = Lab::from_color(Srgb::new(1.0, 0.0, 0.0));
+        let black = Lab::new(0.0, 0.0, 0.0);
+
+        let preview = render(&[red, black]);
+        assert_eq!(preview.dimensions(), (2 * SWATCH_SIZE, SWATCH_SIZE));
+        assert_eq!(preview.get_pixel(4, 4), &Rgb([255, 0, 0]));
+        assert_eq!(preview.get_pixel(SWATCH_SIZE + 4, 4), &Rgb([0, 0, 0]));
+
+        // The label of the black swatch is drawn in white
+        assert!((SWATCH_SIZE..2 * SWATCH_SIZE)
+            .flat_map(|x| (0..SWATCH_SIZE).map(move |y| (x, y)))
+            .any(|(x, y)| preview.get_pixel(x, y) == &Rgb([255, 255, 255])));
+    }
+}
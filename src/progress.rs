@@ -0,0 +1,38 @@
+use std::sync::atomic::{AtomicU64, Ordering};
+
+/// Called with the number of completed steps and the total number of steps
+pub type ProgressCallback<'a> = dyn Fn(u64, u64) + Sync + 'a;
+
+pub(crate) struct Progress<'a> {
+    callback: Option<&'a ProgressCallback<'a>>,
+    position: AtomicU64,
+    length: u64,
+}
+
+impl<'a> Progress<'a> {
+    pub(crate) fn new(callback: Option<&'a ProgressCallback<'a>>, length: u64) -> Self {
+        let progress = Progress {
+            callback,
+            position: AtomicU64::new(0),
+            length,
+        };
+        progress.report(0);
+        progress
+    }
+
+    pub(crate) fn inc(&self, delta: u64) {
+        let position = self.position.fetch_add(delta, Ordering::Relaxed) + delta;
+        self.report(position);
+    }
+
+    pub(crate) fn finish(&self) {
+        self.position.store(self.length, Ordering::Relaxed);
+        self.report(self.length);
+    }
+
+    fn report(&self, position: u64) {
+        if let Some(callback) = self.callback {
+            callback(position.min(self.length), self.length);
+        }
+    }
+}
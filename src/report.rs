@@ -0,0 +1,418 @@
+use std::fs;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use crate::config::AppError;
+use crate::constants::VERSION;
+
+use image_colorizer::{AppConfig, PaletteCoverage, ProgressMode, Timings};
+use palette::Lab;
+
+use indicatif::{MultiProgress, ProgressBar, ProgressDrawTarget, ProgressStyle};
+
+/// Creates the `MultiProgress` that every image's progress bar is added to. Bars are still
+/// created in the other modes, but never drawn
+pub fn multi_progress(mode: ProgressMode) -> MultiProgress {
+    match mode {
+        ProgressMode::Bar => MultiProgress::new(),
+        ProgressMode::None | ProgressMode::Json => {
+            MultiProgress::with_draw_target(ProgressDrawTarget::hidden())
+        }
+    }
+}
+
+/// Reports the progress of a single image, as a progress bar or as JSON lines on stderr
+pub struct Reporter {
+    mode: ProgressMode,
+    bar: ProgressBar,
+    json: Option<JsonProgress>,
+    /// The colorizing and encoding time of the image, shared with the reporters of its frames so
+    /// that animations add up every frame
+    timings: Arc<Mutex<(Timings, Duration)>>,
+    /// How the blend factor of the image was decided, shared with the reporters of its frames so
+    /// that every frame of an animation is blended the same
+    blend_factor: Arc<Mutex<BlendFactor>>,
+}
+
+/// How the blend factor of an image was decided
+#[derive(Debug, Clone, Copy)]
+enum BlendFactor {
+    /// Taken from the options, unless `--auto-blend` chooses one
+    Configured,
+    /// Chosen by `--auto-blend`
+    Auto(f32),
+    /// Set by the sidecar file of the image, which takes precedence over `--auto-blend`
+    Sidecar(f32),
+}
+
+struct JsonProgress {
+    path: String,
+    stage: Mutex<&'static str>,
+    /// The last whole percent that was emitted, so that a line isn't written for every pixel row
+    last_percent: AtomicU64,
+}
+
+impl Reporter {
+    pub fn new(mode: ProgressMode, multi_progress: &MultiProgress, path: &str) -> Reporter {
+        let bar = multi_progress.add(ProgressBar::new(100));
+        bar.set_style(ProgressStyle::default_bar()
+            .template("{spinner:.green} [{elapsed_precise}] [{bar:40.cyan/blue}] {percent_precise}% ({eta}) {msg}")
+            .unwrap()
+            .progress_chars("#>-"));
+
+        let json = (mode == ProgressMode::Json).then(|| JsonProgress {
+            path: path.to_string(),
+            stage: Mutex::new("waiting"),
+            last_percent: AtomicU64::new(u64::MAX),
+        });
+
+        Reporter {
+            mode,
+            bar,
+            json,
+            timings: Arc::default(),
+            blend_factor: Arc::new(Mutex::new(BlendFactor::Configured)),
+        }
+    }
+
+    /// Creates an indented bar below this one for a single frame of an animation. Frames are only
+    /// reported as JSON through the `inc` of the whole animation
+    pub fn frame(&self, multi_progress: &MultiProgress, message: String) -> Reporter {
+        let bar = multi_progress.insert_after(&self.bar, ProgressBar::new(100));
+        bar.set_style(
+            ProgressStyle::default_bar()
+                .template("  {spinner:.green} [{bar:40.cyan/blue}] {percent_precise}% {msg}")
+                .unwrap()
+                .progress_chars("#>-"),
+        );
+        bar.set_message(message);
+
+        Reporter {
+            mode: self.mode,
+            bar,
+            json: None,
+            timings: Arc::clone(&self.timings),
+            blend_factor: Arc::clone(&self.blend_factor),
+        }
+    }
+
+    /// Moves on to a new stage, such as `processing` or `finished`, with a message for the bar
+    pub fn stage(&self, stage: &'static str, message: String) {
+        self.bar.set_message(message);
+
+        if let Some(json) = &self.json {
+            *json.stage.lock().unwrap() = stage;
+            self.emit(json, true);
+        }
+    }
+
+    pub fn set_progress(&self, position: u64, length: u64) {
+        self.bar.set_length(length);
+        self.bar.set_position(position);
+
+        if let Some(json) = &self.json {
+            self.emit(json, false);
+        }
+    }
+
+    pub fn set_length(&self, length: u64) {
+        self.set_progress(self.bar.position(), length);
+    }
+
+    pub fn inc(&self, delta: u64) {
+        self.set_progress(self.bar.position() + delta, self.bar.length().unwrap_or(0));
+    }
+
+    pub fn finish(&self, stage: &'static str, message: String) {
+        self.stage(stage, message);
+        self.bar.finish();
+    }
+
+    pub fn finish_and_clear(&self) {
+        self.bar.finish_and_clear();
+    }
+
+    pub fn add_timings(&self, timings: Timings) {
+        self.timings.lock().unwrap().0 += timings;
+    }
+
+    pub fn add_encode_time(&self, encode: Duration) {
+        self.timings.lock().unwrap().1 += encode;
+    }
+
+    /// The blend factor chosen for the image, which is chosen with `choose` for its first frame.
+    /// `None` if its sidecar file sets one
+    pub fn auto_blend(&self, choose: impl FnOnce() -> f32) -> Option<f32> {
+        let mut blend_factor = self.blend_factor.lock().unwrap();
+        match *blend_factor {
+            BlendFactor::Configured => {
+                let chosen = choose();
+                *blend_factor = BlendFactor::Auto(chosen);
+                Some(chosen)
+            }
+            BlendFactor::Auto(chosen) => Some(chosen),
+            BlendFactor::Sidecar(_) => None,
+        }
+    }
+
+    /// Records that the sidecar file of the image sets its blend factor, so that `--auto-blend`
+    /// doesn't choose another
+    pub fn set_sidecar_blend_factor(&self, blend_factor: f32) {
+        *self.blend_factor.lock().unwrap() = BlendFactor::Sidecar(blend_factor);
+    }
+
+    /// The blend factor the image was colorized with, or `None` if it was the configured one
+    pub fn blend_factor(&self) -> Option<f32> {
+        match *self.blend_factor.lock().unwrap() {
+            BlendFactor::Configured => None,
+            BlendFactor::Auto(blend_factor) | BlendFactor::Sidecar(blend_factor) => {
+                Some(blend_factor)
+            }
+        }
+    }
+
+    /// Prints the blend factor chosen by `--auto-blend`, if one was chosen
+    pub fn print_auto_blend(&self, path: &str) {
+        let BlendFactor::Auto(blend_factor) = *self.blend_factor.lock().unwrap() else {
+            return;
+        };
+
+        match self.mode {
+            ProgressMode::Json => eprintln!(
+                "{}",
+                serde_json::json!({
+                    "path": path,
+                    "stage": "auto_blend",
+                    "blend_factor": decimal(blend_factor),
+                })
+            ),
+            ProgressMode::Bar | ProgressMode::None => self
+                .bar
+                .suspend(|| eprintln!("Blend factor for {}: {:.2}", path, blend_factor)),
+        }
+    }
+
+    /// Prints the time taken by each stage, clearing the progress bars first so they aren't drawn over
+    pub fn print_timings(&self, path: &str) {
+        let (timings, encode) = *self.timings.lock().unwrap();
+        let stages = [
+            ("palette pass", timings.palette_pass),
+            ("readback", timings.readback),
+            ("integral image", timings.integral_image),
+            ("spatial averaging pass", timings.spatial_averaging_pass),
+            ("encode", encode),
+        ];
+
+        match self.mode {
+            ProgressMode::Json => {
+                let mut line = serde_json::json!({ "path": path, "stage": "timings" });
+                for (stage, duration) in stages {
+                    line[format!("{}_ms", stage.replace(' ', "_"))] = milliseconds(duration).into();
+                }
+                eprintln!("{}", line);
+            }
+            ProgressMode::Bar | ProgressMode::None => {
+                let stages = stages
+                    .iter()
+                    .map(|(stage, duration)| format!("{} {:.1?}", stage, duration))
+                    .collect::<Vec<_>>()
+                    .join(", ");
+                self.bar
+                    .suspend(|| eprintln!("Timings for {}: {}", path, stages));
+            }
+        }
+    }
+
+    fn emit(&self, json: &JsonProgress, force: bool) {
+        let length = self.bar.length().unwrap_or(0).max(1);
+        let percent = (self.bar.position().min(length) as f64 / length as f64) * 100.0;
+
+        let whole_percent = percent as u64;
+        if json.last_percent.swap(whole_percent, Ordering::Relaxed) == whole_percent && !force {
+            return;
+        }
+
+        eprintln!(
+            "{}",
+            serde_json::json!({
+                "path": json.path,
+                "stage": *json.stage.lock().unwrap(),
+                "percent": (percent * 100.0).round() / 100.0,
+            })
+        );
+    }
+}
+
+/// Prints how long it took to set up the GPU, which is shared by every image
+pub fn print_device_setup_time(mode: ProgressMode, duration: Duration) {
+    match mode {
+        ProgressMode::Json => eprintln!(
+            "{}",
+            serde_json::json!({ "stage": "device_setup", "device_setup_ms": milliseconds(duration) })
+        ),
+        ProgressMode::Bar | ProgressMode::None => {
+            eprintln!("Device setup took {:.1?}", duration)
+        }
+    }
+}
+
+/// Prints the coverage of every image as a table, or as one JSON line per image. Writes to stderr
+/// instead of stdout when an image is being written there
+pub fn print_coverage(mode: ProgressMode, rows: &[(&str, PaletteCoverage)], to_stderr: bool) {
+    let print = |line: String| {
+        if to_stderr {
+            eprintln!("{}", line)
+        } else {
+            println!("{}", line)
+        }
+    };
+
+    if mode == ProgressMode::Json {
+        for (path, coverage) in rows {
+            print(
+                serde_json::json!({
+                    "path": path,
+                    "colors_used": coverage.colors_used,
+                    "palette_size": coverage.palette_size,
+                    "mean_distance": coverage.mean_distance,
+                    "max_distance": coverage.max_distance,
+                })
+                .to_string(),
+            );
+        }
+        return;
+    }
+
+    let width = rows
+        .iter()
+        .map(|(path, _)| path.chars().count())
+        .chain(["Image".len()])
+        .max()
+        .unwrap_or(0);
+
+    print(format!(
+        "{:<width$}  {:>13}  {:>9}  {:>9}",
+        "Image",
+        "Colors used",
+        "Mean \u{394}E",
+        "Max \u{394}E",
+        width = width
+    ));
+    for (path, coverage) in rows {
+        print(format!(
+            "{:<width$}  {:>13}  {:>9.2}  {:>9.2}",
+            path,
+            format!("{} of {}", coverage.colors_used, coverage.palette_size),
+            coverage.mean_distance,
+            coverage.max_distance,
+            width = width
+        ));
+    }
+}
+
+/// What happened to one image, for `--manifest`
+pub struct ImageResult<'a> {
+    pub config: &'a AppConfig,
+    pub input: &'a str,
+    pub output: &'a str,
+    pub error: Option<String>,
+    /// Whether the original was copied to the output after colorizing failed
+    pub copied: bool,
+    /// The blend factor the image was colorized with, which `--auto-blend` and sidecar files can
+    /// change from the configured one. `None` if nothing was colorized
+    pub blend_factor: Option<f32>,
+    pub duration: Duration,
+}
+
+/// Writes the settings of the run, the palette of each colorscheme and the result of every image
+/// to `path` as JSON. Settings that every colorscheme shares are taken from the first one
+pub fn write_manifest(
+    path: &str,
+    configs: &[Arc<AppConfig>],
+    images: &[ImageResult],
+) -> Result<(), AppError> {
+    let options = &configs[0].options;
+    let manifest = serde_json::json!({
+        "version": VERSION,
+        "settings": {
+            "blend_factor": decimal(options.blend_factor),
+            "blend_curve": options.blend_curve.to_string(),
+            "color_space": options.color_space.to_string(),
+            "dither_mode": options.dither_mode.to_string(),
+            "dither_amount": decimal(options.dither_amount),
+            "spatial_averaging_radius": options.spatial_averaging_radius,
+            "preserve_luminance": options.preserve_luminance,
+            "soft_match": options.soft_match,
+            "distance_metric": options.distance_metric.to_string(),
+            "linear_blend": options.linear_blend,
+            "invert_luminance": options.invert_luminance,
+            "contrast": decimal(options.contrast),
+            "saturation": decimal(options.saturation),
+            "sharpen": decimal(options.sharpen),
+            "preserve_neutrals": decimal(options.preserve_neutrals),
+            "posterize": options.posterize,
+            "seed": options.seed,
+            "mask": options.mask.is_some(),
+            "blend_map": options.blend_map.is_some(),
+            "auto_blend": configs[0].auto_blend,
+            "compose_with": configs[0].compose_with.is_some(),
+        },
+        "schemes": configs
+            .iter()
+            .map(|config| serde_json::json!({
+                "name": config.palette_name,
+                "palette_size": config.colors.len(),
+                "palette_hash": palette_hash(&config.colors, &config.options.palette_weights),
+            }))
+            .collect::<Vec<_>>(),
+        "images": images
+            .iter()
+            .map(|image| serde_json::json!({
+                "input": image.input,
+                "output": image.output,
+                "scheme": image.config.palette_name,
+                "status": match (&image.error, image.copied) {
+                    (None, false) => "succeeded",
+                    (None, true) => "copied",
+                    (Some(_), _) => "failed",
+                },
+                "error": image.error,
+                "blend_factor": image.blend_factor.map(decimal),
+                "duration_ms": milliseconds(image.duration),
+            }))
+            .collect::<Vec<_>>(),
+    });
+
+    let json = serde_json::to_string_pretty(&manifest)
+        .map_err(|e| format!("Failed to serialize the manifest: {}", e))?;
+    fs::write(path, json + "\n")
+        .map_err(|e| format!("Failed to write the manifest to '{}': {}", path, e))?;
+    Ok(())
+}
+
+/// A 64-bit FNV-1a hash of the final palette and its weights, which stays the same between runs
+/// and versions so that manifests can be compared
+fn palette_hash(colors: &[Lab], weights: &[f32]) -> String {
+    let values = colors
+        .iter()
+        .flat_map(|color| [color.l, color.a, color.b])
+        .chain(weights.iter().copied());
+
+    let mut hash: u64 = 0xcbf29ce484222325;
+    for byte in values.flat_map(f32::to_le_bytes) {
+        hash ^= u64::from(byte);
+        hash = hash.wrapping_mul(0x100000001b3);
+    }
+    format!("{:016x}", hash)
+}
+
+/// Widens `value` to the f64 with the same shortest decimal form, so that 0.9 isn't written as
+/// 0.8999999761581421
+fn decimal(value: f32) -> f64 {
+    value.to_string().parse().unwrap_or_default()
+}
+
+fn milliseconds(duration: Duration) -> f64 {
+    (duration.as_secs_f64() * 100_000.0).round() / 100.0
+}
@@ -0,0 +1,106 @@
+struct ColorizedPixel {
+  r: f32, g: f32, b: f32,
+}
+
+struct ScanParams {
+  width: u32, height: u32, radius: u32, is_horizontal: u32, has_opacity: u32,
+}
+
+@group(0) @binding(0) var<storage, read> input : array<ColorizedPixel>;
+@group(0) @binding(1) var<storage, read_write> output : array<ColorizedPixel>;
+@group(0) @binding(2) var<uniform> params : ScanParams;
+@group(0) @binding(3) var<storage, read> opacity : array<f32>;
+
+fn rgb_to_lab(rgb: vec3<f32>) -> vec3<f32> {
+    let xyz = rgb_to_xyz(rgb);
+    return xyz_to_lab(xyz);
+}
+
+fn rgb_to_xyz(rgb: vec3<f32>) -> vec3<f32> {
+    let r = select(rgb.r / 12.92, pow((rgb.r + 0.055) / 1.055, 2.4), rgb.r > 0.04045);
+    let g = select(rgb.g / 12.92, pow((rgb.g + 0.055) / 1.055, 2.4), rgb.g > 0.04045);
+    let b = select(rgb.b / 12.92, pow((rgb.b + 0.055) / 1.055, 2.4), rgb.b > 0.04045);
+
+    return vec3<f32>(r * 0.4124564 + g * 0.3575761 + b * 0.1804375,
+        r * 0.2126729 + g * 0.7151522 + b * 0.0721750,
+        r * 0.0193339 + g * 0.1191920 + b * 0.9503041);
+}
+
+fn xyz_to_lab(xyz: vec3<f32>) -> vec3<f32> {
+    let epsilon = 0.008856;
+    let kappa = 903.3;
+
+    let xr = xyz.x / 0.950489;
+    let yr = xyz.y;
+    let zr = xyz.z / 1.088840;
+
+    let fx = select((kappa * xr + 16.0) / 116.0, pow(xr, 1.0 / 3.0), xr > epsilon);
+    let fy = select((kappa * yr + 16.0) / 116.0, pow(yr, 1.0 / 3.0), yr > epsilon);
+    let fz = select((kappa * zr + 16.0) / 116.0, pow(zr, 1.0 / 3.0), zr > epsilon);
+
+    return vec3<f32>(116.0 * fy - 16.0, 500.0 * (fx - fy), 200.0 * (fy - fz));
+}
+
+// Converts the first pass to Lab once, rather than once for every window a pixel falls in. Only a
+// and b are averaged, so lightness is replaced by the weight of the pixel, which is 0 for fully
+// transparent pixels so that they don't bleed into the averages of their neighbours
+@compute @workgroup_size(16, 16, 1)fn to_lab(@builtin(global_invocation_id) global_id: vec3<u32>) {
+    let x = global_id.x;
+    let y = global_id.y;
+    let index = x + y * params.width;
+
+    if x >= params.width || y >= params.height { return; }
+
+    var weight = 1.0;
+    if params.has_opacity != 0u {
+        weight = opacity[index];
+    }
+
+    let lab = rgb_to_lab(vec3<f32>(input[index].r, input[index].g, input[index].b));
+    output[index] = ColorizedPixel(weight, lab.y * weight, lab.z * weight);
+}
+
+fn index_of(line: u32, position: u32) -> u32 {
+    if params.is_horizontal != 0u {
+        return line * params.width + position;
+    }
+    return position * params.width + line;
+}
+
+fn value_at(line: u32, position: u32) -> vec3<f32> {
+    let index = index_of(line, position);
+    return vec3<f32>(input[index].r, input[index].g, input[index].b);
+}
+
+// Averages each pixel with the pixels within `radius` along one axis, with one invocation sliding a
+// window along each row or column. Averaging rows and then columns gives the same result as
+// averaging the whole square around the pixel, since every row of the square is clamped to the
+// same columns at the edges of the image
+@compute @workgroup_size(64, 1, 1)fn box_average(@builtin(global_invocation_id) global_id: vec3<u32>) {
+    let is_horizontal = params.is_horizontal != 0u;
+    let line = global_id.x;
+    let lines = select(params.width, params.height, is_horizontal);
+    let length = i32(select(params.height, params.width, is_horizontal));
+    let radius = i32(params.radius);
+
+    if line >= lines { return; }
+
+    var sum = vec3<f32>(0.0);
+    for (var i = 0; i <= min(radius, length - 1); i++) {
+        sum += value_at(line, u32(i));
+    }
+
+    for (var position = 0; position < length; position++) {
+        let first = max(position - radius, 0);
+        let last = min(position + radius, length - 1);
+        let average = sum / f32(last - first + 1);
+        output[index_of(line, u32(position))] = ColorizedPixel(average.x, average.y, average.z);
+
+        if position + radius + 1 < length {
+            sum += value_at(line, u32(position + radius + 1));
+        }
+        if position - radius >= 0 {
+            sum -= value_at(line, u32(position - radius));
+        }
+    }
+}
@@ -12,17 +12,74 @@ struct Params {
                    blend_factor: f32,
                                   dither_amount: f32,
                                                   spatial_radius: u32,
+                                                                   dither_mode: u32,
+                                                                                 color_space: u32,
+                                                                                 preserve_luminance: u32,
+                                                                                 linear_blend: u32,
+                                                                                 offset_x: u32,
+                                                                                 offset_y: u32,
+                                                                                 noise_offset_x: u32,
+                                                                                 noise_offset_y: u32,
+                                                                                 has_mask: u32,
+                                                                                 sharpen: f32,
+                                                                                 blend_curve: u32,
+                                                                                 blend_gamma: f32,
+                                                                                 soft_match: u32,
+                                                                                 distance_metric: u32,
 }
 
 @group(0) @binding(0) var<storage, read> input : array<Pixel>;
-@group(0) @binding(1) var<storage, write> output : array<ColorizedPixel>;
-@group(0) @binding(2) var<storage, read> color_palette : array<vec3<f32>>;
+@group(0) @binding(1) var<storage, read_write> output : array<ColorizedPixel>;
+// Each color in the matching space with its weight, followed by the color in Lab
+@group(0) @binding(2) var<storage, read> color_palette : array<vec4<f32>>;
 @group(0) @binding(3) var<uniform> params : Params;
+@group(0) @binding(4) var<storage, read> mask : array<f32>;
+// 64x64 dither thresholds, row by row and four to an element. This is a uniform rather than a
+// storage buffer since downlevel adapters only allow four storage buffers per shader
+@group(0) @binding(5) var<uniform> blue_noise : array<vec4<f32>, 1024>;
+
+// Scales the blend factor by the mask, so that black areas of the mask keep their original color,
+// and by the blend curve at the Lab lightness of the input
+fn blend_factor(index: u32, input_lightness: f32) -> f32 {
+    var factor = f32(params.blend_factor);
+    if params.has_mask != 0u {
+        factor *= mask[index];
+    }
+    return factor * blend_curve(input_lightness / 100.0);
+}
+
+// Mirrors `BlendCurve::weight` in types.rs
+fn blend_curve(lightness: f32) -> f32 {
+    let l = clamp(lightness, 0.0, 1.0);
+    switch params.blend_curve {
+        case 1u: { return l * l; }
+        case 2u: { return 1.0 - (1.0 - l) * (1.0 - l); }
+        case 3u: { return pow(max(l, 1e-6), params.blend_gamma); }
+        default: { return 1.0; }
+    }
+}
 
 fn clamp_color(color: vec3<f32>) -> vec3<f32> {
     return clamp(color, vec3<f32>(0.0), vec3<f32>(1.0));
 }
 
+fn srgb_to_linear(rgb: vec3<f32>) -> vec3<f32> {
+    return select(rgb / 12.92, pow((rgb + 0.055) / 1.055, vec3<f32>(2.4)), rgb > vec3<f32>(0.04045));
+}
+
+fn linear_to_srgb(rgb: vec3<f32>) -> vec3<f32> {
+    return select(12.92 * rgb, 1.055 * pow(rgb, vec3<f32>(1.0 / 2.4)) - 0.055,
+        rgb > vec3<f32>(0.0031308));
+}
+
+// Mixes two sRGB colors, in linear light when `linear_blend` is set
+fn mix_rgb(a: vec3<f32>, b: vec3<f32>, t: f32) -> vec3<f32> {
+    if params.linear_blend != 0u {
+        return linear_to_srgb(mix(srgb_to_linear(a), srgb_to_linear(b), t));
+    }
+    return mix(a, b, t);
+}
+
 fn rgb_to_lab(rgb: vec3<f32>) -> vec3<f32> {
     let xyz = rgb_to_xyz(rgb);
     return xyz_to_lab(xyz);
@@ -89,40 +146,272 @@ fn xyz_to_rgb(xyz: vec3<f32>) -> vec3<f32> {
         clamp(b1, 0.0, 1.0));
 }
 
-fn find_closest_color(lab: vec3<f32>) -> vec3<f32> {
-    var closest_color = vec3<f32>(color_palette[0]);
-    var min_distance = distance(lab, closest_color);
+fn rgb_to_oklab(rgb: vec3<f32>) -> vec3<f32> {
+    let r = select(rgb.r / 12.92, pow((rgb.r + 0.055) / 1.055, 2.4), rgb.r > 0.04045);
+    let g = select(rgb.g / 12.92, pow((rgb.g + 0.055) / 1.055, 2.4), rgb.g > 0.04045);
+    let b = select(rgb.b / 12.92, pow((rgb.b + 0.055) / 1.055, 2.4), rgb.b > 0.04045);
+
+    let l = pow(max(0.4122214708 * r + 0.5363325363 * g + 0.0514459929 * b, 0.0), 1.0 / 3.0);
+    let m = pow(max(0.2119034982 * r + 0.6806995451 * g + 0.1073969566 * b, 0.0), 1.0 / 3.0);
+    let s = pow(max(0.0883024619 * r + 0.2817188376 * g + 0.6299787005 * b, 0.0), 1.0 / 3.0);
+
+    return vec3<f32>(0.2104542553 * l + 0.7936177850 * m - 0.0040720468 * s,
+        1.9779984951 * l - 2.4285922050 * m + 0.4505937099 * s,
+        0.0259040371 * l + 0.7827717662 * m - 0.8086757660 * s);
+}
+
+fn oklab_to_rgb(lab: vec3<f32>) -> vec3<f32> {
+    let l_ = lab.x + 0.3963377774 * lab.y + 0.2158037573 * lab.z;
+    let m_ = lab.x - 0.1055613458 * lab.y - 0.0638541728 * lab.z;
+    let s_ = lab.x - 0.0894841775 * lab.y - 1.2914855480 * lab.z;
+
+    let l = l_ * l_ * l_;
+    let m = m_ * m_ * m_;
+    let s = s_ * s_ * s_;
+
+    let r = 4.0767416621 * l - 3.3077115913 * m + 0.2309699292 * s;
+    let g = -1.2684380046 * l + 2.6097574011 * m - 0.3413193965 * s;
+    let b = -0.0041960863 * l - 0.7034186147 * m + 1.7076147010 * s;
+
+    let r1 = select(12.92 * r, 1.055 * pow(r, 1.0 / 2.4) - 0.055, r > 0.0031308);
+    let g1 = select(12.92 * g, 1.055 * pow(g, 1.0 / 2.4) - 0.055, g > 0.0031308);
+    let b1 = select(12.92 * b, 1.055 * pow(b, 1.0 / 2.4) - 0.055, b > 0.0031308);
+
+    return vec3<f32>(clamp(r1, 0.0, 1.0), clamp(g1, 0.0, 1.0),
+        clamp(b1, 0.0, 1.0));
+}
+
+// Color spaces, matching `Params::color_space` in colorize.rs
+const COLOR_SPACE_OKLAB: u32 = 1u;
+
+fn rgb_to_matching_space(rgb: vec3<f32>) -> vec3<f32> {
+    if params.color_space == COLOR_SPACE_OKLAB {
+        return rgb_to_oklab(rgb);
+    }
+    return rgb_to_lab(rgb);
+}
+
+fn matching_space_to_rgb(color: vec3<f32>) -> vec3<f32> {
+    if params.color_space == COLOR_SPACE_OKLAB {
+        return oklab_to_rgb(color);
+    }
+    return lab_to_rgb(color);
+}
+
+// Distance metrics, matching `Params::distance_metric` in colorize.rs
+const METRIC_CIEDE2000: u32 = 0u;
+const METRIC_CIE76: u32 = 1u;
+
+// Mirrors `ciede2000` in utils.rs
+fn ciede2000(lab1: vec3<f32>, lab2: vec3<f32>) -> f32 {
+    let pi = 3.14159265;
+    let tau = 6.28318531;
+    let pow25_7 = 6103515625.0;
+
+    let mean_chroma = (length(lab1.yz) + length(lab2.yz)) / 2.0;
+    let chroma7 = pow(mean_chroma, 7.0);
+    let g = 0.5 * (1.0 - sqrt(chroma7 / (chroma7 + pow25_7)));
+    let ab1 = vec2<f32>(lab1.y * (1.0 + g), lab1.z);
+    let ab2 = vec2<f32>(lab2.y * (1.0 + g), lab2.z);
+    let c1 = length(ab1);
+    let c2 = length(ab2);
+    var h1 = 0.0;
+    if c1 != 0.0 {
+        h1 = atan2(ab1.y, ab1.x);
+        h1 = select(h1, h1 + tau, h1 < 0.0);
+    }
+    var h2 = 0.0;
+    if c2 != 0.0 {
+        h2 = atan2(ab2.y, ab2.x);
+        h2 = select(h2, h2 + tau, h2 < 0.0);
+    }
+
+    var delta_h = 0.0;
+    if c1 * c2 != 0.0 {
+        delta_h = h2 - h1;
+        if delta_h > pi {
+            delta_h -= tau;
+        } else if delta_h < -pi {
+            delta_h += tau;
+        }
+    }
+    let delta_l = lab2.x - lab1.x;
+    let delta_c = c2 - c1;
+    let delta_big_h = 2.0 * sqrt(c1 * c2) * sin(delta_h / 2.0);
+
+    let mean_l = (lab1.x + lab2.x) / 2.0;
+    let mean_c = (c1 + c2) / 2.0;
+    var mean_h = h1 + h2;
+    if c1 * c2 != 0.0 {
+        if abs(h1 - h2) <= pi {
+            mean_h = (h1 + h2) / 2.0;
+        } else if h1 + h2 < tau {
+            mean_h = (h1 + h2 + tau) / 2.0;
+        } else {
+            mean_h = (h1 + h2 - tau) / 2.0;
+        }
+    }
+
+    let t = 1.0 - 0.17 * cos(mean_h - radians(30.0)) + 0.24 * cos(2.0 * mean_h)
+        + 0.32 * cos(3.0 * mean_h + radians(6.0)) - 0.20 * cos(4.0 * mean_h - radians(63.0));
+    let hue_distance = (degrees(mean_h) - 275.0) / 25.0;
+    let rotation = radians(30.0) * exp(-hue_distance * hue_distance);
+    let mean_c7 = pow(mean_c, 7.0);
+    let r_t = -2.0 * sqrt(mean_c7 / (mean_c7 + pow25_7)) * sin(2.0 * rotation);
+
+    let lightness_distance = (mean_l - 50.0) * (mean_l - 50.0);
+    let s_l = 1.0 + 0.015 * lightness_distance / sqrt(20.0 + lightness_distance);
+    let s_c = 1.0 + 0.045 * mean_c;
+    let s_h = 1.0 + 0.015 * mean_c * t;
+
+    let l = delta_l / s_l;
+    let c = delta_c / s_c;
+    let h = delta_big_h / s_h;
+    return sqrt(max(l * l + c * c + h * h + r_t * c * h, 0.0));
+}
+
+fn palette_size() -> u32 {
+    return arrayLength(&color_palette) / 2u;
+}
+
+fn palette_color(i: u32) -> vec3<f32> {
+    return color_palette[2u * i].xyz;
+}
+
+// The distance from a pixel to the palette color at `i` with the distance metric, divided by the
+// weight of the color so that colors with a higher weight are preferred. `color` is the pixel in
+// the matching space, and `lab` is the pixel in Lab
+fn palette_distance(color: vec3<f32>, lab: vec3<f32>, i: u32) -> f32 {
+    let entry = color_palette[2u * i];
+    let palette_lab = color_palette[2u * i + 1u].xyz;
+    if params.distance_metric == METRIC_CIEDE2000 {
+        return ciede2000(lab, palette_lab) / entry.w;
+    }
+    if params.distance_metric == METRIC_CIE76 {
+        return distance(lab, palette_lab) / entry.w;
+    }
+    return distance(color, entry.xyz) / entry.w;
+}
 
-    for (var i = 1u; i < arrayLength(&color_palette); i = i + 1u) {
-        let current_color = vec3<f32>(color_palette[i]);
-        let current_distance = distance(lab, current_color);
+// Mirrors `Matcher::nearest` in cpu.rs, which is tested against the `map_coordinates` reference
+// in utils.rs for Euclidean distances
+fn find_closest_color(color: vec3<f32>, lab: vec3<f32>) -> vec3<f32> {
+    var closest_color = palette_color(0u);
+    var min_distance = palette_distance(color, lab, 0u);
+
+    for (var i = 1u; i < palette_size(); i = i + 1u) {
+        let current_distance = palette_distance(color, lab, i);
         if current_distance < min_distance {
             min_distance = current_distance;
-            closest_color = current_color;
+            closest_color = palette_color(i);
         }
     }
 
     return closest_color;
 }
 
-fn apply_dithering(color: vec3<f32>, targ: vec3<f32>, amount: f32, global_id: vec3<u32>) -> vec3<f32> {
-    let rand = fract(sin(dot(vec2<f32>(f32(global_id.x), f32(global_id.y)),
-        vec2<f32>(12.9898, 78.233))) * 43758.5453);
-    return color + (targ - color) * amount * rand;
+// Mirrors `soft_match_color` in utils.rs, mixing the two nearest colors weighted by the inverse of
+// their distances. Ties go to the earliest colors, like in `find_closest_color`
+fn soft_match_color(color: vec3<f32>, lab: vec3<f32>) -> vec3<f32> {
+    var first = palette_color(0u);
+    var first_distance = palette_distance(color, lab, 0u);
+    var second = first;
+    var second_distance = -1.0;
+
+    for (var i = 1u; i < palette_size(); i = i + 1u) {
+        let current_color = palette_color(i);
+        let current_distance = palette_distance(color, lab, i);
+        if current_distance < first_distance {
+            second = first;
+            second_distance = first_distance;
+            first = current_color;
+            first_distance = current_distance;
+        } else if second_distance < 0.0 || current_distance < second_distance {
+            second = current_color;
+            second_distance = current_distance;
+        }
+    }
+
+    // A single color has nothing to mix with
+    let total = first_distance + second_distance;
+    if second_distance < 0.0 || total <= 0.0 {
+        return first;
+    }
+    return mix(first, second, first_distance / total);
+}
+
+// Dither modes, matching `dither_mode_index` in colorize.rs
+const DITHER_NONE: u32 = 0u;
+const DITHER_NOISE: u32 = 1u;
+const DITHER_ORDERED: u32 = 2u;
+const DITHER_BLUE_NOISE: u32 = 3u;
+const BLUE_NOISE_SIZE: u32 = 64u;
+
+fn bayer_threshold(x: u32, y: u32, order: u32) -> f32 {
+    var value = 0u;
+    for (var i = 0u; i < order; i = i + 1u) {
+        let bx = (x >> i) & 1u;
+        let by = (y >> i) & 1u;
+        value = value | ((((bx ^ by) << 1u) | by) << (2u * (order - 1u - i)));
+    }
+
+    return (f32(value) + 0.5) / f32(1u << (2u * order));
+}
+
+fn dither_threshold(amount: f32, position: vec2<u32>) -> f32 {
+    if params.dither_mode == DITHER_NOISE {
+        // Offset by the seed, so that each seed produces a different pattern
+        let seeded = position + vec2<u32>(params.noise_offset_x, params.noise_offset_y);
+        return fract(sin(dot(vec2<f32>(f32(seeded.x), f32(seeded.y)),
+            vec2<f32>(12.9898, 78.233))) * 43758.5453);
+    }
+    if params.dither_mode == DITHER_ORDERED {
+        let order = u32(clamp(1.0 + floor(amount * 3.0 + 0.5), 1.0, 4.0));
+        return bayer_threshold(position.x, position.y, order);
+    }
+    if params.dither_mode == DITHER_BLUE_NOISE {
+        let texel = (position + vec2<u32>(params.noise_offset_x, params.noise_offset_y)) % BLUE_NOISE_SIZE;
+        let i = texel.y * BLUE_NOISE_SIZE + texel.x;
+        return blue_noise[i / 4u][i % 4u];
+    }
+    return 0.0;
+}
+
+fn apply_dithering(color: vec3<f32>, targ: vec3<f32>, amount: f32, position: vec2<u32>) -> vec3<f32> {
+    let threshold = dither_threshold(amount, position);
+    return color + (targ - color) * amount * threshold;
+}
+
+// Blends in RGB, or only blends the chroma when preserving luminance so that the lightness of the
+// input is kept exactly
+fn blend(input_color: vec3<f32>, input_lab: vec3<f32>, lab: vec3<f32>, factor: f32) -> vec3<f32> {
+    if params.preserve_luminance != 0u {
+        let chroma = mix(input_lab.yz, lab.yz, factor);
+        return clamp_color(matching_space_to_rgb(vec3<f32>(input_lab.x, chroma)));
+    }
+    return clamp_color(mix_rgb(input_color, matching_space_to_rgb(lab), factor));
 }
 
 @compute @workgroup_size(16, 16, 1)fn main(@builtin(global_invocation_id) global_id: vec3<u32>) {
+    if global_id.x >= params.width || global_id.y >= params.height { return; }
     let index = global_id.x + global_id.y * params.width;
-    if index >= arrayLength(&input) { return; }
+    // Position within the full image rather than the tile
+    let position = global_id.xy + vec2<u32>(params.offset_x, params.offset_y);
 
     let input_color = vec3<f32>(f32(input[index].r), f32(input[index].g), f32(input[index].b));
-    let lab_color = rgb_to_lab(input_color);
-    let closest_color = find_closest_color(lab_color);
+    let lab_color = rgb_to_matching_space(input_color);
+    let input_lab = rgb_to_lab(input_color);
+    var closest_color: vec3<f32>;
+    if params.soft_match != 0u {
+        closest_color = soft_match_color(lab_color, input_lab);
+    } else {
+        closest_color = find_closest_color(lab_color, input_lab);
+    }
     let final_lab = vec3<f32>(lab_color.x, closest_color.y, closest_color.z);
     let dithered_lab = apply_dithering(final_lab, lab_color,
-        f32(params.dither_amount), global_id);
-    let final_rgb = lab_to_rgb(dithered_lab);
-    let blended_rgb = clamp_color(mix(input_color, final_rgb, f32(params.blend_factor)));
+        f32(params.dither_amount), position);
+    let factor = blend_factor(index, input_lab.x);
+    let blended_rgb = blend(input_color, lab_color, dithered_lab, factor);
 
     output[index] = ColorizedPixel(f32(blended_rgb.r), f32(blended_rgb.g),
         f32(blended_rgb.b));
@@ -8,41 +8,67 @@ struct Params {
                    blend_factor: f32,
                                   dither_amount: f32,
                                                   spatial_radius: u32,
+                                                                   dither_mode: u32,
+                                                                                 color_space: u32,
+                                                                                 preserve_luminance: u32,
+                                                                                 linear_blend: u32,
+                                                                                 offset_x: u32,
+                                                                                 offset_y: u32,
+                                                                                 noise_offset_x: u32,
+                                                                                 noise_offset_y: u32,
+                                                                                 has_mask: u32,
+                                                                                 sharpen: f32,
+                                                                                 blend_curve: u32,
+                                                                                 blend_gamma: f32,
+                                                                                 soft_match: u32,
 }
 
 @group(0) @binding(0) var<storage, read> input : array<ColorizedPixel>;
-@group(0) @binding(1) var<storage, read> sat : array<ColorizedPixel>;
-@group(0) @binding(2) var<storage, write> output : array<ColorizedPixel>;
+@group(0) @binding(1) var<storage, read> averages : array<ColorizedPixel>;
+@group(0) @binding(2) var<storage, read_write> output : array<ColorizedPixel>;
 @group(0) @binding(3) var<uniform> params : Params;
+@group(0) @binding(4) var<storage, read> mask : array<f32>;
+
+// Scales the blend factor by the mask, so that black areas of the mask keep their original color,
+// and by the blend curve at the Lab lightness of the input
+fn blend_factor(index: u32, input_lightness: f32) -> f32 {
+    var factor = f32(params.blend_factor);
+    if params.has_mask != 0u {
+        factor *= mask[index];
+    }
+    return factor * blend_curve(input_lightness / 100.0);
+}
+
+// Mirrors `BlendCurve::weight` in types.rs
+fn blend_curve(lightness: f32) -> f32 {
+    let l = clamp(lightness, 0.0, 1.0);
+    switch params.blend_curve {
+        case 1u: { return l * l; }
+        case 2u: { return 1.0 - (1.0 - l) * (1.0 - l); }
+        case 3u: { return pow(max(l, 1e-6), params.blend_gamma); }
+        default: { return 1.0; }
+    }
+}
 
 fn clamp_color(color: vec3<f32>) -> vec3<f32> {
     return clamp(color, vec3<f32>(0.0), vec3<f32>(1.0));
 }
 
-fn fast_spatial_color_average(x: u32, y: u32) -> vec3<f32> {
-    let radius = i32(params.spatial_radius);
-    let x1 = max(i32(x) - radius, 0);
-    let y1 = max(i32(y) - radius, 0);
-    let x2 = min(i32(x) + radius, i32(params.width - 1u));
-    let y2 = min(i32(y) + radius, i32(params.height - 1u));
-
-    let area = f32((x2 - x1 + 1) * (y2 - y1 + 1));
-
-    let top_left = get_sat_value(u32(x1), u32(y1));
-    let top_right = get_sat_value(u32(x2 + 1), u32(y1));
-    let bottom_left = get_sat_value(u32(x1), u32(y2 + 1));
-    let bottom_right = get_sat_value(u32(x2 + 1), u32(y2 + 1));
-
-    let sum = vec3<f32>(bottom_right.r - top_right.r - bottom_left.r + top_left.r,
-        bottom_right.g - top_right.g - bottom_left.g + top_left.g,
-        bottom_right.b - top_right.b - bottom_left.b + top_left.b);
+fn srgb_to_linear(rgb: vec3<f32>) -> vec3<f32> {
+    return select(rgb / 12.92, pow((rgb + 0.055) / 1.055, vec3<f32>(2.4)), rgb > vec3<f32>(0.04045));
+}
 
-    return vec3<f32>(f32(sum.r / area), f32(sum.g / area), f32(sum.b / area));
+fn linear_to_srgb(rgb: vec3<f32>) -> vec3<f32> {
+    return select(12.92 * rgb, 1.055 * pow(rgb, vec3<f32>(1.0 / 2.4)) - 0.055,
+        rgb > vec3<f32>(0.0031308));
 }
 
-fn get_sat_value(x: u32, y: u32) -> vec3<f32> {
-    let index = y * (params.width + 1u) + x;
-    return vec3<f32>(sat[index].r, sat[index].g, sat[index].b);
+// Mixes two sRGB colors, in linear light when `linear_blend` is set
+fn mix_rgb(a: vec3<f32>, b: vec3<f32>, t: f32) -> vec3<f32> {
+    if params.linear_blend != 0u {
+        return linear_to_srgb(mix(srgb_to_linear(a), srgb_to_linear(b), t));
+    }
+    return mix(a, b, t);
 }
 
 fn rgb_to_lab(rgb: vec3<f32>) -> vec3<f32> {
@@ -111,6 +137,28 @@ fn xyz_to_rgb(xyz: vec3<f32>) -> vec3<f32> {
         clamp(b1, 0.0, 1.0));
 }
 
+// Moves the averaged color of a pixel back toward its color before averaging, past it when
+// `sharpen` is above 1. The result stays within the colors of its neighbours before averaging, so
+// that edges don't get halos. Mirrors `sharpen` in cpu.rs
+fn sharpen(averaged: vec3<f32>, unaveraged: vec3<f32>, x: u32, y: u32) -> vec3<f32> {
+    if params.sharpen == 0.0 {
+        return averaged;
+    }
+
+    var low = unaveraged;
+    var high = unaveraged;
+    for (var ny = max(y, 1u) - 1u; ny <= min(y + 1u, params.height - 1u); ny = ny + 1u) {
+        for (var nx = max(x, 1u) - 1u; nx <= min(x + 1u, params.width - 1u); nx = nx + 1u) {
+            let neighbour = input[nx + ny * params.width];
+            let color = vec3<f32>(neighbour.r, neighbour.g, neighbour.b);
+            low = min(low, color);
+            high = max(high, color);
+        }
+    }
+
+    return clamp(averaged + (unaveraged - averaged) * params.sharpen, low, high);
+}
+
 @compute @workgroup_size(16, 16, 1)fn main(@builtin(global_invocation_id) global_id: vec3<u32>) {
     let x = global_id.x;
     let y = global_id.y;
@@ -119,17 +167,29 @@ fn xyz_to_rgb(xyz: vec3<f32>) -> vec3<f32> {
     if x >= params.width || y >= params.height { return; }
 
     let input_color = vec3<f32>(f32(input[index].r), f32(input[index].g), f32(input[index].b));
-    let avg_lab = fast_spatial_color_average(x, y);
-
     let input_lab = rgb_to_lab(input_color);
 
-    let luminance_transferred_lab = vec3<f32>(input_lab.r, avg_lab.g, avg_lab.b);
-    let luminance_transferred_rgb = lab_to_rgb(luminance_transferred_lab);
-
-    let final_color = mix(input_color, luminance_transferred_rgb, f32(params.blend_factor));
-
-    let clamped_color = clamp_color(final_color);
-  // let clamped_color = clamp_color(get_sat_value(x, y));
+    // The averages are weighted by opacity, with the total weight in place of lightness. Pixels
+    // with only transparent neighbours keep their own color
+    let weight = averages[index].r;
+    var avg_lab = input_lab;
+    if weight > 0.0 {
+        avg_lab = vec3<f32>(input_lab.x, averages[index].g / weight, averages[index].b / weight);
+    }
+
+    let factor = blend_factor(index, input_lab.x);
+    var final_color: vec3<f32>;
+    if params.preserve_luminance != 0u {
+        let chroma = mix(input_lab.yz, avg_lab.yz, factor);
+        final_color = lab_to_rgb(vec3<f32>(input_lab.x, chroma));
+    } else {
+        let luminance_transferred_lab = vec3<f32>(input_lab.r, avg_lab.g, avg_lab.b);
+        let luminance_transferred_rgb = lab_to_rgb(luminance_transferred_lab);
+
+        final_color = mix_rgb(input_color, luminance_transferred_rgb, factor);
+    }
+
+    let clamped_color = clamp_color(sharpen(final_color, input_color, x, y));
 
     output[index] = ColorizedPixel(f32(clamped_color.r), f32(clamped_color.g),
         f32(clamped_color.b));
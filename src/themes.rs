@@ -0,0 +1,280 @@
+use crate::config::AppError;
+
+use std::fs;
+use std::io::Cursor;
+use std::path::Path;
+
+use image_colorizer::utils::rgb_to_hex;
+
+use palette::Srgb;
+
+const ANSI_NAMES: [&str; 8] = [
+    "black", "red", "green", "yellow", "blue", "magenta", "cyan", "white",
+];
+
+// Windows Terminal calls magenta "purple" and names each bright color separately
+const WINDOWS_TERMINAL_NAMES: [&str; 16] = [
+    "black",
+    "red",
+    "green",
+    "yellow",
+    "blue",
+    "purple",
+    "cyan",
+    "white",
+    "brightBlack",
+    "brightRed",
+    "brightGreen",
+    "brightYellow",
+    "brightBlue",
+    "brightPurple",
+    "brightCyan",
+    "brightWhite",
+];
+
+/// Loads the 16 ANSI colors plus the foreground and background of a terminal theme, detecting
+/// the format from the extension of `path`
+pub fn load_terminal_theme(path: &Path) -> Result<Vec<String>, AppError> {
+    let name = path.display().to_string();
+    let extension = path
+        .extension()
+        .and_then(|ext| ext.to_str())
+        .map(|ext| ext.to_lowercase());
+
+    match extension.as_deref() {
+        Some("json") => parse_windows_terminal_scheme(&fs::read_to_string(path)?, &name),
+        Some("itermcolors") => parse_iterm_colors(&fs::read(path)?, &name),
+        Some("toml") => parse_alacritty_theme(&fs::read_to_string(path)?, &name),
+        _ => Err(AppError::Other(format!(
+            "'{}' is not a supported terminal theme. Expected a Windows Terminal .json, iTerm2 .itermcolors, or Alacritty .toml file",
+            name
+        ))),
+    }
+}
+
+/// Parses a Windows Terminal color scheme, either on its own or as the only entry in `schemes`
+fn parse_windows_terminal_scheme(content: &str, name: &str) -> Result<Vec<String>, AppError> {
+    let json: serde_json::Value = serde_json::from_str(content)
+        .map_err(|e| format!("Failed to parse colorscheme '{}': {}", name, e))?;
+
+    let scheme = match json.get("schemes").and_then(|schemes| schemes.as_array()) {
+        Some(schemes) if schemes.len() == 1 => &schemes[0],
+        Some(_) => {
+            return Err(AppError::Other(format!(
+                "'{}' contains more than one color scheme",
+                name
+            )))
+        }
+        None => &json,
+    };
+
+    let keys = WINDOWS_TERMINAL_NAMES
+        .iter()
+        .chain(&["foreground", "background"]);
+
+    keys.map(|key| {
+        scheme
+            .get(key)
+            .and_then(|value| value.as_str())
+            .map(|hex| hex.to_string())
+            .ok_or_else(|| AppError::Other(format!("Colorscheme '{}' is missing {}", name, key)))
+    })
+    .collect()
+}
+
+/// Parses an iTerm2 `.itermcolors` plist, where each color is a dictionary of 0.0-1.0 components
+fn parse_iterm_colors(content: &[u8], name: &str) -> Result<Vec<String>, AppError> {
+    let plist = plist::Value::from_reader(Cursor::new(content))
+        .map_err(|e| format!("Failed to parse colorscheme '{}': {}", name, e))?;
+    let colors = plist.as_dictionary().ok_or_else(|| {
+        format!(
+            "Failed to parse colorscheme '{}': expected a dictionary",
+            name
+        )
+    })?;
+
+    let keys = (0..16).map(|i| format!("Ansi {} Color", i)).chain([
+        "Foreground Color".to_string(),
+        "Background Color".to_string(),
+    ]);
+
+    keys.map(|key| {
+        let color = colors
+            .get(&key)
+            .and_then(|value| value.as_dictionary())
+            .ok_or_else(|| format!("Colorscheme '{}' is missing {}", name, key))?;
+
+        let component = |component: &str| {
+            color
+                .get(&format!("{} Component", component))
+                .and_then(|value| value.as_real())
+                .map(|value| value as f32)
+                .ok_or_else(|| {
+                    format!(
+                        "'{}' in colorscheme '{}' is missing {}",
+                        key, name, component
+                    )
+                })
+        };
+
+        Ok(rgb_to_hex(Srgb::new(
+            component("Red")?,
+            component("Green")?,
+            component("Blue")?,
+        )))
+    })
+    .collect()
+}
+
+/// Parses the `colors.normal`, `colors.bright`, and `colors.primary` tables of an Alacritty theme
+fn parse_alacritty_theme(content: &str, name: &str) -> Result<Vec<String>, AppError> {
+    let theme: toml::Value = toml::from_str(content)?;
+
+    let keys = ["normal", "bright"]
+        .iter()
+        .flat_map(|table| ANSI_NAMES.iter().map(move |color| (*table, *color)))
+        .chain([("primary", "foreground"), ("primary", "background")]);
+
+    keys.map(|(table, color)| {
+        let hex = theme
+            .get("colors")
+            .and_then(|colors| colors.get(table))
+            .and_then(|table| table.get(color))
+            .and_then(|value| value.as_str())
+            .ok_or_else(|| {
+                format!(
+                    "Colorscheme '{}' is missing colors.{}.{}",
+                    name, table, color
+                )
+            })?;
+
+        // Alacritty also accepts colors written as 0xrrggbb
+        Ok(format!(
+            "#{}",
+            hex.trim_start_matches("0x").trim_start_matches('#')
+        ))
+    })
+    .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A distinct color for each of the 18 colors of a theme, in the order they are returned
+    fn expected() -> Vec<String> {
+        (0..18).map(|i| format!("#0000{:02x}", i * 8)).collect()
+    }
+
+    #[test]
+    fn windows_terminal_schemes_are_parsed_alone_or_as_the_only_scheme() {
+        let keys = WINDOWS_TERMINAL_NAMES
+            .iter()
+            .chain(&["foreground", "background"]);
+        let entries: Vec<String> = keys
+            .zip(expected())
+            .map(|(key, hex)| format!("\"{}\": \"{}\"", key, hex))
+            .collect();
+        let scheme = format!("{{\"name\": \"Test\", {}}}", entries.join(", "));
+
+        assert_eq!(
+            parse_windows_terminal_scheme(&scheme, "test").unwrap(),
+            expected()
+        );
+        let settings = format!("{{\"schemes\": [{}]}}", scheme);
+        assert_eq!(
+            parse_windows_terminal_scheme(&settings, "test").unwrap(),
+            expected()
+        );
+    }
+
+    #[test]
+    fn malformed_windows_terminal_schemes_are_rejected() {
+        let missing = parse_windows_terminal_scheme("{\"black\": \"#000000\"}", "test");
+        assert_eq!(
+            missing.unwrap_err().to_string(),
+            "Error: Colorscheme 'test' is missing red"
+        );
+        assert!(parse_windows_terminal_scheme("{\"black\": ", "test").is_err());
+        assert!(parse_windows_terminal_scheme("{\"schemes\": [{}, {}]}", "test").is_err());
+    }
+
+    fn iterm_color(key: &str, blue: f32) -> String {
+        format!(
+            "<key>{}</key><dict>\
+             <key>Red Component</key><real>0</real>\
+             <key>Green Component</key><real>0</real>\
+             <key>Blue Component</key><real>{}</real>\
+             </dict>",
+            key, blue
+        )
+    }
+
+    fn iterm_plist(colors: &[String]) -> Vec<u8> {
+        format!(
+            "<?xml version=\"1.0\" encoding=\"UTF-8\"?>\
+             <plist version=\"1.0\"><dict>{}</dict></plist>",
+            colors.concat()
+        )
+        .into_bytes()
+    }
+
+    #[test]
+    fn iterm_colors_are_parsed() {
+        let keys = (0..16).map(|i| format!("Ansi {} Color", i)).chain([
+            "Foreground Color".to_string(),
+            "Background Color".to_string(),
+        ]);
+        let colors: Vec<String> = keys
+            .enumerate()
+            .map(|(i, key)| iterm_color(&key, (i * 8) as f32 / 255.0))
+            .collect();
+
+        assert_eq!(
+            parse_iterm_colors(&iterm_plist(&colors), "test").unwrap(),
+            expected()
+        );
+    }
+
+    #[test]
+    fn malformed_iterm_colors_are_rejected() {
+        let missing = parse_iterm_colors(&iterm_plist(&[iterm_color("Ansi 0 Color", 0.0)]), "test");
+        assert_eq!(
+            missing.unwrap_err().to_string(),
+            "Error: Colorscheme 'test' is missing Ansi 1 Color"
+        );
+        assert!(parse_iterm_colors(b"<plist><dict>", "test").is_err());
+    }
+
+    #[test]
+    fn alacritty_themes_are_parsed_with_either_prefix() {
+        let table = |colors: &[String]| {
+            ANSI_NAMES
+                .iter()
+                .zip(colors)
+                .map(|(name, hex)| format!("{} = '{}'\n", name, hex))
+                .collect::<String>()
+        };
+        let expected = expected();
+        let theme = format!(
+            "[colors.primary]\nforeground = '{}'\nbackground = '{}'\n\
+             [colors.normal]\n{}[colors.bright]\n{}",
+            expected[16].replace('#', "0x"),
+            expected[17],
+            table(&expected[..8]),
+            table(&expected[8..16]),
+        );
+
+        assert_eq!(parse_alacritty_theme(&theme, "test").unwrap(), expected);
+    }
+
+    #[test]
+    fn malformed_alacritty_themes_are_rejected() {
+        let missing = parse_alacritty_theme("[colors.normal]\nblack = '#000000'\n", "test");
+        assert_eq!(
+            missing.unwrap_err().to_string(),
+            "Error: Colorscheme 'test' is missing colors.normal.red"
+        );
+        assert!(parse_alacritty_theme("[colors.normal", "test").is_err());
+    }
+}
@@ -1,10 +1,580 @@
+use std::fmt;
+use std::ops::AddAssign;
+use std::str::FromStr;
+use std::sync::Arc;
+use std::time::Duration;
+
+use image::{GrayImage, ImageFormat, RgbaImage};
 use palette::Lab;
 
 #[derive(Debug)]
 pub struct AppConfig {
+    /// The name outputs are given after the palette, such as the name of the colorscheme
+    pub palette_name: String,
     pub input_output_pairs: Vec<(String, String)>,
-    pub blend_factor: f32,
     pub colors: Vec<Lab>,
+    pub options: ColorizeOptions,
+    pub use_cpu: bool,
+    pub gpu_options: GpuOptions,
+    /// The maximum number of images to process at once
+    pub jobs: usize,
+    /// Overrides the format of every output, which otherwise matches its input
+    pub output_format: Option<ImageFormat>,
+    /// JPEG and lossy WebP quality, from 1 to 100
+    pub quality: Option<u8>,
+    /// Whether WebP outputs are lossless or lossy
+    pub webp_compression: WebPCompression,
+    /// Saves PNG outputs as palette-indexed PNGs when their colors fit in one
+    pub indexed: bool,
+    /// Saves the original and colorized images together in this layout, rather than only the
+    /// colorized image
+    pub compare: Option<CompareLayout>,
+    /// Downscales images so that their longest side is at most this many pixels before colorizing
+    pub max_dimension: Option<u32>,
+    /// Upscales downscaled images back to their original size after colorizing
+    pub restore_size: bool,
+    /// What to do when the mask is a different size than an image
+    pub mask_resize: MaskResize,
+    pub progress: ProgressMode,
+    /// Prints how long each stage took for every image
+    pub timings: bool,
+    /// Prints how well the palette covers each image once every image is done
+    pub coverage: bool,
+    /// Only measures coverage, without colorizing or saving anything
+    pub no_save: bool,
+    /// Copies the original image to its output path when colorizing it fails
+    pub copy_on_failure: bool,
+    /// Where to write a JSON record of the settings and the result of every image
+    pub manifest: Option<String>,
+    /// Chooses the blend factor of each image from its lightness histogram and the palette, in
+    /// place of the one in `options`
+    pub auto_blend: bool,
+    /// An image, such as a logo, that is alpha composited onto every colorized image
+    pub compose_with: Option<Arc<RgbaImage>>,
+    /// Where `compose_with` is placed on each image
+    pub compose_position: ComposePosition,
+}
+
+/// How long each stage of `colorize_image_with_timings` took. Stages that didn't run, such as
+/// readback on the CPU, take no time, and images split into tiles add up the time of every tile
+#[derive(Debug, Default, Clone, Copy)]
+pub struct Timings {
+    /// Matching pixels to the palette, including uploading them to the GPU
+    pub palette_pass: Duration,
+    /// Copying the results of both passes back from the GPU
+    pub readback: Duration,
+    /// Computing the summed-area table used for spatial averaging on the CPU. The GPU averages
+    /// as part of its spatial averaging pass instead
+    pub integral_image: Duration,
+    /// Spatial averaging and blending with the original image
+    pub spatial_averaging_pass: Duration,
+}
+
+impl AddAssign for Timings {
+    fn add_assign(&mut self, other: Timings) {
+        self.palette_pass += other.palette_pass;
+        self.readback += other.readback;
+        self.integral_image += other.integral_image;
+        self.spatial_averaging_pass += other.spatial_averaging_pass;
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct ColorizeOptions {
+    pub blend_factor: f32,
+    /// Scales the blend factor of each pixel by its lightness, so that shadows and highlights can
+    /// be blended by different amounts
+    pub blend_curve: BlendCurve,
+    pub color_space: ColorSpace,
     pub dither_amount: f32,
+    pub dither_mode: DitherMode,
     pub spatial_averaging_radius: u32,
+    /// Only blends the chroma of each pixel toward the palette, keeping its original lightness
+    pub preserve_luminance: bool,
+    /// Matches each pixel to a mix of its two nearest palette colors, weighted by the inverse of
+    /// their distances, rather than snapping it to the nearest one
+    pub soft_match: bool,
+    /// How the distance from each pixel to the palette colors is measured when matching it.
+    /// Euclidean distance in `color_space` is the fastest, since the CPU can search a k-d tree
+    /// for it, while CIEDE2000 compares every color
+    pub distance_metric: DistanceMetric,
+    /// Mixes the original and colorized pixels in linear light rather than sRGB, which keeps
+    /// midtones from darkening when they are blended
+    pub linear_blend: bool,
+    /// Flips the lightness of every pixel before matching it to the palette, so that light
+    /// backgrounds map to dark colors and the other way around
+    pub invert_luminance: bool,
+    /// Scales the lightness of every pixel away from the mean lightness of the output, after
+    /// colorizing. 1 leaves it unchanged
+    pub contrast: f32,
+    /// Scales the chroma of every pixel after colorizing. 1 leaves it unchanged
+    pub saturation: f32,
+    /// Moves each pixel back toward its color before spatial averaging, and past it above 1, to
+    /// recover edges that averaging softened. 0 leaves the averaged image unchanged
+    pub sharpen: f32,
+    /// Leaves pixels whose Lab chroma is below this unchanged, so that grays and near-grays keep
+    /// their original color. 0 colorizes every pixel
+    pub preserve_neutrals: f32,
+    /// Quantizes the lightness, a and b of every pixel to this many levels after colorizing, for a
+    /// posterized look. 0 leaves the output unquantized
+    pub posterize: u32,
+    /// Varies the pattern of noise and blue noise dithering. The same seed always produces the
+    /// same output
+    pub seed: Option<u64>,
+    /// Scales the blend factor of each pixel, from black keeping the original color to white
+    /// blending fully. Must be the same size as the image
+    pub mask: Option<Arc<GrayImage>>,
+    /// Sets the blend factor of each pixel in place of `blend_factor`, from black keeping the
+    /// original color to white blending fully. It is resized to fit the image, and the mask and
+    /// blend curve still scale it
+    pub blend_map: Option<Arc<GrayImage>>,
+    /// How strongly each palette color is preferred when matching, in the same order as the
+    /// palette. Distances to a color are divided by its weight, so colors weighted above 1 win
+    /// over nearer colors. Empty weighs every color 1
+    pub palette_weights: Vec<f32>,
+}
+
+impl Default for ColorizeOptions {
+    fn default() -> Self {
+        ColorizeOptions {
+            blend_factor: 0.9,
+            blend_curve: BlendCurve::Linear,
+            color_space: ColorSpace::Lab,
+            dither_amount: 0.1,
+            dither_mode: DitherMode::Noise,
+            spatial_averaging_radius: 10,
+            preserve_luminance: false,
+            soft_match: false,
+            distance_metric: DistanceMetric::Euclidean,
+            linear_blend: false,
+            invert_luminance: false,
+            contrast: 1.0,
+            saturation: 1.0,
+            sharpen: 0.0,
+            preserve_neutrals: 0.0,
+            posterize: 0,
+            seed: None,
+            mask: None,
+            blend_map: None,
+            palette_weights: Vec::new(),
+        }
+    }
+}
+
+/// Controls which adapter `init_gpu_with` creates a device on
+#[derive(Debug, Clone, Default)]
+pub struct GpuOptions {
+    /// Index into the adapters returned by `list_adapters`. By default the high performance
+    /// adapter is used
+    pub adapter: Option<usize>,
+    /// Restricts adapters to a single graphics API
+    pub backend: GpuBackend,
+}
+
+/// The graphics API that wgpu runs on
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum GpuBackend {
+    /// Lets wgpu use any backend available on this platform
+    #[default]
+    All,
+    Vulkan,
+    Metal,
+    Dx12,
+    Gl,
+}
+
+impl GpuBackend {
+    pub fn backends(self) -> wgpu::Backends {
+        match self {
+            GpuBackend::All => wgpu::Backends::all(),
+            GpuBackend::Vulkan => wgpu::Backends::VULKAN,
+            GpuBackend::Metal => wgpu::Backends::METAL,
+            GpuBackend::Dx12 => wgpu::Backends::DX12,
+            GpuBackend::Gl => wgpu::Backends::GL,
+        }
+    }
+}
+
+impl FromStr for GpuBackend {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "all" => Ok(GpuBackend::All),
+            "vulkan" => Ok(GpuBackend::Vulkan),
+            "metal" => Ok(GpuBackend::Metal),
+            "dx12" => Ok(GpuBackend::Dx12),
+            "gl" => Ok(GpuBackend::Gl),
+            _ => Err(format!(
+                "Invalid GPU backend: '{}'. Expected one of vulkan, metal, dx12, gl, all.",
+                s
+            )),
+        }
+    }
+}
+
+impl fmt::Display for GpuBackend {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            GpuBackend::All => "all",
+            GpuBackend::Vulkan => "vulkan",
+            GpuBackend::Metal => "metal",
+            GpuBackend::Dx12 => "dx12",
+            GpuBackend::Gl => "gl",
+        };
+        write!(f, "{}", name)
+    }
+}
+
+/// How the progress of each image is reported
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ProgressMode {
+    /// Draws a progress bar for each image
+    Bar,
+    /// Only prints errors
+    None,
+    /// Writes a JSON object with the path, stage, and percent of an image to stderr each time it
+    /// progresses, one per line
+    Json,
+}
+
+impl FromStr for ProgressMode {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "bar" => Ok(ProgressMode::Bar),
+            "none" => Ok(ProgressMode::None),
+            "json" => Ok(ProgressMode::Json),
+            _ => Err(format!(
+                "Invalid progress mode: '{}'. Expected one of bar, none, json.",
+                s
+            )),
+        }
+    }
+}
+
+/// How a mask that is a different size than the image it's applied to is handled
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MaskResize {
+    /// Stretches the mask to the size of the image
+    Stretch,
+    /// Fails to colorize the image
+    Error,
+}
+
+impl FromStr for MaskResize {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "stretch" => Ok(MaskResize::Stretch),
+            "error" => Ok(MaskResize::Error),
+            _ => Err(format!(
+                "Invalid mask resize policy: '{}'. Expected one of stretch, error.",
+                s
+            )),
+        }
+    }
+}
+
+/// How the original and colorized images are put together by `--compare`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CompareLayout {
+    /// The original on the left, and the colorized image on the right
+    Horizontal,
+    /// The original above the colorized image
+    Vertical,
+    /// A single image, with the original on its left half and the colorized image on its right
+    Split,
+}
+
+impl FromStr for CompareLayout {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "horizontal" => Ok(CompareLayout::Horizontal),
+            "vertical" => Ok(CompareLayout::Vertical),
+            "split" => Ok(CompareLayout::Split),
+            _ => Err(format!(
+                "Invalid compare layout: '{}'. Expected one of horizontal, vertical, split.",
+                s
+            )),
+        }
+    }
+}
+
+/// Which part of an image `--compose-with` places its overlay in
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Corner {
+    TopLeft,
+    TopRight,
+    BottomLeft,
+    BottomRight,
+    Center,
+}
+
+/// Where `--compose-with` places its overlay, as a corner and how far in from it the overlay is
+/// moved. Offsets move a centered overlay right and down
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ComposePosition {
+    pub corner: Corner,
+    pub x: u32,
+    pub y: u32,
+}
+
+impl Default for ComposePosition {
+    fn default() -> Self {
+        ComposePosition {
+            corner: Corner::BottomRight,
+            x: 0,
+            y: 0,
+        }
+    }
+}
+
+impl FromStr for ComposePosition {
+    type Err = String;
+
+    /// Parses a corner with an optional offset, such as `bottom-right` or `bottom-right+16+8`
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = || {
+            format!(
+                "Invalid compose position: '{}'. Expected one of top-left, top-right, bottom-left, bottom-right, center, optionally followed by an offset such as +16+8.",
+                s
+            )
+        };
+
+        let mut parts = s.split('+');
+        let corner = match parts.next() {
+            Some("top-left") => Corner::TopLeft,
+            Some("top-right") => Corner::TopRight,
+            Some("bottom-left") => Corner::BottomLeft,
+            Some("bottom-right") => Corner::BottomRight,
+            Some("center") => Corner::Center,
+            _ => return Err(invalid()),
+        };
+        let offsets = parts
+            .map(|offset| offset.parse::<u32>().map_err(|_| invalid()))
+            .collect::<Result<Vec<_>, _>>()?;
+        let (x, y) = match offsets[..] {
+            [] => (0, 0),
+            [x, y] => (x, y),
+            _ => return Err(invalid()),
+        };
+
+        Ok(ComposePosition { corner, x, y })
+    }
+}
+
+/// How WebP outputs are compressed
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum WebPCompression {
+    /// Keeps every pixel exactly, at the cost of larger files
+    Lossless,
+    /// Much smaller files, at the quality set by `AppConfig::quality`
+    Lossy,
+}
+
+impl FromStr for WebPCompression {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "lossless" => Ok(WebPCompression::Lossless),
+            "lossy" => Ok(WebPCompression::Lossy),
+            _ => Err(format!(
+                "Invalid WebP compression: '{}'. Expected one of lossless, lossy.",
+                s
+            )),
+        }
+    }
+}
+
+/// The color space that pixels are matched to palette colors in
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ColorSpace {
+    Lab,
+    Oklab,
+}
+
+impl FromStr for ColorSpace {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "lab" => Ok(ColorSpace::Lab),
+            "oklab" => Ok(ColorSpace::Oklab),
+            _ => Err(format!(
+                "Invalid color space: '{}'. Expected one of lab, oklab.",
+                s
+            )),
+        }
+    }
+}
+
+impl fmt::Display for ColorSpace {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            ColorSpace::Lab => "lab",
+            ColorSpace::Oklab => "oklab",
+        };
+        write!(f, "{}", name)
+    }
+}
+
+/// How the distance between colors is measured, both between neighbouring colors when
+/// interpolating a colorscheme and between pixels and the palette when matching them
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DistanceMetric {
+    /// CIEDE2000 in Lab, which follows perceived differences most closely but is the slowest
+    Ciede2000,
+    /// Euclidean distance in Lab
+    Cie76,
+    /// Euclidean distance in the color space that pixels are matched in, scaled by 100 for Oklab
+    /// so that thresholds are comparable with Lab
+    Euclidean,
+}
+
+impl DistanceMetric {
+    /// The metric used without `--distance-metric`
+    pub fn default_for(color_space: ColorSpace) -> Self {
+        match color_space {
+            ColorSpace::Lab => DistanceMetric::Ciede2000,
+            ColorSpace::Oklab => DistanceMetric::Euclidean,
+        }
+    }
+}
+
+impl fmt::Display for DistanceMetric {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            DistanceMetric::Ciede2000 => "ciede2000",
+            DistanceMetric::Cie76 => "cie76",
+            DistanceMetric::Euclidean => "euclidean",
+        };
+        write!(f, "{}", name)
+    }
+}
+
+impl FromStr for DistanceMetric {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "ciede2000" => Ok(DistanceMetric::Ciede2000),
+            "cie76" => Ok(DistanceMetric::Cie76),
+            "euclidean" => Ok(DistanceMetric::Euclidean),
+            _ => Err(format!(
+                "Invalid distance metric: '{}'. Expected one of ciede2000, cie76, euclidean.",
+                s
+            )),
+        }
+    }
+}
+
+/// How the blend factor of each pixel changes with its lightness, from shadows at 0 to highlights
+/// at 1
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum BlendCurve {
+    /// Blends every pixel by the blend factor
+    Linear,
+    /// Scales the blend factor by the square of the lightness, so that shadows and midtones stay
+    /// close to their original color while highlights are snapped to the palette
+    EaseIn,
+    /// Scales the blend factor by `1 - (1 - lightness)²`, so that everything but the deepest
+    /// shadows is snapped to the palette
+    EaseOut,
+    /// Scales the blend factor by the lightness raised to this power. Above 1 keeps more of the
+    /// shadows, and below 1 snaps more of them
+    Gamma(f32),
+}
+
+impl BlendCurve {
+    /// How much of the blend factor a pixel with `lightness`, from 0 to 1, is blended by
+    pub fn weight(self, lightness: f32) -> f32 {
+        let lightness = lightness.clamp(0.0, 1.0);
+        match self {
+            BlendCurve::Linear => 1.0,
+            BlendCurve::EaseIn => lightness * lightness,
+            BlendCurve::EaseOut => 1.0 - (1.0 - lightness) * (1.0 - lightness),
+            // Kept above 0 like the shaders, where pow(0, y) is undefined
+            BlendCurve::Gamma(gamma) => lightness.max(1e-6).powf(gamma),
+        }
+    }
+}
+
+impl FromStr for BlendCurve {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "linear" => Ok(BlendCurve::Linear),
+            "ease-in" => Ok(BlendCurve::EaseIn),
+            "ease-out" => Ok(BlendCurve::EaseOut),
+            _ => match s.parse::<f32>() {
+                Ok(gamma) if (0.1..=10.0).contains(&gamma) => Ok(BlendCurve::Gamma(gamma)),
+                _ => Err(format!(
+                    "Invalid blend curve: '{}'. Expected one of linear, ease-in, ease-out, or a gamma from 0.1 to 10.",
+                    s
+                )),
+            },
+        }
+    }
+}
+
+impl fmt::Display for BlendCurve {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            BlendCurve::Linear => write!(f, "linear"),
+            BlendCurve::EaseIn => write!(f, "ease-in"),
+            BlendCurve::EaseOut => write!(f, "ease-out"),
+            BlendCurve::Gamma(gamma) => write!(f, "{}", gamma),
+        }
+    }
+}
+
+/// How palette colors are dithered. Error diffusion is sequential, so `FloydSteinberg` always runs
+/// on the CPU, even when a GPU is available
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DitherMode {
+    None,
+    Noise,
+    FloydSteinberg,
+    /// Uses a Bayer matrix whose size grows with the dither amount
+    Ordered,
+    /// Uses a tileable blue noise texture, which has finer and less clumpy grain than `Noise`
+    BlueNoise,
+}
+
+impl FromStr for DitherMode {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "none" => Ok(DitherMode::None),
+            "noise" => Ok(DitherMode::Noise),
+            "floyd-steinberg" => Ok(DitherMode::FloydSteinberg),
+            "ordered" => Ok(DitherMode::Ordered),
+            "blue-noise" => Ok(DitherMode::BlueNoise),
+            _ => Err(format!(
+                "Invalid dither mode: '{}'. Expected one of none, noise, floyd-steinberg, ordered, blue-noise.",
+                s
+            )),
+        }
+    }
+}
+
+impl fmt::Display for DitherMode {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            DitherMode::None => "none",
+            DitherMode::Noise => "noise",
+            DitherMode::FloydSteinberg => "floyd-steinberg",
+            DitherMode::Ordered => "ordered",
+            DitherMode::BlueNoise => "blue-noise",
+        };
+        write!(f, "{}", name)
+    }
 }
@@ -1,39 +1,120 @@
-use image::RgbImage;
-use indicatif::ProgressBar;
-use palette::{IntoColor, Lab, Srgb};
-
-pub fn hex_to_rgb(input: &str) -> Result<Srgb<f32>, String> {
-    let cleaned = input.trim_start_matches('#');
-
-    match cleaned.len() {
-        3 => {
-            // Three-character hex code
-            let r = u8::from_str_radix(&cleaned[0..1].repeat(2), 16).map_err(|e| e.to_string())?
-                as f32
-                / 255.0;
-            let g = u8::from_str_radix(&cleaned[1..2].repeat(2), 16).map_err(|e| e.to_string())?
-                as f32
-                / 255.0;
-            let b = u8::from_str_radix(&cleaned[2..3].repeat(2), 16).map_err(|e| e.to_string())?
-                as f32
-                / 255.0;
-            Ok(Srgb::new(r, g, b))
-        }
-        6 => {
-            // Six-character hex code
-            let r =
-                u8::from_str_radix(&cleaned[0..2], 16).map_err(|e| e.to_string())? as f32 / 255.0;
-            let g =
-                u8::from_str_radix(&cleaned[2..4], 16).map_err(|e| e.to_string())? as f32 / 255.0;
-            let b =
-                u8::from_str_radix(&cleaned[4..6], 16).map_err(|e| e.to_string())? as f32 / 255.0;
-            Ok(Srgb::new(r, g, b))
+use crate::kdtree::Match;
+use crate::progress::Progress;
+use crate::types::ColorSpace;
+
+use image::{DynamicImage, GrayImage, ImageBuffer, Rgb, Rgb32FImage, Rgba, Rgba32FImage};
+use palette::{Clamp, FromColor, IntoColor, Lab, Oklab, Srgb};
+use rayon::prelude::*;
+use std::sync::Arc;
+
+/// Parses a `#rgb`, `#rgba`, `#rrggbb`, `#rrggbbaa`, `rgb()`, or `rgba()` color, returning the
+/// color and its alpha separately
+pub fn hex_to_rgb(input: &str) -> Result<(Srgb<f32>, f32), String> {
+    let trimmed = input.trim();
+
+    if let Some(args) = strip_function(trimmed, "rgba").or_else(|| strip_function(trimmed, "rgb")) {
+        return parse_rgb_function(input, args);
+    }
+
+    let cleaned = trimmed.trim_start_matches('#');
+    if !cleaned.is_ascii() {
+        return Err(invalid_color(input));
+    }
+
+    let digits = match cleaned.len() {
+        // Three or four-character hex code
+        3 | 4 => cleaned.chars().flat_map(|c| [c, c]).collect(),
+        // Six or eight-character hex code
+        6 | 8 => cleaned.to_string(),
+        _ => return Err(invalid_color(input)),
+    };
+
+    let channel = |i: usize| {
+        u8::from_str_radix(&digits[i * 2..i * 2 + 2], 16)
+            .map(|c| c as f32 / 255.0)
+            .map_err(|e| e.to_string())
+    };
+
+    let alpha = if digits.len() == 8 { channel(3)? } else { 1.0 };
+
+    Ok((Srgb::new(channel(0)?, channel(1)?, channel(2)?), alpha))
+}
+
+pub fn rgb_to_hex(rgb: Srgb<f32>) -> String {
+    let rgb: Srgb<u8> = rgb.clamp().into_format();
+    format!("#{:02x}{:02x}{:02x}", rgb.red, rgb.green, rgb.blue)
+}
+
+fn invalid_color(input: &str) -> String {
+    format!(
+        "Invalid input: '{}'. Expected a 3 or 6-digit hex code.",
+        input
+    )
+}
+
+fn strip_function<'a>(input: &'a str, name: &str) -> Option<&'a str> {
+    input
+        .strip_prefix(name)?
+        .trim_start()
+        .strip_prefix('(')?
+        .strip_suffix(')')
+}
+
+/// Parses the arguments of `rgb(34, 40, 49)`, `rgba(34, 40, 49, 0.5)`, or `rgb(34 40 49 / 50%)`
+fn parse_rgb_function(input: &str, args: &str) -> Result<(Srgb<f32>, f32), String> {
+    let (channels, alpha) = match args.split_once('/') {
+        Some((channels, alpha)) => (channels, Some(alpha)),
+        None => (args, None),
+    };
+
+    let mut values: Vec<&str> = channels
+        .split(|c: char| c == ',' || c.is_whitespace())
+        .filter(|value| !value.is_empty())
+        .collect();
+
+    let alpha = match (alpha, values.len()) {
+        (Some(alpha), 3) => alpha.trim(),
+        (None, 4) => values.pop().unwrap(),
+        (None, 3) => "1",
+        _ => return Err(invalid_color(input)),
+    };
+
+    let channel = |value: &str| -> Result<f32, String> {
+        let channel = match value.strip_suffix('%') {
+            Some(percent) => percent.parse::<f32>().map_err(|e| e.to_string())? / 100.0,
+            None => value.parse::<f32>().map_err(|e| e.to_string())? / 255.0,
+        };
+
+        if (0.0..=1.0).contains(&channel) {
+            Ok(channel)
+        } else {
+            Err(format!(
+                "Invalid input: '{}'. '{}' is out of range.",
+                input, value
+            ))
         }
-        _ => Err(format!(
-            "Invalid input: '{}'. Expected a 3 or 6-digit hex code.",
+    };
+
+    let alpha = match alpha.strip_suffix('%') {
+        Some(percent) => percent.parse::<f32>().map_err(|e| e.to_string())? / 100.0,
+        None => alpha.parse::<f32>().map_err(|e| e.to_string())?,
+    };
+
+    if !(0.0..=1.0).contains(&alpha) {
+        return Err(format!(
+            "Invalid input: '{}'. Alpha must be between 0 and 1.",
             input
-        )),
+        ));
     }
+
+    Ok((
+        Srgb::new(
+            channel(values[0])?,
+            channel(values[1])?,
+            channel(values[2])?,
+        ),
+        alpha,
+    ))
 }
 
 pub fn interpolate_color(color1: &Lab, color2: &Lab, t: f32) -> Lab {
@@ -44,37 +125,683 @@ pub fn interpolate_color(color1: &Lab, color2: &Lab, t: f32) -> Lab {
     )
 }
 
-pub fn compute_integral_image(
-    image: &RgbImage,
-    progress_bar: &ProgressBar,
-) -> Vec<Vec<(f64, f64, f64)>> {
+/// Offsets the position that noise dithering is hashed from, so that each seed produces a
+/// different pattern. Without a seed there is no offset
+pub(crate) fn noise_offset(seed: Option<u64>) -> [u32; 2] {
+    let Some(seed) = seed else {
+        return [0, 0];
+    };
+
+    // splitmix64, so that similar seeds don't produce similar offsets
+    let mut z = seed.wrapping_add(0x9e3779b97f4a7c15);
+    z = (z ^ (z >> 30)).wrapping_mul(0xbf58476d1ce4e5b9);
+    z = (z ^ (z >> 27)).wrapping_mul(0x94d049bb133111eb);
+    z ^= z >> 31;
+
+    // Kept small, since the hash loses precision for large positions
+    [(z & 0xfff) as u32, ((z >> 32) & 0xfff) as u32]
+}
+
+/// Width and height of the blue noise texture, which tiles across the image
+pub(crate) const BLUE_NOISE_SIZE: u32 = 64;
+
+/// A tileable 64x64 blue noise texture made with the void-and-cluster method, where every value
+/// from 0 to 255 appears 16 times. Neighbouring values are as different as possible, so it has
+/// little of the low frequency clumping of white noise
+static BLUE_NOISE: &[u8; (BLUE_NOISE_SIZE * BLUE_NOISE_SIZE) as usize] =
+    include_bytes!("textures/blue_noise.bin");
+
+/// The blue noise dither threshold of a pixel, between 0 and 1. The position is offset the same
+/// way as noise dithering, so that each seed produces a different pattern
+pub(crate) fn blue_noise_threshold(x: u32, y: u32, noise_offset: [u32; 2]) -> f32 {
+    let x = (x + noise_offset[0]) % BLUE_NOISE_SIZE;
+    let y = (y + noise_offset[1]) % BLUE_NOISE_SIZE;
+    blue_noise_value(BLUE_NOISE[(y * BLUE_NOISE_SIZE + x) as usize])
+}
+
+/// Every threshold of the blue noise texture, row by row, for uploading to the GPU
+pub(crate) fn blue_noise_thresholds() -> Vec<f32> {
+    BLUE_NOISE.iter().copied().map(blue_noise_value).collect()
+}
+
+fn blue_noise_value(value: u8) -> f32 {
+    (f32::from(value) + 0.5) / 256.0
+}
+
+/// Finds the palette color nearest to `src` and moves the chroma of `src` toward it by `blend`,
+/// keeping the lightness of `src`. An empty palette leaves `src` unchanged.
+///
+/// This is the reference for the palette matching done by `colorize_pass1.wgsl` on the GPU and by
+/// the CPU fallback, and can be used without either
+pub fn map_pixel(src: Lab, palette: &[Lab], blend: f32) -> Lab {
+    let [l, a, b] = map_coordinates(
+        [src.l, src.a, src.b],
+        palette
+            .iter()
+            .map(|color| ([color.l, color.a, color.b], 1.0)),
+        blend,
+    );
+    Lab::new(l, a, b)
+}
+
+/// `map_pixel` for coordinates in any Lab-like color space, such as those from
+/// `palette_coordinates`, with a weight for every color
+pub(crate) fn map_coordinates(
+    src: [f32; 3],
+    palette: impl IntoIterator<Item = ([f32; 3], f32)>,
+    blend: f32,
+) -> [f32; 3] {
+    blend_chroma(src, nearest_color(src, palette), blend)
+}
+
+/// Finds the color of `palette` nearest to `src` by comparing every color, with the distance to
+/// each color divided by its weight. Ties go to the earliest color, like in the shader
+pub(crate) fn nearest_color(
+    src: [f32; 3],
+    palette: impl IntoIterator<Item = ([f32; 3], f32)>,
+) -> Option<[f32; 3]> {
+    palette
+        .into_iter()
+        .min_by(|&(a, a_weight), &(b, b_weight)| {
+            weighted_distance(src, a, a_weight).total_cmp(&weighted_distance(src, b, b_weight))
+        })
+        .map(|(color, _)| color)
+}
+
+/// The two colors of `palette` nearest to `src` by CIEDE2000, with the distance to each color
+/// divided by its weight. Every color is compared, since the k-d tree can only prune Euclidean
+/// distances. Ties go to the earliest color, like in `KdTree::nearest_two`
+pub(crate) fn nearest_two_by_ciede2000(
+    src: [f32; 3],
+    palette: &[[f32; 3]],
+    weights: &[f32],
+) -> Option<(Match, Option<Match>)> {
+    let mut best: [Option<Match>; 2] = [None, None];
+    for (index, &color) in palette.iter().enumerate() {
+        let distance = ciede2000(src, color) / palette_weight(weights, index);
+        if let Some(position) = best.iter().position(
+            |best| !matches!(*best, Some((_, best_distance)) if best_distance <= distance),
+        ) {
+            best[position..].rotate_right(1);
+            best[position] = Some((index, distance));
+        }
+    }
+    let [first, second] = best;
+    first.map(|first| (first, second))
+}
+
+/// The distance used to match colors to a palette, which makes colors with a higher weight seem
+/// nearer
+pub(crate) fn weighted_distance(a: [f32; 3], b: [f32; 3], weight: f32) -> f32 {
+    color_distance(a, b) / weight
+}
+
+/// The weight of the palette color at `index`, where colors without a weight weigh 1
+pub(crate) fn palette_weight(weights: &[f32], index: usize) -> f32 {
+    weights.get(index).copied().unwrap_or(1.0)
+}
+
+/// Moves the chroma of `src` toward `nearest` by `blend`, keeping its lightness
+pub(crate) fn blend_chroma(src: [f32; 3], nearest: Option<[f32; 3]>, blend: f32) -> [f32; 3] {
+    match nearest {
+        Some(nearest) => [
+            src[0],
+            src[1] + (nearest[1] - src[1]) * blend,
+            src[2] + (nearest[2] - src[2]) * blend,
+        ],
+        None => src,
+    }
+}
+
+/// The color between the two nearest palette colors for `--soft-match`, weighted by the inverse of
+/// their distances so that the nearer color counts for more. Matches `soft_match_color` in
+/// `colorize_pass1.wgsl`
+pub(crate) fn soft_match_color(
+    (first, first_distance): ([f32; 3], f32),
+    second: Option<([f32; 3], f32)>,
+) -> [f32; 3] {
+    let Some((second, second_distance)) = second else {
+        return first;
+    };
+    let total = first_distance + second_distance;
+    if total <= 0.0 {
+        return first;
+    }
+
+    // 1 / d1 and 1 / d2 normalized, which leaves d1 / (d1 + d2) for the second color
+    let t = first_distance / total;
+    std::array::from_fn(|c| first[c] + (second[c] - first[c]) * t)
+}
+
+pub(crate) fn color_distance(a: [f32; 3], b: [f32; 3]) -> f32 {
+    ((a[0] - b[0]).powi(2) + (a[1] - b[1]).powi(2) + (a[2] - b[2]).powi(2)).sqrt()
+}
+
+/// The CIEDE2000 difference between two Lab colors, which matches `ciede2000` in
+/// `colorize_pass1.wgsl` rather than using `palette` so that the CPU and GPU match pixels alike
+pub(crate) fn ciede2000(lab1: [f32; 3], lab2: [f32; 3]) -> f32 {
+    use std::f32::consts::{PI, TAU};
+
+    let [l1, a1, b1] = lab1;
+    let [l2, a2, b2] = lab2;
+
+    let chroma7 = ((a1.hypot(b1) + a2.hypot(b2)) / 2.0).powi(7);
+    let g = 0.5 * (1.0 - (chroma7 / (chroma7 + 25f32.powi(7))).sqrt());
+    let (a1, a2) = (a1 * (1.0 + g), a2 * (1.0 + g));
+    let (c1, c2) = (a1.hypot(b1), a2.hypot(b2));
+    let hue = |a: f32, b: f32| {
+        if a == 0.0 && b == 0.0 {
+            0.0
+        } else {
+            b.atan2(a).rem_euclid(TAU)
+        }
+    };
+    let (h1, h2) = (hue(a1, b1), hue(a2, b2));
+
+    let delta_h = match h2 - h1 {
+        _ if c1 * c2 == 0.0 => 0.0,
+        delta if delta > PI => delta - TAU,
+        delta if delta < -PI => delta + TAU,
+        delta => delta,
+    };
+    let delta_l = l2 - l1;
+    let delta_c = c2 - c1;
+    let delta_h = 2.0 * (c1 * c2).sqrt() * (delta_h / 2.0).sin();
+
+    let mean_l = (l1 + l2) / 2.0;
+    let mean_c = (c1 + c2) / 2.0;
+    let mean_h = if c1 * c2 == 0.0 {
+        h1 + h2
+    } else if (h1 - h2).abs() <= PI {
+        (h1 + h2) / 2.0
+    } else if h1 + h2 < TAU {
+        (h1 + h2 + TAU) / 2.0
+    } else {
+        (h1 + h2 - TAU) / 2.0
+    };
+
+    let t = 1.0 - 0.17 * (mean_h - 30f32.to_radians()).cos()
+        + 0.24 * (2.0 * mean_h).cos()
+        + 0.32 * (3.0 * mean_h + 6f32.to_radians()).cos()
+        - 0.20 * (4.0 * mean_h - 63f32.to_radians()).cos();
+    let rotation = 30f32.to_radians() * (-((mean_h.to_degrees() - 275.0) / 25.0).powi(2)).exp();
+    let mean_c7 = mean_c.powi(7);
+    let r_t = -2.0 * (mean_c7 / (mean_c7 + 25f32.powi(7))).sqrt() * (2.0 * rotation).sin();
+
+    let s_l = 1.0 + 0.015 * (mean_l - 50.0).powi(2) / (20.0 + (mean_l - 50.0).powi(2)).sqrt();
+    let s_c = 1.0 + 0.045 * mean_c;
+    let s_h = 1.0 + 0.015 * mean_c * t;
+
+    let (l, c, h) = (delta_l / s_l, delta_c / s_c, delta_h / s_h);
+    (l * l + c * c + h * h + r_t * c * h).max(0.0).sqrt()
+}
+
+/// Converts the palette into the coordinates used for matching in `color_space`
+pub(crate) fn palette_coordinates(palette: &[Lab], color_space: ColorSpace) -> Vec<[f32; 3]> {
+    palette
+        .iter()
+        .map(|&lab| match color_space {
+            ColorSpace::Lab => [lab.l, lab.a, lab.b],
+            ColorSpace::Oklab => {
+                let oklab = Oklab::from_color(lab);
+                [oklab.l, oklab.a, oklab.b]
+            }
+        })
+        .collect()
+}
+
+/// Summed-area table of the weight of each pixel of an image and its Lab a and b scaled by that
+/// weight, with an extra row and column of zeros. Averaging keeps the lightness of each pixel, so
+/// it isn't summed
+pub(crate) type IntegralImage = Vec<Vec<(f64, f64, f64)>>;
+
+/// Computes the integral image of `image`, where pixels weigh their value in `opacity`, or 1
+/// without it
+pub(crate) fn compute_integral_image(
+    image: &Rgb32FImage,
+    opacity: Option<&GrayImage>,
+    progress_bar: &Progress<'_>,
+) -> IntegralImage {
     let (width, height) = image.dimensions();
-    let mut integral = vec![vec![(0.0, 0.0, 0.0); width as usize + 1]; height as usize + 1];
-
-    for y in 1..=height as usize {
-        for x in 1..=width as usize {
-            let pixel = image.get_pixel(x as u32 - 1, y as u32 - 1);
-            let lab: Lab = Srgb::new(
-                pixel[0] as f32 / 255.0,
-                pixel[1] as f32 / 255.0,
-                pixel[2] as f32 / 255.0,
-            )
-            .into_color();
-
-            integral[y][x] = (
-                integral[y - 1][x].0 + integral[y][x - 1].0 - integral[y - 1][x - 1].0
-                    + lab.l as f64,
-                integral[y - 1][x].1 + integral[y][x - 1].1 - integral[y - 1][x - 1].1
-                    + lab.a as f64,
-                integral[y - 1][x].2 + integral[y][x - 1].2 - integral[y - 1][x - 1].2
-                    + lab.b as f64,
-            );
 
-            if (y * width as usize + x) % 100 == 0 {
-                progress_bar.inc(100);
+    // Converting each pixel to Lab is most of the work, and is independent between pixels, so
+    // every row is converted in parallel, with each cell holding the pixel's own values for now
+    let mut integral: IntegralImage = (0..=height)
+        .into_par_iter()
+        .map(|y| {
+            let mut row = vec![(0.0, 0.0, 0.0); width as usize + 1];
+            if y == 0 {
+                return row;
+            }
+
+            for x in 0..width {
+                let pixel = image.get_pixel(x, y - 1);
+                let lab: Lab = Srgb::new(pixel[0], pixel[1], pixel[2]).into_color();
+                let weight = opacity.map_or(1.0, |opacity| {
+                    f64::from(opacity.get_pixel(x, y - 1)[0]) / 255.0
+                });
+
+                row[x as usize + 1] = (weight, lab.a as f64 * weight, lab.b as f64 * weight);
             }
+
+            progress_bar.inc(u64::from(width));
+            row
+        })
+        .collect();
+
+    // The sums are then accumulated serially, with the same additions in the same order as the
+    // usual recurrence, so that the result is exactly the same as computing it in one pass
+    for y in 1..integral.len() {
+        let (above, rows) = integral.split_at_mut(y);
+        let (above, row) = (&above[y - 1], &mut rows[0]);
+        for x in 1..row.len() {
+            let (left, value) = (row[x - 1], row[x]);
+            row[x] = (
+                above[x].0 + left.0 - above[x - 1].0 + value.0,
+                above[x].1 + left.1 - above[x - 1].1 + value.1,
+                above[x].2 + left.2 - above[x - 1].2 + value.2,
+            );
         }
     }
 
     integral
 }
+
+#[cfg(feature = "native")]
+/// Runs `compute_integral_image` on tokio's blocking thread pool, so that it doesn't hold up a
+/// runtime thread that other images and their progress bars could be using. Progress is sent back
+/// over a channel, since `pb` can't be moved to another thread. Outside of a tokio runtime the
+/// integral image is computed in place
+pub(crate) async fn compute_integral_image_off_runtime(
+    img: Rgb32FImage,
+    opacity: Option<Arc<GrayImage>>,
+    pb: &Progress<'_>,
+) -> anyhow::Result<(Rgb32FImage, IntegralImage)> {
+    if tokio::runtime::Handle::try_current().is_err() {
+        let integral = compute_integral_image(&img, opacity.as_deref(), pb);
+        return Ok((img, integral));
+    }
+
+    let (width, height) = img.dimensions();
+    let (sender, mut receiver) = tokio::sync::mpsc::unbounded_channel();
+    let task = tokio::task::spawn_blocking(move || {
+        let report = move |position, _| {
+            let _ = sender.send(position);
+        };
+        let progress = Progress::new(Some(&report), u64::from(width) * u64::from(height));
+        let integral = compute_integral_image(&img, opacity.as_deref(), &progress);
+        (img, integral)
+    });
+
+    // Rows finish out of order across threads, so only positions past the last one are counted.
+    // The channel closes when the task finishes and drops the sender
+    let mut reported = 0;
+    while let Some(position) = receiver.recv().await {
+        if position > reported {
+            pb.inc(position - reported);
+            reported = position;
+        }
+    }
+
+    Ok(task.await?)
+}
+
+/// Without tokio, such as on the web, there is no blocking thread pool to move the integral image
+/// to, so it is always computed in place
+#[cfg(not(feature = "native"))]
+pub(crate) async fn compute_integral_image_off_runtime(
+    img: Rgb32FImage,
+    opacity: Option<Arc<GrayImage>>,
+    pb: &Progress<'_>,
+) -> anyhow::Result<(Rgb32FImage, IntegralImage)> {
+    let integral = compute_integral_image(&img, opacity.as_deref(), pb);
+    Ok((img, integral))
+}
+
+/// The weight of each pixel of `img` in spatial averaging, which is 0 for fully transparent pixels
+/// and 255 for every other pixel. Images without fully transparent pixels don't need weights
+pub(crate) fn opacity_weights(img: &DynamicImage) -> Option<GrayImage> {
+    if !img.color().has_alpha() {
+        return None;
+    }
+
+    let alpha = img.to_rgba32f();
+    if !alpha.pixels().any(|pixel| pixel[3] == 0.0) {
+        return None;
+    }
+
+    Some(GrayImage::from_fn(alpha.width(), alpha.height(), |x, y| {
+        image::Luma([if alpha.get_pixel(x, y)[3] == 0.0 {
+            0
+        } else {
+            255
+        }])
+    }))
+}
+
+/// Converts a colorized image back to the format of `original`, reattaching its alpha channel and
+/// keeping 16 bits per channel if `original` had more than 8, so that high bit depth images don't
+/// band. 8-bit channels are truncated rather than rounded, as they always have been, so that
+/// outputs don't shift by a level between versions
+pub(crate) fn restore_format(colorized: Rgb32FImage, original: &DynamicImage) -> DynamicImage {
+    let color = original.color();
+    let (width, height) = colorized.dimensions();
+
+    if color.bytes_per_pixel() > color.channel_count() {
+        return if color.has_alpha() {
+            let original = original.to_rgba32f();
+            let output: Rgba32FImage = ImageBuffer::from_fn(width, height, |x, y| {
+                let rgb = colorized.get_pixel(x, y);
+                Rgba([rgb[0], rgb[1], rgb[2], original.get_pixel(x, y)[3]])
+            });
+            DynamicImage::ImageRgba16(DynamicImage::ImageRgba32F(output).to_rgba16())
+        } else {
+            DynamicImage::ImageRgb16(DynamicImage::ImageRgb32F(colorized).to_rgb16())
+        };
+    }
+
+    let truncate = |c: f32| (c.clamp(0.0, 1.0) * 255.0) as u8;
+    if color.has_alpha() {
+        let original = original.to_rgba8();
+        DynamicImage::ImageRgba8(ImageBuffer::from_fn(width, height, |x, y| {
+            let [r, g, b] = colorized.get_pixel(x, y).0.map(truncate);
+            Rgba([r, g, b, original.get_pixel(x, y)[3]])
+        }))
+    } else {
+        DynamicImage::ImageRgb8(ImageBuffer::from_fn(width, height, |x, y| {
+            Rgb(colorized.get_pixel(x, y).0.map(truncate))
+        }))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::time::Instant;
+
+    fn assert_lab_eq(actual: Lab, expected: Lab) {
+        assert!(
+            (actual.l - expected.l).abs() < 1e-4
+                && (actual.a - expected.a).abs() < 1e-4
+                && (actual.b - expected.b).abs() < 1e-4,
+            "{:?} != {:?}",
+            actual,
+            expected
+        );
+    }
+
+    #[test]
+    fn hex_to_rgb_parses_every_notation() {
+        let cases = [
+            ("#fff", [255, 255, 255], 1.0),
+            ("#1f1f28", [0x1f, 0x1f, 0x28], 1.0),
+            ("  1f1f28 ", [0x1f, 0x1f, 0x28], 1.0),
+            ("#f008", [255, 0, 0], 0x88 as f32 / 255.0),
+            ("#1f1f2880", [0x1f, 0x1f, 0x28], 0x80 as f32 / 255.0),
+            ("rgb(34, 40, 49)", [34, 40, 49], 1.0),
+            ("rgb(100%, 0%, 50%)", [255, 0, 128], 1.0),
+            ("rgba(34, 40, 49, 0.5)", [34, 40, 49], 0.5),
+            ("rgba (34,40,49,25%)", [34, 40, 49], 0.25),
+            ("rgb(34 40 49)", [34, 40, 49], 1.0),
+            ("rgb(34 40 49 / 50%)", [34, 40, 49], 0.5),
+            ("rgba(34 40 49 / 0.2)", [34, 40, 49], 0.2),
+        ];
+
+        for (input, expected, expected_alpha) in cases {
+            let (rgb, alpha) = hex_to_rgb(input).unwrap();
+            let rgb: Srgb<u8> = rgb.into_format();
+            assert_eq!([rgb.red, rgb.green, rgb.blue], expected, "{}", input);
+            assert!(
+                (alpha - expected_alpha).abs() < 1e-6,
+                "{}: {}",
+                input,
+                alpha
+            );
+        }
+    }
+
+    #[test]
+    fn hex_to_rgb_rejects_invalid_colors() {
+        let cases = [
+            "",
+            "#",
+            "#ff",
+            "#fffff",
+            "#1f1f28801",
+            "#ggg",
+            "#1f1f2g",
+            "#ffé",
+            "rgb(34, 40)",
+            "rgb(34, 40, 49, 0.5, 1)",
+            "rgb(34, 40, 49",
+            "rgb(256, 0, 0)",
+            "rgb(-1, 0, 0)",
+            "rgb(101%, 0%, 0%)",
+            "rgb(a, b, c)",
+            "rgba(34, 40, 49, 1.5)",
+            "rgb(34 40 49 / 150%)",
+            "rgb(34 40 / 50%)",
+            "hsl(0, 0%, 0%)",
+        ];
+
+        for input in cases {
+            assert!(hex_to_rgb(input).is_err(), "{}", input);
+        }
+    }
+
+    #[test]
+    fn hex_to_rgb_keeps_the_original_error_message() {
+        assert_eq!(
+            hex_to_rgb("#12345").unwrap_err(),
+            "Invalid input: '#12345'. Expected a 3 or 6-digit hex code."
+        );
+    }
+
+    #[test]
+    fn map_pixel_keeps_exact_matches() {
+        let palette = [Lab::new(20.0, 5.0, 5.0), Lab::new(60.0, -30.0, 40.0)];
+        assert_lab_eq(map_pixel(palette[1], &palette, 1.0), palette[1]);
+    }
+
+    #[test]
+    fn map_pixel_blends_chroma_toward_nearest_color() {
+        let palette = [Lab::new(50.0, 40.0, -20.0), Lab::new(90.0, -80.0, 80.0)];
+        let src = Lab::new(40.0, 0.0, 0.0);
+
+        assert_lab_eq(map_pixel(src, &palette, 0.5), Lab::new(40.0, 20.0, -10.0));
+        assert_lab_eq(map_pixel(src, &palette, 1.0), Lab::new(40.0, 40.0, -20.0));
+        assert_lab_eq(map_pixel(src, &palette, 0.0), src);
+    }
+
+    #[test]
+    fn map_pixel_leaves_color_unchanged_without_palette() {
+        let src = Lab::new(40.0, 10.0, -10.0);
+        assert_lab_eq(map_pixel(src, &[], 1.0), src);
+    }
+
+    #[test]
+    fn sixteen_bit_images_round_trip_through_floats() {
+        let rgb = ImageBuffer::from_fn(32, 4, |x, y| {
+            Rgb([
+                x as u16 * 2039 + 1,
+                y as u16 * 16001 + 3,
+                65535 - x as u16 * 7,
+            ])
+        });
+        let original = DynamicImage::ImageRgb16(rgb);
+        let restored = restore_format(original.to_rgb32f(), &original);
+        assert_eq!(restored, original);
+
+        let rgba = ImageBuffer::from_fn(32, 4, |x, y| {
+            Rgba([
+                x as u16 * 2039 + 1,
+                12345,
+                54321,
+                y as u16 * 21000 + x as u16,
+            ])
+        });
+        let original = DynamicImage::ImageRgba16(rgba);
+        let restored = restore_format(original.to_rgb32f(), &original);
+        assert_eq!(restored, original);
+    }
+
+    #[test]
+    fn eight_bit_images_are_truncated_and_keep_their_alpha() {
+        let original = DynamicImage::ImageRgba8(ImageBuffer::from_pixel(2, 1, Rgba([0, 0, 0, 77])));
+        let colorized = ImageBuffer::from_pixel(2, 1, Rgb([0.999, 0.5, 1.5]));
+
+        let DynamicImage::ImageRgba8(restored) = restore_format(colorized, &original) else {
+            panic!("8-bit images should stay 8-bit");
+        };
+        assert_eq!(restored.get_pixel(1, 0), &Rgba([254, 127, 255, 77]));
+    }
+
+    #[test]
+    fn ciede2000_matches_palette() {
+        use palette::color_difference::Ciede2000;
+        use rand::{rngs::StdRng, Rng, SeedableRng};
+
+        let to_lab = |[l, a, b]: [f32; 3]| -> Lab { Lab::new(l, a, b) };
+        let mut rng = StdRng::seed_from_u64(74);
+        let mut random_lab = || -> [f32; 3] {
+            [
+                rng.gen_range(0.0..100.0),
+                rng.gen_range(-128.0..128.0),
+                rng.gen_range(-128.0..128.0),
+            ]
+        };
+
+        for _ in 0..1000 {
+            let (a, b) = (random_lab(), random_lab());
+            let expected = to_lab(a).difference(to_lab(b));
+            assert!(
+                (ciede2000(a, b) - expected).abs() < 1e-3 * expected.max(1.0),
+                "{:?} {:?}: {} != {}",
+                a,
+                b,
+                ciede2000(a, b),
+                expected
+            );
+        }
+        assert_eq!(ciede2000([50.0, 0.0, 0.0], [50.0, 0.0, 0.0]), 0.0);
+    }
+
+    #[test]
+    fn soft_match_weighs_colors_by_inverse_distance() {
+        let (a, b) = ([50.0, 0.0, 0.0], [50.0, 30.0, 0.0]);
+
+        assert_eq!(
+            soft_match_color((a, 10.0), Some((b, 10.0))),
+            [50.0, 15.0, 0.0]
+        );
+        // Three times as far away counts for a third as much
+        assert_eq!(
+            soft_match_color((a, 5.0), Some((b, 15.0))),
+            [50.0, 7.5, 0.0]
+        );
+        assert_eq!(soft_match_color((a, 0.0), Some((b, 20.0))), a);
+        assert_eq!(soft_match_color((a, 0.0), Some((b, 0.0))), a);
+        assert_eq!(soft_match_color((a, 10.0), None), a);
+    }
+
+    #[test]
+    fn blue_noise_uses_every_threshold_equally() {
+        let mut counts = [0; 256];
+        for &value in BLUE_NOISE.iter() {
+            counts[value as usize] += 1;
+        }
+        assert!(counts.iter().all(|&count| count == 16), "{:?}", counts);
+    }
+
+    #[test]
+    fn blue_noise_has_less_low_frequency_energy_than_white_noise() {
+        // Variance of the 3x3 mean around each texel, which only low frequencies survive
+        let blurred_variance = |threshold: &dyn Fn(u32, u32) -> f32| {
+            let size = BLUE_NOISE_SIZE;
+            let means: Vec<f32> = (0..size * size)
+                .map(|i| {
+                    let (x, y) = (i % size + size, i / size + size);
+                    let sum: f32 = (0..9)
+                        .map(|j| threshold((x + j % 3 - 1) % size, (y + j / 3 - 1) % size))
+                        .sum();
+                    sum / 9.0
+                })
+                .collect();
+            let mean = means.iter().sum::<f32>() / means.len() as f32;
+            means.iter().map(|m| (m - mean).powi(2)).sum::<f32>() / means.len() as f32
+        };
+        let white_noise = |x: u32, y: u32| {
+            let hash = x.wrapping_mul(374761393) ^ y.wrapping_mul(668265263);
+            let hash = (hash ^ (hash >> 13)).wrapping_mul(1274126177);
+            (hash >> 8) as f32 / (1 << 24) as f32
+        };
+
+        let blue = blurred_variance(&|x, y| blue_noise_threshold(x, y, [0, 0]));
+        let white = blurred_variance(&white_noise);
+        assert!(blue * 3.0 < white, "{} vs {}", blue, white);
+    }
+
+    /// The single-threaded recurrence that `compute_integral_image` replaced, which adds each
+    /// pixel to the sums above and to the left of it, less the sum they share
+    fn compute_integral_image_serial(image: &Rgb32FImage) -> Vec<Vec<(f64, f64, f64)>> {
+        let (width, height) = image.dimensions();
+        let mut integral = vec![vec![(0.0, 0.0, 0.0); width as usize + 1]; height as usize + 1];
+
+        for y in 1..=height as usize {
+            for x in 1..=width as usize {
+                let pixel = image.get_pixel(x as u32 - 1, y as u32 - 1);
+                let lab: Lab = Srgb::new(pixel[0], pixel[1], pixel[2]).into_color();
+
+                integral[y][x] = (
+                    integral[y - 1][x].0 + integral[y][x - 1].0 - integral[y - 1][x - 1].0 + 1.0,
+                    integral[y - 1][x].1 + integral[y][x - 1].1 - integral[y - 1][x - 1].1
+                        + lab.a as f64,
+                    integral[y - 1][x].2 + integral[y][x - 1].2 - integral[y - 1][x - 1].2
+                        + lab.b as f64,
+                );
+            }
+        }
+
+        integral
+    }
+
+    fn test_image(width: u32, height: u32) -> Rgb32FImage {
+        Rgb32FImage::from_fn(width, height, |x, y| {
+            Rgb([
+                (x * 7 % 256) as f32 / 255.0,
+                (y * 13 % 256) as f32 / 255.0,
+                ((x ^ y) % 256) as f32 / 255.0,
+            ])
+        })
+    }
+
+    fn assert_matches_serial(image: &Rgb32FImage, integral: &[Vec<(f64, f64, f64)>]) {
+        assert_eq!(integral, compute_integral_image_serial(image));
+    }
+
+    #[test]
+    fn parallel_integral_image_matches_serial() {
+        let image = test_image(257, 131);
+        let integral = compute_integral_image(&image, None, &Progress::new(None, 0));
+
+        assert_matches_serial(&image, &integral);
+    }
+
+    #[test]
+    #[ignore = "benchmark, run with `cargo test --release -- --ignored --nocapture`"]
+    fn benchmark_parallel_integral_image() {
+        let image = test_image(4000, 3000);
+
+        let start = Instant::now();
+        compute_integral_image_serial(&image);
+        let serial = start.elapsed();
+
+        let start = Instant::now();
+        let integral = compute_integral_image(&image, None, &Progress::new(None, 0));
+        let parallel = start.elapsed();
+
+        println!("serial: {:?}, parallel: {:?}", serial, parallel);
+        assert_matches_serial(&image, &integral);
+    }
+}
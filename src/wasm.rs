@@ -0,0 +1,37 @@
+//! Entry points for running the colorizer in a browser through WebGPU, exported with wasm-bindgen.
+//! Build the library with `--no-default-features --target wasm32-unknown-unknown` and
+//! `RUSTFLAGS=--cfg=web_sys_unstable_apis`, which wgpu needs for WebGPU
+
+use js_sys::Uint8Array;
+use palette::{FromColor, Lab};
+use wasm_bindgen::prelude::*;
+
+use crate::{colorize_bytes, init_gpu, utils::hex_to_rgb, ColorizeOptions};
+
+/// Colorizes an encoded image with `colors`, each a hex or `rgb()` color, and returns it encoded
+/// as a PNG. Runs on WebGPU when the browser has an adapter, and on the CPU otherwise
+#[wasm_bindgen]
+pub async fn colorize(
+    image: Vec<u8>,
+    colors: Vec<String>,
+    blend_factor: f32,
+) -> Result<Uint8Array, JsError> {
+    let palette = colors
+        .iter()
+        .map(|color| {
+            hex_to_rgb(color)
+                .map(|(rgb, _alpha)| Lab::from_color(rgb))
+                .map_err(|e| JsError::new(&format!("Invalid color '{}': {}", color, e)))
+        })
+        .collect::<Result<Vec<_>, _>>()?;
+    let options = ColorizeOptions {
+        blend_factor: blend_factor.clamp(0.0, 1.0),
+        ..ColorizeOptions::default()
+    };
+
+    let gpu = init_gpu().await.map_err(|e| JsError::new(&e.to_string()))?;
+    let png = colorize_bytes(&image, &palette, &options, gpu.as_ref(), None)
+        .await
+        .map_err(|e| JsError::new(&format!("{:#}", e)))?;
+    Ok(Uint8Array::from(png.as_slice()))
+}
@@ -0,0 +1,406 @@
+mod common;
+
+use std::sync::Arc;
+
+use image::{DynamicImage, GrayImage, Luma, Rgb, RgbImage};
+use image_colorizer::{
+    colorize_image, BlendCurve, ColorSpace, ColorizeOptions, DistanceMetric, DitherMode,
+};
+use palette::{FromColor, Lab, Srgb};
+
+use common::{assert_close, gpu_or_skip, palette};
+
+fn image() -> DynamicImage {
+    RgbImage::from_fn(32, 32, |x, y| Rgb([(x * 8) as u8, (y * 8) as u8, 128])).into()
+}
+
+async fn colorize(options: ColorizeOptions) -> RgbImage {
+    colorize_image(&image(), &palette(), &options, None, None)
+        .await
+        .unwrap()
+        .to_rgb8()
+}
+
+#[tokio::test]
+async fn zero_saturation_makes_the_output_grayscale() {
+    let output = colorize(ColorizeOptions {
+        saturation: 0.0,
+        ..ColorizeOptions::default()
+    })
+    .await;
+
+    for pixel in output.pixels() {
+        let [r, g, b] = pixel.0;
+        assert!(r.abs_diff(g) <= 1 && g.abs_diff(b) <= 1, "{:?}", pixel);
+    }
+}
+
+#[tokio::test]
+async fn contrast_spreads_lightness_away_from_the_mean() {
+    let spread = |img: &RgbImage| {
+        let luma = DynamicImage::ImageRgb8(img.clone()).to_luma8();
+        let max = luma.pixels().map(|p| p[0]).max().unwrap();
+        let min = luma.pixels().map(|p| p[0]).min().unwrap();
+        max - min
+    };
+
+    let unchanged = colorize(ColorizeOptions::default()).await;
+    let boosted = colorize(ColorizeOptions {
+        contrast: 1.5,
+        ..ColorizeOptions::default()
+    })
+    .await;
+
+    assert!(spread(&boosted) > spread(&unchanged));
+}
+
+#[tokio::test]
+async fn inverting_luminance_darkens_a_light_image() {
+    let light: DynamicImage =
+        RgbImage::from_fn(32, 32, |x, _| Rgb([240, 240, 200 + x as u8])).into();
+    let mean_luma = |options: ColorizeOptions| {
+        let light = &light;
+        async move {
+            let output = colorize_image(light, &palette(), &options, None, None)
+                .await
+                .unwrap()
+                .to_luma8();
+            output.pixels().map(|p| u32::from(p[0])).sum::<u32>() / (32 * 32)
+        }
+    };
+
+    let unchanged = mean_luma(ColorizeOptions::default()).await;
+    let inverted = mean_luma(ColorizeOptions {
+        invert_luminance: true,
+        ..ColorizeOptions::default()
+    })
+    .await;
+
+    assert!(unchanged > 128, "{}", unchanged);
+    assert!(inverted < 128, "{}", inverted);
+}
+
+#[tokio::test]
+async fn linear_blending_keeps_midtones_lighter_than_srgb_blending() {
+    // Colorizing keeps the lightness of each pixel but takes the chroma of the palette, so a
+    // gradient of reds matched to cyan is blended between complementary colors of equal lightness
+    let gradient: DynamicImage = RgbImage::from_fn(32, 1, |x, _| {
+        Rgb([(x * 8) as u8, (x * 2) as u8, (x * 2) as u8])
+    })
+    .into();
+    let cyan = [Lab::from_color(Srgb::new(0.2, 0.7, 0.7))];
+    let lightness = |linear_blend: bool| {
+        let gradient = &gradient;
+        async move {
+            let options = ColorizeOptions {
+                blend_factor: 0.5,
+                dither_mode: DitherMode::None,
+                spatial_averaging_radius: 0,
+                linear_blend,
+                ..ColorizeOptions::default()
+            };
+            let output = colorize_image(gradient, &cyan, &options, None, None)
+                .await
+                .unwrap()
+                .to_rgb8();
+            lab_colors(&output)
+                .iter()
+                .map(|lab| lab.l)
+                .collect::<Vec<_>>()
+        }
+    };
+
+    let srgb = lightness(false).await;
+    let linear = lightness(true).await;
+
+    // Mixing two colors in linear light and encoding the result is never darker than mixing their
+    // encoded values
+    for (x, (linear, srgb)) in linear.iter().zip(&srgb).enumerate() {
+        assert!(linear + 0.5 >= *srgb, "{}: {} < {}", x, linear, srgb);
+    }
+    let midtones = 8..24;
+    let difference = midtones.map(|x| linear[x] - srgb[x]).sum::<f32>() / 16.0;
+    assert!(difference > 3.0, "{}", difference);
+}
+
+#[tokio::test]
+async fn neutral_pixels_are_preserved() {
+    // Gray on the left half and saturated red on the right
+    let img: DynamicImage = RgbImage::from_fn(32, 32, |x, y| {
+        if x < 16 {
+            Rgb([(y * 8) as u8; 3])
+        } else {
+            Rgb([220, 30, 30])
+        }
+    })
+    .into();
+
+    let output = colorize_image(
+        &img,
+        &palette(),
+        &ColorizeOptions {
+            preserve_neutrals: 5.0,
+            ..ColorizeOptions::default()
+        },
+        None,
+        None,
+    )
+    .await
+    .unwrap()
+    .to_rgb8();
+    let img = img.to_rgb8();
+
+    for (x, y, pixel) in output.enumerate_pixels() {
+        if x < 16 {
+            assert_eq!(pixel, img.get_pixel(x, y), "({}, {})", x, y);
+        } else if x >= 24 {
+            assert_ne!(pixel, img.get_pixel(x, y), "({}, {})", x, y);
+        }
+    }
+}
+
+#[tokio::test]
+async fn posterizing_limits_the_number_of_colors() {
+    let output = colorize(ColorizeOptions {
+        posterize: 3,
+        ..ColorizeOptions::default()
+    })
+    .await;
+
+    let colors: std::collections::HashSet<_> = output.pixels().collect();
+    assert!(colors.len() <= 3 * 3 * 3, "{}", colors.len());
+    assert!(colors.len() > 1, "{}", colors.len());
+}
+
+fn lab_colors(img: &RgbImage) -> Vec<Lab> {
+    img.pixels()
+        .map(|p| Lab::from_color(Srgb::new(p[0], p[1], p[2]).into_format::<f32>()))
+        .collect()
+}
+
+#[tokio::test]
+async fn posterizing_to_two_levels_keeps_the_ends_of_each_chroma_axis() {
+    let output = colorize(ColorizeOptions {
+        posterize: 2,
+        ..ColorizeOptions::default()
+    })
+    .await;
+
+    // a and b are either -128 or 128, so the output has color rather than only black and white
+    let colors: std::collections::HashSet<_> = output.pixels().collect();
+    assert!(colors.len() <= 2 * 2 * 2, "{}", colors.len());
+    assert!(
+        lab_colors(&output)
+            .iter()
+            .any(|lab| lab.a.hypot(lab.b) > 20.0),
+        "{:?}",
+        colors
+    );
+}
+
+#[tokio::test]
+async fn posterizing_to_an_even_number_of_levels_spreads_them_over_the_chroma_range() {
+    let output = colorize(ColorizeOptions {
+        posterize: 4,
+        ..ColorizeOptions::default()
+    })
+    .await;
+
+    // Levels of a and b are at ±128 and ±128 / 3, and of lightness at thirds of 100
+    let step = 128.0 / 3.0;
+    let on_level = |lab: &Lab| {
+        [(lab.l, 100.0 / 3.0), (lab.a, step), (lab.b, step)]
+            .iter()
+            .all(|&(value, step)| (value / step - (value / step).round()).abs() * step < 1.0)
+    };
+    let colors = lab_colors(&output);
+    assert!(
+        colors.iter().any(|lab| {
+            on_level(lab) && (step / 2.0..64.0).contains(&lab.a.abs()) && lab.b.abs() < 64.0
+        }),
+        "{:?}",
+        colors
+    );
+}
+
+#[tokio::test]
+async fn ease_in_keeps_shadows_closer_to_the_original() {
+    let original = image().to_rgb8();
+    let linear = colorize(ColorizeOptions::default()).await;
+    let ease_in = colorize(ColorizeOptions {
+        blend_curve: BlendCurve::EaseIn,
+        ..ColorizeOptions::default()
+    })
+    .await;
+
+    // How far the darkest corner of the image moved from the original
+    let shadow_change = |img: &RgbImage| -> u32 {
+        (0..8)
+            .flat_map(|x| (0..8).map(move |y| (x, y)))
+            .map(|(x, y)| {
+                let (a, b) = (img.get_pixel(x, y), original.get_pixel(x, y));
+                (0..3).map(|c| a[c].abs_diff(b[c]) as u32).sum::<u32>()
+            })
+            .sum()
+    };
+    assert!(shadow_change(&ease_in) < shadow_change(&linear) / 2);
+}
+
+#[tokio::test]
+async fn gpu_follows_the_blend_curve_like_the_cpu() {
+    let Some(gpu) = gpu_or_skip().await else {
+        return;
+    };
+
+    for blend_curve in [BlendCurve::EaseOut, BlendCurve::Gamma(2.2)] {
+        let options = ColorizeOptions {
+            blend_curve,
+            ..ColorizeOptions::default()
+        };
+        let cpu = colorize(options.clone()).await;
+        let colorized = colorize_image(&image(), &palette(), &options, Some(&gpu), None)
+            .await
+            .unwrap()
+            .to_rgb8();
+
+        assert_close(&cpu, &colorized, 1);
+    }
+}
+
+#[tokio::test]
+async fn soft_matching_smooths_the_banding_of_snapping() {
+    // The largest change in chroma between neighbouring pixels, which is a band edge when
+    // snapping to the nearest color
+    let largest_step = |soft_match: bool| async move {
+        let output = colorize(ColorizeOptions {
+            soft_match,
+            blend_factor: 1.0,
+            dither_mode: DitherMode::None,
+            spatial_averaging_radius: 0,
+            ..ColorizeOptions::default()
+        })
+        .await;
+        let chroma = |x: u32, y: u32| {
+            let [r, g, b] = output.get_pixel(x, y).0;
+            let lab = Lab::from_color(Srgb::new(r, g, b).into_format::<f32>());
+            (lab.a, lab.b)
+        };
+
+        let mut largest: f32 = 0.0;
+        for y in 0..output.height() {
+            for x in 1..output.width() {
+                let ((a1, b1), (a2, b2)) = (chroma(x - 1, y), chroma(x, y));
+                largest = largest.max((a1 - a2).hypot(b1 - b2));
+            }
+        }
+        largest
+    };
+
+    let (soft, snapped) = (largest_step(true).await, largest_step(false).await);
+    assert!(soft * 1.5 < snapped, "{} vs {}", soft, snapped);
+}
+
+#[tokio::test]
+async fn distance_metric_changes_which_colors_pixels_match() {
+    let with_metric = |distance_metric, color_space| async move {
+        colorize(ColorizeOptions {
+            distance_metric,
+            color_space,
+            blend_factor: 1.0,
+            dither_mode: DitherMode::None,
+            spatial_averaging_radius: 0,
+            ..ColorizeOptions::default()
+        })
+        .await
+    };
+
+    let euclidean = with_metric(DistanceMetric::Euclidean, ColorSpace::Lab).await;
+    // Euclidean distance in Lab is CIE76
+    assert_eq!(
+        with_metric(DistanceMetric::Cie76, ColorSpace::Lab).await,
+        euclidean
+    );
+    assert_ne!(
+        with_metric(DistanceMetric::Ciede2000, ColorSpace::Lab).await,
+        euclidean
+    );
+    assert_ne!(
+        with_metric(DistanceMetric::Cie76, ColorSpace::Oklab).await,
+        with_metric(DistanceMetric::Euclidean, ColorSpace::Oklab).await
+    );
+}
+
+#[tokio::test]
+async fn gpu_matches_with_the_distance_metric_like_the_cpu() {
+    let Some(gpu) = gpu_or_skip().await else {
+        return;
+    };
+
+    for (distance_metric, color_space, soft_match) in [
+        (DistanceMetric::Ciede2000, ColorSpace::Lab, false),
+        (DistanceMetric::Ciede2000, ColorSpace::Oklab, true),
+        (DistanceMetric::Cie76, ColorSpace::Oklab, false),
+    ] {
+        let options = ColorizeOptions {
+            distance_metric,
+            color_space,
+            soft_match,
+            ..ColorizeOptions::default()
+        };
+        let cpu = colorize(options.clone()).await;
+        let colorized = colorize_image(&image(), &palette(), &options, Some(&gpu), None)
+            .await
+            .unwrap()
+            .to_rgb8();
+
+        assert_close(&cpu, &colorized, 1);
+    }
+}
+
+#[tokio::test]
+async fn blend_map_is_resized_and_sets_the_blend_factor_of_each_pixel() {
+    let snapped = ColorizeOptions {
+        blend_factor: 1.0,
+        dither_mode: DitherMode::None,
+        spatial_averaging_radius: 0,
+        ..ColorizeOptions::default()
+    };
+    let fully_blended = colorize(snapped.clone()).await;
+    let colorized = colorize(ColorizeOptions {
+        blend_map: Some(Arc::new(GrayImage::from_raw(2, 1, vec![0, 255]).unwrap())),
+        ..snapped
+    })
+    .await;
+
+    let original = image().to_rgb8();
+    let width = colorized.width();
+    for y in 0..colorized.height() {
+        let (left, right) = (colorized.get_pixel(0, y), colorized.get_pixel(width - 1, y));
+        for channel in 0..3 {
+            assert!(left[channel].abs_diff(original.get_pixel(0, y)[channel]) <= 8);
+            assert!(right[channel].abs_diff(fully_blended.get_pixel(width - 1, y)[channel]) <= 8);
+        }
+    }
+}
+
+#[tokio::test]
+async fn gpu_follows_the_blend_map_like_the_cpu() {
+    let Some(gpu) = gpu_or_skip().await else {
+        return;
+    };
+
+    let blend_map = GrayImage::from_fn(8, 8, |x, y| Luma([(x * 32 + y * 4) as u8]));
+    let mask = GrayImage::from_fn(32, 32, |x, _| Luma([255 - x as u8 * 4]));
+    let options = ColorizeOptions {
+        blend_map: Some(Arc::new(blend_map)),
+        mask: Some(Arc::new(mask)),
+        ..ColorizeOptions::default()
+    };
+    let cpu = colorize(options.clone()).await;
+    let colorized = colorize_image(&image(), &palette(), &options, Some(&gpu), None)
+        .await
+        .unwrap()
+        .to_rgb8();
+
+    assert_close(&cpu, &colorized, 1);
+}
@@ -0,0 +1,751 @@
+mod common;
+
+use std::fs;
+use std::io::Cursor;
+use std::path::{Path, PathBuf};
+
+use image::{ImageFormat, ImageOutputFormat, Rgb, RgbImage};
+
+use common::{colorizer, temp_dir};
+
+fn encoded_png(seed: u32) -> Vec<u8> {
+    let img = RgbImage::from_fn(32, 32, |x, y| {
+        Rgb([(x * 8) as u8, (y * 8) as u8, (seed * 60) as u8])
+    });
+    let mut encoded = Cursor::new(Vec::new());
+    img.write_to(&mut encoded, ImageOutputFormat::Png).unwrap();
+    encoded.into_inner()
+}
+
+#[test]
+fn corrupt_input_does_not_stop_the_rest_of_the_batch() {
+    let dir = temp_dir("batch");
+    let inputs = ["first.png", "truncated.png", "second.png"].map(|name| dir.join(name));
+
+    fs::write(&inputs[0], encoded_png(0)).unwrap();
+    let truncated = encoded_png(1);
+    fs::write(&inputs[1], &truncated[..truncated.len() / 2]).unwrap();
+    fs::write(&inputs[2], encoded_png(2)).unwrap();
+
+    let output = colorizer(&dir)
+        .args(&inputs)
+        .arg("--output")
+        .arg(dir.join("out"))
+        .arg("--cpu")
+        .output()
+        .unwrap();
+    let stderr = String::from_utf8_lossy(&output.stderr);
+
+    assert_eq!(output.status.code(), Some(1), "{}", stderr);
+    assert!(dir.join("out/first_kanagawa.png").is_file());
+    assert!(dir.join("out/second_kanagawa.png").is_file());
+    assert!(!dir.join("out/truncated_kanagawa.png").exists());
+    assert!(
+        stderr.contains(&format!("Failed to colorize {}", inputs[1].display())),
+        "{}",
+        stderr
+    );
+    assert!(stderr.contains("2 succeeded, 1 failed"), "{}", stderr);
+
+    fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn dry_run_prints_outputs_without_writing_them() {
+    let dir = temp_dir("dry-run");
+    let inputs = ["first.png", "second.png"].map(|name| dir.join(name));
+    for (seed, input) in inputs.iter().enumerate() {
+        fs::write(input, encoded_png(seed as u32)).unwrap();
+    }
+    fs::write(dir.join("out/second_kanagawa.png"), encoded_png(3)).unwrap();
+
+    let output = colorizer(&dir)
+        .args(&inputs)
+        .arg("--output")
+        .arg(dir.join("out/{stem}/{stem}_{scheme}.{ext}"))
+        .arg("--dry-run")
+        .output()
+        .unwrap();
+    let stdout = String::from_utf8_lossy(&output.stdout);
+
+    assert!(output.status.success(), "{}", stdout);
+    assert!(stdout.contains(&format!(
+        "{} -> {}",
+        inputs[0].display(),
+        dir.join("out/first/first_kanagawa.png").display()
+    )));
+    assert!(!dir.join("out/first").exists());
+
+    let output = colorizer(&dir)
+        .args(&inputs)
+        .arg("--output")
+        .arg(dir.join("out"))
+        .arg("--dry-run")
+        .output()
+        .unwrap();
+    let stdout = String::from_utf8_lossy(&output.stdout);
+
+    assert!(
+        stdout.contains("second_kanagawa.png (skipped, already exists)"),
+        "{}",
+        stdout
+    );
+    assert!(!dir.join("out/first_kanagawa.png").exists());
+
+    fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn no_save_prints_coverage_without_writing_outputs() {
+    let dir = temp_dir("no-save");
+    let inputs = ["first.png", "second.png"].map(|name| dir.join(name));
+    for (seed, input) in inputs.iter().enumerate() {
+        fs::write(input, encoded_png(seed as u32)).unwrap();
+    }
+
+    let output = colorizer(&dir)
+        .args(&inputs)
+        .arg("--output")
+        .arg(dir.join("out"))
+        .arg("--no-save")
+        .arg("--progress")
+        .arg("none")
+        .output()
+        .unwrap();
+    let stdout = String::from_utf8_lossy(&output.stdout);
+
+    assert!(output.status.success(), "{}", stdout);
+    let lines: Vec<&str> = stdout.lines().collect();
+    assert_eq!(lines.len(), 3, "{}", stdout);
+    assert!(lines[0].contains("Colors used"));
+    assert!(lines[1].starts_with(&inputs[0].display().to_string()));
+    assert_eq!(fs::read_dir(dir.join("out")).unwrap().count(), 0);
+
+    fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn verbose_logs_only_when_asked_for() {
+    let dir = temp_dir("verbose");
+    let input = dir.join("input.png");
+    fs::write(&input, encoded_png(0)).unwrap();
+
+    let run = |args: &[&str]| {
+        let output = colorizer(&dir)
+            .arg(&input)
+            .arg("--output")
+            .arg(dir.join("out"))
+            .args(["--cpu", "--progress", "none", "--overwrite"])
+            .args(args)
+            .output()
+            .unwrap();
+        assert!(output.status.success());
+        String::from_utf8_lossy(&output.stderr).into_owned()
+    };
+
+    assert!(!run(&[]).contains("[INFO"));
+
+    let stderr = run(&["-v"]);
+    assert!(stderr.contains("Interpolated"), "{}", stderr);
+    assert!(!stderr.contains("[DEBUG"), "{}", stderr);
+
+    let stderr = run(&["-vv"]);
+    assert!(stderr.contains("[DEBUG"), "{}", stderr);
+
+    fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn missing_output_directories_are_created() {
+    let dir = temp_dir("create-output-dir");
+    let input = dir.join("input.png");
+    fs::write(&input, encoded_png(0)).unwrap();
+
+    let run = |output_dir: PathBuf| {
+        colorizer(&dir)
+            .arg(&input)
+            .arg("--output")
+            .arg(output_dir)
+            .args(["--cpu", "--progress", "none"])
+            .output()
+            .unwrap()
+    };
+
+    let output = run(dir.join("out/colorized/nested"));
+    assert!(
+        output.status.success(),
+        "{}",
+        String::from_utf8_lossy(&output.stderr)
+    );
+    assert!(dir
+        .join("out/colorized/nested/input_kanagawa.png")
+        .is_file());
+
+    // A file in the way of the directory can't be replaced
+    fs::write(dir.join("out/blocked"), "").unwrap();
+    let output = run(dir.join("out/blocked/colorized"));
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(!output.status.success());
+    assert!(
+        stderr.contains("Failed to create the output directory"),
+        "{}",
+        stderr
+    );
+
+    fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn each_colorscheme_gets_its_own_output() {
+    let dir = temp_dir("several-schemes");
+    let input = dir.join("input.png");
+    fs::write(&input, encoded_png(0)).unwrap();
+
+    let output = colorizer(&dir)
+        .arg(&input)
+        .arg("--output")
+        .arg(dir.join("out"))
+        .args(["--colorscheme", "nord, gruvbox,nord", "--cpu"])
+        .output()
+        .unwrap();
+    let stderr = String::from_utf8_lossy(&output.stderr);
+
+    assert!(output.status.success(), "{}", stderr);
+    let nord = image::open(dir.join("out/input_nord.png")).unwrap();
+    let gruvbox = image::open(dir.join("out/input_gruvbox.png")).unwrap();
+    assert_ne!(nord, gruvbox);
+    assert!(stderr.contains("2 succeeded, 0 failed"), "{}", stderr);
+
+    fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn threads_do_not_change_the_output() {
+    let dir = temp_dir("threads");
+    let input = dir.join("input.png");
+    fs::write(&input, encoded_png(0)).unwrap();
+
+    let colorize = |threads: &str| {
+        let output = colorizer(&dir)
+            .arg(&input)
+            .arg("--output")
+            .arg(dir.join("out"))
+            .args([
+                "--threads",
+                threads,
+                "--cpu",
+                "--overwrite",
+                "--progress",
+                "none",
+            ])
+            .args(["--dither-amount", "0"])
+            .output()
+            .unwrap();
+        assert!(
+            output.status.success(),
+            "{}",
+            String::from_utf8_lossy(&output.stderr)
+        );
+        fs::read(dir.join("out/input_kanagawa.png")).unwrap()
+    };
+
+    assert_eq!(colorize("1"), colorize("4"));
+
+    let output = colorizer(&dir)
+        .arg(&input)
+        .args(["--threads", "0"])
+        .output()
+        .unwrap();
+    assert!(String::from_utf8_lossy(&output.stderr).contains("not a positive integer"));
+
+    fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn outputs_are_written_to_a_temporary_file_and_renamed_into_place() {
+    let dir = temp_dir("rename-output");
+    let input = dir.join("input.png");
+    fs::write(&input, encoded_png(0)).unwrap();
+
+    let run = || {
+        colorizer(&dir)
+            .arg(&input)
+            .arg("--output")
+            .arg(dir.join("out"))
+            .args(["--cpu", "--overwrite", "--progress", "none"])
+            .output()
+            .unwrap()
+    };
+
+    let output = run();
+    assert!(
+        output.status.success(),
+        "{}",
+        String::from_utf8_lossy(&output.stderr)
+    );
+    assert!(image::open(dir.join("out/input_kanagawa.png")).is_ok());
+    assert!(!dir.join("out/input_kanagawa.png.tmp").exists());
+
+    // A directory in the way of the output can't be replaced, and the temporary file is cleaned up
+    fs::remove_file(dir.join("out/input_kanagawa.png")).unwrap();
+    fs::create_dir_all(dir.join("out/input_kanagawa.png/blocked")).unwrap();
+    let output = run();
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(!output.status.success());
+    assert!(stderr.contains("into place"), "{}", stderr);
+    assert!(!dir.join("out/input_kanagawa.png.tmp").exists());
+
+    fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn build_info_is_printed_as_json() {
+    let dir = temp_dir("build-info");
+    let output = colorizer(&dir).arg("--build-info").output().unwrap();
+    assert!(output.status.success());
+    fs::remove_dir_all(&dir).unwrap();
+
+    let info: serde_json::Value = serde_json::from_slice(&output.stdout).unwrap();
+    assert_eq!(info["version"], env!("CARGO_PKG_VERSION"));
+    assert!(info["git_commit"].is_string());
+    assert!(info["wgpu_version"].as_str().unwrap().starts_with("0.16"));
+    assert!(info["backends"].is_array() && info["adapters"].is_array());
+}
+
+#[test]
+fn environment_variables_override_the_config_file_but_not_flags() {
+    let dir = temp_dir("environment");
+    let input = dir.join("input.png");
+    fs::write(&input, encoded_png(0)).unwrap();
+    fs::create_dir_all(dir.join(".config/image-colorizer")).unwrap();
+    fs::write(
+        dir.join(".config/image-colorizer/config.toml"),
+        "colorscheme = \"nord\"\n",
+    )
+    .unwrap();
+
+    let dry_run = |flags: &[&str]| {
+        let output = colorizer(&dir)
+            .arg(&input)
+            .arg("--output")
+            .arg(dir.join("out"))
+            .arg("--dry-run")
+            .args(flags)
+            .env("IMAGE_COLORIZER_COLORSCHEME", "dracula")
+            .env("IMAGE_COLORIZER_PRESERVE_LUMINANCE", "true")
+            .output()
+            .unwrap();
+        let stdout = String::from_utf8_lossy(&output.stdout).to_string();
+        assert!(output.status.success(), "{}", stdout);
+        stdout
+    };
+
+    assert!(dry_run(&[]).contains("input_dracula.png"));
+    assert!(dry_run(&["--colorscheme", "gruvbox"]).contains("input_gruvbox.png"));
+
+    fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn the_last_interpolation_flag_overrides_the_config_file() {
+    let dir = temp_dir("interpolation");
+    fs::create_dir_all(dir.join(".config/image-colorizer")).unwrap();
+    fs::write(
+        dir.join(".config/image-colorizer/config.toml"),
+        "interpolate_colors = false\n",
+    )
+    .unwrap();
+
+    let interpolates = |flags: &[&str]| {
+        let output = colorizer(&dir)
+            .args(flags)
+            .arg("--palette-preview")
+            .arg(dir.join("out/preview.png"))
+            .arg("--verbose")
+            .output()
+            .unwrap();
+        let stderr = String::from_utf8_lossy(&output.stderr).to_string();
+        assert!(output.status.success(), "{}", stderr);
+        stderr.contains("Interpolated")
+    };
+
+    assert!(!interpolates(&[]));
+    assert!(interpolates(&["--interpolate-colors"]));
+    assert!(!interpolates(&["--interpolate-colors", "--no-interpolate"]));
+    assert!(interpolates(&["--no-interpolation", "-i"]));
+
+    fs::remove_dir_all(&dir).unwrap();
+}
+
+/// The settings recorded in the manifest after colorizing a single image with `flags`, with
+/// `config` as the config file
+fn settings_with_config(dir: &Path, config: &str, flags: &[&str]) -> serde_json::Value {
+    fs::create_dir_all(dir.join(".config/image-colorizer")).unwrap();
+    fs::write(dir.join(".config/image-colorizer/config.toml"), config).unwrap();
+    fs::write(dir.join("input.png"), encoded_png(0)).unwrap();
+
+    let output = colorizer(dir)
+        .arg(dir.join("input.png"))
+        .arg("--output")
+        .arg(dir.join("out"))
+        .arg("--manifest")
+        .arg(dir.join("manifest.json"))
+        .args(["--cpu", "--progress", "none", "--overwrite"])
+        .args(flags)
+        .output()
+        .unwrap();
+    assert!(
+        output.status.success(),
+        "{}",
+        String::from_utf8_lossy(&output.stderr)
+    );
+
+    let manifest: serde_json::Value =
+        serde_json::from_slice(&fs::read(dir.join("manifest.json")).unwrap()).unwrap();
+    manifest["settings"].clone()
+}
+
+#[test]
+fn the_last_linear_blend_flag_overrides_the_config_file() {
+    let dir = temp_dir("linear-blend");
+    let blends_linearly = |flags: &[&str]| {
+        settings_with_config(&dir, "linear_blend = true\n", flags)["linear_blend"] == true
+    };
+
+    assert!(blends_linearly(&[]));
+    assert!(!blends_linearly(&["--no-linear-blend"]));
+    assert!(!blends_linearly(&["--linear-blend", "--no-linear-blend"]));
+    assert!(blends_linearly(&["--no-linear-blend", "--linear-blend"]));
+
+    fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn the_last_preserve_luminance_flag_overrides_the_config_file() {
+    let dir = temp_dir("preserve-luminance");
+    let preserves_luminance = |flags: &[&str]| {
+        settings_with_config(&dir, "preserve_luminance = true\n", flags)["preserve_luminance"]
+            == true
+    };
+
+    assert!(preserves_luminance(&[]));
+    assert!(!preserves_luminance(&["--no-preserve-luminance"]));
+    assert!(!preserves_luminance(&[
+        "--preserve-luminance",
+        "--no-preserve-luminance"
+    ]));
+    assert!(preserves_luminance(&[
+        "--no-preserve-luminance",
+        "--preserve-luminance"
+    ]));
+
+    fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn copy_on_failure_saves_the_original_in_place_of_a_failed_output() {
+    let dir = temp_dir("copy-on-failure");
+    let inputs = ["first.png", "truncated.png"].map(|name| dir.join(name));
+    fs::write(&inputs[0], encoded_png(0)).unwrap();
+    let truncated = encoded_png(1);
+    fs::write(&inputs[1], &truncated[..truncated.len() / 2]).unwrap();
+
+    let output = colorizer(&dir)
+        .args(&inputs)
+        .arg("--output")
+        .arg(dir.join("out"))
+        .args(["--cpu", "--copy-on-failure"])
+        .output()
+        .unwrap();
+    let stderr = String::from_utf8_lossy(&output.stderr);
+
+    assert!(output.status.success(), "{}", stderr);
+    assert!(dir.join("out/first_kanagawa.png").is_file());
+    assert_eq!(
+        fs::read(dir.join("out/truncated_kanagawa.png")).unwrap(),
+        &truncated[..truncated.len() / 2]
+    );
+    assert!(!dir.join("out/truncated_kanagawa.png.tmp").exists());
+    assert!(
+        stderr.contains(&format!("Failed to colorize {}", inputs[1].display())),
+        "{}",
+        stderr
+    );
+    assert!(stderr.contains("Copied the original"), "{}", stderr);
+
+    fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn copied_originals_are_converted_to_the_output_format() {
+    let dir = temp_dir("copy-on-failure-format");
+    let inputs = ["first.png", "truncated.png"].map(|name| dir.join(name));
+    fs::write(&inputs[0], encoded_png(0)).unwrap();
+    let truncated = encoded_png(1);
+    fs::write(&inputs[1], &truncated[..truncated.len() / 2]).unwrap();
+    // A mask the wrong size fails every image after it has been decoded
+    RgbImage::new(8, 8).save(dir.join("mask.png")).unwrap();
+
+    let output = colorizer(&dir)
+        .args(&inputs)
+        .arg("--output")
+        .arg(dir.join("out"))
+        .arg("--mask")
+        .arg(dir.join("mask.png"))
+        .args(["--mask-resize", "error", "--format", "bmp"])
+        .args(["--cpu", "--copy-on-failure"])
+        .output()
+        .unwrap();
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(output.status.success(), "{}", stderr);
+
+    let converted = fs::read(dir.join("out/first_kanagawa.bmp")).unwrap();
+    assert_eq!(image::guess_format(&converted).unwrap(), ImageFormat::Bmp);
+    assert_eq!(
+        image::load_from_memory(&converted).unwrap().to_rgb8(),
+        image::load_from_memory(&encoded_png(0)).unwrap().to_rgb8()
+    );
+    // Originals that can't be decoded are still copied as they are
+    assert_eq!(
+        fs::read(dir.join("out/truncated_kanagawa.bmp")).unwrap(),
+        &truncated[..truncated.len() / 2]
+    );
+
+    fs::remove_dir_all(&dir).unwrap();
+}
+
+#[cfg(target_os = "linux")]
+#[test]
+fn config_is_read_from_xdg_config_home() {
+    let dir = temp_dir("xdg-config-home");
+    let input = dir.join("input.png");
+    fs::write(&input, encoded_png(0)).unwrap();
+    fs::create_dir_all(dir.join("xdg/image-colorizer")).unwrap();
+    fs::write(
+        dir.join("xdg/image-colorizer/config.toml"),
+        "colorscheme = \"nord\"\n",
+    )
+    .unwrap();
+
+    let output = colorizer(&dir)
+        .arg(&input)
+        .arg("--output")
+        .arg(dir.join("out"))
+        .arg("--dry-run")
+        .env("XDG_CONFIG_HOME", dir.join("xdg"))
+        .output()
+        .unwrap();
+    let stdout = String::from_utf8_lossy(&output.stdout);
+
+    assert!(output.status.success(), "{}", stdout);
+    assert!(stdout.contains("input_nord.png"), "{}", stdout);
+
+    fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn manifest_records_settings_and_the_result_of_every_image() {
+    let dir = temp_dir("manifest");
+    let inputs = ["first.png", "truncated.png"].map(|name| dir.join(name));
+    fs::write(&inputs[0], encoded_png(0)).unwrap();
+    let truncated = encoded_png(1);
+    fs::write(&inputs[1], &truncated[..truncated.len() / 2]).unwrap();
+
+    let output = colorizer(&dir)
+        .args(&inputs)
+        .arg("--output")
+        .arg(dir.join("out"))
+        .arg("--manifest")
+        .arg(dir.join("manifest.json"))
+        .args(["--cpu", "--progress", "none", "--blend-factor", "0.7"])
+        .output()
+        .unwrap();
+    assert_eq!(output.status.code(), Some(1));
+
+    let manifest: serde_json::Value =
+        serde_json::from_slice(&fs::read(dir.join("manifest.json")).unwrap()).unwrap();
+    assert_eq!(manifest["settings"]["blend_factor"], 0.7);
+    assert_eq!(manifest["settings"]["dither_mode"], "noise");
+    assert_eq!(manifest["schemes"][0]["name"], "kanagawa");
+    assert_eq!(
+        manifest["schemes"][0]["palette_hash"]
+            .as_str()
+            .unwrap()
+            .len(),
+        16
+    );
+
+    let images = manifest["images"].as_array().unwrap();
+    assert_eq!(images.len(), 2);
+    assert_eq!(images[0]["input"], inputs[0].to_str().unwrap());
+    assert_eq!(
+        images[0]["output"],
+        dir.join("out/first_kanagawa.png").to_str().unwrap()
+    );
+    assert_eq!(images[0]["status"], "succeeded");
+    assert_eq!(images[0]["blend_factor"], 0.7);
+    assert!(images[0]["duration_ms"].as_f64().unwrap() > 0.0);
+    assert_eq!(images[1]["status"], "failed");
+    assert!(images[1]["error"].is_string());
+    assert!(images[1]["blend_factor"].is_null());
+
+    fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn colorize_errors_fail_the_image_instead_of_panicking() {
+    let dir = temp_dir("colorize-error");
+    let input = dir.join("input.png");
+    fs::write(&input, encoded_png(0)).unwrap();
+    let mask = dir.join("mask.png");
+    RgbImage::new(8, 8).save(&mask).unwrap();
+
+    // A mask that doesn't fit the image is only an error while colorizing
+    let output = colorizer(&dir)
+        .arg(&input)
+        .arg("--output")
+        .arg(dir.join("out"))
+        .args(["--cpu", "--mask-resize", "error", "--mask"])
+        .arg(&mask)
+        .output()
+        .unwrap();
+    let stderr = String::from_utf8_lossy(&output.stderr);
+
+    assert_eq!(output.status.code(), Some(1), "{}", stderr);
+    assert!(!stderr.contains("panicked"), "{}", stderr);
+    assert!(
+        stderr.contains("The mask is 8x8, but the image is 32x32"),
+        "{}",
+        stderr
+    );
+    assert!(!dir.join("out/input_kanagawa.png").exists());
+
+    fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn auto_blend_prints_the_chosen_factor_unless_one_is_given() {
+    let dir = temp_dir("auto-blend");
+    let input = dir.join("input.png");
+    fs::write(&input, encoded_png(0)).unwrap();
+
+    let run = |args: &[&str]| {
+        let output = colorizer(&dir)
+            .arg(&input)
+            .arg("--output")
+            .arg(dir.join("out"))
+            .args(["--cpu", "--progress", "json", "--auto-blend"])
+            .args(args)
+            .output()
+            .unwrap();
+        let stderr = String::from_utf8_lossy(&output.stderr).to_string();
+        assert!(output.status.success(), "{}", stderr);
+        stderr
+    };
+
+    let chosen = run(&[]);
+    let line = chosen
+        .lines()
+        .find(|line| line.contains("\"auto_blend\""))
+        .unwrap_or_else(|| panic!("{}", chosen));
+    let line: serde_json::Value = serde_json::from_str(line).unwrap();
+    let blend_factor = line["blend_factor"].as_f64().unwrap();
+    assert!((0.4..=1.0).contains(&blend_factor), "{}", line);
+
+    let given = run(&["--blend-factor", "0.5"]);
+    assert!(!given.contains("\"auto_blend\""), "{}", given);
+
+    fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn sidecar_blend_factors_take_precedence_over_auto_blend_in_the_manifest() {
+    let dir = temp_dir("auto-blend-sidecar");
+    let inputs = ["chosen.png", "sidecar.png"].map(|name| dir.join(name));
+    for input in &inputs {
+        fs::write(input, encoded_png(0)).unwrap();
+    }
+    fs::write(dir.join("sidecar.png.toml"), "blend_factor = 0.25\n").unwrap();
+
+    let output = colorizer(&dir)
+        .args(&inputs)
+        .arg("--output")
+        .arg(dir.join("out"))
+        .arg("--manifest")
+        .arg(dir.join("manifest.json"))
+        .args(["--cpu", "--progress", "json", "--auto-blend"])
+        .output()
+        .unwrap();
+    let stderr = String::from_utf8_lossy(&output.stderr).to_string();
+    assert!(output.status.success(), "{}", stderr);
+
+    // Only the image without a sidecar file has its blend factor chosen
+    let chosen: Vec<serde_json::Value> = stderr
+        .lines()
+        .filter(|line| line.contains("\"auto_blend\""))
+        .map(|line| serde_json::from_str(line).unwrap())
+        .collect();
+    assert_eq!(chosen.len(), 1, "{}", stderr);
+    assert_eq!(chosen[0]["path"], inputs[0].to_str().unwrap());
+
+    let manifest: serde_json::Value =
+        serde_json::from_slice(&fs::read(dir.join("manifest.json")).unwrap()).unwrap();
+    let images = manifest["images"].as_array().unwrap();
+    assert_eq!(images[0]["blend_factor"], chosen[0]["blend_factor"]);
+    assert_eq!(images[1]["blend_factor"], 0.25);
+
+    fs::remove_dir_all(&dir).unwrap();
+}
+
+#[cfg(unix)]
+#[test]
+fn palettes_are_extracted_from_the_first_frame_of_videos() {
+    use std::os::unix::fs::PermissionsExt;
+
+    let dir = temp_dir("palette-from-video");
+    let input = dir.join("input.png");
+    fs::write(&input, encoded_png(0)).unwrap();
+    fs::write(dir.join("frame.png"), encoded_png(2)).unwrap();
+    fs::write(dir.join("clip.mp4"), b"not really a video").unwrap();
+
+    // Stands in for ffmpeg, writing a frame to stdout when it's asked for the first one
+    let bin = dir.join("bin");
+    fs::create_dir_all(&bin).unwrap();
+    let ffmpeg = bin.join("ffmpeg");
+    fs::write(
+        &ffmpeg,
+        format!(
+            "#!/bin/sh\ncase \"$*\" in *\"-frames:v 1\"*) cat '{}' ;; *) exit 1 ;; esac\n",
+            dir.join("frame.png").display()
+        ),
+    )
+    .unwrap();
+    fs::set_permissions(&ffmpeg, fs::Permissions::from_mode(0o755)).unwrap();
+
+    let run = |path: &std::ffi::OsStr| {
+        colorizer(&dir)
+            .arg(&input)
+            .arg("--output")
+            .arg(dir.join("out"))
+            .args(["--cpu", "--palette-size", "4", "--palette-from"])
+            .arg(dir.join("clip.mp4"))
+            .env("PATH", path)
+            .output()
+            .unwrap()
+    };
+
+    let mut path = std::ffi::OsString::from(&bin);
+    path.push(":");
+    path.push(std::env::var_os("PATH").unwrap_or_default());
+    let output = run(&path);
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(output.status.success(), "{}", stderr);
+    assert!(dir.join("out/input_clip.png").is_file());
+
+    let output = run(dir.join("missing").as_os_str());
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(!output.status.success());
+    assert!(stderr.contains("Install ffmpeg"), "{}", stderr);
+
+    fs::remove_dir_all(&dir).unwrap();
+}
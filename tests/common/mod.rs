@@ -0,0 +1,70 @@
+// Each test file only uses some of these
+#![allow(dead_code)]
+
+use std::fmt::Debug;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+
+use image::{ImageBuffer, Pixel};
+use image_colorizer::{init_gpu, Gpu};
+use palette::{FromColor, Lab, Srgb};
+
+/// A dark, a mid and a light color, which is enough for every pixel to have a clear nearest color
+pub fn palette() -> Vec<Lab> {
+    [(0.1, 0.1, 0.15), (0.5, 0.6, 0.8), (0.9, 0.85, 0.7)]
+        .iter()
+        .map(|&(r, g, b)| Lab::from_color(Srgb::new(r, g, b)))
+        .collect()
+}
+
+/// The GPU to compare against, or `None` when there is no adapter, in which case the test should
+/// return early
+pub async fn gpu_or_skip() -> Option<Gpu> {
+    let gpu = init_gpu().await.unwrap();
+    if gpu.is_none() {
+        eprintln!("No GPU adapter found, skipping test");
+    }
+    gpu
+}
+
+/// Asserts that two images are the same size and that no channel of any pixel differs by more
+/// than `tolerance`
+pub fn assert_close<P>(a: &ImageBuffer<P, Vec<u8>>, b: &ImageBuffer<P, Vec<u8>>, tolerance: u8)
+where
+    P: Pixel<Subpixel = u8> + Debug,
+{
+    assert_eq!(a.dimensions(), b.dimensions());
+    for ((x, y, a), b) in a.enumerate_pixels().zip(b.pixels()) {
+        let close = a
+            .channels()
+            .iter()
+            .zip(b.channels())
+            .all(|(a, b)| a.abs_diff(*b) <= tolerance);
+        assert!(close, "{:?} != {:?} at ({}, {})", a, b, x, y);
+    }
+}
+
+/// A new empty directory for a test, with an `out` directory in it for outputs. `name` keeps tests
+/// that run at the same time from sharing one
+pub fn temp_dir(name: &str) -> PathBuf {
+    let dir = std::env::temp_dir().join(format!("image-colorizer-{}-{}", name, std::process::id()));
+    let _ = fs::remove_dir_all(&dir);
+    fs::create_dir_all(dir.join("out")).unwrap();
+    dir
+}
+
+/// A command that runs the binary with `dir` as its home and config directory, and without any
+/// `IMAGE_COLORIZER_` variables from the environment, so that no user config is picked up
+pub fn colorizer(dir: &Path) -> Command {
+    let mut command = Command::new(env!("CARGO_BIN_EXE_image-colorizer"));
+    command
+        .env("HOME", dir)
+        .env("XDG_CONFIG_HOME", dir.join(".config"));
+    for (key, _) in std::env::vars_os() {
+        if key.to_string_lossy().starts_with("IMAGE_COLORIZER_") {
+            command.env_remove(key);
+        }
+    }
+    command
+}
@@ -0,0 +1,121 @@
+mod common;
+
+use std::fs;
+use std::io::{Cursor, Read, Write};
+use std::net::TcpListener;
+use std::path::Path;
+use std::process::Output;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::thread;
+
+use image::{ImageOutputFormat, Rgb, RgbImage};
+
+use common::{colorizer, temp_dir};
+
+/// Serves a colorscheme repository that answers each request with the next of `statuses`,
+/// returning its URL and the number of requests it has answered
+fn serve(statuses: &'static [u16]) -> (String, Arc<AtomicUsize>) {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let url = format!("http://{}", listener.local_addr().unwrap());
+    let requests = Arc::new(AtomicUsize::new(0));
+
+    let counter = Arc::clone(&requests);
+    thread::spawn(move || {
+        for &status in statuses {
+            let (mut stream, _) = listener.accept().unwrap();
+            let mut request = Vec::new();
+            let mut buffer = [0; 1024];
+            while !request.windows(4).any(|window| window == b"\r\n\r\n") {
+                let read = stream.read(&mut buffer).unwrap();
+                if read == 0 {
+                    break;
+                }
+                request.extend_from_slice(&buffer[..read]);
+            }
+            counter.fetch_add(1, Ordering::SeqCst);
+
+            let body = if status == 200 {
+                "#1f1f28\n#dcd7ba\n"
+            } else {
+                ""
+            };
+            let _ = write!(
+                stream,
+                "HTTP/1.1 {} Status\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+                status,
+                body.len(),
+                body
+            );
+        }
+    });
+
+    (url, requests)
+}
+
+fn colorize_with_scheme_repo(dir: &Path, url: &str, retries: &str) -> Output {
+    let input = dir.join("input.png");
+    let img = RgbImage::from_fn(16, 16, |x, y| Rgb([(x * 16) as u8, (y * 16) as u8, 128]));
+    let mut encoded = Cursor::new(Vec::new());
+    img.write_to(&mut encoded, ImageOutputFormat::Png).unwrap();
+    fs::write(&input, encoded.into_inner()).unwrap();
+
+    // HOME is pointed at the test directory so that the scheme isn't found locally
+    colorizer(dir)
+        .arg(&input)
+        .arg("--output")
+        .arg(dir.join("out"))
+        .args(["--colorscheme", "flaky", "--scheme-repo", url])
+        .args(["--retries", retries, "--cpu", "--progress", "none"])
+        .output()
+        .unwrap()
+}
+
+#[test]
+fn downloads_are_retried_after_server_errors() {
+    let dir = temp_dir("download-retry");
+    let (url, requests) = serve(&[503, 500, 200]);
+
+    let output = colorize_with_scheme_repo(&dir, &url, "2");
+
+    assert!(
+        output.status.success(),
+        "{}",
+        String::from_utf8_lossy(&output.stderr)
+    );
+    assert_eq!(requests.load(Ordering::SeqCst), 3);
+    assert!(dir.join("out/input_flaky.png").is_file());
+
+    fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn downloads_give_up_after_the_last_retry() {
+    let dir = temp_dir("download-give-up");
+    let (url, requests) = serve(&[503, 503]);
+
+    let output = colorize_with_scheme_repo(&dir, &url, "1");
+    let stderr = String::from_utf8_lossy(&output.stderr);
+
+    assert!(!output.status.success());
+    assert!(stderr.contains("gave up after 2 attempts"), "{}", stderr);
+    assert_eq!(requests.load(Ordering::SeqCst), 2);
+
+    fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn missing_colorschemes_are_not_retried() {
+    let dir = temp_dir("download-not-found");
+    let (url, requests) = serve(&[404, 200]);
+
+    let output = colorize_with_scheme_repo(&dir, &url, "2");
+    let stderr = String::from_utf8_lossy(&output.stderr);
+
+    assert!(!output.status.success());
+    assert!(stderr.contains("404"), "{}", stderr);
+    assert!(!stderr.contains("attempts"), "{}", stderr);
+    assert_eq!(requests.load(Ordering::SeqCst), 1);
+
+    fs::remove_dir_all(&dir).unwrap();
+}
@@ -0,0 +1,55 @@
+mod common;
+
+use std::io::Cursor;
+
+use image::{ImageOutputFormat, Rgb, RgbImage, Rgba, RgbaImage};
+use image_colorizer::{colorize_bytes, ColorizeOptions};
+
+use common::palette;
+
+fn encode(img: impl Into<image::DynamicImage>, format: ImageOutputFormat) -> Vec<u8> {
+    let mut encoded = Cursor::new(Vec::new());
+    img.into().write_to(&mut encoded, format).unwrap();
+    encoded.into_inner()
+}
+
+#[tokio::test]
+async fn colorize_bytes_returns_a_png_of_the_same_size() {
+    let img = RgbImage::from_fn(40, 30, |x, y| Rgb([(x * 6) as u8, (y * 8) as u8, 100]));
+    let jpeg = encode(img, ImageOutputFormat::Jpeg(90));
+
+    let png = colorize_bytes(&jpeg, &palette(), &ColorizeOptions::default(), None, None)
+        .await
+        .unwrap();
+
+    assert_eq!(image::guess_format(&png).unwrap(), image::ImageFormat::Png);
+    let colorized = image::load_from_memory(&png).unwrap();
+    assert_eq!((colorized.width(), colorized.height()), (40, 30));
+}
+
+#[tokio::test]
+async fn colorize_bytes_keeps_alpha() {
+    let img = RgbaImage::from_fn(8, 8, |x, _| Rgba([200, 50, 50, (x * 30) as u8]));
+    let png = encode(img, ImageOutputFormat::Png);
+
+    let colorized = colorize_bytes(&png, &palette(), &ColorizeOptions::default(), None, None)
+        .await
+        .unwrap();
+    let colorized = image::load_from_memory(&colorized).unwrap().to_rgba8();
+
+    assert_eq!(colorized.get_pixel(3, 0)[3], 90);
+}
+
+#[tokio::test]
+async fn colorize_bytes_rejects_data_that_is_not_an_image() {
+    let err = colorize_bytes(
+        b"not an image",
+        &palette(),
+        &ColorizeOptions::default(),
+        None,
+        None,
+    )
+    .await
+    .unwrap_err();
+    assert!(err.to_string().contains("Failed to decode"), "{}", err);
+}
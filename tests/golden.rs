@@ -0,0 +1,141 @@
+mod common;
+
+use std::path::{Path, PathBuf};
+
+use image::{DynamicImage, RgbaImage};
+use image_colorizer::utils::hex_to_rgb;
+use image_colorizer::{colorize_image, ColorSpace, ColorizeOptions, DitherMode, Gpu};
+use palette::{FromColor, Lab};
+
+use common::gpu_or_skip;
+
+/// How far each channel of an output can be from its golden, which leaves room for floating point
+/// differences between platforms and between the GPU and the CPU
+const TOLERANCE: u8 = 2;
+
+fn fixtures_dir() -> PathBuf {
+    Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/fixtures")
+}
+
+fn input() -> DynamicImage {
+    image::open(fixtures_dir().join("input.png")).unwrap()
+}
+
+fn palette() -> Vec<Lab> {
+    [
+        "#1f1f28", "#dcd7ba", "#c34043", "#76946a", "#c0a36e", "#7e9cd8", "#957fb8", "#6a9589",
+    ]
+    .iter()
+    .map(|hex| Lab::from_color(hex_to_rgb(hex).unwrap().0))
+    .collect()
+}
+
+/// Each golden is named after the options it was colorized with. Noise dithering is seeded so that
+/// its pattern doesn't change between runs
+fn cases() -> Vec<(&'static str, ColorizeOptions)> {
+    vec![
+        (
+            "default",
+            ColorizeOptions {
+                seed: Some(1),
+                ..ColorizeOptions::default()
+            },
+        ),
+        (
+            "preserve-luminance-ordered",
+            ColorizeOptions {
+                preserve_luminance: true,
+                dither_mode: DitherMode::Ordered,
+                dither_amount: 0.3,
+                ..ColorizeOptions::default()
+            },
+        ),
+        (
+            "blue-noise",
+            ColorizeOptions {
+                dither_mode: DitherMode::BlueNoise,
+                dither_amount: 0.4,
+                seed: Some(7),
+                ..ColorizeOptions::default()
+            },
+        ),
+        (
+            "soft-match",
+            ColorizeOptions {
+                soft_match: true,
+                blend_factor: 0.6,
+                dither_mode: DitherMode::None,
+                ..ColorizeOptions::default()
+            },
+        ),
+        (
+            "oklab-floyd-steinberg",
+            ColorizeOptions {
+                color_space: ColorSpace::Oklab,
+                dither_mode: DitherMode::FloydSteinberg,
+                spatial_averaging_radius: 4,
+                ..ColorizeOptions::default()
+            },
+        ),
+    ]
+}
+
+async fn colorize(options: &ColorizeOptions, gpu: Option<&Gpu>) -> RgbaImage {
+    colorize_image(&input(), &palette(), options, gpu, None)
+        .await
+        .unwrap()
+        .to_rgba8()
+}
+
+/// Compares `output` to the golden named `name`, or replaces the golden with it when
+/// `UPDATE_GOLDENS` is set, such as after a change that is meant to alter the output
+fn check_golden(name: &str, output: &RgbaImage) {
+    let path = fixtures_dir().join("golden").join(format!("{}.png", name));
+    if std::env::var_os("UPDATE_GOLDENS").is_some() {
+        std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+        output.save(&path).unwrap();
+        return;
+    }
+
+    let golden = image::open(&path)
+        .unwrap_or_else(|e| panic!("Failed to open golden '{}': {}", path.display(), e))
+        .to_rgba8();
+    assert_eq!(output.dimensions(), golden.dimensions(), "{}", name);
+
+    let mismatches: Vec<_> = output
+        .enumerate_pixels()
+        .zip(golden.pixels())
+        .filter(|((_, _, a), b)| (0..4).any(|c| a[c].abs_diff(b[c]) > TOLERANCE))
+        .map(|((x, y, a), b)| (x, y, a.0, b.0))
+        .collect();
+    assert!(
+        mismatches.is_empty(),
+        "{} pixels of '{}' differ from the golden by more than {}, starting with {:?}",
+        mismatches.len(),
+        name,
+        TOLERANCE,
+        mismatches[0]
+    );
+}
+
+#[tokio::test]
+async fn cpu_output_matches_the_goldens() {
+    for (name, options) in cases() {
+        check_golden(name, &colorize(&options, None).await);
+    }
+}
+
+#[tokio::test]
+async fn gpu_output_matches_the_goldens() {
+    // The goldens are always written from the CPU
+    if std::env::var_os("UPDATE_GOLDENS").is_some() {
+        return;
+    }
+    let Some(gpu) = gpu_or_skip().await else {
+        return;
+    };
+
+    for (name, options) in cases() {
+        check_golden(name, &colorize(&options, Some(&gpu)).await);
+    }
+}
@@ -0,0 +1,200 @@
+mod common;
+
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use image::codecs::gif::GifEncoder;
+use image::codecs::ico::{IcoEncoder, IcoFrame};
+use image::{ColorType, DynamicImage, Frame, Rgb, RgbImage, Rgba, RgbaImage};
+
+use common::{colorizer, temp_dir};
+
+/// Colorizes `input` to `output`, which is a template with only `{stem}` in it, and returns the
+/// path of the output
+fn colorize(dir: &Path, input: &Path, output: &str, args: &[&str]) -> PathBuf {
+    let status = colorizer(dir)
+        .arg(input)
+        .arg("--output")
+        .arg(dir.join(output))
+        .args(["--cpu", "--progress", "none"])
+        .args(args)
+        .status()
+        .unwrap();
+    assert!(status.success());
+    dir.join(output.replace("{stem}", "input"))
+}
+
+#[test]
+fn webp_compression_selects_lossless_or_lossy() {
+    let dir = temp_dir("webp");
+    let input = dir.join("input.png");
+    RgbImage::from_fn(64, 64, |x, y| {
+        Rgb([(x * 4) as u8, (y * 4) as u8, ((x * y) % 256) as u8])
+    })
+    .save(&input)
+    .unwrap();
+
+    let png = image::open(colorize(&dir, &input, "{stem}-out.png", &[])).unwrap();
+    let lossless = colorize(&dir, &input, "{stem}-lossless.webp", &[]);
+    let lossy = colorize(
+        &dir,
+        &input,
+        "{stem}-lossy.webp",
+        &["--webp-compression", "lossy"],
+    );
+
+    assert_eq!(image::open(&lossless).unwrap().to_rgb8(), png.to_rgb8());
+    assert_ne!(image::open(&lossy).unwrap().to_rgb8(), png.to_rgb8());
+    assert!(fs::metadata(&lossy).unwrap().len() < fs::metadata(&lossless).unwrap().len());
+
+    // Setting the quality makes WebP lossy unless lossless is asked for
+    let with_quality = colorize(
+        &dir,
+        &input,
+        "{stem}-quality.webp",
+        &["--quality", "90", "--webp-compression", "lossless"],
+    );
+    assert_eq!(image::open(&with_quality).unwrap().to_rgb8(), png.to_rgb8());
+
+    fs::remove_dir_all(&dir).unwrap();
+}
+
+/// Decodes every entry of an icon written by the colorizer, which are all PNGs
+fn icon_entries(encoded: &[u8]) -> Vec<DynamicImage> {
+    let count = u16::from_le_bytes([encoded[4], encoded[5]]) as usize;
+    (0..count)
+        .map(|i| {
+            let entry = &encoded[6 + i * 16..6 + (i + 1) * 16];
+            let size = u32::from_le_bytes(entry[8..12].try_into().unwrap()) as usize;
+            let offset = u32::from_le_bytes(entry[12..16].try_into().unwrap()) as usize;
+            image::load_from_memory(&encoded[offset..offset + size]).unwrap()
+        })
+        .collect()
+}
+
+#[test]
+fn every_resolution_of_an_icon_is_colorized() {
+    let dir = temp_dir("icon");
+    let sizes = [16, 48, 32];
+    let images: Vec<RgbaImage> = sizes
+        .iter()
+        .map(|&size| {
+            RgbaImage::from_fn(size, size, |x, y| {
+                Rgba([(x * 255 / size) as u8, (y * 255 / size) as u8, 90, 255])
+            })
+        })
+        .collect();
+
+    let input = dir.join("input.ico");
+    let frames: Vec<_> = images
+        .iter()
+        .map(|img| IcoFrame::as_png(img, img.width(), img.height(), ColorType::Rgba8).unwrap())
+        .collect();
+    IcoEncoder::new(fs::File::create(&input).unwrap())
+        .encode_images(&frames)
+        .unwrap();
+
+    let output = fs::read(colorize(&dir, &input, "{stem}-out.ico", &[])).unwrap();
+    let entries = icon_entries(&output);
+    assert_eq!(entries.len(), sizes.len());
+
+    // Each resolution is colorized just like the same image on its own
+    for (entry, img) in entries.iter().zip(&images) {
+        let still = dir.join(format!("input{}.png", img.width()));
+        img.save(&still).unwrap();
+        colorize(&dir, &still, "{stem}-out.png", &[]);
+        let expected = image::open(dir.join(format!("input{}-out.png", img.width()))).unwrap();
+        assert_eq!(entry.to_rgba8(), expected.to_rgba8(), "{}", img.width());
+    }
+
+    // Other formats get the largest resolution, which is all that image decodes
+    let png = image::open(colorize(&dir, &input, "{stem}-out.png", &[])).unwrap();
+    assert_eq!((png.width(), png.height()), (48, 48));
+
+    fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn overlays_are_composited_onto_the_output() {
+    let dir = temp_dir("compose-with");
+    let input = dir.join("input.png");
+    RgbImage::from_fn(32, 32, |x, y| Rgb([(x * 8) as u8, (y * 8) as u8, 128]))
+        .save(&input)
+        .unwrap();
+    let overlay = dir.join("overlay.png");
+    RgbaImage::from_fn(8, 8, |x, _| {
+        Rgba([255, 0, 255, if x < 4 { 255 } else { 0 }])
+    })
+    .save(&overlay)
+    .unwrap();
+
+    let plain = image::open(colorize(&dir, &input, "{stem}-plain.png", &[]))
+        .unwrap()
+        .to_rgba8();
+    let overlay = overlay.to_str().unwrap();
+    let composed = image::open(colorize(
+        &dir,
+        &input,
+        "{stem}-composed.png",
+        &[
+            "--compose-with",
+            overlay,
+            "--compose-position",
+            "top-left+2+2",
+        ],
+    ))
+    .unwrap()
+    .to_rgba8();
+
+    for (x, y, pixel) in composed.enumerate_pixels() {
+        let covered = (2..6).contains(&x) && (2..10).contains(&y);
+        if covered {
+            assert_eq!(pixel, &Rgba([255, 0, 255, 255]), "{}, {}", x, y);
+        } else {
+            assert_eq!(pixel, plain.get_pixel(x, y), "{}, {}", x, y);
+        }
+    }
+
+    fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn overlays_are_rejected_for_animations() {
+    let dir = temp_dir("compose-with-animation");
+    let input = dir.join("input.gif");
+    let frames = [40, 200].map(|red| {
+        Frame::new(RgbaImage::from_fn(16, 16, |x, y| {
+            Rgba([red, (x * 16) as u8, (y * 16) as u8, 255])
+        }))
+    });
+    GifEncoder::new(fs::File::create(&input).unwrap())
+        .encode_frames(frames)
+        .unwrap();
+    let overlay = dir.join("overlay.png");
+    RgbaImage::from_pixel(4, 4, Rgba([255, 0, 255, 255]))
+        .save(&overlay)
+        .unwrap();
+
+    // The animation colorizes without an overlay, so only the overlay is rejected
+    colorize(&dir, &input, "{stem}-plain.gif", &[]);
+
+    let output = colorizer(&dir)
+        .arg(&input)
+        .arg("--output")
+        .arg(dir.join("{stem}-composed.gif"))
+        .args(["--cpu", "--progress", "none", "--compose-with"])
+        .arg(&overlay)
+        .output()
+        .unwrap();
+    let stderr = String::from_utf8_lossy(&output.stderr);
+
+    assert!(!output.status.success());
+    assert!(
+        stderr.contains("--compose-with can't be used with animations"),
+        "{}",
+        stderr
+    );
+    assert!(!dir.join("input-composed.gif").exists());
+
+    fs::remove_dir_all(&dir).unwrap();
+}
@@ -0,0 +1,83 @@
+mod common;
+
+use std::sync::Mutex;
+
+use image::{DynamicImage, Rgba, RgbaImage};
+use image_colorizer::{colorize_image, ColorizeOptions, DitherMode, Gpu};
+
+use common::{assert_close, gpu_or_skip, palette};
+
+/// Sizes below the 16x16 workgroup size of the shaders, down to a single pixel
+const SIZES: [(u32, u32); 5] = [(1, 1), (1, 7), (7, 1), (3, 5), (15, 15)];
+
+fn image(width: u32, height: u32) -> DynamicImage {
+    RgbaImage::from_fn(width, height, |x, y| {
+        Rgba([
+            (x * 17) as u8,
+            (y * 37) as u8,
+            128,
+            if x == 0 { 0 } else { 255 },
+        ])
+    })
+    .into()
+}
+
+fn cases() -> Vec<ColorizeOptions> {
+    vec![
+        ColorizeOptions::default(),
+        ColorizeOptions {
+            dither_mode: DitherMode::FloydSteinberg,
+            sharpen: 0.5,
+            ..ColorizeOptions::default()
+        },
+        ColorizeOptions {
+            spatial_averaging_radius: 0,
+            ..ColorizeOptions::default()
+        },
+    ]
+}
+
+/// Colorizes `img`, checking that the progress callback ends on its last step
+async fn colorize(img: &DynamicImage, options: &ColorizeOptions, gpu: Option<&Gpu>) -> RgbaImage {
+    let last = Mutex::new(None);
+    let progress = |position, length| *last.lock().unwrap() = Some((position, length));
+
+    let colorized = colorize_image(img, &palette(), options, gpu, Some(&progress))
+        .await
+        .unwrap()
+        .to_rgba8();
+
+    let (position, length) = last.into_inner().unwrap().unwrap();
+    assert!(length > 0);
+    assert_eq!(position, length, "{}x{}", img.width(), img.height());
+    colorized
+}
+
+#[tokio::test]
+async fn cpu_colorizes_images_smaller_than_a_workgroup() {
+    for (width, height) in SIZES {
+        let img = image(width, height);
+        for options in cases() {
+            let colorized = colorize(&img, &options, None).await;
+            assert_eq!(colorized.dimensions(), (width, height));
+            assert_eq!(colorized.get_pixel(0, 0)[3], 0);
+        }
+    }
+}
+
+#[tokio::test]
+async fn gpu_colorizes_images_smaller_than_a_workgroup_like_the_cpu() {
+    let Some(gpu) = gpu_or_skip().await else {
+        return;
+    };
+
+    for (width, height) in SIZES {
+        let img = image(width, height);
+        for options in cases() {
+            let cpu = colorize(&img, &options, None).await;
+            let colorized = colorize(&img, &options, Some(&gpu)).await;
+            assert_eq!(colorized.dimensions(), (width, height));
+            assert_close(&cpu, &colorized, 2);
+        }
+    }
+}
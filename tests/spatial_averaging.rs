@@ -0,0 +1,179 @@
+mod common;
+
+use std::time::{Duration, Instant};
+
+use image::{DynamicImage, Rgb, RgbImage, Rgba, RgbaImage};
+use image_colorizer::{colorize_image_with_timings, ColorizeOptions, Gpu};
+use palette::{FromColor, Lab, Srgb};
+
+use common::{assert_close, gpu_or_skip, palette};
+
+fn image(size: u32) -> DynamicImage {
+    RgbImage::from_fn(size, size, |x, y| {
+        Rgb([(x % 256) as u8, (y % 256) as u8, ((x + y) % 256) as u8])
+    })
+    .into()
+}
+
+fn options(spatial_averaging_radius: u32) -> ColorizeOptions {
+    ColorizeOptions {
+        spatial_averaging_radius,
+        ..ColorizeOptions::default()
+    }
+}
+
+#[tokio::test]
+async fn radius_of_zero_skips_the_integral_image_and_second_pass() {
+    let img = image(64);
+
+    let (_, timings) = colorize_image_with_timings(&img, &palette(), &options(0), None, None)
+        .await
+        .unwrap();
+    assert_eq!(timings.integral_image, Duration::ZERO);
+    assert_eq!(timings.spatial_averaging_pass, Duration::ZERO);
+
+    let (_, timings) = colorize_image_with_timings(&img, &palette(), &options(10), None, None)
+        .await
+        .unwrap();
+    assert!(timings.integral_image > Duration::ZERO);
+}
+
+#[tokio::test]
+async fn gpu_averages_like_the_cpu_integral_image() {
+    let Some(gpu) = gpu_or_skip().await else {
+        return;
+    };
+    let img = image(96);
+
+    let (cpu, _) = colorize_image_with_timings(&img, &palette(), &options(10), None, None)
+        .await
+        .unwrap();
+    let (colorized, timings) =
+        colorize_image_with_timings(&img, &palette(), &options(10), Some(&gpu), None)
+            .await
+            .unwrap();
+    assert_eq!(timings.integral_image, Duration::ZERO);
+
+    assert_close(&cpu.to_rgb8(), &colorized.to_rgb8(), 1);
+}
+
+#[tokio::test]
+async fn sharpening_by_one_undoes_averaging() {
+    let img = image(64);
+    let sharpened = ColorizeOptions {
+        sharpen: 1.0,
+        ..options(10)
+    };
+
+    let (unaveraged, _) = colorize_image_with_timings(&img, &palette(), &options(0), None, None)
+        .await
+        .unwrap();
+    let (colorized, _) = colorize_image_with_timings(&img, &palette(), &sharpened, None, None)
+        .await
+        .unwrap();
+    assert_eq!(colorized.to_rgb8(), unaveraged.to_rgb8());
+}
+
+#[tokio::test]
+async fn gpu_sharpens_like_the_cpu() {
+    let Some(gpu) = gpu_or_skip().await else {
+        return;
+    };
+    let img = image(96);
+    let sharpened = ColorizeOptions {
+        sharpen: 2.5,
+        ..options(10)
+    };
+
+    let (cpu, _) = colorize_image_with_timings(&img, &palette(), &sharpened, None, None)
+        .await
+        .unwrap();
+    let (colorized, _) =
+        colorize_image_with_timings(&img, &palette(), &sharpened, Some(&gpu), None)
+            .await
+            .unwrap();
+
+    // Sharpening amplifies the rounding differences between the GPU and CPU along with the detail
+    assert_close(&cpu.to_rgb8(), &colorized.to_rgb8(), 4);
+}
+
+/// A red square on a transparent background, whose hidden color is green so that it would tint
+/// the edges of the square if it were averaged
+fn sprite() -> DynamicImage {
+    RgbaImage::from_fn(32, 32, |x, y| {
+        if (8..24).contains(&x) && (8..24).contains(&y) {
+            Rgba([200, 40, 40, 255])
+        } else {
+            Rgba([0, 255, 0, 0])
+        }
+    })
+    .into()
+}
+
+async fn assert_sprite_edges_stay_clean(gpu: Option<&Gpu>) {
+    let palette: Vec<Lab> = [(0.8, 0.2, 0.2), (0.2, 0.8, 0.2)]
+        .iter()
+        .map(|&(r, g, b)| Lab::from_color(Srgb::new(r, g, b)))
+        .collect();
+    let options = ColorizeOptions {
+        dither_amount: 0.0,
+        ..options(6)
+    };
+
+    let (colorized, _) = colorize_image_with_timings(&sprite(), &palette, &options, gpu, None)
+        .await
+        .unwrap();
+    let colorized = colorized.to_rgba8();
+
+    let center = colorized.get_pixel(16, 16);
+    for (x, y) in [(8, 8), (8, 16), (23, 16), (16, 23), (23, 23)] {
+        let edge = colorized.get_pixel(x, y);
+        for c in 0..4 {
+            assert!(
+                center[c].abs_diff(edge[c]) <= 1,
+                "{:?} at ({}, {}) != {:?}",
+                edge,
+                x,
+                y,
+                center
+            );
+        }
+    }
+}
+
+#[tokio::test]
+async fn transparent_pixels_are_left_out_of_averages() {
+    assert_sprite_edges_stay_clean(None).await;
+}
+
+#[tokio::test]
+async fn gpu_leaves_transparent_pixels_out_of_averages() {
+    let Some(gpu) = gpu_or_skip().await else {
+        return;
+    };
+
+    assert_sprite_edges_stay_clean(Some(&gpu)).await;
+}
+
+/// Compares colorizing with and without spatial averaging, which costs a second GPU pass
+#[tokio::test]
+#[ignore = "benchmark that needs a GPU adapter, run with `cargo test -- --ignored`"]
+async fn radius_of_zero_is_faster_on_the_gpu() {
+    let Some(gpu) = gpu_or_skip().await else {
+        return;
+    };
+    let img = image(1024);
+    let palette = palette();
+
+    let mut elapsed = Vec::new();
+    for radius in [10, 0] {
+        let start = Instant::now();
+        colorize_image_with_timings(&img, &palette, &options(radius), Some(&gpu), None)
+            .await
+            .unwrap();
+        elapsed.push(start.elapsed());
+    }
+
+    println!("Radius 10: {:?}, radius 0: {:?}", elapsed[0], elapsed[1]);
+    assert!(elapsed[1] * 3 < elapsed[0] * 2);
+}
@@ -0,0 +1,68 @@
+mod common;
+
+use std::fs;
+use std::path::PathBuf;
+
+use futures::StreamExt;
+use image::{Rgb, RgbImage};
+use image_colorizer::{colorize_batch, ColorizeOptions};
+
+use common::{palette, temp_dir};
+
+fn save_gradient(path: &PathBuf, width: u32, height: u32) {
+    RgbImage::from_fn(width, height, |x, y| {
+        Rgb([(x % 256) as u8, (y % 256) as u8, 90])
+    })
+    .save(path)
+    .unwrap();
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn colorize_batch_yields_images_as_they_finish() {
+    let dir = temp_dir("stream-order");
+    let large = dir.join("large.png");
+    save_gradient(&large, 1024, 1024);
+    let small = dir.join("small.png");
+    save_gradient(&small, 8, 8);
+
+    // The large image takes long enough on the CPU that the small one, given to the batch after
+    // it, is always done first
+    let options = ColorizeOptions::default();
+    let results: Vec<_> =
+        colorize_batch([large.clone(), small.clone()], palette(), options, None, 2)
+            .map(|result| {
+                let (path, colorized) = result.unwrap();
+                (path, colorized.dimensions())
+            })
+            .collect()
+            .await;
+
+    assert_eq!(results, [(small, (8, 8)), (large, (1024, 1024))]);
+
+    fs::remove_dir_all(&dir).unwrap();
+}
+
+#[tokio::test]
+async fn colorize_batch_errors_name_the_failed_image() {
+    let dir = temp_dir("stream-error");
+    let good = dir.join("good.png");
+    save_gradient(&good, 8, 8);
+    let bad = dir.join("bad.png");
+    fs::write(&bad, "not an image").unwrap();
+
+    let options = ColorizeOptions::default();
+    let results: Vec<_> = colorize_batch([bad.clone(), good.clone()], palette(), options, None, 1)
+        .collect()
+        .await;
+
+    assert_eq!(results.len(), 2);
+    let error = results[0].as_ref().unwrap_err();
+    assert!(
+        format!("{:#}", error).contains(&bad.display().to_string()),
+        "{:#}",
+        error
+    );
+    assert_eq!(results[1].as_ref().unwrap().0, good);
+
+    fs::remove_dir_all(&dir).unwrap();
+}